@@ -0,0 +1,668 @@
+//! The `/_conduit/admin` endpoints. They can only be used by server admins and are mounted
+//! separately from the Matrix endpoints.
+
+use crate::{client_server, pdu::PduBuilder, utils, Database, Error, Result, Ruma};
+use rocket::{response::content::Json, State};
+use ruma::{
+    api::client::{error::ErrorKind, r0::account::whoami},
+    events::{room::redaction, EventType},
+    EventId, RoomId, UserId,
+};
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+
+#[cfg(feature = "conduit_bin")]
+use rocket::{get, post, put, routes};
+
+const DEFAULT_REPORTS_LIMIT: usize = 100;
+const MAX_REPORTS_LIMIT: usize = 1000;
+
+/// Returns the admin endpoints with paths relative to `/_conduit/admin`.
+#[cfg(feature = "conduit_bin")]
+pub fn routes() -> Vec<rocket::Route> {
+    routes![
+        list_users_route,
+        create_user_route,
+        set_admin_route,
+        deactivate_user_route,
+        reset_password_route,
+        get_room_state_route,
+        redact_event_route,
+        purge_room_route,
+        send_server_notice_route,
+        list_reports_route,
+        list_destinations_route,
+        rotate_signing_key_route,
+        create_registration_token_route,
+    ]
+}
+
+/// # `GET /_conduit/admin/users`
+///
+/// Lists all accounts of this server.
+///
+/// - The admin endpoints don't have ruma types, so their requests are parsed as
+/// [`GET /_matrix/client/r0/account/whoami`](../client_server/fn.whoami_route.html), which only
+/// checks the access token
+#[cfg_attr(feature = "conduit_bin", get("/users", data = "<body>"))]
+pub fn list_users_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+) -> Result<Json<String>> {
+    check_admin(&db, &body)?;
+
+    let users = db
+        .users
+        .iter()
+        .filter_map(|r| r.ok())
+        .map(|user_id| {
+            Ok(json!({
+                "user_id": user_id,
+                "displayname": db.users.displayname(&user_id)?,
+                "admin": db.users.is_admin(&user_id)?,
+                "guest": db.users.is_guest(&user_id)?,
+                "deactivated": db.users.is_deactivated(&user_id)?,
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Json(json!({ "users": users }).to_string()))
+}
+
+/// # `POST /_conduit/admin/users`
+///
+/// Creates a local account, even if registration is disabled.
+///
+/// - The body has the `username` and `password` of the account, and optionally its
+/// `displayname` and whether it is an `admin`
+#[cfg_attr(feature = "conduit_bin", post("/users", data = "<body>"))]
+pub fn create_user_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+) -> Result<Json<String>> {
+    check_admin(&db, &body)?;
+
+    let request = json_body(&body)?;
+    let field = |name: &str| request.get(name).and_then(|value| value.as_str());
+
+    let username = field("username").ok_or(Error::BadRequest(
+        ErrorKind::MissingParam,
+        "Missing username.",
+    ))?;
+    let password = field("password").ok_or(Error::BadRequest(
+        ErrorKind::MissingParam,
+        "Missing password.",
+    ))?;
+
+    let user_id = UserId::parse_with_server_name(username.to_lowercase(), db.globals.server_name())
+        .ok()
+        .filter(|user_id| {
+            !user_id.is_historical() && user_id.server_name() == db.globals.server_name()
+        })
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidUsername,
+            "Username is invalid.",
+        ))?;
+
+    if db.users.exists(&user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::UserInUse,
+            "Desired user ID is already taken.",
+        ));
+    }
+
+    client_server::create_local_account(
+        &db,
+        &user_id,
+        password,
+        field("displayname").map(str::to_owned),
+        request
+            .get("admin")
+            .and_then(|admin| admin.as_bool())
+            .unwrap_or(false),
+    )?;
+
+    Ok(Json(json!({ "user_id": user_id }).to_string()))
+}
+
+/// # `PUT /_conduit/admin/users/{userId}/admin`
+///
+/// Makes a local account a server admin or takes the flag away, depending on `admin` in the body.
+///
+/// - Admins can't change their own flag, so the server doesn't lose its last admin by accident
+#[cfg_attr(
+    feature = "conduit_bin",
+    put("/users/<user_id>/admin", data = "<body>")
+)]
+pub fn set_admin_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+    user_id: String,
+) -> Result<Json<String>> {
+    let sender_id = check_admin(&db, &body)?;
+    let user_id = local_user(&db, &user_id)?;
+
+    let admin = json_body(&body)?
+        .get("admin")
+        .and_then(|admin| admin.as_bool())
+        .ok_or(Error::BadRequest(ErrorKind::MissingParam, "Missing admin."))?;
+
+    set_admin(&db, sender_id, &user_id, admin)?;
+
+    Ok(Json(json!({}).to_string()))
+}
+
+/// Changes the admin flag of a user. Admins can't change their own flag, so the last admin can't
+/// lock everyone out.
+fn set_admin(db: &Database<'_>, sender_id: &UserId, user_id: &UserId, admin: bool) -> Result<()> {
+    if user_id == sender_id {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You can't change your own admin flag.",
+        ));
+    }
+
+    if admin {
+        db.users.make_admin(user_id)
+    } else {
+        db.users.remove_admin(user_id)
+    }
+}
+
+/// # `POST /_conduit/admin/users/{userId}/deactivate`
+///
+/// Deactivates a local account like [`POST /_matrix/client/r0/account/deactivate`](../client_server/fn.deactivate_route.html).
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/users/<user_id>/deactivate", data = "<body>")
+)]
+pub fn deactivate_user_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+    user_id: String,
+) -> Result<Json<String>> {
+    check_admin(&db, &body)?;
+    let user_id = local_user(&db, &user_id)?;
+
+    if db.users.is_deactivated(&user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "User is already deactivated.",
+        ));
+    }
+
+    client_server::deactivate_account(&db, &user_id)?;
+
+    Ok(Json(json!({}).to_string()))
+}
+
+/// # `POST /_conduit/admin/users/{userId}/password`
+///
+/// Sets a new password for a local account.
+///
+/// - All devices of the user are logged out, unless `logout_devices` is false
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/users/<user_id>/password", data = "<body>")
+)]
+pub fn reset_password_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+    user_id: String,
+) -> Result<Json<String>> {
+    check_admin(&db, &body)?;
+    let user_id = local_user(&db, &user_id)?;
+
+    if db.users.is_deactivated(&user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::UserDeactivated,
+            "User is deactivated.",
+        ));
+    }
+
+    let request = json_body(&body)?;
+    let new_password = request
+        .get("new_password")
+        .and_then(|password| password.as_str())
+        .ok_or(Error::BadRequest(
+            ErrorKind::MissingParam,
+            "Missing new_password.",
+        ))?;
+
+    db.users.set_password(&user_id, new_password)?;
+
+    if request
+        .get("logout_devices")
+        .and_then(|logout| logout.as_bool())
+        .unwrap_or(true)
+    {
+        for device_id in db.users.all_device_ids(&user_id).filter_map(|r| r.ok()) {
+            db.users.remove_device(&user_id, &device_id)?;
+            db.pushers.remove_device_pushers(&user_id, &device_id)?;
+        }
+    }
+
+    Ok(Json(json!({}).to_string()))
+}
+
+/// # `GET /_conduit/admin/rooms/{roomId}/state`
+///
+/// Returns the current state of a room, even if the admin is not in it.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/rooms/<room_id>/state", data = "<body>")
+)]
+pub fn get_room_state_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+    room_id: String,
+) -> Result<Json<String>> {
+    check_admin(&db, &body)?;
+    let room_id = known_room(&db, &room_id)?;
+
+    let state = db
+        .rooms
+        .room_state_full(&room_id)?
+        .values()
+        .map(|pdu| pdu.to_state_event())
+        .collect::<Vec<_>>();
+
+    Ok(Json(json!({ "state": state }).to_string()))
+}
+
+/// # `POST /_conduit/admin/rooms/{roomId}/redact/{eventId}`
+///
+/// Redacts an event of a room, for example a state event with abusive content.
+///
+/// - The redaction is sent by the admin, so they need to be allowed to redact in the room
+/// - `reason` in the body is the reason of the redaction
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/rooms/<room_id>/redact/<event_id>", data = "<body>")
+)]
+pub fn redact_event_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+    room_id: String,
+    event_id: String,
+) -> Result<Json<String>> {
+    let sender_id = check_admin(&db, &body)?;
+    let room_id = known_room(&db, &room_id)?;
+    let event_id = EventId::try_from(event_id)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid event id."))?;
+
+    if db
+        .rooms
+        .get_pdu(&event_id)?
+        .map_or(true, |pdu| pdu.room_id != room_id)
+    {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Event not found."));
+    }
+
+    let reason = json_body(&body)
+        .ok()
+        .and_then(|request| request.get("reason")?.as_str().map(str::to_owned));
+
+    let redaction_id = db.rooms.append_pdu(
+        PduBuilder {
+            room_id,
+            sender: sender_id.clone(),
+            event_type: EventType::RoomRedaction,
+            content: serde_json::to_value(redaction::RedactionEventContent { reason })
+                .expect("event is valid, we just created it"),
+            unsigned: None,
+            state_key: None,
+            redacts: Some(event_id),
+        },
+        &db.globals,
+        &db.account_data,
+    )?;
+
+    Ok(Json(json!({ "event_id": redaction_id }).to_string()))
+}
+
+/// # `POST /_conduit/admin/rooms/{roomId}/purge`
+///
+/// Removes a room and all of its events from this server.
+///
+/// - Local users leave the room first
+/// - Other servers keep their copy of the room
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/rooms/<room_id>/purge", data = "<body>")
+)]
+pub fn purge_room_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+    room_id: String,
+) -> Result<Json<String>> {
+    check_admin(&db, &body)?;
+    let room_id = known_room(&db, &room_id)?;
+
+    db.rooms
+        .purge_room(&room_id, &db.globals, &db.account_data)?;
+
+    Ok(Json(json!({}).to_string()))
+}
+
+/// # `POST /_conduit/admin/server_notices`
+///
+/// Sends a message from the server notices user to a local user, see
+/// [`Database::send_server_notice`](../struct.Database.html#method.send_server_notice).
+///
+/// - The body has the `user_id` of the user and the `content` of the `m.room.message` event
+#[cfg_attr(feature = "conduit_bin", post("/server_notices", data = "<body>"))]
+pub fn send_server_notice_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+) -> Result<Json<String>> {
+    check_admin(&db, &body)?;
+
+    if db.globals.server_notices_user().is_none() {
+        return Err(Error::BadRequest(
+            ErrorKind::Unknown,
+            "Server notices are disabled.",
+        ));
+    }
+
+    let request = json_body(&body)?;
+    let user_id = request
+        .get("user_id")
+        .and_then(|user_id| user_id.as_str())
+        .ok_or(Error::BadRequest(
+            ErrorKind::MissingParam,
+            "Missing user_id.",
+        ))?;
+    let user_id = local_user(&db, user_id)?;
+    let content = request
+        .get("content")
+        .filter(|content| content.get("msgtype").is_some() && content.get("body").is_some())
+        .ok_or(Error::BadRequest(
+            ErrorKind::BadJson,
+            "The content needs a msgtype and a body.",
+        ))?;
+
+    let event_id = db.send_server_notice(&user_id, content.clone())?;
+
+    Ok(Json(json!({ "event_id": event_id }).to_string()))
+}
+
+/// # `GET /_conduit/admin/reports`
+///
+/// Lists the events users reported with [`POST /_matrix/client/r0/rooms/{roomId}/report/{eventId}`](../client_server/fn.report_event_route.html),
+/// oldest first.
+///
+/// - Every report contains the reporter, the room, the event and its sender, the score, the
+/// reason and when it was received
+/// - `next_batch` can be passed as `since` to get the next reports, it is missing after the
+/// newest report
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/reports?<since>&<limit>", data = "<body>")
+)]
+pub fn list_reports_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+    since: Option<u64>,
+    limit: Option<usize>,
+) -> Result<Json<String>> {
+    check_admin(&db, &body)?;
+
+    let limit = limit
+        .unwrap_or(DEFAULT_REPORTS_LIMIT)
+        .max(1)
+        .min(MAX_REPORTS_LIMIT);
+
+    let mut reports = db
+        .reports
+        .since(since.unwrap_or(0))
+        .take(limit + 1)
+        .collect::<Result<Vec<_>>>()?;
+
+    let next_batch = if reports.len() > limit {
+        reports.truncate(limit);
+        reports.last().map(|(report_id, _)| report_id.to_string())
+    } else {
+        None
+    };
+
+    let reports = reports
+        .into_iter()
+        .map(|(report_id, report)| {
+            let mut report = serde_json::to_value(report).expect("ContentReport can be serialized");
+            report["report_id"] = report_id.to_string().into();
+            report
+        })
+        .collect::<Vec<_>>();
+
+    Ok(Json(
+        json!({
+            "reports": reports,
+            "next_batch": next_batch,
+            "total": db.reports.count(),
+        })
+        .to_string(),
+    ))
+}
+
+/// # `GET /_conduit/admin/destinations`
+///
+/// Lists the other servers that didn't accept the last transaction, so their events are queued.
+///
+/// - Every server has the number of failed transactions in a row, when it is tried again and how
+/// many events are queued for it
+/// - `failures` is 0 if the server could be contacted again and is sent to with the next event
+#[cfg_attr(feature = "conduit_bin", get("/destinations", data = "<body>"))]
+pub fn list_destinations_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+) -> Result<Json<String>> {
+    check_admin(&db, &body)?;
+
+    let destinations = db
+        .outgoing
+        .destinations()
+        .map(|r| {
+            let (destination, state) = r?;
+            Ok(json!({
+                "destination": destination,
+                "failures": state.failures,
+                "next_retry_ts": state.next_retry_ts,
+                "queued": db.outgoing.queue_len(&destination),
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Json(json!({ "destinations": destinations }).to_string()))
+}
+
+/// # `POST /_conduit/admin/signing_key/rotate`
+///
+/// Replaces the signing key of this server with a newly generated one and returns its key id.
+///
+/// - The old public key is still published, so signatures made before the rotation stay valid
+/// - Other servers only see the new key after their cached keys of this server expired
+#[cfg_attr(feature = "conduit_bin", post("/signing_key/rotate", data = "<body>"))]
+pub fn rotate_signing_key_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+) -> Result<Json<String>> {
+    check_admin(&db, &body)?;
+
+    let key_version = db.globals.rotate_signing_key()?;
+
+    Ok(Json(
+        json!({ "key_id": format!("ed25519:{}", key_version) }).to_string(),
+    ))
+}
+
+/// # `POST /_conduit/admin/registration_tokens`
+///
+/// Creates a token that allows registering an account even if registration is disabled.
+///
+/// - `token` in the body is the token, a random one is generated if it is missing
+/// - `uses_allowed` and `expiry_time` (millis since the unix epoch) are unlimited if they are
+/// missing
+#[cfg_attr(feature = "conduit_bin", post("/registration_tokens", data = "<body>"))]
+pub fn create_registration_token_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+) -> Result<Json<String>> {
+    check_admin(&db, &body)?;
+
+    let request = json_body(&body)?;
+
+    let token = match request.get("token") {
+        Some(token) => token
+            .as_str()
+            .filter(|token| {
+                !token.is_empty()
+                    && token.len() <= 64
+                    && token
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "._~-".contains(c))
+            })
+            .ok_or(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Tokens can only have up to 64 letters, digits and the characters ._~-",
+            ))?
+            .to_owned(),
+        None => utils::random_string(16),
+    };
+    let number = |name: &str| match request.get(name) {
+        Some(value) => value.as_u64().map(Some).ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "uses_allowed and expiry_time have to be positive integers.",
+        )),
+        None => Ok(None),
+    };
+    let uses_allowed = number("uses_allowed")?;
+    let expiry_time = number("expiry_time")?;
+
+    if !db
+        .users
+        .create_registration_token(&token, uses_allowed, expiry_time)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "The token already exists.",
+        ));
+    }
+
+    Ok(Json(
+        json!({
+            "token": token,
+            "uses_allowed": uses_allowed,
+            "expiry_time": expiry_time,
+        })
+        .to_string(),
+    ))
+}
+
+/// Returns the user of the request if they are a server admin.
+fn check_admin<'a>(db: &Database<'_>, body: &'a Ruma<whoami::Request>) -> Result<&'a UserId> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    if !db.users.is_admin(sender_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You are not a server admin.",
+        ));
+    }
+
+    Ok(sender_id)
+}
+
+/// Parses the user id of a path and checks that the user has an account on this server.
+fn local_user(db: &Database<'_>, user_id: &str) -> Result<UserId> {
+    match UserId::try_from(user_id) {
+        Ok(user_id)
+            if user_id.server_name() == db.globals.server_name()
+                && db.users.exists(&user_id)? =>
+        {
+            Ok(user_id)
+        }
+        _ => Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "User does not exist.",
+        )),
+    }
+}
+
+/// Parses the room id of a path and checks that this server knows the room.
+fn known_room(db: &Database<'_>, room_id: &str) -> Result<RoomId> {
+    match RoomId::try_from(room_id) {
+        Ok(room_id) if db.rooms.exists(&room_id)? => Ok(room_id),
+        _ => Err(Error::BadRequest(ErrorKind::NotFound, "Unknown room.")),
+    }
+}
+
+fn json_body(body: &Ruma<whoami::Request>) -> Result<Value> {
+    serde_json::from_str(
+        body.json_body
+            .as_ref()
+            .ok_or(Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?
+            .get(),
+    )
+    .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(sender_id: &UserId) -> Ruma<whoami::Request> {
+        Ruma {
+            body: whoami::Request {},
+            sender_id: Some(sender_id.clone()),
+            device_id: None,
+            json_body: None,
+            client_ip: None,
+            appservice_id: None,
+        }
+    }
+
+    #[test]
+    fn only_admins_pass_the_admin_check() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let db = Database::load_temporary(Vec::new());
+            let alice = UserId::try_from("@alice:example.com").unwrap();
+            let bob = UserId::try_from("@bob:example.com").unwrap();
+            db.users.create(&alice, "password").unwrap();
+            db.users.create(&bob, "password").unwrap();
+            db.users.make_admin(&alice).unwrap();
+
+            let body = request(&alice);
+            assert_eq!(check_admin(&db, &body).unwrap(), &alice);
+            assert!(matches!(
+                check_admin(&db, &request(&bob)),
+                Err(Error::BadRequest(ErrorKind::Forbidden, _))
+            ));
+        });
+    }
+
+    #[test]
+    fn admins_cant_change_their_own_admin_flag() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let db = Database::load_temporary(Vec::new());
+            let alice = UserId::try_from("@alice:example.com").unwrap();
+            let bob = UserId::try_from("@bob:example.com").unwrap();
+            db.users.create(&alice, "password").unwrap();
+            db.users.create(&bob, "password").unwrap();
+            db.users.make_admin(&alice).unwrap();
+
+            assert!(matches!(
+                set_admin(&db, &alice, &alice, false),
+                Err(Error::BadRequest(ErrorKind::Forbidden, _))
+            ));
+            assert!(db.users.is_admin(&alice).unwrap());
+
+            set_admin(&db, &alice, &bob, true).unwrap();
+            assert!(db.users.is_admin(&bob).unwrap());
+            set_admin(&db, &alice, &bob, false).unwrap();
+            assert!(!db.users.is_admin(&bob).unwrap());
+        });
+    }
+}
@@ -1,5 +1,12 @@
-use super::{State, DEVICE_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH};
-use crate::{pdu::PduBuilder, utils, ConduitResult, Database, Error, Ruma};
+use super::{
+    create_refresh_token, join_room_by_id_helper, remote_alias, set_access_token_expiry,
+    update_member_events, wants_refresh_token, State, DEVICE_ID_LENGTH, SESSION_ID_LENGTH,
+    TOKEN_LENGTH,
+};
+use crate::{
+    database::rate_limiter::RateLimitClass, pdu::PduBuilder, ruma_wrapper::ClientIp, utils,
+    ConduitResult, Database, Error, Ruma,
+};
 use ruma::{
     api::client::{
         error::ErrorKind,
@@ -8,24 +15,48 @@ use ruma::{
                 change_password, deactivate, get_username_availability, register, whoami,
                 ThirdPartyIdRemovalStatus,
             },
-            uiaa::{AuthFlow, UiaaInfo},
+            uiaa::{AuthData, AuthFlow, UiaaInfo},
         },
     },
-    events::{room::member, EventType},
-    UserId,
+    events::{
+        room::{member, redaction},
+        EventType,
+    },
+    DeviceId, RoomId, RoomIdOrAliasId, ServerName, UserId,
 };
 
 use register::RegistrationKind;
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, post};
+use rocket::{response::content::Json, tokio::io::AsyncReadExt, Data};
+use serde::Deserialize;
+use serde_json::json;
+use std::convert::TryFrom;
+use tracing::warn;
 
 const GUEST_NAME_LENGTH: usize = 10;
+const GUEST_PASSWORD_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 32;
+
+#[derive(Deserialize)]
+struct SharedSecretRegistration {
+    nonce: String,
+    username: String,
+    password: String,
+    #[serde(default)]
+    admin: bool,
+    mac: String,
+    displayname: Option<String>,
+}
 
 /// # `GET /_matrix/client/r0/register/available`
 ///
 /// Checks if a username is valid and available on this server.
 ///
-/// - Returns true if no user or appservice on this server claimed this username
+/// - Returns true if no user on this server has this username and it is not reserved by the
+/// `reserved_usernames` config
+/// - Deactivated accounts keep their username, so it can't be used again
+/// - Fails with `M_FORBIDDEN` if registration is disabled
 /// - This will not reserve the username, so the username might become invalid when trying to register
 #[cfg_attr(
     feature = "conduit_bin",
@@ -35,16 +66,23 @@ pub fn get_register_available_route(
     db: State<'_, Database<'_>>,
     body: Ruma<get_username_availability::Request>,
 ) -> ConduitResult<get_username_availability::Response> {
+    check_username_available(&db, body.username.clone())?;
+
+    Ok(get_username_availability::Response { available: true }.into())
+}
+
+/// Checks if a new account can be registered with the username, see
+/// [`get_register_available_route`](fn.get_register_available_route.html).
+fn check_username_available(db: &Database<'_>, username: String) -> Result<(), Error> {
+    if db.globals.registration_disabled() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Registration has been disabled.",
+        ));
+    }
+
     // Validate user id
-    let user_id = UserId::parse_with_server_name(body.username.clone(), db.globals.server_name())
-        .ok()
-        .filter(|user_id| {
-            !user_id.is_historical() && user_id.server_name() == db.globals.server_name()
-        })
-        .ok_or(Error::BadRequest(
-            ErrorKind::InvalidUsername,
-            "Username is invalid.",
-        ))?;
+    let user_id = parse_new_username(username, db.globals.server_name())?;
 
     // Check if username is creative enough
     if db.users.exists(&user_id)? {
@@ -54,10 +92,31 @@ pub fn get_register_available_route(
         ));
     }
 
-    // TODO add check for appservice namespaces
+    if db.globals.is_reserved_username(user_id.localpart()) {
+        return Err(Error::BadRequest(
+            ErrorKind::Exclusive,
+            "Desired user ID is reserved.",
+        ));
+    }
 
     // If no if check is true we have an username that's available to be used.
-    Ok(get_username_availability::Response { available: true }.into())
+    Ok(())
+}
+
+/// Parses the username of a new account on this server. Historical user ids, for example with
+/// uppercase letters, and empty usernames can't be registered anymore.
+fn parse_new_username(username: String, server_name: &ServerName) -> Result<UserId, Error> {
+    UserId::parse_with_server_name(username, server_name)
+        .ok()
+        .filter(|user_id| {
+            !user_id.is_historical()
+                && !user_id.localpart().is_empty()
+                && user_id.server_name() == server_name
+        })
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidUsername,
+            "Username is invalid.",
+        ))
 }
 
 /// # `POST /_matrix/client/r0/register`
@@ -69,27 +128,50 @@ pub fn get_register_available_route(
 /// ignored
 /// - Creates a new account and a device for it
 /// - The account will be populated with default account data
+/// - Appservices can register users of their namespaces without authentication
+/// - With `refresh_token: true`, the access token expires and the client gets a refresh token
+/// - The user joins the `auto_join_rooms` of the config, guests only with `auto_join_guests`
+/// and users of appservices never
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/register", data = "<body>")
 )]
-pub fn register_route(
+pub async fn register_route(
     db: State<'_, Database<'_>>,
     body: Ruma<register::Request>,
-) -> ConduitResult<register::Response> {
-    if db.globals.registration_disabled() {
+) -> Result<Json<String>, Error> {
+    let appservice = body
+        .appservice_id
+        .as_deref()
+        .and_then(|id| db.globals.appservice(id));
+
+    if appservice.map_or(true, |appservice| appservice.rate_limited) {
+        db.globals
+            .rate_limiter()
+            .check_ip(RateLimitClass::Register, body.client_ip)?;
+    }
+
+    let is_guest = matches!(body.kind, Some(RegistrationKind::Guest));
+
+    if is_guest && !db.globals.allow_guests() {
+        return Err(Error::BadRequest(
+            ErrorKind::GuestAccessForbidden,
+            "Guest registration is disabled.",
+        ));
+    }
+
+    // If registration is disabled, users can still register with a registration token
+    if db.globals.registration_disabled() && is_guest {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
             "Registration has been disabled.",
         ));
     }
 
-    let is_guest = matches!(body.kind, Some(RegistrationKind::Guest));
-
     let mut missing_username = false;
 
     // Validate user id
-    let user_id = UserId::parse_with_server_name(
+    let user_id = parse_new_username(
         if is_guest {
             utils::random_string(GUEST_NAME_LENGTH)
         } else {
@@ -103,13 +185,7 @@ pub fn register_route(
         }
         .to_lowercase(),
         db.globals.server_name(),
-    )
-    .ok()
-    .filter(|user_id| !user_id.is_historical() && user_id.server_name() == db.globals.server_name())
-    .ok_or(Error::BadRequest(
-        ErrorKind::InvalidUsername,
-        "Username is invalid.",
-    ))?;
+    )?;
 
     // Check if username is creative enough
     if !missing_username && db.users.exists(&user_id)? {
@@ -119,21 +195,131 @@ pub fn register_route(
         ));
     }
 
+    if let Some(appservice) = appservice {
+        if !missing_username && !appservice.is_user_match(&user_id) {
+            return Err(Error::BadRequest(
+                ErrorKind::Exclusive,
+                "Appservices can only register users in their namespaces.",
+            ));
+        }
+    } else if !missing_username && !is_guest && db.globals.is_reserved_username(user_id.localpart())
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Exclusive,
+            "Desired user ID is reserved.",
+        ));
+    }
+
     // UIAA
+    let mut stages = Vec::new();
+    let mut params = serde_json::Map::new();
+    if let Some(public_key) = db.globals.recaptcha_public_key() {
+        if db.globals.recaptcha_private_key().is_some() {
+            stages.push("m.login.recaptcha".to_owned());
+            params.insert(
+                "m.login.recaptcha".to_owned(),
+                json!({ "public_key": public_key }),
+            );
+        }
+    }
+    if db.globals.registration_disabled() {
+        stages.push("m.login.registration_token".to_owned());
+    } else if stages.is_empty() {
+        stages.push("m.login.dummy".to_owned());
+    }
+
+    let mut flows = vec![AuthFlow {
+        stages: stages.clone(),
+    }];
+    if db.globals.smtp_server().is_some() {
+        // The email stage is always last, so the registration finishes in the same request
+        let mut email_stages = stages
+            .into_iter()
+            .filter(|stage| stage != "m.login.dummy")
+            .collect::<Vec<_>>();
+        email_stages.push("m.login.email.identity".to_owned());
+        flows.push(AuthFlow {
+            stages: email_stages,
+        });
+    }
+
+    let mut validated_email = None;
+
     let mut uiaainfo = UiaaInfo {
-        flows: vec![AuthFlow {
-            stages: vec!["m.login.dummy".to_owned()],
-        }],
+        flows,
         completed: Vec::new(),
-        params: Default::default(),
+        params: params.into(),
         session: None,
         auth_error: None,
     };
 
-    if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) =
-            db.uiaa
-                .try_auth(&user_id, "".into(), auth, &uiaainfo, &db.users, &db.globals)?;
+    if is_guest || appservice.is_some() {
+        // Guests and appservices don't have to authenticate
+    } else if let Some(auth) = &body.auth {
+        let (worked, uiaainfo) = match auth {
+            AuthData::DirectRequest {
+                kind,
+                session,
+                auth_parameters,
+            } if kind == "m.login.recaptcha" => {
+                let response = auth_parameters
+                    .get("response")
+                    .and_then(|response| response.as_str())
+                    .ok_or(Error::BadRequest(
+                        ErrorKind::MissingParam,
+                        "m.login.recaptcha needs a response.",
+                    ))?;
+
+                if !verify_recaptcha(&db, response).await? {
+                    uiaainfo.session = session.clone();
+                    uiaainfo.auth_error = Some(ruma::api::client::error::ErrorBody {
+                        kind: ErrorKind::Unauthorized,
+                        message: "Captcha response is invalid.".to_owned(),
+                    });
+                    return Err(Error::Uiaa(uiaainfo));
+                }
+
+                db.uiaa.complete_stage(
+                    &user_id,
+                    "".into(),
+                    session.as_deref(),
+                    "m.login.recaptcha",
+                    &uiaainfo,
+                )?
+            }
+            AuthData::DirectRequest {
+                kind,
+                session,
+                auth_parameters,
+            } if kind == "m.login.email.identity" => {
+                let (sid, client_secret) = threepid_creds(auth_parameters)?;
+
+                let email = match db.threepid_sessions.validated_email(sid, client_secret)? {
+                    Some(email) => email,
+                    None => {
+                        uiaainfo.session = session.clone();
+                        uiaainfo.auth_error = Some(ruma::api::client::error::ErrorBody {
+                            kind: ErrorKind::ThreepidAuthFailed,
+                            message: "Email address has not been validated.".to_owned(),
+                        });
+                        return Err(Error::Uiaa(uiaainfo));
+                    }
+                };
+
+                let result = db.uiaa.complete_stage(
+                    &user_id,
+                    "".into(),
+                    session.as_deref(),
+                    "m.login.email.identity",
+                    &uiaainfo,
+                )?;
+                validated_email = Some((sid.to_owned(), email));
+                result
+            }
+            _ => db
+                .uiaa
+                .try_auth(&user_id, "".into(), auth, &uiaainfo, &db.users, &db.globals)?,
+        };
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
@@ -151,8 +337,9 @@ pub fn register_route(
         ));
     }
 
-    let password = if is_guest {
-        None
+    let password = if is_guest || (appservice.is_some() && body.password.is_none()) {
+        // Guests and users of appservices can't log in with a password
+        Some(utils::random_string(GUEST_PASSWORD_LENGTH))
     } else {
         body.password.clone()
     }
@@ -160,6 +347,16 @@ pub fn register_route(
 
     // Create user
     db.users.create(&user_id, &password)?;
+    if is_guest {
+        db.users.set_guest(&user_id)?;
+    } else if db.globals.admin_user() == Some(&user_id) {
+        db.users.make_admin(&user_id)?;
+    }
+
+    if let Some((sid, email)) = validated_email {
+        db.users.add_email(&user_id, &email)?;
+        db.threepid_sessions.remove(&sid)?;
+    }
 
     // Initial data
     db.account_data.update(
@@ -174,13 +371,16 @@ pub fn register_route(
         &db.globals,
     )?;
 
+    if joins_auto_join_rooms(
+        appservice.is_some(),
+        is_guest,
+        db.globals.auto_join_guests(),
+    ) {
+        join_auto_join_rooms(&db, &user_id).await;
+    }
+
     if !is_guest && body.inhibit_login {
-        return Ok(register::Response {
-            access_token: None,
-            user_id,
-            device_id: None,
-        }
-        .into());
+        return Ok(Json(json!({ "user_id": user_id }).to_string()));
     }
 
     // Generate new device id if the user didn't specify one
@@ -202,21 +402,308 @@ pub fn register_route(
         body.initial_device_display_name.clone(),
     )?;
 
-    Ok(register::Response {
-        access_token: Some(token),
-        user_id,
-        device_id: Some(device_id),
+    let mut response = json!({
+        "user_id": user_id,
+        "access_token": token,
+        "home_server": db.globals.server_name().as_str(),
+        "device_id": device_id,
+    });
+
+    if wants_refresh_token(body.json_body.as_deref()) {
+        let (refresh_token, expires_in_ms) =
+            create_refresh_token(&db, &user_id, &device_id, &token)?;
+        response["refresh_token"] = json!(refresh_token);
+        response["expires_in_ms"] = json!(expires_in_ms);
+    } else if let Some(expires_in_ms) = set_access_token_expiry(&db, &token)? {
+        response["expires_in_ms"] = json!(expires_in_ms);
     }
-    .into())
+
+    Ok(Json(response.to_string()))
+}
+
+/// Joins a new user to the `auto_join_rooms` of the config. Rooms that can't be joined are
+/// logged and skipped, registration never fails because of them.
+async fn join_auto_join_rooms(db: &Database<'static>, user_id: &UserId) {
+    for room in db.globals.auto_join_rooms() {
+        if let Err(e) = join_auto_join_room(db, user_id, room).await {
+            warn!(
+                "{} could not join the auto-join room {}: {}",
+                user_id, room, e
+            );
+        }
+    }
+}
+
+/// Checks if a newly registered user joins the `auto_join_rooms`. Users of appservices never do,
+/// guests only with `auto_join_guests`.
+fn joins_auto_join_rooms(is_appservice_user: bool, is_guest: bool, auto_join_guests: bool) -> bool {
+    !is_appservice_user && (!is_guest || auto_join_guests)
+}
+
+/// Resolves the room id or alias and joins the user to the room.
+///
+/// - Rooms of other servers are joined over federation
+/// - Local aliases without room are created by the `admin_user` if `autocreate_auto_join_rooms`
+/// is set. The admin_user has to be registered already
+async fn join_auto_join_room(
+    db: &Database<'static>,
+    user_id: &UserId,
+    room: &RoomIdOrAliasId,
+) -> Result<(), Error> {
+    let room_id = match RoomId::try_from(room.clone()) {
+        Ok(room_id) => room_id,
+        Err(alias) if alias.server_name() != db.globals.server_name() => {
+            remote_alias(db, &alias).await?.0
+        }
+        Err(alias) => match db.rooms.id_from_alias(&alias)? {
+            Some(room_id) => room_id,
+            None if db.globals.autocreate_auto_join_rooms() => {
+                let creator = db
+                    .globals
+                    .admin_user()
+                    .expect("autocreate_auto_join_rooms requires admin_user");
+                if !db.users.exists(creator)? {
+                    return Err(Error::BadConfig(
+                        "The admin_user has to be registered to create auto-join rooms.",
+                    ));
+                }
+                db.create_auto_join_room(&alias, creator)?
+            }
+            None => {
+                return Err(Error::BadRequest(
+                    ErrorKind::NotFound,
+                    "Room with alias not found.",
+                ))
+            }
+        },
+    };
+
+    // The admin_user is already in the rooms it created
+    if db.rooms.is_joined(user_id, &room_id)? {
+        return Ok(());
+    }
+
+    join_room_by_id_helper(db, user_id, &room_id).await
+}
+
+/// Asks Google if the reCAPTCHA response of a client is valid.
+async fn verify_recaptcha(db: &Database<'_>, response: &str) -> Result<bool, Error> {
+    let private_key = db.globals.recaptcha_private_key().ok_or(Error::BadRequest(
+        ErrorKind::Unrecognized,
+        "Captchas are not enabled on this server.",
+    ))?;
+
+    let body = db
+        .globals
+        .reqwest_client()
+        .post("https://www.google.com/recaptcha/api/siteverify")
+        .form(&[("secret", private_key), ("response", response)])
+        .send()
+        .await?
+        .text()
+        .await?;
+
+    Ok(serde_json::from_str::<serde_json::Value>(&body)
+        .map_err(|_| Error::BadServerResponse("Invalid reCAPTCHA response."))?
+        .get("success")
+        .and_then(|success| success.as_bool())
+        .unwrap_or(false))
+}
+
+/// # `GET /_matrix/client/v1/register/m.login.registration_token/validity`
+///
+/// Checks if a registration token is valid without using it.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/v1/register/m.login.registration_token/validity?<token>")
+)]
+pub fn registration_token_validity_route(
+    db: State<'_, Database<'_>>,
+    token: String,
+) -> Result<Json<String>, Error> {
+    Ok(Json(
+        json!({ "valid": db.users.registration_token_valid(&token)? }).to_string(),
+    ))
+}
+
+/// # `GET /_synapse/admin/v1/register`
+///
+/// Returns a nonce for shared-secret registration.
+///
+/// - Anyone can request nonces, so they share the rate limit of registrations
+#[cfg_attr(feature = "conduit_bin", get("/_synapse/admin/v1/register"))]
+pub fn get_shared_secret_register_nonce_route(
+    db: State<'_, Database<'_>>,
+    client_ip: ClientIp,
+) -> Result<Json<String>, Error> {
+    if db.globals.registration_shared_secret().is_none() {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Shared-secret registration is disabled.",
+        ));
+    }
+
+    db.globals
+        .rate_limiter()
+        .check_ip(RateLimitClass::Register, client_ip.0)?;
+
+    let nonce = db.users.create_registration_nonce(NONCE_LENGTH)?;
+
+    Ok(Json(json!({ "nonce": nonce }).to_string()))
+}
+
+/// # `POST /_synapse/admin/v1/register`
+///
+/// Registers an account using the `registration_shared_secret` from the config.
+///
+/// - The mac is a hex encoded HMAC-SHA1 of `nonce\0username\0password\0admin` (or `notadmin`)
+/// - Each nonce can only be used once
+/// - Works even if registration is disabled
+/// - The user joins the `auto_join_rooms` of the config
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_synapse/admin/v1/register", data = "<body>")
+)]
+pub async fn shared_secret_register_route(
+    db: State<'_, Database<'_>>,
+    body: Data,
+) -> Result<Json<String>, Error> {
+    let shared_secret = db
+        .globals
+        .registration_shared_secret()
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Shared-secret registration is disabled.",
+        ))?;
+
+    let mut bytes = Vec::new();
+    body.open()
+        .take(db.globals.max_request_size().into())
+        .read_to_end(&mut bytes)
+        .await
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid request body."))?;
+    let body = serde_json::from_slice::<SharedSecretRegistration>(&bytes)
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid request body."))?;
+
+    if !db.users.take_registration_nonce(&body.nonce)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Unknown or expired nonce.",
+        ));
+    }
+
+    let key = ring::hmac::Key::new(
+        ring::hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+        shared_secret.as_bytes(),
+    );
+    let mac = ring::hmac::sign(
+        &key,
+        format!(
+            "{}\0{}\0{}\0{}",
+            body.nonce,
+            body.username,
+            body.password,
+            if body.admin { "admin" } else { "notadmin" }
+        )
+        .as_bytes(),
+    )
+    .as_ref()
+    .iter()
+    .map(|byte| format!("{:02x}", byte))
+    .collect::<String>();
+
+    if ring::constant_time::verify_slices_are_equal(
+        mac.as_bytes(),
+        body.mac.to_lowercase().as_bytes(),
+    )
+    .is_err()
+    {
+        return Err(Error::BadRequest(ErrorKind::Forbidden, "Invalid mac."));
+    }
+
+    let user_id = parse_new_username(body.username.to_lowercase(), db.globals.server_name())?;
+
+    if db.users.exists(&user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::UserInUse,
+            "Desired user ID is already taken.",
+        ));
+    }
+
+    create_local_account(
+        &db,
+        &user_id,
+        &body.password,
+        body.displayname.clone(),
+        body.admin,
+    )?;
+
+    join_auto_join_rooms(&db, &user_id).await;
+
+    let device_id = utils::random_string(DEVICE_ID_LENGTH);
+    let token = utils::random_string(TOKEN_LENGTH);
+    db.users
+        .create_device(&user_id, device_id.as_str().into(), &token, None)?;
+
+    let mut response = json!({
+        "user_id": user_id,
+        "access_token": token,
+        "home_server": db.globals.server_name().as_str(),
+        "device_id": device_id,
+    });
+    if let Some(expires_in_ms) = set_access_token_expiry(&db, &token)? {
+        response["expires_in_ms"] = json!(expires_in_ms);
+    }
+
+    Ok(Json(response.to_string()))
+}
+
+/// Creates an account with the initial push rules, even if registration is disabled. Used by
+/// shared-secret registration and the admin API.
+///
+/// - The `admin_user` of the config always becomes an admin
+pub fn create_local_account(
+    db: &Database<'_>,
+    user_id: &UserId,
+    password: &str,
+    displayname: Option<String>,
+    admin: bool,
+) -> Result<(), Error> {
+    db.users.create(user_id, password)?;
+    if displayname.is_some() {
+        db.users.set_displayname(user_id, displayname)?;
+    }
+
+    if admin || db.globals.admin_user() == Some(user_id) {
+        db.users.make_admin(user_id)?;
+    }
+
+    // Initial data
+    db.account_data.update(
+        None,
+        user_id,
+        EventType::PushRules,
+        &ruma::events::push_rules::PushRulesEvent {
+            content: ruma::events::push_rules::PushRulesEventContent {
+                global: crate::push_rules::default_pushrules(user_id),
+            },
+        },
+        &db.globals,
+    )?;
+
+    Ok(())
 }
 
 /// # `POST /_matrix/client/r0/account/password`
 ///
 /// Changes the password of this account.
 ///
-/// - Invalidates all other access tokens if logout_devices is true
-/// - Deletes all other devices and most of their data (to-device events, last seen, etc.) if
-/// logout_devices is true
+/// - Invalidates all other access tokens unless logout_devices is false
+/// - Deletes all other devices and most of their data (to-device events, last seen, etc.) unless
+/// logout_devices is false
+/// - The access token of the current device stays valid
+/// - Users that are not logged in can reset their password with a validated email address
+/// - Forbidden if password logins are disabled
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/account/password", data = "<body>")
@@ -225,8 +712,17 @@ pub fn change_password_route(
     db: State<'_, Database<'_>>,
     body: Ruma<change_password::Request>,
 ) -> ConduitResult<change_password::Response> {
-    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
-    let device_id = body.device_id.as_ref().expect("user is authenticated");
+    if !db.globals.password_changes_allowed() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Password changes are disabled on this server.",
+        ));
+    }
+
+    let (sender_id, device_id) = match (&body.sender_id, &body.device_id) {
+        (Some(sender_id), Some(device_id)) => (sender_id, device_id),
+        _ => return reset_password_with_email(&db, &body),
+    };
 
     let mut uiaainfo = UiaaInfo {
         flows: vec![AuthFlow {
@@ -259,36 +755,164 @@ pub fn change_password_route(
 
     db.users.set_password(&sender_id, &body.new_password)?;
 
-    // TODO: Read logout_devices field when it's available and respect that, currently not supported in Ruma
+    // Ruma doesn't know the logout_devices field yet, so it's read from the JSON body
     // See: https://github.com/ruma/ruma/issues/107
-    // Logout all devices except the current one
-    for id in db
-        .users
-        .all_device_ids(&sender_id)
-        .filter_map(|id| id.ok())
-        .filter(|id| id != device_id)
-    {
-        db.users.remove_device(&sender_id, &id)?;
+    let logout_devices = body
+        .json_body
+        .as_ref()
+        .and_then(|json| serde_json::from_str::<serde_json::Value>(json.get()).ok())
+        .and_then(|json| json.get("logout_devices")?.as_bool())
+        .unwrap_or(true);
+
+    if logout_devices {
+        // Logout all devices except the current one
+        for id in db
+            .users
+            .all_device_ids(&sender_id)
+            .filter_map(|id| id.ok())
+            .filter(|id| id != device_id)
+        {
+            db.users.remove_device(&sender_id, &id)?;
+            db.pushers.remove_device_pushers(&sender_id, &id)?;
+        }
     }
 
     Ok(change_password::Response.into())
 }
 
+/// Changes the password of the user that owns the validated email address of the
+/// `m.login.email.identity` stage. All devices of the user are logged out.
+fn reset_password_with_email(
+    db: &Database<'_>,
+    body: &change_password::Request,
+) -> ConduitResult<change_password::Response> {
+    let (session, auth_parameters) = match &body.auth {
+        Some(AuthData::DirectRequest {
+            kind,
+            session,
+            auth_parameters,
+        }) if kind == "m.login.email.identity" => (session, auth_parameters),
+        _ => {
+            return Err(Error::Uiaa(UiaaInfo {
+                flows: vec![AuthFlow {
+                    stages: vec!["m.login.email.identity".to_owned()],
+                }],
+                completed: Vec::new(),
+                params: Default::default(),
+                session: Some(utils::random_string(SESSION_ID_LENGTH)),
+                auth_error: None,
+            }))
+        }
+    };
+
+    let (sid, client_secret) = threepid_creds(auth_parameters)?;
+
+    let user_id = db
+        .threepid_sessions
+        .validated_email(sid, client_secret)?
+        .map_or(Ok(None), |email| db.users.find_from_email(&email))?
+        .ok_or_else(|| {
+            Error::Uiaa(UiaaInfo {
+                flows: vec![AuthFlow {
+                    stages: vec!["m.login.email.identity".to_owned()],
+                }],
+                completed: Vec::new(),
+                params: Default::default(),
+                session: session.clone(),
+                auth_error: Some(ruma::api::client::error::ErrorBody {
+                    kind: ErrorKind::ThreepidAuthFailed,
+                    message: "Email address has not been validated.".to_owned(),
+                }),
+            })
+        })?;
+
+    db.users.set_password(&user_id, &body.new_password)?;
+    db.threepid_sessions.remove(sid)?;
+
+    // The password might have been reset because the account was compromised
+    for id in db.users.all_device_ids(&user_id).filter_map(|id| id.ok()) {
+        db.users.remove_device(&user_id, &id)?;
+        db.pushers.remove_device_pushers(&user_id, &id)?;
+    }
+
+    Ok(change_password::Response.into())
+}
+
+/// Reads the sid and client_secret of an `m.login.email.identity` stage.
+fn threepid_creds(
+    auth_parameters: &serde_json::Map<String, serde_json::Value>,
+) -> Result<(&str, &str), Error> {
+    let threepid_creds = auth_parameters
+        .get("threepid_creds")
+        .or_else(|| auth_parameters.get("threepidCreds"))
+        .ok_or(Error::BadRequest(
+            ErrorKind::MissingParam,
+            "m.login.email.identity needs threepid_creds.",
+        ))?;
+
+    let sid = threepid_creds
+        .get("sid")
+        .and_then(|sid| sid.as_str())
+        .ok_or(Error::BadRequest(
+            ErrorKind::MissingParam,
+            "threepid_creds need a sid.",
+        ))?;
+    let client_secret = threepid_creds
+        .get("client_secret")
+        .and_then(|client_secret| client_secret.as_str())
+        .ok_or(Error::BadRequest(
+            ErrorKind::MissingParam,
+            "threepid_creds need a client_secret.",
+        ))?;
+
+    Ok((sid, client_secret))
+}
+
 /// # `GET _matrix/client/r0/account/whoami`
 ///
 /// Get user_id of this account.
 ///
-/// - Also works for Application Services
+/// - Also works for Application Services, they get the user they masquerade as
+/// - Also returns the device of the access token and if the account is a guest account.
+/// Appservice requests have no device
+/// - Expired tokens are rejected with `soft_logout`, like on all endpoints
+/// - Ruma's response has no device_id and is_guest yet, so we build the json ourselves
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/account/whoami", data = "<body>")
 )]
-pub fn whoami_route(body: Ruma<whoami::Request>) -> ConduitResult<whoami::Response> {
+pub fn whoami_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+) -> Result<Json<String>, Error> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
-    Ok(whoami::Response {
-        user_id: sender_id.clone(),
+
+    // Appservices masquerade as the user, their requests don't belong to a device
+    let device_id = if body.appservice_id.is_none() {
+        Some(&**body.device_id.as_ref().expect("user is authenticated"))
+    } else {
+        None
+    };
+
+    Ok(Json(
+        whoami_response(sender_id, device_id, db.users.is_guest(sender_id)?).to_string(),
+    ))
+}
+
+/// The body of a whoami response, without a device for appservices.
+fn whoami_response(
+    user_id: &UserId,
+    device_id: Option<&DeviceId>,
+    is_guest: bool,
+) -> serde_json::Value {
+    let mut response = json!({
+        "user_id": user_id,
+        "is_guest": is_guest,
+    });
+    if let Some(device_id) = device_id {
+        response["device_id"] = device_id.as_str().into();
     }
-    .into())
+    response
 }
 
 /// # `POST /_matrix/client/r0/account/deactivate`
@@ -297,13 +921,15 @@ pub fn whoami_route(body: Ruma<whoami::Request>) -> ConduitResult<whoami::Respon
 ///
 /// - Leaves all rooms and rejects all invitations
 /// - Invalidates all access tokens
-/// - Deletes all devices
-/// - Removes ability to log in again
+/// - Deletes all devices, pushers, e2ee keys and key backups
+/// - Removes ability to log in again, the username can't be registered again
+/// - If `erase` is true, redacts all messages of the user and removes their profile, also from
+/// their member events
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/account/deactivate", data = "<body>")
 )]
-pub fn deactivate_route(
+pub async fn deactivate_route(
     db: State<'_, Database<'_>>,
     body: Ruma<deactivate::Request>,
 ) -> ConduitResult<deactivate::Response> {
@@ -339,11 +965,85 @@ pub fn deactivate_route(
         return Err(Error::Uiaa(uiaainfo));
     }
 
+    let erase = body
+        .json_body
+        .as_ref()
+        .and_then(|json| serde_json::from_str::<serde_json::Value>(json.get()).ok())
+        .and_then(|json| json.get("erase")?.as_bool())
+        .unwrap_or(false);
+
+    if erase {
+        // Redacting can take long for users with many messages, so it doesn't block the
+        // executor. It happens while the user is still in the rooms
+        tokio::task::block_in_place(|| redact_all_messages(&db, &sender_id))?;
+
+        // The member events show the profile too, so they are updated before leaving
+        db.users.set_displayname(&sender_id, None)?;
+        db.users.set_avatar_url(&sender_id, None)?;
+        update_member_events(&db, &sender_id, |content| {
+            content.displayname = None;
+            content.avatar_url = None;
+        })
+        .await?;
+    }
+
+    deactivate_account(&db, &sender_id)?;
+
+    Ok(deactivate::Response {
+        id_server_unbind_result: ThirdPartyIdRemovalStatus::NoSupport,
+    }
+    .into())
+}
+
+/// Redacts all messages the user sent in their joined rooms. State events are kept, they are
+/// needed to authorize other events.
+fn redact_all_messages(db: &Database<'_>, user_id: &UserId) -> Result<(), Error> {
+    for room_id in db.rooms.rooms_joined(user_id) {
+        let room_id = room_id?;
+        let event_ids = db
+            .rooms
+            .all_pdus(user_id, &room_id)?
+            .filter_map(|pdu| pdu.ok()) // Filter out buggy events
+            .filter(|pdu| {
+                pdu.sender == *user_id
+                    && pdu.state_key.is_none()
+                    && pdu.kind != EventType::RoomRedaction
+                    && !pdu.unsigned.contains_key("redacted_because")
+            })
+            .map(|pdu| pdu.event_id)
+            .collect::<Vec<_>>();
+
+        for event_id in event_ids {
+            db.rooms.append_pdu(
+                PduBuilder {
+                    room_id: room_id.clone(),
+                    sender: user_id.clone(),
+                    event_type: EventType::RoomRedaction,
+                    content: serde_json::to_value(redaction::RedactionEventContent {
+                        reason: None,
+                    })
+                    .expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: None,
+                    redacts: Some(event_id),
+                },
+                &db.globals,
+                &db.account_data,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Leaves all rooms of the user, rejects their invitations, removes their devices, pushers,
+/// keys and key backups and makes sure they can't log in again.
+pub fn deactivate_account(db: &Database<'_>, user_id: &UserId) -> Result<(), Error> {
     // Leave all joined rooms and reject all invitations
     for room_id in db
         .rooms
-        .rooms_joined(&sender_id)
-        .chain(db.rooms.rooms_invited(&sender_id))
+        .rooms_joined(user_id)
+        .chain(db.rooms.rooms_invited(user_id))
     {
         let room_id = room_id?;
         let event = member::MemberEventContent {
@@ -357,11 +1057,11 @@ pub fn deactivate_route(
         db.rooms.append_pdu(
             PduBuilder {
                 room_id: room_id.clone(),
-                sender: sender_id.clone(),
+                sender: user_id.clone(),
                 event_type: EventType::RoomMember,
                 content: serde_json::to_value(event).expect("event is valid, we just created it"),
                 unsigned: None,
-                state_key: Some(sender_id.to_string()),
+                state_key: Some(user_id.to_string()),
                 redacts: None,
             },
             &db.globals,
@@ -369,11 +1069,122 @@ pub fn deactivate_route(
         )?;
     }
 
-    // Remove devices and mark account as deactivated
-    db.users.deactivate_account(&sender_id)?;
+    for device_id in db.users.all_device_ids(user_id) {
+        db.pushers.remove_device_pushers(user_id, &device_id?)?;
+    }
 
-    Ok(deactivate::Response {
-        id_server_unbind_result: ThirdPartyIdRemovalStatus::NoSupport,
+    db.key_backups.delete_all_backups(user_id)?;
+
+    // Remove devices and keys and mark account as deactivated
+    db.users.deactivate_account(user_id)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_username_available, joins_auto_join_rooms, parse_new_username, whoami_response,
+    };
+    use crate::{Database, Error};
+    use ruma::{api::client::error::ErrorKind, DeviceId, ServerName, UserId};
+    use serde_json::json;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn new_usernames_are_parsed_for_this_server() {
+        let server_name = Box::<ServerName>::try_from("example.com").unwrap();
+
+        let user_id = parse_new_username("alice".to_owned(), &server_name).unwrap();
+        assert_eq!(user_id.as_str(), "@alice:example.com");
+        let user_id = parse_new_username("@bob:example.com".to_owned(), &server_name).unwrap();
+        assert_eq!(user_id.as_str(), "@bob:example.com");
+    }
+
+    #[test]
+    fn malformed_usernames_are_rejected() {
+        let server_name = Box::<ServerName>::try_from("example.com").unwrap();
+
+        for username in &[
+            "",
+            "Alice",
+            "al ice",
+            "alice:example.com",
+            "@alice:other.example",
+        ] {
+            assert!(
+                parse_new_username(username.to_string(), &server_name).is_err(),
+                "{}",
+                username
+            );
+        }
+    }
+
+    #[test]
+    fn whoami_returns_the_device_and_guest_status() {
+        let alice = UserId::try_from("@alice:example.com").unwrap();
+        let device_id = Box::<DeviceId>::from("ABCDEF");
+        assert_eq!(
+            whoami_response(&alice, Some(&device_id), false),
+            json!({ "user_id": "@alice:example.com", "device_id": "ABCDEF", "is_guest": false })
+        );
+
+        let guest = UserId::try_from("@123456:example.com").unwrap();
+        assert_eq!(
+            whoami_response(&guest, Some(&device_id), true),
+            json!({ "user_id": "@123456:example.com", "device_id": "ABCDEF", "is_guest": true })
+        );
+
+        // An appservice request as one of its users
+        let bridged = UserId::try_from("@irc_bob:example.com").unwrap();
+        assert_eq!(
+            whoami_response(&bridged, None, false),
+            json!({ "user_id": "@irc_bob:example.com", "is_guest": false })
+        );
+    }
+
+    #[test]
+    fn new_users_join_the_auto_join_rooms() {
+        assert!(joins_auto_join_rooms(false, false, false));
+        assert!(joins_auto_join_rooms(false, true, true));
+        assert!(!joins_auto_join_rooms(false, true, false));
+        assert!(!joins_auto_join_rooms(true, false, true));
+    }
+
+    #[test]
+    fn free_usernames_are_available_and_taken_ones_are_not() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let db = Database::load_temporary(Vec::new());
+            let alice = UserId::try_from("@alice:example.com").unwrap();
+            db.users.create(&alice, "password").unwrap();
+
+            assert!(check_username_available(&db, "bob".to_owned()).is_ok());
+            assert!(matches!(
+                check_username_available(&db, "alice".to_owned()),
+                Err(Error::BadRequest(ErrorKind::UserInUse, _))
+            ));
+            assert!(matches!(
+                check_username_available(&db, "@alice:example.com".to_owned()),
+                Err(Error::BadRequest(ErrorKind::UserInUse, _))
+            ));
+            assert!(matches!(
+                check_username_available(&db, "Bob".to_owned()),
+                Err(Error::BadRequest(ErrorKind::InvalidUsername, _))
+            ));
+        });
+    }
+
+    #[test]
+    fn usernames_are_unavailable_while_registration_is_disabled() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let db = Database::load_temporary(vec![("registration_disabled", true.into())]);
+
+            assert!(matches!(
+                check_username_available(&db, "bob".to_owned()),
+                Err(Error::BadRequest(ErrorKind::Forbidden, _))
+            ));
+        });
     }
-    .into())
 }
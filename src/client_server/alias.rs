@@ -1,16 +1,24 @@
 use super::State;
-use crate::{server_server, ConduitResult, Database, Error, Ruma};
-use ruma::api::{
-    client::{
+use crate::{server_server, utils, ConduitResult, Database, Error, Ruma};
+use ruma::{
+    api::client::{
         error::ErrorKind,
         r0::alias::{create_alias, delete_alias, get_alias},
     },
-    federation,
+    RoomAliasId, RoomId,
 };
+use std::convert::TryFrom;
+use tracing::warn;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{delete, get, put};
 
+/// # `PUT /_matrix/client/r0/directory/room/{roomAlias}`
+///
+/// Creates a new alias for a room.
+///
+/// - Only aliases on this server can be created
+/// - The creator is remembered, so they can delete the alias again
 #[cfg_attr(
     feature = "conduit_bin",
     put("/_matrix/client/r0/directory/room/<_>", data = "<body>")
@@ -19,16 +27,49 @@ pub fn create_alias_route(
     db: State<'_, Database<'_>>,
     body: Ruma<create_alias::IncomingRequest>,
 ) -> ConduitResult<create_alias::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    if body.room_alias.server_name() != db.globals.server_name() {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Alias is from another server.",
+        ));
+    }
+
     if db.rooms.id_from_alias(&body.room_alias)?.is_some() {
         return Err(Error::Conflict("Alias already exists."));
     }
 
+    if !db.rooms.exists(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Room does not exist.",
+        ));
+    }
+
+    // Only the appservice can create aliases in its exclusive namespaces
+    if db.globals.appservices().iter().any(|appservice| {
+        Some(&appservice.id) != body.appservice_id.as_ref()
+            && appservice.is_exclusive_room_alias_match(&body.room_alias)
+    }) {
+        return Err(Error::BadRequest(
+            ErrorKind::Exclusive,
+            "This alias is reserved by an appservice.",
+        ));
+    }
+
     db.rooms
         .set_alias(&body.room_alias, Some(&body.room_id), &db.globals)?;
+    db.rooms.set_alias_creator(&body.room_alias, &sender_id)?;
 
     Ok(create_alias::Response.into())
 }
 
+/// # `DELETE /_matrix/client/r0/directory/room/{roomAlias}`
+///
+/// Deletes a local alias.
+///
+/// - Only the creator of the alias and room admins can delete it
 #[cfg_attr(
     feature = "conduit_bin",
     delete("/_matrix/client/r0/directory/room/<_>", data = "<body>")
@@ -37,11 +78,56 @@ pub fn delete_alias_route(
     db: State<'_, Database<'_>>,
     body: Ruma<delete_alias::IncomingRequest>,
 ) -> ConduitResult<delete_alias::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    if body.room_alias.server_name() != db.globals.server_name() {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Alias is from another server.",
+        ));
+    }
+
+    let room_id = db
+        .rooms
+        .id_from_alias(&body.room_alias)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Alias does not exist.",
+        ))?;
+
+    // Appservices can delete all aliases in their namespaces
+    let is_appservice_alias = db.globals.appservices().iter().any(|appservice| {
+        Some(&appservice.id) == body.appservice_id.as_ref()
+            && appservice.is_room_alias_match(&body.room_alias)
+    });
+
+    if !is_appservice_alias
+        && db.rooms.alias_creator(&body.room_alias)?.as_ref() != Some(sender_id)
+        && !db.rooms.is_room_admin(&room_id, &sender_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Only the creator of the alias and room admins can delete it.",
+        ));
+    }
+
+    if db.rooms.is_canonical_alias(&room_id, &body.room_alias)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The alias is used by the m.room.canonical_alias event of the room, remove it there first.",
+        ));
+    }
+
     db.rooms.set_alias(&body.room_alias, None, &db.globals)?;
 
     Ok(delete_alias::Response.into())
 }
 
+/// # `GET /_matrix/client/r0/directory/room/{roomAlias}`
+///
+/// Returns the room id and the servers in the room of an alias.
+///
+/// - Aliases of other servers are resolved by asking that server
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/directory/room/<_>", data = "<body>")
@@ -51,20 +137,9 @@ pub async fn get_alias_route(
     body: Ruma<get_alias::IncomingRequest>,
 ) -> ConduitResult<get_alias::Response> {
     if body.room_alias.server_name() != db.globals.server_name() {
-        let response = server_server::send_request(
-            &db,
-            body.room_alias.server_name().to_string(),
-            federation::query::get_room_information::v1::Request {
-                room_alias: body.room_alias.to_string(),
-            },
-        )
-        .await?;
-
-        return Ok(get_alias::Response {
-            room_id: response.room_id,
-            servers: response.servers,
-        }
-        .into());
+        let (room_id, servers) = remote_alias(&db, &body.room_alias).await?;
+
+        return Ok(get_alias::Response { room_id, servers }.into());
     }
 
     let room_id = db
@@ -76,8 +151,99 @@ pub async fn get_alias_route(
         ))?;
 
     Ok(get_alias::Response {
+        servers: alias_servers(&db, &room_id)?,
         room_id,
-        servers: vec![db.globals.server_name().to_string()],
     }
     .into())
 }
+
+/// The servers in the room that can be used to join it, starting with this server.
+pub fn alias_servers(db: &Database<'_>, room_id: &RoomId) -> crate::Result<Vec<String>> {
+    let mut servers = vec![db.globals.server_name().to_string()];
+
+    for user_id in db.rooms.room_members(room_id) {
+        let server = user_id?.server_name().to_string();
+        if !servers.contains(&server) {
+            servers.push(server);
+        }
+    }
+
+    Ok(servers)
+}
+
+/// Asks the server of a remote alias for the room id and the servers in the room.
+pub async fn remote_alias(
+    db: &Database<'static>,
+    room_alias: &RoomAliasId,
+) -> crate::Result<(RoomId, Vec<String>)> {
+    let response = server_server::send_json_request(
+        db,
+        room_alias.server_name().as_str(),
+        reqwest::Method::GET,
+        &format!(
+            "/_matrix/federation/v1/query/directory?room_alias={}",
+            utils::percent_encode(room_alias.as_str())
+        ),
+        None,
+    )
+    .await
+    .map_err(|e| match e {
+        Error::BadServerResponse(_) => Error::BadRequest(
+            ErrorKind::NotFound,
+            "Room with alias not found on its server.",
+        ),
+        _ => Error::BadRequest(
+            ErrorKind::Unknown,
+            "The server of the alias is not reachable.",
+        ),
+    })?;
+
+    let room_id = response
+        .get("room_id")
+        .and_then(|room_id| room_id.as_str())
+        .and_then(|room_id| RoomId::try_from(room_id).ok())
+        .ok_or(Error::BadServerResponse(
+            "Server returned an invalid room id.",
+        ))?;
+    let servers = response
+        .get("servers")
+        .and_then(|servers| servers.as_array())
+        .map(|servers| {
+            servers
+                .iter()
+                .filter_map(|server| server.as_str().map(|server| server.to_owned()))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok((room_id, servers))
+}
+
+/// Checks that all aliases in the content of an `m.room.canonical_alias` event point to the room.
+///
+/// - Aliases of other servers are checked by asking them, but skipped if they are not reachable
+pub async fn validate_canonical_alias(
+    db: &Database<'static>,
+    room_id: &RoomId,
+    content: &serde_json::Value,
+) -> crate::Result<()> {
+    for alias in db
+        .rooms
+        .validate_canonical_alias(room_id, content, &db.globals)?
+    {
+        match remote_alias(db, &alias).await {
+            Ok((alias_room_id, _)) if &alias_room_id == room_id => {}
+            Ok(_) | Err(Error::BadRequest(ErrorKind::NotFound, _)) => {
+                return Err(Error::BadAlias(
+                    "Aliases of the canonical alias event have to point to the room.",
+                ))
+            }
+            Err(_) => warn!(
+                "Could not validate canonical alias {} of room {}, its server is not reachable",
+                alias, room_id
+            ),
+        }
+    }
+
+    Ok(())
+}
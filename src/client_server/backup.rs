@@ -258,7 +258,8 @@ pub fn delete_backup_keys_route(
 ) -> ConduitResult<delete_backup_keys::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    db.key_backups.delete_all_keys(&sender_id, &body.version)?;
+    db.key_backups
+        .delete_all_keys(&sender_id, &body.version, &db.globals)?;
 
     Ok(delete_backup_keys::Response {
         count: (db.key_backups.count_keys(sender_id, &body.version)? as u32).into(),
@@ -278,7 +279,7 @@ pub fn delete_backup_key_sessions_route(
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
     db.key_backups
-        .delete_room_keys(&sender_id, &body.version, &body.room_id)?;
+        .delete_room_keys(&sender_id, &body.version, &body.room_id, &db.globals)?;
 
     Ok(delete_backup_key_sessions::Response {
         count: (db.key_backups.count_keys(sender_id, &body.version)? as u32).into(),
@@ -297,8 +298,13 @@ pub fn delete_backup_key_session_route(
 ) -> ConduitResult<delete_backup_key_session::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    db.key_backups
-        .delete_room_key(&sender_id, &body.version, &body.room_id, &body.session_id)?;
+    db.key_backups.delete_room_key(
+        &sender_id,
+        &body.version,
+        &body.room_id,
+        &body.session_id,
+        &db.globals,
+    )?;
 
     Ok(delete_backup_key_session::Response {
         count: (db.key_backups.count_keys(sender_id, &body.version)? as u32).into(),
@@ -1,5 +1,7 @@
-use crate::ConduitResult;
+use super::State;
+use crate::{database::globals::supported_room_versions, ConduitResult, Database};
 use ruma::{api::client::r0::capabilities::get_capabilities, RoomVersionId};
+use serde_json::json;
 use std::collections::BTreeMap;
 
 #[cfg(feature = "conduit_bin")]
@@ -8,27 +10,107 @@ use rocket::get;
 /// # `GET /_matrix/client/r0/capabilities`
 ///
 /// Get information on this server's supported feature set and other relevent capabilities.
+///
+/// - The capabilities follow the config, so clients can hide what users can't change
+/// - All supported room versions are stable
+/// - Ruma has no fields for the profile and 3pid capabilities yet, so they are custom capabilities
 #[cfg_attr(feature = "conduit_bin", get("/_matrix/client/r0/capabilities"))]
-pub fn get_capabilities_route() -> ConduitResult<get_capabilities::Response> {
+pub fn get_capabilities_route(
+    db: State<'_, Database<'_>>,
+) -> ConduitResult<get_capabilities::Response> {
+    Ok(get_capabilities::Response {
+        capabilities: capabilities(
+            db.globals.default_room_version(),
+            db.globals.password_changes_allowed(),
+            !db.globals.displayname_changes_disabled(),
+            !db.globals.avatar_url_changes_disabled(),
+            !db.globals.threepid_changes_disabled(),
+        ),
+    }
+    .into())
+}
+
+/// The capabilities with the default room version of the config and what users may change.
+fn capabilities(
+    default_room_version: &RoomVersionId,
+    change_password: bool,
+    set_displayname: bool,
+    set_avatar_url: bool,
+    threepid_changes: bool,
+) -> get_capabilities::Capabilities {
     let mut available = BTreeMap::new();
-    available.insert(
-        RoomVersionId::Version5,
-        get_capabilities::RoomVersionStability::Stable,
+    for room_version in supported_room_versions() {
+        available.insert(room_version, get_capabilities::RoomVersionStability::Stable);
+    }
+
+    let mut custom_capabilities = BTreeMap::new();
+    custom_capabilities.insert(
+        "m.set_displayname".to_owned(),
+        json!({ "enabled": set_displayname }),
     );
-    available.insert(
-        RoomVersionId::Version6,
-        get_capabilities::RoomVersionStability::Stable,
+    custom_capabilities.insert(
+        "m.set_avatar_url".to_owned(),
+        json!({ "enabled": set_avatar_url }),
+    );
+    custom_capabilities.insert(
+        "m.3pid_changes".to_owned(),
+        json!({ "enabled": threepid_changes }),
     );
 
-    Ok(get_capabilities::Response {
-        capabilities: get_capabilities::Capabilities {
-            change_password: None, // None means it is possible
-            room_versions: Some(get_capabilities::RoomVersionsCapability {
-                default: "6".to_owned(),
-                available,
-            }),
-            custom_capabilities: BTreeMap::new(),
-        },
+    get_capabilities::Capabilities {
+        change_password: Some(get_capabilities::ChangePasswordCapability {
+            enabled: change_password,
+        }),
+        room_versions: Some(get_capabilities::RoomVersionsCapability {
+            default: default_room_version.to_string(),
+            available,
+        }),
+        custom_capabilities,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::capabilities;
+    use ruma::RoomVersionId;
+    use serde_json::json;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn capabilities_report_the_default_room_version() {
+        for default_room_version in &["6", "10"] {
+            let default_room_version = RoomVersionId::try_from(*default_room_version).unwrap();
+            let capabilities = capabilities(&default_room_version, true, true, true, true);
+
+            let room_versions = capabilities.room_versions.unwrap();
+            assert_eq!(room_versions.default, default_room_version.as_str());
+            assert!(room_versions.available.contains_key(&default_room_version));
+        }
+    }
+
+    #[test]
+    fn capabilities_follow_the_config() {
+        let capabilities = capabilities(&RoomVersionId::Version6, false, true, false, true);
+
+        let capabilities = serde_json::to_value(capabilities).unwrap();
+        assert_eq!(
+            capabilities["m.change_password"],
+            json!({ "enabled": false })
+        );
+        assert_eq!(
+            capabilities["m.set_displayname"],
+            json!({ "enabled": true })
+        );
+        assert_eq!(
+            capabilities["m.set_avatar_url"],
+            json!({ "enabled": false })
+        );
+        assert_eq!(capabilities["m.3pid_changes"], json!({ "enabled": true }));
+        for room_version in &["5", "6", "7", "8", "9", "10"] {
+            assert_eq!(
+                capabilities["m.room_versions"]["available"][room_version],
+                "stable"
+            );
+        }
     }
-    .into())
 }
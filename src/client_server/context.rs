@@ -1,11 +1,20 @@
 use super::State;
-use crate::{ConduitResult, Database, Error, Ruma};
+use crate::{database::rooms::Viewer, ConduitResult, Database, Error, Ruma};
 use ruma::api::client::{error::ErrorKind, r0::context::get_context};
 use std::convert::TryFrom;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::get;
 
+/// # `GET /_matrix/client/r0/rooms/{roomId}/context/{eventId}`
+///
+/// Returns the event and the events before and after it.
+///
+/// - Half of the `limit` is used for the events before the event, the rest for the events after
+/// - Events the user could not see when they were sent are not returned, world readable rooms
+/// can be read without joining
+/// - `start` and `end` can be used as `from` tokens for `/messages`
+/// - `state` is the state of the room at the last returned event
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/rooms/<_>/context/<_>", data = "<body>")
@@ -16,74 +25,82 @@ pub fn get_context_route(
 ) -> ConduitResult<get_context::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    if !db.rooms.is_joined(sender_id, &body.room_id)? {
-        return Err(Error::BadRequest(
-            ErrorKind::Forbidden,
-            "You don't have permission to view this room.",
-        ));
-    }
+    // Users can't find out which events exist in rooms they can't see
+    let not_found = Error::BadRequest(ErrorKind::NotFound, "Base event not found.");
 
-    let base_event = db
-        .rooms
-        .get_pdu(&body.event_id)?
-        .ok_or(Error::BadRequest(
-            ErrorKind::NotFound,
-            "Base event not found.",
-        ))?
-        .to_room_event();
+    let base_event = match db.rooms.get_pdu(&body.event_id)? {
+        Some(pdu) if pdu.room_id == body.room_id => pdu,
+        _ => return Err(not_found),
+    };
 
     let base_token = db
         .rooms
         .get_pdu_count(&body.event_id)?
         .expect("event still exists");
 
+    let mut visibility = db
+        .rooms
+        .event_visibility(Viewer::User(&sender_id), &body.room_id)?;
+    if !visibility.can_see(base_token, &base_event)? {
+        return Err(not_found);
+    }
+
+    let limit = u32::try_from(body.limit)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Limit value is invalid."))?
+        as usize;
+    let before_limit = limit / 2;
+    let after_limit = limit - before_limit;
+
     let events_before = db
         .rooms
         .pdus_until(&sender_id, &body.room_id, base_token)
-        .take(
-            u32::try_from(body.limit).map_err(|_| {
-                Error::BadRequest(ErrorKind::InvalidParam, "Limit value is invalid.")
-            })? as usize
-                / 2,
-        )
+        .take(before_limit)
         .filter_map(|r| r.ok()) // Remove buggy events
         .collect::<Vec<_>>();
 
-    let start_token = events_before.last().map(|(count, _)| count.to_string());
+    // The tokens point at the outermost events, so /messages continues from there
+    let start_token = events_before
+        .last()
+        .map_or(base_token, |(count, _)| *count)
+        .to_string();
+
+    // Events of ignored users and events the user could not see are left out, the tokens still
+    // point at the outermost events
+    let ignored_users = db.account_data.ignored_users(&sender_id)?;
 
     let events_before = events_before
         .into_iter()
+        .filter(|(_, pdu)| !super::sent_by_ignored_user(&ignored_users, pdu))
+        .filter(|(count, pdu)| visibility.can_see(*count, pdu).unwrap_or(false))
         .map(|(_, pdu)| pdu.to_room_event())
         .collect::<Vec<_>>();
 
     let events_after = db
         .rooms
         .pdus_after(&sender_id, &body.room_id, base_token)
-        .take(
-            u32::try_from(body.limit).map_err(|_| {
-                Error::BadRequest(ErrorKind::InvalidParam, "Limit value is invalid.")
-            })? as usize
-                / 2,
-        )
+        .take(after_limit)
         .filter_map(|r| r.ok()) // Remove buggy events
         .collect::<Vec<_>>();
 
-    let end_token = events_after.last().map(|(count, _)| count.to_string());
+    let end_count = events_after.last().map_or(base_token, |(count, _)| *count);
 
     let events_after = events_after
         .into_iter()
+        .filter(|(_, pdu)| !super::sent_by_ignored_user(&ignored_users, pdu))
+        .filter(|(count, pdu)| visibility.can_see(*count, pdu).unwrap_or(false))
         .map(|(_, pdu)| pdu.to_room_event())
         .collect::<Vec<_>>();
 
     Ok(get_context::Response {
-        start: start_token,
-        end: end_token,
+        start: Some(start_token),
+        // The position after the newest event
+        end: Some((end_count + 1).to_string()),
         events_before,
-        event: Some(base_event),
+        event: Some(base_event.to_room_event()),
         events_after,
-        state: db // TODO: State at event
+        state: db
             .rooms
-            .room_state_full(&body.room_id)?
+            .state_at(&body.room_id, end_count)?
             .values()
             .map(|pdu| pdu.to_state_event())
             .collect(),
@@ -12,6 +12,11 @@ use super::SESSION_ID_LENGTH;
 #[cfg(feature = "conduit_bin")]
 use rocket::{delete, get, post, put};
 
+/// # `GET /_matrix/client/r0/devices`
+///
+/// Get metadata on all devices of the sender user.
+///
+/// - `last_seen_ts` and `last_seen_ip` are updated by every authenticated request of the device
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/devices", data = "<body>")
@@ -31,6 +36,9 @@ pub fn get_devices_route(
     Ok(get_devices::Response { devices }.into())
 }
 
+/// # `GET /_matrix/client/r0/devices/{deviceId}`
+///
+/// Get metadata on a single device of the sender user.
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/devices/<_device_id>", data = "<body>")
@@ -50,6 +58,9 @@ pub fn get_device_route(
     Ok(get_device::Response { device }.into())
 }
 
+/// # `PUT /_matrix/client/r0/devices/{deviceId}`
+///
+/// Updates the display name of a device of the sender user.
 #[cfg_attr(
     feature = "conduit_bin",
     put("/_matrix/client/r0/devices/<_device_id>", data = "<body>")
@@ -74,6 +85,13 @@ pub fn update_device_route(
     Ok(update_device::Response.into())
 }
 
+/// # `DELETE /_matrix/client/r0/devices/{deviceId}`
+///
+/// Deletes a device of the sender user.
+///
+/// - Requires UIAA
+/// - Invalidates the access token of the device, so deleting the current device logs out
+/// - Removes the pushers, one-time keys and device keys of the device
 #[cfg_attr(
     feature = "conduit_bin",
     delete("/_matrix/client/r0/devices/<_device_id>", data = "<body>")
@@ -117,10 +135,19 @@ pub fn delete_device_route(
     }
 
     db.users.remove_device(&sender_id, &body.body.device_id)?;
+    db.pushers
+        .remove_device_pushers(&sender_id, &body.body.device_id)?;
 
     Ok(delete_device::Response.into())
 }
 
+/// # `POST /_matrix/client/r0/delete_devices`
+///
+/// Deletes the given devices of the sender user.
+///
+/// - Requires UIAA
+/// - Works like [`DELETE /_matrix/client/r0/devices/{deviceId}`](fn.delete_device_route.html)
+/// for every device
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/delete_devices", data = "<body>")
@@ -163,7 +190,8 @@ pub fn delete_devices_route(
     }
 
     for device_id in &body.devices {
-        db.users.remove_device(&sender_id, &device_id)?
+        db.users.remove_device(&sender_id, &device_id)?;
+        db.pushers.remove_device_pushers(&sender_id, &device_id)?;
     }
 
     Ok(delete_devices::Response.into())
@@ -1,5 +1,6 @@
 use super::State;
 use crate::{server_server, ConduitResult, Database, Error, Result, Ruma};
+use js_int::UInt;
 use ruma::{
     api::{
         client::{
@@ -15,15 +16,28 @@ use ruma::{
         federation,
     },
     events::{
-        room::{avatar, canonical_alias, guest_access, history_visibility, name, topic},
+        room::{
+            avatar, canonical_alias, guest_access, history_visibility, join_rules, name, topic,
+        },
         EventType,
     },
     Raw,
 };
+use serde::{Deserialize, Serialize};
+use tracing::warn;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, post, put};
 
+/// A remote room directory response, in the form we cache it.
+#[derive(Serialize, Deserialize)]
+struct RemotePublicRooms {
+    chunk: Vec<directory::PublicRoomsChunk>,
+    prev_batch: Option<String>,
+    next_batch: Option<String>,
+    total_room_count_estimate: Option<UInt>,
+}
+
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/publicRooms", data = "<body>")
@@ -32,39 +46,28 @@ pub async fn get_public_rooms_filtered_route(
     db: State<'_, Database<'_>>,
     body: Ruma<get_public_rooms_filtered::IncomingRequest>,
 ) -> ConduitResult<get_public_rooms_filtered::Response> {
+    let search_term = body
+        .filter
+        .as_ref()
+        .and_then(|filter| filter.generic_search_term.as_ref())
+        .map(|term| term.to_lowercase());
+
     if let Some(other_server) = body
         .server
         .clone()
         .filter(|server| server != &db.globals.server_name().as_str())
     {
-        let response = server_server::send_request(
-            &db,
-            other_server,
-            federation::directory::get_public_rooms::v1::Request {
-                limit: body.limit,
-                since: body.since.clone(),
-                room_network: federation::directory::get_public_rooms::v1::RoomNetwork::Matrix,
-            },
-        )
-        .await?;
+        let mut response = remote_public_rooms(&db, other_server, &body).await?;
 
-        return Ok(get_public_rooms_filtered::Response {
-            chunk: response
+        // The federation endpoint has no filter, so we apply the search term ourselves
+        if let Some(search_term) = &search_term {
+            response
                 .chunk
-                .into_iter()
-                .map(|c| {
-                    // Convert ruma::api::federation::directory::get_public_rooms::v1::PublicRoomsChunk
-                    // to ruma::api::client::r0::directory::PublicRoomsChunk
-                    Ok::<_, Error>(
-                        serde_json::from_str(
-                            &serde_json::to_string(&c)
-                                .expect("PublicRoomsChunk::to_string always works"),
-                        )
-                        .expect("federation and client-server PublicRoomsChunk are the same type"),
-                    )
-                })
-                .filter_map(|r| r.ok())
-                .collect(),
+                .retain(|chunk| matches_search_term(chunk, search_term));
+        }
+
+        return Ok(get_public_rooms_filtered::Response {
+            chunk: response.chunk,
             prev_batch: response.prev_batch,
             next_batch: response.next_batch,
             total_room_count_estimate: response.total_room_count_estimate,
@@ -72,6 +75,17 @@ pub async fn get_public_rooms_filtered_route(
         .into());
     }
 
+    // We don't bridge any third party networks
+    if let get_public_rooms_filtered::RoomNetwork::ThirdParty(_) = body.room_network {
+        return Ok(get_public_rooms_filtered::Response {
+            chunk: Vec::new(),
+            prev_batch: None,
+            next_batch: None,
+            total_room_count_estimate: Some(0_u32.into()),
+        }
+        .into());
+    }
+
     let limit = body.limit.map_or(10, u64::from);
     let mut since = 0_u64;
 
@@ -98,16 +112,31 @@ pub async fn get_public_rooms_filtered_route(
         }
     }
 
-    let mut all_rooms =
-        db.rooms
-            .public_rooms()
-            .map(|room_id| {
-                let room_id = room_id?;
+    let mut all_rooms = db
+        .rooms
+        .public_rooms()
+        .map(|room_id| {
+            let room_id = room_id?;
 
-                // TODO: Do not load full state?
-                let state = db.rooms.room_state_full(&room_id)?;
+            // TODO: Do not load full state?
+            let state = db.rooms.room_state_full(&room_id)?;
 
-                let chunk = directory::PublicRoomsChunk {
+            // Only rooms that anyone can join or read are listed, even if they were published
+            let join_rule = state
+                .get(&(EventType::RoomJoinRules, "".to_owned()))
+                .map(|s| {
+                    serde_json::from_value::<Raw<join_rules::JoinRulesEventContent>>(
+                        s.content.clone(),
+                    )
+                    .expect("from_value::<Raw<..>> can never fail")
+                    .deserialize()
+                    .map_err(|_| Error::bad_database("Invalid room join rules event in database."))
+                })
+                .transpose()?
+                .map(|c| c.join_rule);
+
+            let chunk =
+                directory::PublicRoomsChunk {
                     aliases: Vec::new(),
                     canonical_alias: state
                         .get(&(EventType::RoomCanonicalAlias, "".to_owned()))
@@ -204,15 +233,26 @@ pub async fn get_public_rooms_filtered_route(
                         })
                         .transpose()?,
                 };
-                Ok(chunk)
-            })
-            .filter_map(|r: Result<_>| r.ok()) // Filter out buggy rooms
-            // We need to collect all, so we can sort by member count
-            .collect::<Vec<_>>();
+
+            if join_rule != Some(join_rules::JoinRule::Public) && !chunk.world_readable {
+                return Ok(None);
+            }
+
+            if let Some(search_term) = &search_term {
+                if !matches_search_term(&chunk, search_term) {
+                    return Ok(None);
+                }
+            }
+
+            Ok(Some(chunk))
+        })
+        .filter_map(|r: Result<_>| r.ok().flatten()) // Filter out buggy and unlisted rooms
+        // We need to collect all, so we can sort by member count
+        .collect::<Vec<_>>();
 
     all_rooms.sort_by(|l, r| r.num_joined_members.cmp(&l.num_joined_members));
 
-    let total_room_count_estimate = (all_rooms.len() as u32).into();
+    let total_room_count_estimate = all_rooms.len() as u64;
 
     let chunk = all_rooms
         .into_iter()
@@ -226,21 +266,110 @@ pub async fn get_public_rooms_filtered_route(
         Some(format!("p{}", since))
     };
 
-    let next_batch = if chunk.len() < limit as usize {
-        None
-    } else {
+    let next_batch = if since + limit < total_room_count_estimate {
         Some(format!("n{}", since + limit))
+    } else {
+        None
     };
 
     Ok(get_public_rooms_filtered::Response {
         chunk,
         prev_batch,
         next_batch,
-        total_room_count_estimate: Some(total_room_count_estimate),
+        total_room_count_estimate: Some((total_room_count_estimate as u32).into()),
     }
     .into())
 }
 
+/// Checks if the lowercase search term is part of the name, topic or canonical alias of the room.
+fn matches_search_term(chunk: &directory::PublicRoomsChunk, search_term: &str) -> bool {
+    let matches =
+        |field: Option<&str>| field.map_or(false, |f| f.to_lowercase().contains(search_term));
+
+    matches(chunk.name.as_deref())
+        || matches(chunk.topic.as_deref())
+        || matches(chunk.canonical_alias.as_ref().map(|a| a.as_str()))
+}
+
+/// Asks another server for its room directory. Responses are cached for a short time, so clients
+/// that page through the directory don't cause a request to the remote server every time.
+async fn remote_public_rooms(
+    db: &Database<'static>,
+    server: String,
+    body: &get_public_rooms_filtered::IncomingRequest,
+) -> Result<RemotePublicRooms> {
+    let (room_network, network_key) = match &body.room_network {
+        get_public_rooms_filtered::RoomNetwork::Matrix => (
+            federation::directory::get_public_rooms::v1::RoomNetwork::Matrix,
+            "matrix".to_owned(),
+        ),
+        get_public_rooms_filtered::RoomNetwork::All => (
+            federation::directory::get_public_rooms::v1::RoomNetwork::All,
+            "all".to_owned(),
+        ),
+        get_public_rooms_filtered::RoomNetwork::ThirdParty(instance_id) => (
+            federation::directory::get_public_rooms::v1::RoomNetwork::ThirdParty(
+                instance_id.clone(),
+            ),
+            format!("thirdparty:{}", instance_id),
+        ),
+    };
+
+    let cache_key = format!(
+        "{}|{}|{}|{}",
+        server,
+        network_key,
+        body.since.as_deref().unwrap_or(""),
+        body.limit.map_or(0, u64::from)
+    );
+
+    if let Some(cached) = db.globals.cached_remote_public_rooms(&cache_key) {
+        if let Ok(response) = serde_json::from_str(&cached) {
+            return Ok(response);
+        }
+    }
+
+    let response = server_server::send_request(
+        db,
+        server,
+        federation::directory::get_public_rooms::v1::Request {
+            limit: body.limit,
+            since: body.since.clone(),
+            room_network,
+        },
+    )
+    .await
+    .map_err(|e| {
+        warn!("Could not get the room directory of another server: {}", e);
+        Error::BadServerResponse("The room directory of the server is not available.")
+    })?;
+
+    let response = RemotePublicRooms {
+        chunk: response
+            .chunk
+            .into_iter()
+            .map(|c| {
+                // Convert ruma::api::federation::directory::get_public_rooms::v1::PublicRoomsChunk
+                // to ruma::api::client::r0::directory::PublicRoomsChunk
+                serde_json::from_str(
+                    &serde_json::to_string(&c).expect("PublicRoomsChunk::to_string always works"),
+                )
+                .expect("federation and client-server PublicRoomsChunk are the same type")
+            })
+            .collect(),
+        prev_batch: response.prev_batch,
+        next_batch: response.next_batch,
+        total_room_count_estimate: response.total_room_count_estimate,
+    };
+
+    db.globals.cache_remote_public_rooms(
+        cache_key,
+        serde_json::to_string(&response).expect("RemotePublicRooms::to_string always works"),
+    );
+
+    Ok(response)
+}
+
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/publicRooms", data = "<body>")
@@ -259,6 +388,8 @@ pub async fn get_public_rooms_route(
         sender_id,
         device_id,
         json_body,
+        client_ip,
+        appservice_id,
     } = body;
 
     let get_public_rooms_filtered::Response {
@@ -279,6 +410,8 @@ pub async fn get_public_rooms_route(
             sender_id,
             device_id,
             json_body,
+            client_ip,
+            appservice_id,
         },
     )
     .await?
@@ -301,6 +434,34 @@ pub async fn set_room_visibility_route(
     db: State<'_, Database<'_>>,
     body: Ruma<set_room_visibility::Request>,
 ) -> ConduitResult<set_room_visibility::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    // Publishing a room is like changing its state
+    let power_levels = db
+        .rooms
+        .room_state_get(&body.room_id, &EventType::RoomPowerLevels, "")?
+        .map(|pdu| pdu.content);
+    let required_level = power_levels
+        .as_ref()
+        .and_then(|p| p.get("state_default")?.as_i64())
+        .unwrap_or(50);
+    let sender_level = power_levels
+        .as_ref()
+        .and_then(|p| {
+            p.get("users")
+                .and_then(|users| users.get(sender_id.as_str()))
+                .or_else(|| p.get("users_default"))?
+                .as_i64()
+        })
+        .unwrap_or(0);
+
+    if !db.rooms.is_joined(sender_id, &body.room_id)? || sender_level < required_level {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to change the visibility of this room.",
+        ));
+    }
+
     match body.visibility {
         room::Visibility::Public => db.rooms.set_public(&body.room_id, true)?,
         room::Visibility::Private => db.rooms.set_public(&body.room_id, false)?,
@@ -1,29 +1,302 @@
-use crate::{utils, ConduitResult};
-use ruma::api::client::r0::filter::{self, create_filter, get_filter};
+use super::State;
+use crate::{ConduitResult, Database, Error, PduEvent, Result, Ruma};
+use ruma::{
+    api::client::{
+        error::ErrorKind,
+        r0::filter::{self, create_filter, get_filter},
+    },
+    RoomId, UserId,
+};
+use serde_json::{json, Value};
+use std::collections::HashSet;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, post};
 
-#[cfg_attr(feature = "conduit_bin", get("/_matrix/client/r0/user/<_>/filter/<_>"))]
-pub fn get_filter_route() -> ConduitResult<get_filter::Response> {
-    // TODO
+/// # `GET /_matrix/client/r0/user/{userId}/filter/{filterId}`
+///
+/// Returns a filter the user created.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/user/<_>/filter/<_>", data = "<body>")
+)]
+pub fn get_filter_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_filter::Request>,
+) -> ConduitResult<get_filter::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    if sender_id != &body.user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You can only get your own filters.",
+        ));
+    }
+
+    let filter = db
+        .users
+        .get_filter(sender_id, &body.filter_id)?
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Filter not found."))?;
+
     Ok(get_filter::Response {
-        filter: filter::FilterDefinition {
-            event_fields: None,
-            event_format: None,
-            account_data: None,
-            room: None,
-            presence: None,
-        },
+        filter: serde_json::from_value::<filter::FilterDefinition>(filter)
+            .map_err(|_| Error::bad_database("Filter in db is invalid."))?,
     }
     .into())
 }
 
-#[cfg_attr(feature = "conduit_bin", post("/_matrix/client/r0/user/<_>/filter"))]
-pub fn create_filter_route() -> ConduitResult<create_filter::Response> {
-    // TODO
+/// # `POST /_matrix/client/r0/user/{userId}/filter`
+///
+/// Stores a filter, which can be used with `/sync` by its id.
+///
+/// - The JSON of the request is stored as it is, so fields like `lazy_load_members` are kept
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/user/<_>/filter", data = "<body>")
+)]
+pub fn create_filter_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<create_filter::Request>,
+) -> ConduitResult<create_filter::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    if sender_id != &body.user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You can only create filters for yourself.",
+        ));
+    }
+
+    let filter = serde_json::from_str::<Value>(
+        body.json_body
+            .as_ref()
+            .ok_or(Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?
+            .get(),
+    )
+    .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?;
+
     Ok(create_filter::Response {
-        filter_id: utils::random_string(10),
+        filter_id: db.users.create_filter(sender_id, &filter)?,
     }
     .into())
 }
+
+/// Returns the filter of a `filter` parameter, which is either the JSON of a filter or the id of
+/// a filter the user created. Without a parameter, the filter is empty and allows everything.
+pub fn load_filter(db: &Database<'_>, user_id: &UserId, filter: Option<&str>) -> Result<Value> {
+    let filter = match filter {
+        Some(filter) => filter,
+        None => return Ok(json!({})),
+    };
+
+    if filter.trim_start().starts_with('{') {
+        serde_json::from_str(filter)
+            .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid filter JSON."))
+    } else {
+        db.users
+            .get_filter(user_id, filter)?
+            .ok_or(Error::BadRequest(ErrorKind::NotFound, "Filter not found."))
+    }
+}
+
+/// Checks the event against the `types`, `not_types`, `senders`, `not_senders`, `rooms` and
+/// `not_rooms` of a room event filter.
+pub fn filter_allows_event(filter: &Value, pdu: &PduEvent) -> bool {
+    lists_allow(filter, "types", "not_types", &pdu.kind.to_string())
+        && lists_allow(filter, "senders", "not_senders", pdu.sender.as_str())
+        && lists_allow(filter, "rooms", "not_rooms", pdu.room_id.as_str())
+}
+
+/// Checks if the event is hidden because the user ignores its sender. State events like
+/// memberships are still shown, so the state of the room stays complete.
+pub fn sent_by_ignored_user(ignored_users: &HashSet<UserId>, pdu: &PduEvent) -> bool {
+    pdu.state_key.is_none() && ignored_users.contains(&pdu.sender)
+}
+
+/// Checks the room against the `rooms` and `not_rooms` of a room filter.
+pub fn filter_allows_room(filter: &Value, room_id: &RoomId) -> bool {
+    lists_allow(filter, "rooms", "not_rooms", room_id.as_str())
+}
+
+/// Returns the `limit` of a room event filter, which is 10 by default.
+pub fn timeline_limit(filter: &Value) -> usize {
+    filter
+        .get("limit")
+        .and_then(|limit| limit.as_u64())
+        .map_or(10, |limit| limit as usize)
+}
+
+/// Returns the last `limit` events in their order and if there are more events before them.
+pub fn last_events<T>(
+    mut events: impl DoubleEndedIterator<Item = T>,
+    limit: usize,
+) -> (Vec<T>, bool) {
+    let mut last = events.by_ref().rev().take(limit).collect::<Vec<_>>();
+    last.reverse();
+
+    (last, events.next().is_some())
+}
+
+/// Checks if the filter requests the events in the format of federation.
+pub fn federation_format(filter: &Value) -> bool {
+    filter
+        .get("event_format")
+        .and_then(|format| format.as_str())
+        == Some("federation")
+}
+
+/// A missing list allows everything, the exclusion list wins over the inclusion list. Patterns
+/// can contain `*` as a wildcard.
+fn lists_allow(filter: &Value, list: &str, not_list: &str, value: &str) -> bool {
+    let list_matches = |key: &str| {
+        filter
+            .get(key)
+            .and_then(|list| list.as_array())
+            .map(|list| {
+                list.iter()
+                    .filter_map(|pattern| pattern.as_str())
+                    .any(|pattern| wildcard_matches(pattern, value))
+            })
+    };
+
+    list_matches(not_list) != Some(true) && list_matches(list) != Some(false)
+}
+
+fn wildcard_matches(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    if !value.starts_with(first) {
+        return false;
+    }
+
+    let mut rest = &value[first.len()..];
+    let mut parts = parts.collect::<Vec<_>>();
+    let last = match parts.pop() {
+        Some(last) => last,
+        // There is no wildcard
+        None => return rest.is_empty(),
+    };
+
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        filter_allows_event, last_events, sent_by_ignored_user, timeline_limit, wildcard_matches,
+    };
+    use crate::PduEvent;
+    use ruma::UserId;
+    use serde_json::json;
+    use std::{collections::HashSet, convert::TryFrom};
+
+    fn pdu(kind: &str, sender: &str) -> PduEvent {
+        serde_json::from_value(json!({
+            "event_id": "$event:example.com",
+            "room_id": "!room:example.com",
+            "sender": sender,
+            "origin": "example.com",
+            "origin_server_ts": 0,
+            "type": kind,
+            "content": {},
+            "prev_events": [],
+            "depth": 0,
+            "auth_events": [],
+            "hashes": { "sha256": "" },
+            "signatures": {},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn types_include_and_exclude_events() {
+        let message = pdu("m.room.message", "@alice:example.com");
+        let topic = pdu("m.room.topic", "@alice:example.com");
+
+        let filter = json!({ "types": ["m.room.message"] });
+        assert!(filter_allows_event(&filter, &message));
+        assert!(!filter_allows_event(&filter, &topic));
+
+        let filter = json!({ "not_types": ["m.room.message"] });
+        assert!(!filter_allows_event(&filter, &message));
+        assert!(filter_allows_event(&filter, &topic));
+
+        // The exclusion list wins
+        let filter = json!({ "types": ["m.room.*"], "not_types": ["m.room.topic"] });
+        assert!(filter_allows_event(&filter, &message));
+        assert!(!filter_allows_event(&filter, &topic));
+
+        let filter = json!({ "types": [] });
+        assert!(!filter_allows_event(&filter, &message));
+        assert!(filter_allows_event(&json!({}), &message));
+    }
+
+    #[test]
+    fn senders_and_rooms_filter_events() {
+        let message = pdu("m.room.message", "@alice:example.com");
+
+        assert!(filter_allows_event(
+            &json!({ "senders": ["@alice:example.com"] }),
+            &message
+        ));
+        assert!(!filter_allows_event(
+            &json!({ "not_senders": ["@alice:example.com"] }),
+            &message
+        ));
+        assert!(!filter_allows_event(
+            &json!({ "rooms": ["!other:example.com"] }),
+            &message
+        ));
+        assert!(filter_allows_event(
+            &json!({ "not_rooms": ["!other:example.com"] }),
+            &message
+        ));
+    }
+
+    #[test]
+    fn wildcards_match_any_characters() {
+        assert!(wildcard_matches("m.*", "m.room.message"));
+        assert!(wildcard_matches("*.message", "m.room.message"));
+        assert!(wildcard_matches("m.*.message", "m.room.message"));
+        assert!(wildcard_matches("*", ""));
+        assert!(!wildcard_matches("m.room", "m.room.message"));
+        assert!(!wildcard_matches("m.*.topic", "m.room.message"));
+    }
+
+    #[test]
+    fn timeline_limit_is_respected() {
+        assert_eq!(timeline_limit(&json!({})), 10);
+        assert_eq!(timeline_limit(&json!({ "limit": 3 })), 3);
+
+        let limit = timeline_limit(&json!({ "limit": 3 }));
+        assert_eq!(last_events(1..=5, limit), (vec![3, 4, 5], true));
+        assert_eq!(last_events(1..=3, limit), (vec![1, 2, 3], false));
+        assert_eq!(last_events(1..=2, limit), (vec![1, 2], false));
+        assert_eq!(last_events(1..=2, 0), (vec![], true));
+    }
+
+    #[test]
+    fn messages_of_ignored_users_are_hidden_from_the_ignorer_only() {
+        let message = pdu("m.room.message", "@mallory:example.com");
+        let mut membership = pdu("m.room.member", "@mallory:example.com");
+        membership.state_key = Some("@mallory:example.com".to_owned());
+
+        let mut ignorer_ignores = HashSet::new();
+        ignorer_ignores.insert(UserId::try_from("@mallory:example.com").unwrap());
+        assert!(sent_by_ignored_user(&ignorer_ignores, &message));
+        // Memberships stay visible, so the room state is complete
+        assert!(!sent_by_ignored_user(&ignorer_ignores, &membership));
+
+        let mut other_ignores = HashSet::new();
+        other_ignores.insert(UserId::try_from("@eve:example.com").unwrap());
+        assert!(!sent_by_ignored_user(&other_ignores, &message));
+        assert!(!sent_by_ignored_user(&HashSet::new(), &message));
+    }
+}
@@ -1,5 +1,6 @@
 use super::{State, SESSION_ID_LENGTH};
-use crate::{utils, ConduitResult, Database, Error, Ruma};
+use crate::{server_server, utils, ConduitResult, Database, Error, Result, Ruma};
+use rocket::response::content::Json;
 use ruma::{
     api::client::{
         error::ErrorKind,
@@ -12,7 +13,9 @@ use ruma::{
         },
     },
     encryption::UnsignedDeviceInfo,
+    DeviceId, DeviceKeyAlgorithm, UserId,
 };
+use serde_json::json;
 use std::collections::{BTreeMap, HashSet};
 
 #[cfg(feature = "conduit_bin")]
@@ -36,6 +39,24 @@ pub fn upload_keys_route(
         }
     }
 
+    // Fallback keys are not part of the request type yet
+    if let Some(fallback_keys) = body
+        .json_body
+        .as_ref()
+        .and_then(|json| serde_json::from_str::<serde_json::Value>(json.get()).ok())
+        .and_then(|json| {
+            json.get("fallback_keys")
+                .or_else(|| json.get("org.matrix.msc2732.fallback_keys"))
+                .and_then(|keys| keys.as_object())
+                .cloned()
+        })
+    {
+        for (key_id, key) in &fallback_keys {
+            db.users
+                .add_fallback_key(sender_id, device_id, key_id, key)?;
+        }
+    }
+
     if let Some(device_keys) = &body.device_keys {
         // This check is needed to assure that signatures are kept
         if db.users.get_device_keys(sender_id, device_id)?.is_none() {
@@ -130,42 +151,121 @@ pub fn get_keys_route(
     .into())
 }
 
+/// # `POST /_matrix/client/r0/keys/claim`
+///
+/// Claims one-time keys of the given devices.
+///
+/// - Every one-time key is only handed out once, even to concurrent requests
+/// - If a device has no one-time keys left, its fallback key is returned with `fallback: true`
+/// - Keys of remote users are claimed over federation
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/keys/claim", data = "<body>")
 )]
-pub fn claim_keys_route(
+pub async fn claim_keys_route(
     db: State<'_, Database<'_>>,
     body: Ruma<claim_keys::Request>,
-) -> ConduitResult<claim_keys::Response> {
-    let mut one_time_keys = BTreeMap::new();
-    for (user_id, map) in &body.one_time_keys {
-        let mut container = BTreeMap::new();
+) -> Result<Json<String>> {
+    let mut local_users = BTreeMap::new();
+    let mut remote_servers = BTreeMap::<_, serde_json::Map<_, _>>::new();
+
+    for (user_id, devices) in &body.one_time_keys {
+        if user_id.server_name() == db.globals.server_name() {
+            local_users.insert(user_id.clone(), devices.clone());
+        } else {
+            remote_servers
+                .entry(user_id.server_name().to_string())
+                .or_default()
+                .insert(user_id.to_string(), json!(devices));
+        }
+    }
+
+    let mut one_time_keys = claim_local_keys(&db, &local_users)?;
+    let mut failures = serde_json::Map::new();
+
+    for (server, users) in remote_servers {
+        let response = server_server::send_json_request(
+            &db,
+            &server,
+            reqwest::Method::POST,
+            "/_matrix/federation/v1/user/keys/claim",
+            Some(json!({ "one_time_keys": users })),
+        )
+        .await;
+
+        match response
+            .ok()
+            .and_then(|r| r.get("one_time_keys")?.as_object().cloned())
+        {
+            Some(keys) => one_time_keys.extend(keys),
+            None => {
+                failures.insert(server, json!({}));
+            }
+        }
+    }
+
+    Ok(Json(
+        json!({
+            "one_time_keys": one_time_keys,
+            "failures": failures,
+        })
+        .to_string(),
+    ))
+}
+
+/// Claims one one-time key (or the fallback key) for each of the devices of local users.
+pub fn claim_local_keys(
+    db: &Database<'_>,
+    users: &BTreeMap<UserId, BTreeMap<Box<DeviceId>, DeviceKeyAlgorithm>>,
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let mut one_time_keys = serde_json::Map::new();
+    for (user_id, map) in users {
+        let mut container = serde_json::Map::new();
         for (device_id, key_algorithm) in map {
-            if let Some(one_time_keys) =
+            let mut c = serde_json::Map::new();
+            if let Some((key_id, key)) =
                 db.users
                     .take_one_time_key(user_id, device_id, key_algorithm, &db.globals)?
             {
-                let mut c = BTreeMap::new();
-                c.insert(one_time_keys.0, one_time_keys.1);
-                container.insert(device_id.clone(), c);
+                c.insert(
+                    serde_json::to_value(key_id)
+                        .expect("DeviceKeyId can be serialized")
+                        .as_str()
+                        .expect("DeviceKeyId is a string")
+                        .to_owned(),
+                    json!(key),
+                );
+            } else if let Some((key_id, mut key)) =
+                db.users
+                    .get_fallback_key(user_id, device_id, key_algorithm)?
+            {
+                if let Some(key) = key.as_object_mut() {
+                    key.insert("fallback".to_owned(), true.into());
+                }
+                c.insert(key_id, key);
+            } else {
+                continue;
             }
+            container.insert(device_id.to_string(), c.into());
         }
-        one_time_keys.insert(user_id.clone(), container);
+        one_time_keys.insert(user_id.to_string(), container.into());
     }
 
-    Ok(claim_keys::Response {
-        failures: BTreeMap::new(),
-        one_time_keys,
-    }
-    .into())
+    Ok(one_time_keys)
 }
 
+/// # `POST /_matrix/client/unstable/keys/device_signing/upload`
+///
+/// Uploads the cross-signing keys of the sender user.
+///
+/// - Requires UIAA
+/// - The self-signing and user-signing keys have to be signed by the master key
+/// - The master and self-signing keys are sent to all servers that share a room with the user
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/unstable/keys/device_signing/upload", data = "<body>")
 )]
-pub fn upload_signing_keys_route(
+pub async fn upload_signing_keys_route(
     db: State<'_, Database<'_>>,
     body: Ruma<upload_signing_keys::Request>,
 ) -> ConduitResult<upload_signing_keys::Response> {
@@ -211,16 +311,21 @@ pub fn upload_signing_keys_route(
             &db.rooms,
             &db.globals,
         )?;
+
+        server_server::send_signing_key_update_edu(&db, &sender_id).await?;
     }
 
     Ok(upload_signing_keys::Response.into())
 }
 
+/// # `POST /_matrix/client/unstable/keys/signatures/upload`
+///
+/// Adds signatures of the sender user to keys of the sender or other users.
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/unstable/keys/signatures/upload", data = "<body>")
 )]
-pub fn upload_signatures_route(
+pub async fn upload_signatures_route(
     db: State<'_, Database<'_>>,
     body: Ruma<upload_signatures::Request>,
 ) -> ConduitResult<upload_signatures::Response> {
@@ -271,6 +376,11 @@ pub fn upload_signatures_route(
         }
     }
 
+    // Other servers need the new signatures of the master key
+    if body.signed_keys.contains_key(sender_id) {
+        server_server::send_signing_key_update_edu(&db, &sender_id).await?;
+    }
+
     Ok(upload_signatures::Response.into())
 }
 
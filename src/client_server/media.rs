@@ -1,26 +1,66 @@
 use super::State;
-use crate::{database::media::FileMeta, utils, ConduitResult, Database, Error, Ruma};
-use ruma::api::client::{
-    error::ErrorKind,
-    r0::media::{create_content, get_content, get_content_thumbnail, get_media_config},
+use crate::{
+    database::media::FileMeta, server_server, utils, ConduitResult, Database, Error, Result, Ruma,
 };
+use hyper::body::HttpBody;
+use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE, LOCATION};
+use rocket::response::content::Json;
+use ruma::{
+    api::client::{
+        error::ErrorKind,
+        r0::{
+            account::whoami,
+            media::{create_content, get_content, get_content_thumbnail, get_media_config},
+        },
+    },
+    ServerName,
+};
+use serde_json::{json, Value};
+use tracing::warn;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, post};
-use std::convert::TryInto;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryInto,
+    io,
+    net::IpAddr,
+    time::Duration,
+};
 
 const MXC_LENGTH: usize = 256;
 
+/// Pages and images bigger than this are not used for url previews
+const MAX_URL_PREVIEW_SIZE: usize = 10 * 1024 * 1024;
+
+/// How many redirects are followed for a url preview
+const MAX_URL_PREVIEW_REDIRECTS: usize = 5;
+
+/// How long fetching a page or image for a url preview may take, including redirects
+const URL_PREVIEW_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a url preview is reused before the page is fetched again
+const URL_PREVIEW_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// # `GET /_matrix/media/r0/config`
+///
+/// Returns the biggest upload this server accepts as `m.upload.size`.
 #[cfg_attr(feature = "conduit_bin", get("/_matrix/media/r0/config"))]
 pub fn get_media_config_route(
     db: State<'_, Database<'_>>,
 ) -> ConduitResult<get_media_config::Response> {
     Ok(get_media_config::Response {
-        upload_size: db.globals.max_request_size().into(),
+        upload_size: db.globals.media_max_upload_size().into(),
     }
     .into())
 }
 
+/// # `POST /_matrix/media/r0/upload`
+///
+/// Stores the uploaded file and returns its mxc uri.
+///
+/// - Files bigger than `media_max_upload_size` are rejected with M_TOO_LARGE before they are
+/// read completely
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/media/r0/upload", data = "<body>")
@@ -34,41 +74,59 @@ pub fn create_content_route(
         db.globals.server_name(),
         utils::random_string(MXC_LENGTH)
     );
-    db.media.create(
-        mxc.clone(),
-        body.filename.as_ref(),
-        &body.content_type,
-        &body.file,
-    )?;
+    // Uploads can be big, writing them shouldn't block the executor
+    tokio::task::block_in_place(|| {
+        db.media.create(
+            mxc.clone(),
+            body.filename.as_ref(),
+            &body.content_type,
+            &body.file,
+        )
+    })?;
 
     Ok(create_content::Response { content_uri: mxc }.into())
 }
 
+/// # `GET /_matrix/media/r0/download/{serverName}/{mediaId}`
+///
+/// Returns a file of the media repository.
+///
+/// - Files of other servers are downloaded from their server on the first request and stored,
+/// see [`fetch_remote_content`](fn.fetch_remote_content.html)
 #[cfg_attr(
     feature = "conduit_bin",
     get(
-        "/_matrix/media/r0/download/<_server_name>/<_media_id>",
+        "/_matrix/media/r0/download/<_server_name>/<_media_id>?<allow_remote>",
         data = "<body>"
     )
 )]
-pub fn get_content_route(
+pub async fn get_content_route(
     db: State<'_, Database<'_>>,
     body: Ruma<get_content::Request>,
     _server_name: String,
     _media_id: String,
+    allow_remote: Option<bool>,
 ) -> ConduitResult<get_content::Response> {
+    fetch_remote_content(
+        &db,
+        &body.server_name,
+        &body.media_id,
+        allow_remote.unwrap_or(true),
+    )
+    .await?;
+
     if let Some(FileMeta {
         filename,
         content_type,
         file,
-    }) = db
-        .media
-        .get(format!("mxc://{}/{}", body.server_name, body.media_id))?
-    {
+    }) = tokio::task::block_in_place(|| {
+        db.media
+            .get(format!("mxc://{}/{}", body.server_name, body.media_id))
+    })? {
         Ok(get_content::Response {
             file,
             content_type,
-            content_disposition: filename.unwrap_or_default(), // TODO: Spec says this should be optional
+            content_disposition: content_disposition(filename.as_deref()),
         }
         .into())
     } else {
@@ -76,32 +134,463 @@ pub fn get_content_route(
     }
 }
 
+/// # `GET /_matrix/media/r0/thumbnail/{serverName}/{mediaId}`
+///
+/// Returns a thumbnail of a file of the media repository.
+///
+/// - Thumbnails of files of other servers are generated here, from the original file that is
+/// downloaded like in [`GET /_matrix/media/r0/download/{serverName}/{mediaId}`](fn.get_content_route.html)
 #[cfg_attr(
     feature = "conduit_bin",
     get(
-        "/_matrix/media/r0/thumbnail/<_server_name>/<_media_id>",
+        "/_matrix/media/r0/thumbnail/<_server_name>/<_media_id>?<allow_remote>",
         data = "<body>"
     )
 )]
-pub fn get_content_thumbnail_route(
+pub async fn get_content_thumbnail_route(
     db: State<'_, Database<'_>>,
     body: Ruma<get_content_thumbnail::Request>,
     _server_name: String,
     _media_id: String,
+    allow_remote: Option<bool>,
 ) -> ConduitResult<get_content_thumbnail::Response> {
+    let width = body
+        .width
+        .try_into()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Width is invalid."))?;
+    let height = body
+        .height
+        .try_into()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Height is invalid."))?;
+
+    fetch_remote_content(
+        &db,
+        &body.server_name,
+        &body.media_id,
+        allow_remote.unwrap_or(true),
+    )
+    .await?;
+
+    // Thumbnails are generated on the first request, which takes a while for big images
     if let Some(FileMeta {
         content_type, file, ..
-    }) = db.media.get_thumbnail(
-        format!("mxc://{}/{}", body.server_name, body.media_id),
-        body.width
-            .try_into()
-            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Width is invalid."))?,
-        body.height
-            .try_into()
-            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Width is invalid."))?,
-    )? {
+    }) = tokio::task::block_in_place(|| {
+        db.media.get_thumbnail(
+            format!("mxc://{}/{}", body.server_name, body.media_id),
+            width,
+            height,
+            body.method
+                .as_ref()
+                .unwrap_or(&get_content_thumbnail::Method::Scale),
+        )
+    })? {
         Ok(get_content_thumbnail::Response { file, content_type }.into())
     } else {
         Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."))
     }
 }
+
+/// # `GET /_matrix/media/r0/preview_url?url=...`
+///
+/// Returns the OpenGraph data of a web page, like `og:title`, `og:description` and `og:image`.
+///
+/// - Only available if `url_preview_enabled` is true
+/// - Pages and images on private, loopback or link-local addresses are never fetched, also not
+/// after redirects
+/// - The image is stored in the media repository, so `og:image` is an mxc uri
+/// - Previews are cached for an hour
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/media/r0/preview_url?<url>", data = "<body>")
+)]
+pub async fn get_media_preview_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+    url: String,
+) -> Result<Json<String>> {
+    let _sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    let client = db.globals.url_preview_client().ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "Url previews are disabled on this server.",
+    ))?;
+
+    if let Some(preview) = db.media.url_preview(&url)? {
+        return Ok(Json(preview.to_string()));
+    }
+
+    let page_url = reqwest::Url::parse(&url)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid url."))?;
+    let (content_type, page, page_url) = fetch_public_url(client, page_url).await?;
+
+    let mut preview = serde_json::Map::new();
+    let image = if content_type.starts_with("image/") {
+        // The url is the image itself
+        Some((content_type, page))
+    } else {
+        let properties = parse_opengraph(&String::from_utf8_lossy(&page));
+        let image_url = properties
+            .get("og:image")
+            .and_then(|image_url| page_url.join(image_url).ok());
+        for (key, value) in properties {
+            if key != "og:image" {
+                preview.insert(key, value.into());
+            }
+        }
+
+        match image_url {
+            Some(image_url) => match fetch_public_url(client, image_url).await {
+                Ok((content_type, image, _)) if content_type.starts_with("image/") => {
+                    Some((content_type, image))
+                }
+                Ok(_) => None,
+                Err(e) => {
+                    warn!("Failed to download the image of a url preview: {}", e);
+                    None
+                }
+            },
+            None => None,
+        }
+    };
+
+    if let Some((content_type, image)) = image {
+        let mxc = format!(
+            "mxc://{}/{}",
+            db.globals.server_name(),
+            utils::random_string(MXC_LENGTH)
+        );
+        tokio::task::block_in_place(|| db.media.create(mxc.clone(), None, &content_type, &image))?;
+
+        preview.insert("og:image".to_owned(), mxc.into());
+        preview.insert("og:image:type".to_owned(), content_type.into());
+        preview.insert("matrix:image:size".to_owned(), image.len().into());
+    }
+
+    let preview = Value::Object(preview);
+    db.media.set_url_preview(
+        &url,
+        &preview,
+        utils::millis_since_unix_epoch() + URL_PREVIEW_LIFETIME.as_millis() as u64,
+    )?;
+
+    Ok(Json(preview.to_string()))
+}
+
+/// Makes sure that a file of another server is in the media store. It is downloaded from its
+/// server on the first request and served from the media store afterwards.
+///
+/// - Files of other servers are not found if `allow_remote_media` is false
+/// - With `allow_remote` false, only files that are already stored are found
+async fn fetch_remote_content(
+    db: &Database<'static>,
+    server_name: &ServerName,
+    media_id: &str,
+    allow_remote: bool,
+) -> Result<()> {
+    if server_name == db.globals.server_name() {
+        return Ok(());
+    }
+
+    if !db.globals.allow_remote_media() {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."));
+    }
+
+    let mxc = format!("mxc://{}/{}", server_name, media_id);
+    if !allow_remote || db.media.exists(&mxc)? {
+        return Ok(());
+    }
+
+    let FileMeta {
+        filename,
+        content_type,
+        file,
+    } = server_server::fetch_remote_media(db, server_name, media_id)
+        .await
+        .map_err(|e| {
+            warn!("Could not download {}: {}", mxc, e);
+            e
+        })?;
+
+    tokio::task::block_in_place(|| {
+        db.media
+            .create(mxc, filename.as_ref(), &content_type, &file)
+    })
+}
+
+/// Downloads a page or image and returns its content type, its content and its url after
+/// redirects. Every url is checked with `check_public_url` before it is fetched.
+async fn fetch_public_url(
+    client: &utils::UrlPreviewClient,
+    url: reqwest::Url,
+) -> Result<(String, Vec<u8>, reqwest::Url)> {
+    tokio::time::timeout(URL_PREVIEW_TIMEOUT, fetch_public_url_inner(client, url))
+        .await
+        .map_err(|_| Error::BadServerResponse("The url took too long to load."))?
+}
+
+async fn fetch_public_url_inner(
+    client: &utils::UrlPreviewClient,
+    mut url: reqwest::Url,
+) -> Result<(String, Vec<u8>, reqwest::Url)> {
+    for _ in 0..=MAX_URL_PREVIEW_REDIRECTS {
+        check_public_url(&url)?;
+
+        let uri = url
+            .as_str()
+            .parse::<hyper::Uri>()
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid url."))?;
+        let mut response = client.get(uri).await.map_err(url_preview_error)?;
+
+        if response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or(Error::BadServerResponse("Redirect without location."))?;
+            url = url
+                .join(location)
+                .map_err(|_| Error::BadServerResponse("Redirect to an invalid url."))?;
+            continue;
+        }
+
+        if !response.status().is_success() {
+            return Err(Error::BadServerResponse("The url could not be fetched."));
+        }
+
+        let content_length = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse::<u64>().ok());
+        if content_length.unwrap_or(0) > MAX_URL_PREVIEW_SIZE as u64 {
+            return Err(Error::BadRequest(
+                ErrorKind::TooLarge,
+                "The content of the url is too big.",
+            ));
+        }
+
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .unwrap_or("")
+            .to_owned();
+
+        // The content length can be missing or wrong, so the limit is also checked while reading
+        let mut content = Vec::new();
+        while let Some(chunk) = response.body_mut().data().await {
+            let chunk = chunk.map_err(url_preview_error)?;
+            if content.len() + chunk.len() > MAX_URL_PREVIEW_SIZE {
+                return Err(Error::BadRequest(
+                    ErrorKind::TooLarge,
+                    "The content of the url is too big.",
+                ));
+            }
+            content.extend_from_slice(&chunk);
+        }
+
+        return Ok((content_type, content, url));
+    }
+
+    Err(Error::BadServerResponse("The url redirects too often."))
+}
+
+/// Rejects urls that are not http(s) or whose host is an address that is not public, so users
+/// can't make the server fetch pages of its internal network. Host names are checked by
+/// `utils::PublicResolver` when the client connects.
+fn check_public_url(url: &reqwest::Url) -> Result<()> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Only http and https urls can be previewed.",
+        ));
+    }
+
+    let host = url.host_str().ok_or(Error::BadRequest(
+        ErrorKind::InvalidParam,
+        "Url has no host.",
+    ))?;
+    // Hosts of IPv6 urls are in brackets
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        if !utils::is_public_ip(ip) {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "The url points to a private address.",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Converts the error of a url preview request. Hosts with private addresses fail in the
+/// resolver with `PermissionDenied`.
+fn url_preview_error(error: hyper::Error) -> Error {
+    let mut source = std::error::Error::source(&error);
+    while let Some(e) = source {
+        if e.downcast_ref::<io::Error>()
+            .map_or(false, |e| e.kind() == io::ErrorKind::PermissionDenied)
+        {
+            return Error::BadRequest(ErrorKind::Forbidden, "The url points to a private address.");
+        }
+        source = e.source();
+    }
+
+    warn!("Could not fetch url for a preview: {}", error);
+    Error::BadServerResponse("The url could not be fetched.")
+}
+
+/// Returns the OpenGraph properties of a page. Pages without `og:title` or `og:description` use
+/// their `<title>` and their `description` meta tag instead.
+fn parse_opengraph(html: &str) -> BTreeMap<String, String> {
+    // Lowercasing ASCII keeps all byte positions, so they can be used for both strings
+    let lowercase = html.to_ascii_lowercase();
+
+    let mut properties = BTreeMap::new();
+    let mut description = None;
+    let mut offset = 0;
+    while let Some(start) = lowercase[offset..].find("<meta") {
+        let start = offset + start + "<meta".len();
+        let end = match lowercase[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        offset = end;
+
+        let attributes = parse_attributes(&html[start..end]);
+        let key = match attributes
+            .get("property")
+            .or_else(|| attributes.get("name"))
+        {
+            Some(key) => key.to_lowercase(),
+            None => continue,
+        };
+        let content = match attributes.get("content") {
+            Some(content) => content.clone(),
+            None => continue,
+        };
+
+        if key.starts_with("og:") {
+            // The first value wins, later ones are usually less important images
+            properties.entry(key).or_insert(content);
+        } else if key == "description" && description.is_none() {
+            description = Some(content);
+        }
+    }
+
+    if !properties.contains_key("og:title") {
+        let title = lowercase.find("<title").and_then(|start| {
+            let start = start + lowercase[start..].find('>')? + 1;
+            let end = start + lowercase[start..].find("</title")?;
+            Some(decode_entities(html[start..end].trim()))
+        });
+        if let Some(title) = title.filter(|title| !title.is_empty()) {
+            properties.insert("og:title".to_owned(), title);
+        }
+    }
+
+    if let Some(description) = description {
+        properties
+            .entry("og:description".to_owned())
+            .or_insert(description);
+    }
+
+    properties
+}
+
+/// Parses the attributes of an HTML tag, the names are lowercased.
+fn parse_attributes(tag: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    let mut chars = tag.chars().peekable();
+
+    loop {
+        while chars
+            .peek()
+            .map_or(false, |c| c.is_whitespace() || *c == '/')
+        {
+            chars.next();
+        }
+
+        let mut name = String::new();
+        while let Some(c) = chars.peek().copied() {
+            if c.is_whitespace() || c == '=' || c == '/' {
+                break;
+            }
+            name.push(c.to_ascii_lowercase());
+            chars.next();
+        }
+        if name.is_empty() {
+            break;
+        }
+
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek() != Some(&'=') {
+            attributes.entry(name).or_insert_with(String::new);
+            continue;
+        }
+        chars.next();
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+
+        let mut value = String::new();
+        match chars.peek().copied() {
+            Some(quote) if quote == '"' || quote == '\'' => {
+                chars.next();
+                for c in &mut chars {
+                    if c == quote {
+                        break;
+                    }
+                    value.push(c);
+                }
+            }
+            _ => {
+                while let Some(c) = chars.peek().copied() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+            }
+        }
+
+        attributes
+            .entry(name)
+            .or_insert_with(|| decode_entities(&value));
+    }
+
+    attributes
+}
+
+/// Decodes the HTML entities that are common in titles and descriptions.
+fn decode_entities(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+/// Builds the Content-Disposition header for a download. The filename comes from the uploader,
+/// so everything that could escape the quoted string or the header is removed.
+fn content_disposition(filename: Option<&str>) -> String {
+    let filename = filename
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, '/' | '\\' | '"'))
+        .collect::<String>();
+
+    if filename.is_empty() {
+        "inline".to_owned()
+    } else {
+        format!("inline; filename=\"{}\"", filename)
+    }
+}
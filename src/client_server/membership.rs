@@ -1,7 +1,9 @@
 use super::State;
 use crate::{
-    client_server, pdu::PduBuilder, server_server, utils, ConduitResult, Database, Error, Ruma,
+    client_server, database::globals::supported_room_versions, pdu::PduBuilder, server_server,
+    state_res, utils, ConduitResult, Database, Error, PduEvent, Result, Ruma,
 };
+use rocket::response::content::Json;
 use ruma::{
     api::{
         client::{
@@ -18,13 +20,25 @@ use ruma::{
         federation,
     },
     events::{room::member, EventType},
-    EventId, Raw, RoomId, RoomVersionId,
+    EventId, Raw, RoomId, RoomVersionId, ServerName, UserId,
 };
-use std::{collections::BTreeMap, convert::TryFrom};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    convert::TryFrom,
+};
+use tracing::warn;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, post};
 
+/// # `POST /_matrix/client/r0/rooms/{roomId}/join`
+///
+/// Joins the room.
+///
+/// - Restricted rooms can be joined by members of the allowed rooms, a local user with permission
+/// to invite is named in `join_authorised_via_users_server`
+/// - The same is true for `knock_restricted` rooms, other users can knock on them instead
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/rooms/<_>/join", data = "<body>")
@@ -35,90 +49,120 @@ pub async fn join_room_by_id_route(
 ) -> ConduitResult<join_room_by_id::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    // Ask a remote server if we don't have this room
-    if !db.rooms.exists(&body.room_id)? && body.room_id.server_name() != db.globals.server_name() {
-        let make_join_response = server_server::send_request(
-            &db,
-            body.room_id.server_name().to_string(),
-            federation::membership::create_join_event_template::v1::Request {
-                room_id: body.room_id.clone(),
-                user_id: sender_id.clone(),
-                ver: vec![RoomVersionId::Version5, RoomVersionId::Version6],
-            },
-        )
-        .await?;
-
-        let mut join_event_stub_value =
-            serde_json::from_str::<serde_json::Value>(make_join_response.event.json().get())
-                .map_err(|_| {
-                    Error::BadServerResponse("Invalid make_join event json received from server.")
-                })?;
+    join_room_by_id_helper(&db, sender_id, &body.room_id).await?;
 
-        let join_event_stub =
-            join_event_stub_value
-                .as_object_mut()
-                .ok_or(Error::BadServerResponse(
-                    "Invalid make join event object received from server.",
-                ))?;
+    Ok(join_room_by_id::Response {
+        room_id: body.room_id.clone(),
+    }
+    .into())
+}
 
-        join_event_stub.insert(
-            "origin".to_owned(),
-            db.globals.server_name().to_owned().to_string().into(),
-        );
-        join_event_stub.insert(
-            "origin_server_ts".to_owned(),
-            utils::millis_since_unix_epoch().into(),
-        );
+/// Joins the user to the room, see
+/// [`POST /_matrix/client/r0/rooms/{roomId}/join`](fn.join_room_by_id_route.html).
+pub async fn join_room_by_id_helper(
+    db: &Database<'static>,
+    sender_id: &UserId,
+    room_id: &RoomId,
+) -> Result<()> {
+    // Accepting the invite to a direct chat makes the room a direct chat for the user
+    let direct_inviter = direct_inviter(db, sender_id, room_id)?;
 
-        // Generate event id
-        let event_id = EventId::try_from(&*format!(
-            "${}",
-            ruma::signatures::reference_hash(&join_event_stub_value)
-                .expect("ruma can calculate reference hashes")
-        ))
-        .expect("ruma's reference hashes are valid event ids");
+    // Ask a remote server if no user of this server is in the room. Invites of other servers
+    // are accepted on the server of the inviting user
+    let invite_server = remote_invite_server(db, sender_id, room_id)?;
+    if (room_id.server_name() != db.globals.server_name() || invite_server.is_some())
+        && !db
+            .rooms
+            .room_members(room_id)
+            .filter_map(|r| r.ok())
+            .any(|user_id| user_id.server_name() == db.globals.server_name())
+    {
+        let remote_server = invite_server
+            .as_deref()
+            .unwrap_or_else(|| room_id.server_name());
+        join_room_remotely(db, sender_id, room_id, remote_server).await?;
 
-        // We don't leave the event id into the pdu because that's only allowed in v1 or v2 rooms
-        let join_event_stub = join_event_stub_value.as_object_mut().unwrap();
-        join_event_stub.remove("event_id");
+        if let Some(inviter) = &direct_inviter {
+            db.account_data
+                .add_direct_room(sender_id, inviter, room_id, &db.globals)?;
+        }
 
-        ruma::signatures::hash_and_sign_event(
-            db.globals.server_name().as_str(),
-            db.globals.keypair(),
-            &mut join_event_stub_value,
-        )
-        .expect("event is valid, we just created it");
-
-        let send_join_response = server_server::send_request(
-            &db,
-            body.room_id.server_name().to_string(),
-            federation::membership::create_join_event::v2::Request {
-                room_id: body.room_id.clone(),
-                event_id,
-                pdu_stub: serde_json::from_value::<Raw<_>>(join_event_stub_value)
-                    .expect("Raw::from_value always works"),
-            },
-        )
-        .await?;
+        return Ok(());
+    }
 
-        dbg!(send_join_response);
-        todo!("Take send_join_response and 'create' the room using that data");
+    // Guests can only join rooms that allow guest access
+    if db.users.is_guest(sender_id)?
+        && db
+            .rooms
+            .room_state_get(room_id, &EventType::RoomGuestAccess, "")?
+            .and_then(|pdu| {
+                pdu.content
+                    .get("guest_access")?
+                    .as_str()
+                    .map(|a| a == "can_join")
+            })
+            != Some(true)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::GuestAccessForbidden,
+            "This room does not allow guests to join.",
+        ));
     }
 
     let event = member::MemberEventContent {
         membership: member::MembershipState::Join,
-        displayname: db.users.displayname(&sender_id)?,
-        avatar_url: db.users.avatar_url(&sender_id)?,
+        displayname: db.users.displayname(sender_id)?,
+        avatar_url: db.users.avatar_url(sender_id)?,
         is_direct: None,
         third_party_invite: None,
     };
+    let mut content = serde_json::to_value(event).expect("event is valid, we just created it");
+
+    // Restricted rooms can be joined by members of the allowed rooms without an invite. This is
+    // checked now, the user might have left the allowed rooms since they saw the room.
+    if let Some(allowed_room_ids) = db.rooms.restricted_join_rooms(room_id)? {
+        if !db.rooms.is_joined(sender_id, room_id)? && !db.rooms.is_invited(sender_id, room_id)? {
+            if !allowed_room_ids
+                .iter()
+                .map(|room_id| db.rooms.is_joined(sender_id, room_id))
+                .collect::<Result<Vec<_>>>()?
+                .contains(&true)
+            {
+                // Users that can't join a knock_restricted room directly can still knock
+                let can_knock = db
+                    .rooms
+                    .room_state_get(room_id, &EventType::RoomJoinRules, "")?
+                    .and_then(|pdu| {
+                        Some(pdu.content.get("join_rule")?.as_str()? == "knock_restricted")
+                    })
+                    .unwrap_or(false);
+
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    if can_knock {
+                        "You are not a member of any room that allows joining this room, but you can knock on it."
+                    } else {
+                        "You are not a member of any room that allows joining this room."
+                    },
+                ));
+            }
+
+            let authoriser = db
+                .rooms
+                .join_authoriser(room_id, db.globals.server_name())?
+                .ok_or(Error::UnableToAuthoriseJoin(
+                    "No user of this server is allowed to authorise the join.",
+                ))?;
+            content["join_authorised_via_users_server"] = authoriser.to_string().into();
+        }
+    }
 
     db.rooms.append_pdu(
         PduBuilder {
-            room_id: body.room_id.clone(),
+            room_id: room_id.clone(),
             sender: sender_id.clone(),
             event_type: EventType::RoomMember,
-            content: serde_json::to_value(event).expect("event is valid, we just created it"),
+            content,
             unsigned: None,
             state_key: Some(sender_id.to_string()),
             redacts: None,
@@ -127,10 +171,534 @@ pub async fn join_room_by_id_route(
         &db.account_data,
     )?;
 
-    Ok(join_room_by_id::Response {
-        room_id: body.room_id.clone(),
+    if let Some(inviter) = &direct_inviter {
+        db.account_data
+            .add_direct_room(sender_id, inviter, room_id, &db.globals)?;
     }
-    .into())
+
+    Ok(())
+}
+
+/// Joins a room of another server with make_join and send_join and imports the state of the
+/// room from the response.
+///
+/// - Events of the response with invalid signatures or hashes are ignored
+/// - The state is checked against the auth rules and resolved before it is stored
+/// - Servers that don't support send_join v2 are asked with v1
+async fn join_room_remotely(
+    db: &Database<'static>,
+    sender_id: &UserId,
+    room_id: &RoomId,
+    remote_server: &ServerName,
+) -> Result<()> {
+    let remote_server = remote_server.to_string();
+
+    let make_join_response = server_server::send_request(
+        db,
+        remote_server.clone(),
+        federation::membership::create_join_event_template::v1::Request {
+            room_id: room_id.clone(),
+            user_id: sender_id.clone(),
+            ver: supported_room_versions(),
+        },
+    )
+    .await?;
+
+    // For restricted rooms the remote server already chose the authorising user and put it
+    // into join_authorised_via_users_server
+    let mut join_event_stub_value = serde_json::from_str::<serde_json::Value>(
+        make_join_response.event.json().get(),
+    )
+    .map_err(|_| Error::BadServerResponse("Invalid make_join event json received from server."))?;
+
+    let join_event_stub = join_event_stub_value
+        .as_object_mut()
+        .ok_or(Error::BadServerResponse(
+            "Invalid make join event object received from server.",
+        ))?;
+
+    join_event_stub.insert(
+        "origin".to_owned(),
+        db.globals.server_name().to_owned().to_string().into(),
+    );
+    join_event_stub.insert(
+        "origin_server_ts".to_owned(),
+        utils::millis_since_unix_epoch().into(),
+    );
+
+    // Generate event id
+    let event_id = EventId::try_from(&*format!(
+        "${}",
+        ruma::signatures::reference_hash(&join_event_stub_value)
+            .expect("ruma can calculate reference hashes")
+    ))
+    .expect("ruma's reference hashes are valid event ids");
+
+    // We don't leave the event id into the pdu because that's only allowed in v1 or v2 rooms
+    let join_event_stub = join_event_stub_value.as_object_mut().unwrap();
+    join_event_stub.remove("event_id");
+
+    ruma::signatures::hash_and_sign_event(
+        db.globals.server_name().as_str(),
+        &*db.globals.keypair(),
+        &mut join_event_stub_value,
+    )
+    .expect("event is valid, we just created it");
+
+    let send_join_response = match server_server::send_json_request(
+        db,
+        &remote_server,
+        reqwest::Method::PUT,
+        &format!("/_matrix/federation/v2/send_join/{}/{}", room_id, event_id),
+        Some(join_event_stub_value.clone()),
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("send_join v2 to {} failed, trying v1: {}", remote_server, e);
+            // v1 responds with [200, response]
+            server_server::send_json_request(
+                db,
+                &remote_server,
+                reqwest::Method::PUT,
+                &format!("/_matrix/federation/v1/send_join/{}/{}", room_id, event_id),
+                Some(join_event_stub_value.clone()),
+            )
+            .await?
+            .get(1)
+            .cloned()
+            .ok_or(Error::BadServerResponse("Invalid send_join response."))?
+        }
+    };
+
+    // Rooms without room_version in the make_join response are version 1 rooms
+    let room_version = make_join_response
+        .room_version
+        .clone()
+        .unwrap_or(RoomVersionId::Version1);
+
+    // In restricted rooms the remote server signs the join event too, the reference hash shows
+    // that it didn't change anything else
+    let mut join_event = send_join_response
+        .get("event")
+        .filter(|event| {
+            ruma::signatures::reference_hash(event)
+                .ok()
+                .map(|hash| format!("${}", hash))
+                .as_deref()
+                == Some(event_id.as_str())
+        })
+        .cloned()
+        .unwrap_or(join_event_stub_value);
+    join_event["event_id"] = event_id.to_string().into();
+
+    let mut pdus = BTreeMap::new();
+    let mut state_ids = BTreeSet::new();
+    for (pdu_json, is_state) in send_join_response
+        .get("auth_chain")
+        .and_then(|auth_chain| auth_chain.as_array())
+        .into_iter()
+        .flatten()
+        .map(|pdu_json| (pdu_json, false))
+        .chain(
+            send_join_response
+                .get("state")
+                .and_then(|state| state.as_array())
+                .ok_or(Error::BadServerResponse(
+                    "send_join response contains no state.",
+                ))?
+                .iter()
+                .map(|pdu_json| (pdu_json, true)),
+        )
+    {
+        if pdu_json.get("room_id").and_then(|room_id| room_id.as_str()) != Some(room_id.as_str()) {
+            continue;
+        }
+
+        match server_server::verify_pdu(db, &room_version, pdu_json).await {
+            Ok((event_id, mut pdu_json)) => {
+                pdu_json["event_id"] = event_id.to_string().into();
+                if is_state {
+                    state_ids.insert(event_id.clone());
+                }
+                pdus.insert(event_id, pdu_json);
+            }
+            Err(e) => warn!("Ignoring event of {} from send_join: {}", remote_server, e),
+        }
+    }
+
+    let state = resolve_remote_state(&room_version, &pdus, &state_ids)?;
+    if !state
+        .iter()
+        .any(|pdu_json| pdu_json.get("type").and_then(|t| t.as_str()) == Some("m.room.create"))
+    {
+        return Err(Error::BadServerResponse(
+            "The room state of the send_join response has no create event.",
+        ));
+    }
+
+    tokio::task::block_in_place(|| {
+        for pdu_json in &state {
+            db.rooms
+                .append_remote_pdu(pdu_json, false, &db.globals, &db.account_data)?;
+        }
+        db.rooms
+            .append_remote_pdu(&join_event, true, &db.globals, &db.account_data)?;
+
+        Ok::<_, Error>(())
+    })
+}
+
+/// Returns the server of the user that invited the user to a room this server doesn't
+/// participate in.
+/// Returns the user that invited the user to the room if the invite is for a direct chat.
+fn direct_inviter(db: &Database<'_>, user_id: &UserId, room_id: &RoomId) -> Result<Option<UserId>> {
+    let is_direct_invite = |event: &serde_json::Value| {
+        event.get("content").and_then(|content| {
+            if content.get("membership")?.as_str()? == "invite"
+                && content.get("is_direct")?.as_bool()?
+            {
+                UserId::try_from(event.get("sender")?.as_str()?).ok()
+            } else {
+                None
+            }
+        })
+    };
+
+    if let Some((_, invite_state)) = db.rooms.remote_invite(user_id, room_id)? {
+        return Ok(invite_state.iter().find_map(|event| {
+            if event.get("type")?.as_str()? != "m.room.member"
+                || event.get("state_key")?.as_str()? != user_id.as_str()
+            {
+                return None;
+            }
+            is_direct_invite(event)
+        }));
+    }
+
+    Ok(db
+        .rooms
+        .room_state_get(room_id, &EventType::RoomMember, user_id.as_str())?
+        .and_then(|pdu| {
+            is_direct_invite(&json!({
+                "content": pdu.content,
+                "sender": pdu.sender,
+            }))
+        }))
+}
+
+fn remote_invite_server(
+    db: &Database<'_>,
+    user_id: &UserId,
+    room_id: &RoomId,
+) -> Result<Option<Box<ServerName>>> {
+    Ok(db
+        .rooms
+        .remote_invite(user_id, room_id)?
+        .and_then(|(_, invite_state)| {
+            invite_state.into_iter().find_map(|event| {
+                if event.get("type")?.as_str()? != "m.room.member"
+                    || event.get("state_key")?.as_str()? != user_id.as_str()
+                {
+                    return None;
+                }
+
+                let sender = UserId::try_from(event.get("sender")?.as_str()?).ok()?;
+                Some(sender.server_name().to_owned())
+            })
+        }))
+}
+
+/// Returns the state events that users of other servers see of a room they are invited to or
+/// knocked on.
+pub fn stripped_room_state(db: &Database<'_>, room_id: &RoomId) -> Result<Vec<PduEvent>> {
+    [
+        EventType::RoomCreate,
+        EventType::RoomJoinRules,
+        EventType::RoomName,
+        EventType::RoomCanonicalAlias,
+        EventType::RoomAvatar,
+        EventType::RoomEncryption,
+    ]
+    .iter()
+    .filter_map(|event_type| db.rooms.room_state_get(room_id, event_type, "").transpose())
+    .collect()
+}
+
+/// Knocks on a room of another server with make_knock and send_knock.
+///
+/// - Members of the room can then invite the user like any other user
+async fn knock_remotely(
+    db: &Database<'static>,
+    sender_id: &UserId,
+    room_id: &RoomId,
+    content: serde_json::Value,
+) -> Result<()> {
+    let remote_server = room_id.server_name().as_str();
+
+    let make_knock_response = server_server::send_json_request(
+        db,
+        remote_server,
+        reqwest::Method::GET,
+        &format!(
+            "/_matrix/federation/v1/make_knock/{}/{}?{}",
+            room_id,
+            sender_id,
+            supported_room_versions()
+                .iter()
+                .map(|version| format!("ver={}", version))
+                .collect::<Vec<_>>()
+                .join("&")
+        ),
+        None,
+    )
+    .await?;
+
+    make_knock_response
+        .get("room_version")
+        .and_then(|version| version.as_str())
+        .and_then(|version| RoomVersionId::try_from(version).ok())
+        .filter(|version| supported_room_versions().contains(version))
+        .ok_or(Error::BadServerResponse(
+            "make_knock response has an unsupported room version.",
+        ))?;
+
+    let mut knock_event = make_knock_response
+        .get("event")
+        .filter(|event| event.is_object())
+        .cloned()
+        .ok_or(Error::BadServerResponse(
+            "Invalid make_knock event received from server.",
+        ))?;
+    if knock_event.get("room_id").and_then(|id| id.as_str()) != Some(room_id.as_str())
+        || knock_event.get("sender").and_then(|s| s.as_str()) != Some(sender_id.as_str())
+        || knock_event.get("state_key").and_then(|s| s.as_str()) != Some(sender_id.as_str())
+    {
+        return Err(Error::BadServerResponse(
+            "make_knock event is not a knock of the user.",
+        ));
+    }
+    knock_event["content"] = content;
+    knock_event["origin"] = db.globals.server_name().as_str().into();
+    knock_event["origin_server_ts"] = utils::millis_since_unix_epoch().into();
+    knock_event
+        .as_object_mut()
+        .expect("knock event is an object")
+        .remove("event_id");
+
+    let event_id = EventId::try_from(&*format!(
+        "${}",
+        ruma::signatures::reference_hash(&knock_event)
+            .map_err(|_| Error::BadServerResponse("Invalid make_knock event."))?
+    ))
+    .expect("ruma's reference hashes are valid event ids");
+
+    ruma::signatures::hash_and_sign_event(
+        db.globals.server_name().as_str(),
+        &*db.globals.keypair(),
+        &mut knock_event,
+    )
+    .map_err(|_| Error::BadServerResponse("Invalid make_knock event."))?;
+
+    server_server::send_json_request(
+        db,
+        remote_server,
+        reqwest::Method::PUT,
+        &format!("/_matrix/federation/v1/send_knock/{}/{}", room_id, event_id),
+        Some(knock_event),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Rejects an invite of another server with make_leave and send_leave.
+///
+/// - The invite is removed even if the other server can't be reached, the user doesn't want it
+async fn reject_remote_invite(
+    db: &Database<'static>,
+    sender_id: &UserId,
+    room_id: &RoomId,
+    remote_server: &ServerName,
+) -> Result<()> {
+    let remote_server = remote_server.to_string();
+
+    let result = async {
+        let make_leave_response = server_server::send_json_request(
+            db,
+            &remote_server,
+            reqwest::Method::GET,
+            &format!(
+                "/_matrix/federation/v1/make_leave/{}/{}",
+                room_id, sender_id
+            ),
+            None,
+        )
+        .await?;
+
+        let mut leave_event = make_leave_response
+            .get("event")
+            .filter(|event| event.is_object())
+            .cloned()
+            .ok_or(Error::BadServerResponse(
+                "Invalid make_leave event received from server.",
+            ))?;
+        leave_event["origin"] = db.globals.server_name().as_str().into();
+        leave_event["origin_server_ts"] = utils::millis_since_unix_epoch().into();
+        leave_event
+            .as_object_mut()
+            .expect("leave event is an object")
+            .remove("event_id");
+
+        let event_id = EventId::try_from(&*format!(
+            "${}",
+            ruma::signatures::reference_hash(&leave_event)
+                .map_err(|_| Error::BadServerResponse("Invalid make_leave event."))?
+        ))
+        .expect("ruma's reference hashes are valid event ids");
+
+        ruma::signatures::hash_and_sign_event(
+            db.globals.server_name().as_str(),
+            &*db.globals.keypair(),
+            &mut leave_event,
+        )
+        .map_err(|_| Error::BadServerResponse("Invalid make_leave event."))?;
+
+        if let Err(e) = server_server::send_json_request(
+            db,
+            &remote_server,
+            reqwest::Method::PUT,
+            &format!("/_matrix/federation/v2/send_leave/{}/{}", room_id, event_id),
+            Some(leave_event.clone()),
+        )
+        .await
+        {
+            warn!(
+                "send_leave v2 to {} failed, trying v1: {}",
+                remote_server, e
+            );
+            server_server::send_json_request(
+                db,
+                &remote_server,
+                reqwest::Method::PUT,
+                &format!("/_matrix/federation/v1/send_leave/{}/{}", room_id, event_id),
+                Some(leave_event),
+            )
+            .await?;
+        }
+
+        Ok::<_, Error>(())
+    }
+    .await;
+
+    if let Err(e) = result {
+        warn!(
+            "Could not reject invite to {} on {}: {}",
+            room_id, remote_server, e
+        );
+    }
+
+    db.rooms.remove_remote_invite(sender_id, room_id)
+}
+
+/// Returns the state of a send_join response, ordered by depth.
+///
+/// - An event is only kept if it is allowed by its auth events and all of them were kept too
+/// - The state of a send_join response is a single state set, so several events for the same
+/// type and state key can only come from a broken server. They are resolved with state
+/// resolution like forks of the room
+fn resolve_remote_state(
+    room_version: &RoomVersionId,
+    pdus: &BTreeMap<EventId, serde_json::Value>,
+    state_ids: &BTreeSet<EventId>,
+) -> Result<Vec<serde_json::Value>> {
+    let events = pdus
+        .iter()
+        .filter_map(|(event_id, pdu_json)| {
+            Some((
+                event_id.clone(),
+                serde_json::from_value::<PduEvent>(pdu_json.clone()).ok()?,
+            ))
+        })
+        .collect::<HashMap<_, _>>();
+
+    // The create event has no auth events, everything else is authorised starting from it
+    let mut authorised = HashMap::<EventId, PduEvent>::new();
+    loop {
+        let newly_authorised = events
+            .iter()
+            .filter(|&(event_id, pdu)| {
+                !authorised.contains_key(event_id)
+                    && pdu
+                        .auth_events
+                        .iter()
+                        .all(|auth_event| authorised.contains_key(auth_event))
+                    && state_res::allowed_by_auth_events(pdu, &authorised)
+            })
+            .map(|(event_id, pdu)| (event_id.clone(), pdu.clone()))
+            .collect::<Vec<_>>();
+
+        if newly_authorised.is_empty() {
+            break;
+        }
+        authorised.extend(newly_authorised);
+    }
+
+    let mut candidates = state_res::StateMap::<Vec<EventId>>::new();
+    for pdu in state_ids
+        .iter()
+        .filter_map(|event_id| authorised.get(event_id))
+    {
+        if let Some(state_key) = &pdu.state_key {
+            candidates
+                .entry((pdu.kind.clone(), state_key.clone()))
+                .or_default()
+                .push(pdu.event_id.clone());
+        }
+    }
+
+    // Every state set gets one candidate of each conflicting type and state key
+    let set_count = candidates.values().map(|c| c.len()).max().unwrap_or(1);
+    let state_sets = (0..set_count)
+        .map(|i| {
+            candidates
+                .iter()
+                .map(|(key, c)| (key.clone(), c[i.min(c.len() - 1)].clone()))
+                .collect::<state_res::StateMap<_>>()
+        })
+        .collect::<Vec<_>>();
+    let auth_chains = state_sets
+        .iter()
+        .map(|state_set| {
+            let mut auth_chain = HashSet::new();
+            let mut stack = state_set
+                .values()
+                .filter_map(|event_id| authorised.get(event_id))
+                .flat_map(|pdu| pdu.auth_events.iter().cloned())
+                .collect::<Vec<_>>();
+            while let Some(event_id) = stack.pop() {
+                if let Some(pdu) = authorised.get(&event_id) {
+                    if auth_chain.insert(event_id) {
+                        stack.extend(pdu.auth_events.iter().cloned());
+                    }
+                }
+            }
+            auth_chain
+        })
+        .collect::<Vec<_>>();
+
+    let resolved = state_res::resolve(room_version, &state_sets, &auth_chains, &authorised)?;
+
+    let mut state = resolved
+        .values()
+        .filter_map(|event_id| Some((authorised.get(event_id)?.depth, &pdus[event_id])))
+        .collect::<Vec<_>>();
+    state.sort_by_key(|(depth, _)| *depth);
+    Ok(state
+        .into_iter()
+        .map(|(_, pdu_json)| pdu_json.clone())
+        .collect())
 }
 
 #[cfg_attr(
@@ -152,6 +720,8 @@ pub async fn join_room_by_id_or_alias_route(
                     sender_id: body.sender_id.clone(),
                     device_id: body.device_id.clone(),
                     json_body: None,
+                    client_ip: body.client_ip,
+                    appservice_id: body.appservice_id.clone(),
                 },
             )
             .await?
@@ -164,6 +734,8 @@ pub async fn join_room_by_id_or_alias_route(
         sender_id: body.sender_id.clone(),
         device_id: body.device_id.clone(),
         json_body: None,
+        client_ip: body.client_ip,
+        appservice_id: body.appservice_id.clone(),
         body: join_room_by_id::IncomingRequest {
             room_id,
             third_party_signed: body.third_party_signed.clone(),
@@ -176,16 +748,128 @@ pub async fn join_room_by_id_or_alias_route(
     .into())
 }
 
+/// # `POST /_matrix/client/r0/knock/{roomIdOrAlias}`
+///
+/// Asks to be invited to a room whose join rule is `knock` or `knock_restricted`.
+///
+/// - The request has the same form as [`POST /_matrix/client/r0/join/{roomIdOrAlias}`](fn.join_room_by_id_or_alias_route.html),
+/// so we parse it as a join request
+/// - Members of the room see the knock event and can invite the user, who can then join normally
+/// - Members of the allowed rooms of `knock_restricted` rooms can also join them directly
+/// - Guests can't knock
+/// - Rooms without users of this server are knocked on with make_knock and send_knock on the
+/// server of the room id
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/knock/<_>", data = "<body>")
+)]
+pub async fn knock_room_route(
+    db: State<'_, Database<'_>>,
+    db2: State<'_, Database<'_>>,
+    body: Ruma<join_room_by_id_or_alias::Request>,
+) -> Result<Json<String>> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    if db.users.is_guest(&sender_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::GuestAccessForbidden,
+            "Guests can't knock on rooms.",
+        ));
+    }
+
+    let room_id = match RoomId::try_from(body.room_id_or_alias.clone()) {
+        Ok(room_id) => room_id,
+        Err(room_alias) => {
+            client_server::get_alias_route(
+                db,
+                Ruma {
+                    body: alias::get_alias::IncomingRequest { room_alias },
+                    sender_id: body.sender_id.clone(),
+                    device_id: body.device_id.clone(),
+                    json_body: None,
+                    client_ip: body.client_ip,
+                    appservice_id: body.appservice_id.clone(),
+                },
+            )
+            .await?
+            .0
+            .room_id
+        }
+    };
+
+    let mut content = json!({
+        "membership": "knock",
+        "displayname": db2.users.displayname(&sender_id)?,
+        "avatar_url": db2.users.avatar_url(&sender_id)?,
+    });
+    if let Some(reason) = body
+        .json_body
+        .as_ref()
+        .and_then(|json| serde_json::from_str::<serde_json::Value>(json.get()).ok())
+        .and_then(|json| json.get("reason").cloned())
+    {
+        content["reason"] = reason;
+    }
+
+    // The other server checks the join rule
+    if room_id.server_name() != db2.globals.server_name()
+        && !db2
+            .rooms
+            .room_members(&room_id)
+            .filter_map(|r| r.ok())
+            .any(|user_id| user_id.server_name() == db2.globals.server_name())
+    {
+        knock_remotely(&db2, &sender_id, &room_id, content).await?;
+        return Ok(Json(json!({ "room_id": room_id }).to_string()));
+    }
+
+    let join_rule = db2
+        .rooms
+        .room_state_get(&room_id, &EventType::RoomJoinRules, "")?
+        .and_then(|pdu| Some(pdu.content.get("join_rule")?.as_str()?.to_owned()));
+    if !matches!(
+        join_rule.as_deref(),
+        Some("knock") | Some("knock_restricted")
+    ) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This room does not allow knocking.",
+        ));
+    }
+
+    db2.rooms.append_pdu(
+        PduBuilder {
+            room_id: room_id.clone(),
+            sender: sender_id.clone(),
+            event_type: EventType::RoomMember,
+            content,
+            unsigned: None,
+            state_key: Some(sender_id.to_string()),
+            redacts: None,
+        },
+        &db2.globals,
+        &db2.account_data,
+    )?;
+
+    Ok(Json(json!({ "room_id": room_id }).to_string()))
+}
+
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/rooms/<_>/leave", data = "<body>")
 )]
-pub fn leave_room_route(
+pub async fn leave_room_route(
     db: State<'_, Database<'_>>,
     body: Ruma<leave_room::IncomingRequest>,
 ) -> ConduitResult<leave_room::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
+    // Invites of other servers to rooms we don't know are rejected on the inviting server
+    if let Some(invite_server) = remote_invite_server(&db, &sender_id, &body.room_id)? {
+        reject_remote_invite(&db, &sender_id, &body.room_id, &invite_server).await?;
+        return Ok(leave_room::Response.into());
+    }
+
     let mut event = serde_json::from_value::<Raw<member::MemberEventContent>>(
         db.rooms
             .room_state_get(
@@ -222,42 +906,262 @@ pub fn leave_room_route(
     Ok(leave_room::Response.into())
 }
 
+/// # `POST /_matrix/client/r0/rooms/{roomId}/invite`
+///
+/// Invites a user to the room.
+///
+/// - Email addresses are invited through the identity server, unless they already belong to a
+/// user
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/rooms/<_>/invite", data = "<body>")
 )]
-pub fn invite_user_route(
+pub async fn invite_user_route(
     db: State<'_, Database<'_>>,
     body: Ruma<invite_user::Request>,
 ) -> ConduitResult<invite_user::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    if let invite_user::InvitationRecipient::UserId { user_id } = &body.recipient {
-        db.rooms.append_pdu(
-            PduBuilder {
-                room_id: body.room_id.clone(),
-                sender: sender_id.clone(),
-                event_type: EventType::RoomMember,
-                content: serde_json::to_value(member::MemberEventContent {
-                    membership: member::MembershipState::Invite,
-                    displayname: db.users.displayname(&user_id)?,
-                    avatar_url: db.users.avatar_url(&user_id)?,
-                    is_direct: None,
-                    third_party_invite: None,
-                })
-                .expect("event is valid, we just created it"),
-                unsigned: None,
-                state_key: Some(user_id.to_string()),
-                redacts: None,
-            },
-            &db.globals,
-            &db.account_data,
-        )?;
+    let user_id = match &body.recipient {
+        invite_user::InvitationRecipient::UserId { user_id } => user_id.clone(),
+        invite_user::InvitationRecipient::ThirdPartyId(third_party_id) => {
+            let third_party_id =
+                serde_json::to_value(third_party_id).expect("third party id is valid json");
+            let medium = third_party_id
+                .get("medium")
+                .and_then(|medium| medium.as_str())
+                .unwrap_or_default();
+            let address = third_party_id
+                .get("address")
+                .and_then(|address| address.as_str())
+                .unwrap_or_default();
 
-        Ok(invite_user::Response.into())
+            match invite_third_party_id(&db, sender_id, &body.room_id, medium, address).await? {
+                Some(user_id) => user_id,
+                None => return Ok(invite_user::Response.into()),
+            }
+        }
+    };
+
+    let pdu_builder = PduBuilder {
+        room_id: body.room_id.clone(),
+        sender: sender_id.clone(),
+        event_type: EventType::RoomMember,
+        content: serde_json::to_value(member::MemberEventContent {
+            membership: member::MembershipState::Invite,
+            displayname: db.users.displayname(&user_id)?,
+            avatar_url: db.users.avatar_url(&user_id)?,
+            is_direct: None,
+            third_party_invite: None,
+        })
+        .expect("event is valid, we just created it"),
+        unsigned: None,
+        state_key: Some(user_id.to_string()),
+        redacts: None,
+    };
+
+    if user_id.server_name() == db.globals.server_name() {
+        db.rooms
+            .append_pdu(pdu_builder, &db.globals, &db.account_data)?;
     } else {
-        Err(Error::BadRequest(ErrorKind::NotFound, "User not found."))
+        invite_remote_user(&db, &user_id, pdu_builder).await?;
+    }
+
+    Ok(invite_user::Response.into())
+}
+
+/// Invites a third party id like an email address through the identity server, which tells the
+/// owner of the address about the invite. Returns the user the address already belongs to
+/// instead, that user can be invited directly.
+///
+/// - The identity server returns a token and public keys for the `m.room.third_party_invite`
+/// event. When the address is bound to a user, the identity server signs the token and the user
+/// is invited with it
+pub async fn invite_third_party_id(
+    db: &Database<'static>,
+    sender_id: &UserId,
+    room_id: &RoomId,
+    medium: &str,
+    address: &str,
+) -> Result<Option<UserId>> {
+    let identity_server = db.globals.identity_server().ok_or(Error::BadRequest(
+        ErrorKind::ThreepidDenied,
+        "This server has no identity server for third party invites.",
+    ))?;
+
+    let lookup = db
+        .globals
+        .reqwest_client()
+        .get(&format!(
+            "{}/_matrix/identity/api/v1/lookup",
+            identity_server
+        ))
+        .query(&[("medium", medium), ("address", address)])
+        .send()
+        .await?
+        .json::<serde_json::Value>()
+        .await?;
+    if let Some(user_id) = lookup
+        .get("mxid")
+        .and_then(|mxid| mxid.as_str())
+        .and_then(|mxid| UserId::try_from(mxid).ok())
+    {
+        return Ok(Some(user_id));
+    }
+
+    let room_state = |event_type: &EventType, field: &str| -> Result<Option<serde_json::Value>> {
+        Ok(db
+            .rooms
+            .room_state_get(room_id, event_type, "")?
+            .and_then(|pdu| pdu.content.get(field).cloned()))
+    };
+
+    let response = db
+        .globals
+        .reqwest_client()
+        .post(&format!(
+            "{}/_matrix/identity/api/v1/store-invite",
+            identity_server
+        ))
+        .json(&json!({
+            "medium": medium,
+            "address": address,
+            "room_id": room_id,
+            "sender": sender_id,
+            "room_name": room_state(&EventType::RoomName, "name")?,
+            "room_alias": room_state(&EventType::RoomCanonicalAlias, "alias")?,
+            "room_avatar_url": room_state(&EventType::RoomAvatar, "url")?,
+            "room_join_rules": room_state(&EventType::RoomJoinRules, "join_rule")?,
+            "sender_display_name": db.users.displayname(sender_id)?,
+            "sender_avatar_url": db.users.avatar_url(sender_id)?,
+        }))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        warn!(
+            "{} did not store a third party invite: {}",
+            identity_server,
+            response.status()
+        );
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidDenied,
+            "The identity server rejected the invite.",
+        ));
     }
+    let response = response.json::<serde_json::Value>().await?;
+
+    let token = response
+        .get("token")
+        .and_then(|token| token.as_str())
+        .ok_or(Error::BadServerResponse(
+            "The identity server returned no invite token.",
+        ))?;
+    let public_keys = response
+        .get("public_keys")
+        .and_then(|keys| keys.as_array())
+        .cloned()
+        .unwrap_or_default();
+    let first_key = public_keys.first();
+
+    db.rooms.append_pdu(
+        PduBuilder {
+            room_id: room_id.clone(),
+            sender: sender_id.clone(),
+            event_type: EventType::RoomThirdPartyInvite,
+            content: json!({
+                "display_name": response.get("display_name"),
+                "key_validity_url": first_key.and_then(|key| key.get("key_validity_url")),
+                "public_key": first_key
+                    .and_then(|key| key.get("public_key"))
+                    .or_else(|| response.get("public_key")),
+                "public_keys": public_keys,
+            }),
+            unsigned: None,
+            state_key: Some(token.to_owned()),
+            redacts: None,
+        },
+        &db.globals,
+        &db.account_data,
+    )?;
+
+    Ok(None)
+}
+
+/// Invites a user of another server. Their server has to sign the invite before it is added to
+/// the room.
+///
+/// - The other server sees stripped state of the room, so it can show the invite
+/// - Nothing is added to the room if the other server rejects the invite
+pub async fn invite_remote_user(
+    db: &Database<'static>,
+    user_id: &UserId,
+    pdu_builder: PduBuilder,
+) -> Result<()> {
+    let room_id = pdu_builder.room_id.clone();
+    let sender = pdu_builder.sender.clone();
+    let (pdu, mut pdu_json) = db.rooms.build_pdu(pdu_builder, &db.globals)?;
+
+    let mut invite_room_state = stripped_room_state(db, &room_id)?;
+    invite_room_state.extend(db.rooms.room_state_get(
+        &room_id,
+        &EventType::RoomMember,
+        sender.as_str(),
+    )?);
+
+    // We don't send the event id because it's only allowed in v1 or v2 rooms
+    let mut event = pdu_json.clone();
+    event
+        .as_object_mut()
+        .expect("pdu json is an object")
+        .remove("event_id");
+
+    let response = server_server::send_json_request(
+        db,
+        user_id.server_name().as_str(),
+        reqwest::Method::PUT,
+        &format!("/_matrix/federation/v2/invite/{}/{}", room_id, pdu.event_id),
+        Some(json!({
+            "room_version": db.rooms.room_version(&room_id)?,
+            "event": event,
+            "invite_room_state": invite_room_state
+                .iter()
+                .map(|pdu| pdu.to_stripped_state_event())
+                .collect::<Vec<_>>(),
+        })),
+    )
+    .await
+    .map_err(|e| {
+        warn!("{} rejected an invite: {}", user_id.server_name(), e);
+        Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The server of the user rejected the invite.",
+        )
+    })?;
+
+    // The other server may only add its signature, the reference hash shows that
+    let signatures = response
+        .get("event")
+        .filter(|event| {
+            ruma::signatures::reference_hash(event)
+                .ok()
+                .map(|hash| format!("${}", hash))
+                .as_deref()
+                == Some(pdu.event_id.as_str())
+        })
+        .and_then(|event| event.get("signatures"))
+        .filter(|signatures| signatures.is_object())
+        .cloned()
+        .ok_or(Error::BadServerResponse(
+            "The server of the user returned an invalid invite.",
+        ))?;
+    pdu_json["signatures"] = signatures;
+
+    tokio::task::block_in_place(|| {
+        db.rooms
+            .append_built_pdu(pdu, pdu_json, &db.globals, &db.account_data)
+    })?;
+
+    Ok(())
 }
 
 #[cfg_attr(
@@ -471,6 +1375,16 @@ pub fn get_member_events_route(
     .into())
 }
 
+/// # `GET /_matrix/client/r0/rooms/{roomId}/joined_members`
+///
+/// Returns the joined members of the room with their display names and avatars.
+///
+/// - Only members of the room can see its members
+/// - The profiles come from the current member events, so they are the ones used in the room,
+/// also for users of other servers
+/// - The members are read one by one from the member index, the member events of the room are
+/// never loaded together
+/// - At most `joined_members_limit` members are returned
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/rooms/<_>/joined_members", data = "<body>")
@@ -481,30 +1395,154 @@ pub fn joined_members_route(
 ) -> ConduitResult<joined_members::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    if !db
+    check_joined(&db, sender_id, &body.room_id)?;
+
+    let members = db
         .rooms
-        .is_joined(&sender_id, &body.room_id)
-        .unwrap_or(false)
-    {
+        .room_members(&body.room_id)
+        .filter_map(|r| r.ok())
+        .map(|user_id| -> Result<_> {
+            let content = db
+                .rooms
+                .room_state_get(&body.room_id, &EventType::RoomMember, user_id.as_str())?
+                .map(|pdu| pdu.content)
+                .unwrap_or_default();
+            Ok((user_id, content))
+        });
+    let joined = collect_joined_members(members, db.globals.joined_members_limit())?;
+
+    Ok(joined_members::Response { joined }.into())
+}
+
+/// Only members of a room can see who else is in it.
+fn check_joined(db: &Database<'_>, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+    if !db.rooms.is_joined(user_id, room_id)? {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
             "You aren't a member of the room.",
         ));
     }
 
+    Ok(())
+}
+
+/// Builds the `/joined_members` response from the members and the content of their member events.
+/// Stops after `limit` members, so the rest is never read.
+fn collect_joined_members(
+    members: impl Iterator<Item = Result<(UserId, serde_json::Value)>>,
+    limit: usize,
+) -> Result<BTreeMap<UserId, joined_members::RoomMember>> {
     let mut joined = BTreeMap::new();
-    for user_id in db.rooms.room_members(&body.room_id).filter_map(|r| r.ok()) {
-        let display_name = db.users.displayname(&user_id)?;
-        let avatar_url = db.users.avatar_url(&user_id)?;
+    for member in members.take(limit) {
+        let (user_id, content) = member?;
+        let field = |name: &str| {
+            content
+                .get(name)
+                .and_then(|value| value.as_str())
+                .map(|value| value.to_owned())
+        };
 
         joined.insert(
             user_id,
             joined_members::RoomMember {
-                display_name,
-                avatar_url,
+                display_name: field("displayname"),
+                avatar_url: field("avatar_url"),
             },
         );
     }
 
-    Ok(joined_members::Response { joined }.into())
+    Ok(joined)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_joined, collect_joined_members};
+    use crate::{Database, Error};
+    use ruma::{api::client::error::ErrorKind, RoomId, UserId};
+    use serde_json::json;
+    use std::{cell::Cell, convert::TryFrom, time::Instant};
+
+    fn members<'a>(
+        count: usize,
+        read: &'a Cell<usize>,
+    ) -> impl Iterator<Item = crate::Result<(UserId, serde_json::Value)>> + 'a {
+        (0..count).map(move |i| {
+            read.set(read.get() + 1);
+            let user_id = UserId::try_from(format!("@user{}:example.com", i)).unwrap();
+            let content = json!({
+                "membership": "join",
+                "displayname": format!("User {}", i),
+                "avatar_url": format!("mxc://example.com/{}", i),
+            });
+            Ok((user_id, content))
+        })
+    }
+
+    #[test]
+    fn joined_members_have_the_profile_of_their_member_event() {
+        let user_id = UserId::try_from("@alice:example.com").unwrap();
+        let members = vec![
+            Ok((
+                user_id.clone(),
+                json!({ "membership": "join", "displayname": "Alice" }),
+            )),
+            Ok((
+                UserId::try_from("@bob:example.com").unwrap(),
+                serde_json::Value::Null,
+            )),
+        ];
+
+        let joined = collect_joined_members(members.into_iter(), 10).unwrap();
+        assert_eq!(joined.len(), 2);
+        assert_eq!(joined[&user_id].display_name.as_deref(), Some("Alice"));
+        assert_eq!(joined[&user_id].avatar_url, None);
+    }
+
+    #[test]
+    fn joined_members_stop_at_the_limit() {
+        let read = Cell::new(0);
+        let joined = collect_joined_members(members(1000, &read), 100).unwrap();
+
+        assert_eq!(joined.len(), 100);
+        // The members behind the limit are never read
+        assert_eq!(read.get(), 100);
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture joined_members_benchmark`.
+    #[test]
+    #[ignore]
+    fn joined_members_benchmark() {
+        for &(count, limit) in &[(50_000, 100_000), (50_000, 10_000)] {
+            let read = Cell::new(0);
+            let start = Instant::now();
+            let joined = collect_joined_members(members(count, &read), limit).unwrap();
+
+            println!(
+                "{} members with limit {}: {} returned, {} read in {:?}",
+                count,
+                limit,
+                joined.len(),
+                read.get(),
+                start.elapsed()
+            );
+            assert_eq!(joined.len(), count.min(limit));
+            assert_eq!(read.get(), count.min(limit));
+        }
+    }
+
+    #[test]
+    fn non_members_cant_see_the_joined_members() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let db = Database::load_temporary(Vec::new());
+            let alice = UserId::try_from("@alice:example.com").unwrap();
+            let room_id = RoomId::try_from("!room:example.com").unwrap();
+            db.users.create(&alice, "password").unwrap();
+
+            assert!(matches!(
+                check_joined(&db, &alice, &room_id),
+                Err(Error::BadRequest(ErrorKind::Forbidden, _))
+            ));
+        });
+    }
 }
@@ -1,17 +1,28 @@
 use super::State;
-use crate::{pdu::PduBuilder, utils, ConduitResult, Database, Error, Ruma};
+use crate::{
+    database::{rate_limiter::RateLimitClass, rooms::Viewer},
+    pdu::PduBuilder,
+    server_server, ConduitResult, Database, Error, Result, Ruma,
+};
 use ruma::{
     api::client::{
         error::ErrorKind,
         r0::message::{get_message_events, send_message_event},
     },
+    events::EventType,
     EventId,
 };
-use std::convert::{TryFrom, TryInto};
+use std::{collections::HashSet, convert::TryInto};
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, put};
 
+/// # `PUT /_matrix/client/r0/rooms/{roomId}/send/{eventType}/{txnId}`
+///
+/// Sends a message event into the room.
+///
+/// - Retries with the same transaction id return the event id of the first request, also while
+/// the first request is still running
 #[cfg_attr(
     feature = "conduit_bin",
     put("/_matrix/client/r0/rooms/<_>/send/<_>/<_>", data = "<body>")
@@ -23,43 +34,55 @@ pub fn send_message_event_route(
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
     let device_id = body.device_id.as_ref().expect("user is authenticated");
 
+    // Retries that arrive while the first request is running wait for it
+    let event_id = tokio::task::block_in_place(|| {
+        db.transaction_ids
+            .exclusive(sender_id, device_id, &body.txn_id, || {
+                send_message_event(&db, &body)
+            })
+    })?;
+
+    Ok(send_message_event::Response { event_id }.into())
+}
+
+/// Sends the event of the request, unless it was sent with the same transaction id before.
+fn send_message_event(
+    db: &Database<'_>,
+    body: &Ruma<send_message_event::IncomingRequest>,
+) -> Result<EventId> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+    let device_id = body.device_id.as_ref().expect("user is authenticated");
+
     // Check if this is a new transaction id
-    if let Some(response) = db
-        .transaction_ids
-        .existing_txnid(sender_id, device_id, &body.txn_id)?
+    if let Some(event_id) =
+        db.transaction_ids
+            .existing_event_id(sender_id, device_id, &body.txn_id)?
     {
-        // The client might have sent a txnid of the /sendToDevice endpoint
-        // This txnid has no response associated with it
-        if response.is_empty() {
-            return Err(Error::BadRequest(
-                ErrorKind::InvalidParam,
-                "Tried to use txn id already used for an incompatible endpoint.",
-            ));
-        }
-
-        let event_id = EventId::try_from(
-            utils::string_from_bytes(&response)
-                .map_err(|_| Error::bad_database("Invalid txnid bytes in database."))?,
-        )
-        .map_err(|_| Error::bad_database("Invalid event id in txnid data."))?;
-        return Ok(send_message_event::Response { event_id }.into());
+        return Ok(event_id);
     }
 
+    // Retried transactions were answered above, so they don't count
+    db.globals
+        .rate_limiter()
+        .check(RateLimitClass::Message, sender_id.as_str())?;
+
     let mut unsigned = serde_json::Map::new();
     unsigned.insert("transaction_id".to_owned(), body.txn_id.clone().into());
 
+    let content = serde_json::from_str(
+        body.json_body
+            .as_ref()
+            .ok_or(Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?
+            .get(),
+    )
+    .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?;
+
     let event_id = db.rooms.append_pdu(
         PduBuilder {
             room_id: body.room_id.clone(),
             sender: sender_id.clone(),
             event_type: body.event_type.clone(),
-            content: serde_json::from_str(
-                body.json_body
-                    .as_ref()
-                    .ok_or(Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?
-                    .get(),
-            )
-            .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?,
+            content,
             unsigned: Some(unsigned),
             state_key: None,
             redacts: None,
@@ -68,35 +91,73 @@ pub fn send_message_event_route(
         &db.account_data,
     )?;
 
+    db.pushers.queue_notifications(
+        &event_id,
+        &db.rooms,
+        &db.users,
+        &db.account_data,
+        &db.globals,
+    )?;
+
     db.transaction_ids
         .add_txnid(sender_id, device_id, &body.txn_id, event_id.as_bytes())?;
-    Ok(send_message_event::Response { event_id }.into())
+    Ok(event_id)
 }
 
+/// # `GET /_matrix/client/r0/rooms/{roomId}/messages`
+///
+/// Returns the events of the room before or after the `from` token.
+///
+/// - Tokens are positions between events: Paginating backwards returns the events before the
+/// token, paginating forwards returns the events from the token on. So `end` of a page can be
+/// used in both directions without gaps or duplicates
+/// - `end` is omitted if there are no more events
+/// - The `filter` can limit the events by `types`, `senders` and their `not_` lists
+/// - With `lazy_load_members` in the filter, `state` contains the member events of the senders
+/// - Events the user could not see when they were sent are left out, world readable rooms can be
+/// read without joining
+/// - Events from before the local history are backfilled from other servers in the room. If
+/// none of them answers, `end` stays at the oldest known event
 #[cfg_attr(
     feature = "conduit_bin",
-    get("/_matrix/client/r0/rooms/<_>/messages", data = "<body>")
+    get("/_matrix/client/r0/rooms/<_>/messages?<filter>", data = "<body>")
 )]
-pub fn get_message_events_route(
+pub async fn get_message_events_route(
     db: State<'_, Database<'_>>,
     body: Ruma<get_message_events::Request>,
+    filter: Option<String>,
 ) -> ConduitResult<get_message_events::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    if !db.rooms.is_joined(sender_id, &body.room_id)? {
+    // Former members can still read what they could see before, world readable rooms can be
+    // read by everyone
+    let had_membership = db
+        .rooms
+        .room_state_get(&body.room_id, &EventType::RoomMember, sender_id.as_str())?
+        .is_some();
+    if !had_membership && !db.rooms.is_world_readable(&body.room_id)? {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
             "You don't have permission to view this room.",
         ));
     }
 
+    // Events the user was not allowed to see when they were sent are left out
+    let mut visibility = db
+        .rooms
+        .event_visibility(Viewer::User(&sender_id), &body.room_id)?;
+
     let from = body
         .from
-        .clone()
-        .parse()
+        .parse::<u64>()
         .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from` value."))?;
 
-    let to = body.to.as_ref().map(|t| t.parse());
+    let to = body
+        .to
+        .as_ref()
+        .map(|t| t.parse::<u64>())
+        .transpose()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `to` value."))?;
 
     // Use limit or else 10
     let limit = body
@@ -104,54 +165,102 @@ pub fn get_message_events_route(
         .try_into()
         .map_or(Ok::<_, Error>(10_usize), |l: u32| Ok(l as usize))?;
 
-    match body.dir {
+    let filter = super::load_filter(&db, sender_id, filter.as_deref())?;
+    let lazy_load_members = filter
+        .get("lazy_load_members")
+        .and_then(|lazy_load| lazy_load.as_bool())
+        .unwrap_or(false);
+
+    // Events of ignored users are left out
+    let ignored_users = db.account_data.ignored_users(&sender_id)?;
+
+    let (events, end) = match body.dir {
         get_message_events::Direction::Forward => {
-            let events_after = db
+            let events = db
                 .rooms
-                .pdus_after(&sender_id, &body.room_id, from)
-                .take(limit)
+                // The token is the position before the event, pdus_after would skip it
+                .pdus_after(&sender_id, &body.room_id, from.saturating_sub(1))
                 .filter_map(|r| r.ok()) // Filter out buggy events
-                .take_while(|&(k, _)| Some(Ok(k)) != to) // Stop at `to`
+                .take_while(|&(count, _)| to.map_or(true, |to| count < to)) // Stop at `to`
+                .filter(|(_, pdu)| super::filter_allows_event(&filter, pdu))
+                .filter(|(_, pdu)| !super::sent_by_ignored_user(&ignored_users, pdu))
+                .filter(|(count, pdu)| visibility.can_see(*count, pdu).unwrap_or(false))
+                .take(limit)
                 .collect::<Vec<_>>();
 
-            let end_token = events_after.last().map(|(count, _)| count.to_string());
+            // The position after the newest event
+            let end = events.last().map(|(count, _)| (count + 1).to_string());
 
-            let events_after = events_after
-                .into_iter()
-                .map(|(_, pdu)| pdu.to_room_event())
-                .collect::<Vec<_>>();
-
-            Ok(get_message_events::Response {
-                start: Some(body.from.clone()),
-                end: end_token,
-                chunk: events_after,
-                state: Vec::new(),
-            }
-            .into())
+            (events, end)
         }
         get_message_events::Direction::Backward => {
-            let events_before = db
+            let mut load_events = || {
+                db.rooms
+                    .pdus_until(&sender_id, &body.room_id, from)
+                    .filter_map(|r| r.ok()) // Filter out buggy events
+                    .take_while(|&(count, _)| to.map_or(true, |to| count >= to)) // Stop at `to`
+                    .filter(|(_, pdu)| super::filter_allows_event(&filter, pdu))
+                    .filter(|(_, pdu)| !super::sent_by_ignored_user(&ignored_users, pdu))
+                    .filter(|(count, pdu)| visibility.can_see(*count, pdu).unwrap_or(false))
+                    .take(limit)
+                    .collect::<Vec<_>>()
+            };
+            let mut events = load_events();
+
+            // We reached the oldest event we know, other servers might have older ones
+            let is_federated = db
                 .rooms
-                .pdus_until(&sender_id, &body.room_id, from)
-                .take(limit)
-                .filter_map(|r| r.ok()) // Filter out buggy events
-                .take_while(|&(k, _)| Some(Ok(k)) != to) // Stop at `to`
-                .collect::<Vec<_>>();
+                .room_members(&body.room_id)
+                .filter_map(|r| r.ok())
+                .any(|user_id| user_id.server_name() != db.globals.server_name());
+            if events.len() < limit
+                && to.is_none()
+                && is_federated
+                && server_server::backfill(&db, &body.room_id, limit - events.len()).await? > 0
+            {
+                events = load_events();
+            }
 
-            let start_token = events_before.last().map(|(count, _)| count.to_string());
+            // The position before the oldest event. If the other servers failed, the token
+            // stays the same instead of ending the history
+            let end = events
+                .last()
+                .map(|(count, _)| count.to_string())
+                .or_else(|| Some(from.to_string()).filter(|_| is_federated));
 
-            let events_before = events_before
-                .into_iter()
-                .map(|(_, pdu)| pdu.to_room_event())
-                .collect::<Vec<_>>();
+            (events, end)
+        }
+    };
 
-            Ok(get_message_events::Response {
-                start: Some(body.from.clone()),
-                end: start_token,
-                chunk: events_before,
-                state: Vec::new(),
+    let mut state = Vec::new();
+    if lazy_load_members {
+        let senders = events
+            .iter()
+            .map(|(_, pdu)| &pdu.sender)
+            .collect::<HashSet<_>>();
+        for sender in senders {
+            if let Some(member) =
+                db.rooms
+                    .room_state_get(&body.room_id, &EventType::RoomMember, sender.as_str())?
+            {
+                state.push(member.to_state_event());
             }
-            .into())
         }
     }
+
+    let chunk = events
+        .into_iter()
+        .map(|(_, mut pdu)| {
+            db.rooms.add_bundled_relations(&mut pdu, &sender_id)?;
+            Ok(pdu.to_room_event())
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(get_message_events::Response {
+        start: Some(body.from.clone()),
+        end,
+        chunk,
+        state,
+    }
+    .into())
 }
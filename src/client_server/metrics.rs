@@ -0,0 +1,90 @@
+use super::State;
+use crate::{database::metrics, Database, Error, Result};
+use rocket::response::content::Plain;
+use ruma::api::client::error::ErrorKind;
+use std::fmt::Write;
+
+#[cfg(feature = "conduit_bin")]
+use rocket::{
+    get,
+    request::{FromRequest, Outcome, Request},
+};
+
+/// The token of the `Authorization: Bearer` header, if the request has one.
+pub struct BearerToken(Option<String>);
+
+#[cfg(feature = "conduit_bin")]
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for BearerToken {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(BearerToken(
+            request
+                .headers()
+                .get_one("Authorization")
+                .filter(|header| header.starts_with("Bearer "))
+                .map(|header| header["Bearer ".len()..].to_owned()),
+        ))
+    }
+}
+
+/// # `GET /metrics`
+///
+/// Returns the metrics of this server in the Prometheus text format.
+///
+/// - Only available if `metrics_enabled` is true
+/// - If `metrics_token` is set, it has to be sent as `Authorization: Bearer` token
+/// - Counting the entries of the database trees reads the whole database, so this should not be
+/// scraped too often on big servers
+#[cfg_attr(feature = "conduit_bin", get("/metrics"))]
+pub fn get_metrics_route(db: State<'_, Database<'_>>, token: BearerToken) -> Result<Plain<String>> {
+    let metrics = db.globals.metrics().ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "Metrics are disabled.",
+    ))?;
+
+    if let Some(metrics_token) = db.globals.metrics_token() {
+        if token.0.as_deref() != Some(metrics_token) {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Invalid metrics token.",
+            ));
+        }
+    }
+
+    let mut output = metrics.render();
+
+    output.push_str("# HELP conduit_current_count The newest position in the event stream.\n");
+    output.push_str("# TYPE conduit_current_count gauge\n");
+    writeln!(
+        output,
+        "conduit_current_count {}",
+        db.globals.current_count()?
+    )
+    .expect("writing to a string always works");
+
+    output.push_str("# HELP conduit_database_size_bytes Size of the database on disk.\n");
+    output.push_str("# TYPE conduit_database_size_bytes gauge\n");
+    writeln!(
+        output,
+        "conduit_database_size_bytes {}",
+        db._db.size_on_disk()?
+    )
+    .expect("writing to a string always works");
+
+    output.push_str("# HELP conduit_database_tree_entries Entries of each database tree.\n");
+    output.push_str("# TYPE conduit_database_tree_entries gauge\n");
+    for name in db._db.tree_names() {
+        let tree = db._db.open_tree(&name)?;
+        writeln!(
+            output,
+            "conduit_database_tree_entries{{tree=\"{}\"}} {}",
+            metrics::escape_label(&String::from_utf8_lossy(&name)),
+            tree.len()
+        )
+        .expect("writing to a string always works");
+    }
+
+    Ok(Plain(output))
+}
@@ -11,18 +11,26 @@ mod keys;
 mod media;
 mod membership;
 mod message;
+mod metrics;
+mod openid;
 mod presence;
 mod profile;
 mod push;
 mod read_marker;
 mod redact;
+mod relations;
+mod report;
 mod room;
 mod search;
 mod session;
+mod sliding_sync;
+mod space;
+mod sso;
 mod state;
 mod sync;
 mod tag;
 mod thirdparty;
+mod threepid;
 mod to_device;
 mod typing;
 mod unversioned;
@@ -42,39 +50,93 @@ pub use keys::*;
 pub use media::*;
 pub use membership::*;
 pub use message::*;
+pub use metrics::*;
+pub use openid::*;
 pub use presence::*;
 pub use profile::*;
 pub use push::*;
 pub use read_marker::*;
 pub use redact::*;
+pub use relations::*;
+pub use report::*;
 pub use room::*;
 pub use search::*;
 pub use session::*;
+pub use sliding_sync::*;
+pub use space::*;
+pub use sso::*;
 pub use state::*;
 pub use sync::*;
 pub use tag::*;
 pub use thirdparty::*;
+pub use threepid::*;
 pub use to_device::*;
 pub use typing::*;
 pub use unversioned::*;
 pub use user_directory::*;
 pub use voip::*;
 
+use std::time::Duration;
+
 #[cfg(not(feature = "conduit_bin"))]
 use super::State;
 #[cfg(feature = "conduit_bin")]
 use {
-    crate::ConduitResult,
-    rocket::{options, State},
-    ruma::api::client::r0::to_device::send_event_to_device,
+    crate::{ruma_wrapper::TokenExpired, ConduitResult, Error},
+    rocket::{catch, options, Request, State},
+    ruma::api::client::{error::ErrorKind, r0::to_device::send_event_to_device},
 };
 
 const DEVICE_ID_LENGTH: usize = 10;
 const TOKEN_LENGTH: usize = 256;
 const SESSION_ID_LENGTH: usize = 256;
+const LOGIN_TOKEN_LENGTH: usize = 32;
+
+/// How long the client has to exchange a login token for an access token
+const LOGIN_TOKEN_LIFETIME: Duration = Duration::from_secs(2 * 60);
+
+/// How long access tokens of clients with a refresh token are valid if `access_token_lifetime`
+/// is not set
+const REFRESHABLE_TOKEN_LIFETIME: Duration = Duration::from_secs(5 * 60);
 
 #[cfg(feature = "conduit_bin")]
 #[options("/<_..>")]
 pub fn options_route() -> ConduitResult<send_event_to_device::Response> {
     Ok(send_event_to_device::Response.into())
 }
+
+/// Requests with a missing, unknown or expired access token are rejected before they reach a
+/// route.
+#[cfg(feature = "conduit_bin")]
+#[catch(401)]
+pub fn unauthorized_catcher(request: &Request<'_>) -> Error {
+    if request.headers().get_one("Authorization").is_none()
+        && request.get_query_value::<String>("access_token").is_none()
+    {
+        return Error::BadRequest(ErrorKind::MissingToken, "Missing access token.");
+    }
+
+    if request.local_cache(|| TokenExpired(false)).0 {
+        Error::UnknownToken("Access token expired.", true)
+    } else {
+        Error::UnknownToken("Unknown access token.", false)
+    }
+}
+
+/// Requests with a body bigger than `max_request_size`, or `media_max_upload_size` for uploads,
+/// are rejected before they reach a route.
+#[cfg(feature = "conduit_bin")]
+#[catch(413)]
+pub fn payload_too_large_catcher() -> Error {
+    Error::BadRequest(ErrorKind::TooLarge, "Request body is too large.")
+}
+
+/// Requests of guests to endpoints they can't use are rejected before they reach a route.
+#[cfg(feature = "conduit_bin")]
+#[catch(403)]
+pub fn guest_access_forbidden_catcher() -> Error {
+    Error::BadRequest(
+        ErrorKind::GuestAccessForbidden,
+        "Guests can't use this endpoint.",
+    )
+}
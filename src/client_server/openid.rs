@@ -0,0 +1,64 @@
+use super::State;
+use crate::{utils, Database, Error, Result, Ruma};
+use rocket::response::content::Json;
+use ruma::{
+    api::client::{error::ErrorKind, r0::account::whoami},
+    UserId,
+};
+use serde_json::json;
+use std::convert::TryFrom;
+
+#[cfg(feature = "conduit_bin")]
+use rocket::post;
+
+const OPENID_TOKEN_LENGTH: usize = 32;
+
+/// # `POST /_matrix/client/r0/user/{userId}/openid/request_token`
+///
+/// Creates a token that integrations and widgets can check with
+/// `GET /_matrix/federation/v1/openid/userinfo` to find out who the user is.
+///
+/// - The request has no ruma type, so it is parsed as
+/// [`GET /_matrix/client/r0/account/whoami`](fn.whoami_route.html), which only checks the access
+/// token
+/// - The token is only valid on this server and expires after `openid_token_lifetime`
+#[cfg_attr(
+    feature = "conduit_bin",
+    post(
+        "/_matrix/client/r0/user/<user_id>/openid/request_token",
+        data = "<body>"
+    )
+)]
+pub fn request_openid_token_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+    user_id: String,
+) -> Result<Json<String>> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    let user_id = UserId::try_from(user_id)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid user id."))?;
+    if sender_id != &user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You can only request OpenID tokens for yourself.",
+        ));
+    }
+
+    let lifetime = db.globals.openid_token_lifetime();
+    let access_token = db.users.create_openid_token(
+        sender_id,
+        OPENID_TOKEN_LENGTH,
+        utils::millis_since_unix_epoch() + lifetime.as_millis() as u64,
+    )?;
+
+    Ok(Json(
+        json!({
+            "access_token": access_token,
+            "token_type": "Bearer",
+            "matrix_server_name": db.globals.server_name(),
+            "expires_in": lifetime.as_secs(),
+        })
+        .to_string(),
+    ))
+}
@@ -1,5 +1,5 @@
 use super::State;
-use crate::{utils, ConduitResult, Database, Ruma};
+use crate::{server_server, utils, ConduitResult, Database, Ruma};
 use ruma::api::client::r0::presence::set_presence;
 use std::convert::TryInto;
 
@@ -10,12 +10,16 @@ use rocket::put;
     feature = "conduit_bin",
     put("/_matrix/client/r0/presence/<_>/status", data = "<body>")
 )]
-pub fn set_presence_route(
+pub async fn set_presence_route(
     db: State<'_, Database<'_>>,
     body: Ruma<set_presence::Request>,
 ) -> ConduitResult<set_presence::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
+    if !db.globals.allow_presence() {
+        return Ok(set_presence::Response.into());
+    }
+
     for room_id in db.rooms.rooms_joined(&sender_id) {
         let room_id = room_id?;
 
@@ -41,5 +45,7 @@ pub fn set_presence_route(
         )?;
     }
 
+    server_server::send_presence_edu(&db, &sender_id).await?;
+
     Ok(set_presence::Response.into())
 }
@@ -1,5 +1,8 @@
 use super::State;
-use crate::{pdu::PduBuilder, utils, ConduitResult, Database, Error, Ruma};
+use crate::{
+    database::rate_limiter::RateLimitClass, pdu::PduBuilder, server_server, utils, ConduitResult,
+    Database, Error, Result, Ruma,
+};
 use ruma::{
     api::client::{
         error::ErrorKind,
@@ -7,115 +10,101 @@ use ruma::{
             get_avatar_url, get_display_name, get_profile, set_avatar_url, set_display_name,
         },
     },
-    events::EventType,
-    Raw,
+    events::{room::member::MemberEventContent, EventType},
+    Raw, UserId,
 };
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, put};
-use std::convert::TryInto;
+use std::{collections::BTreeMap, convert::TryInto};
 
+/// # `PUT /_matrix/client/r0/profile/{userId}/displayname`
+///
+/// Updates the display name of the user.
+///
+/// - Sends a new member event into all joined rooms, also to the other servers in them
+/// - Forbidden if `displayname_changes_disabled` is set
 #[cfg_attr(
     feature = "conduit_bin",
     put("/_matrix/client/r0/profile/<_>/displayname", data = "<body>")
 )]
-pub fn set_displayname_route(
+pub async fn set_displayname_route(
     db: State<'_, Database<'_>>,
     body: Ruma<set_display_name::Request>,
 ) -> ConduitResult<set_display_name::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
+    if db.globals.displayname_changes_disabled() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Display name changes are disabled on this server.",
+        ));
+    }
+
+    db.globals
+        .rate_limiter()
+        .check(RateLimitClass::Profile, sender_id.as_str())?;
+
     db.users
         .set_displayname(&sender_id, body.displayname.clone())?;
 
-    // Send a new membership event and presence update into all joined rooms
-    for room_id in db.rooms.rooms_joined(&sender_id) {
-        let room_id = room_id?;
-        db.rooms.append_pdu(
-            PduBuilder {
-                room_id: room_id.clone(),
-                sender: sender_id.clone(),
-                event_type: EventType::RoomMember,
-                content: serde_json::to_value(ruma::events::room::member::MemberEventContent {
-                    displayname: body.displayname.clone(),
-                    ..serde_json::from_value::<Raw<_>>(
-                        db.rooms
-                            .room_state_get(
-                                &room_id,
-                                &EventType::RoomMember,
-                                &sender_id.to_string(),
-                            )?
-                            .ok_or_else(|| {
-                                Error::bad_database(
-                                    "Tried to send displayname update for user not in the room.",
-                                )
-                            })?
-                            .content
-                            .clone(),
-                    )
-                    .expect("from_value::<Raw<..>> can never fail")
-                    .deserialize()
-                    .map_err(|_| Error::bad_database("Database contains invalid PDU."))?
-                })
-                .expect("event is valid, we just created it"),
-                unsigned: None,
-                state_key: Some(sender_id.to_string()),
-                redacts: None,
-            },
-            &db.globals,
-            &db.account_data,
-        )?;
-
-        // Presence update
-        db.rooms.edus.update_presence(
-            &sender_id,
-            &room_id,
-            ruma::events::presence::PresenceEvent {
-                content: ruma::events::presence::PresenceEventContent {
-                    avatar_url: db.users.avatar_url(&sender_id)?,
-                    currently_active: None,
-                    displayname: db.users.displayname(&sender_id)?,
-                    last_active_ago: Some(
-                        utils::millis_since_unix_epoch()
-                            .try_into()
-                            .expect("time is valid"),
-                    ),
-                    presence: ruma::presence::PresenceState::Online,
-                    status_msg: None,
-                },
-                sender: sender_id.clone(),
-            },
-            &db.globals,
-        )?;
-    }
+    update_member_events(&db, &sender_id, |content| {
+        content.displayname = body.displayname.clone();
+    })
+    .await?;
 
     Ok(set_display_name::Response.into())
 }
 
+/// # `GET /_matrix/client/r0/profile/{userId}/displayname`
+///
+/// Returns the display name of the user.
+///
+/// - The profile of remote users is requested from their server
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/profile/<_>/displayname", data = "<body>")
 )]
-pub fn get_displayname_route(
+pub async fn get_displayname_route(
     db: State<'_, Database<'_>>,
     body: Ruma<get_display_name::Request>,
 ) -> ConduitResult<get_display_name::Response> {
-    Ok(get_display_name::Response {
-        displayname: db.users.displayname(&body.user_id)?,
-    }
-    .into())
+    let displayname = if body.user_id.server_name() == db.globals.server_name() {
+        db.users.displayname(&body.user_id)?
+    } else {
+        remote_profile(&db, &body.user_id, Some("displayname"))
+            .await?
+            .get("displayname")
+            .and_then(|displayname| displayname.as_str())
+            .map(|displayname| displayname.to_owned())
+    };
+
+    Ok(get_display_name::Response { displayname }.into())
 }
 
+/// # `PUT /_matrix/client/r0/profile/{userId}/avatar_url`
+///
+/// Updates the avatar url of the user.
+///
+/// - Sends a new member event into all joined rooms, also to the other servers in them
+/// - Forbidden if `avatar_url_changes_disabled` is set
 #[cfg_attr(
     feature = "conduit_bin",
     put("/_matrix/client/r0/profile/<_>/avatar_url", data = "<body>")
 )]
-pub fn set_avatar_url_route(
+pub async fn set_avatar_url_route(
     db: State<'_, Database<'_>>,
     body: Ruma<set_avatar_url::Request>,
 ) -> ConduitResult<set_avatar_url::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
+    if db.globals.avatar_url_changes_disabled() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Avatar changes are disabled on this server.",
+        ));
+    }
+
     if let Some(avatar_url) = &body.avatar_url {
         if !avatar_url.starts_with("mxc://") {
             return Err(Error::BadRequest(
@@ -128,95 +117,76 @@ pub fn set_avatar_url_route(
         // TODO also make sure this is valid mxc:// format (not only starting with it)
     }
 
+    db.globals
+        .rate_limiter()
+        .check(RateLimitClass::Profile, sender_id.as_str())?;
+
     db.users
         .set_avatar_url(&sender_id, body.avatar_url.clone())?;
 
-    // Send a new membership event and presence update into all joined rooms
-    for room_id in db.rooms.rooms_joined(&sender_id) {
-        let room_id = room_id?;
-        db.rooms.append_pdu(
-            PduBuilder {
-                room_id: room_id.clone(),
-                sender: sender_id.clone(),
-                event_type: EventType::RoomMember,
-                content: serde_json::to_value(ruma::events::room::member::MemberEventContent {
-                    avatar_url: body.avatar_url.clone(),
-                    ..serde_json::from_value::<Raw<_>>(
-                        db.rooms
-                            .room_state_get(
-                                &room_id,
-                                &EventType::RoomMember,
-                                &sender_id.to_string(),
-                            )?
-                            .ok_or_else(|| {
-                                Error::bad_database(
-                                    "Tried to send avatar url update for user not in the room.",
-                                )
-                            })?
-                            .content
-                            .clone(),
-                    )
-                    .expect("from_value::<Raw<..>> can never fail")
-                    .deserialize()
-                    .map_err(|_| Error::bad_database("Database contains invalid PDU."))?
-                })
-                .expect("event is valid, we just created it"),
-                unsigned: None,
-                state_key: Some(sender_id.to_string()),
-                redacts: None,
-            },
-            &db.globals,
-            &db.account_data,
-        )?;
-
-        // Presence update
-        db.rooms.edus.update_presence(
-            &sender_id,
-            &room_id,
-            ruma::events::presence::PresenceEvent {
-                content: ruma::events::presence::PresenceEventContent {
-                    avatar_url: db.users.avatar_url(&sender_id)?,
-                    currently_active: None,
-                    displayname: db.users.displayname(&sender_id)?,
-                    last_active_ago: Some(
-                        utils::millis_since_unix_epoch()
-                            .try_into()
-                            .expect("time is valid"),
-                    ),
-                    presence: ruma::presence::PresenceState::Online,
-                    status_msg: None,
-                },
-                sender: sender_id.clone(),
-            },
-            &db.globals,
-        )?;
-    }
+    update_member_events(&db, &sender_id, |content| {
+        content.avatar_url = body.avatar_url.clone();
+    })
+    .await?;
 
     Ok(set_avatar_url::Response.into())
 }
 
+/// # `GET /_matrix/client/r0/profile/{userId}/avatar_url`
+///
+/// Returns the avatar url of the user.
+///
+/// - The profile of remote users is requested from their server
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/profile/<_>/avatar_url", data = "<body>")
 )]
-pub fn get_avatar_url_route(
+pub async fn get_avatar_url_route(
     db: State<'_, Database<'_>>,
     body: Ruma<get_avatar_url::Request>,
 ) -> ConduitResult<get_avatar_url::Response> {
-    Ok(get_avatar_url::Response {
-        avatar_url: db.users.avatar_url(&body.user_id)?,
-    }
-    .into())
+    let avatar_url = if body.user_id.server_name() == db.globals.server_name() {
+        db.users.avatar_url(&body.user_id)?
+    } else {
+        remote_profile(&db, &body.user_id, Some("avatar_url"))
+            .await?
+            .get("avatar_url")
+            .and_then(|avatar_url| avatar_url.as_str())
+            .map(|avatar_url| avatar_url.to_owned())
+    };
+
+    Ok(get_avatar_url::Response { avatar_url }.into())
 }
 
+/// # `GET /_matrix/client/r0/profile/{userId}`
+///
+/// Returns the display name and avatar url of the user.
+///
+/// - The profile of remote users is requested from their server
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/profile/<_>", data = "<body>")
 )]
-pub fn get_profile_route(
+pub async fn get_profile_route(
     db: State<'_, Database<'_>>,
     body: Ruma<get_profile::Request>,
 ) -> ConduitResult<get_profile::Response> {
+    if body.user_id.server_name() != db.globals.server_name() {
+        let profile = remote_profile(&db, &body.user_id, None).await?;
+        let field = |name: &str| {
+            profile
+                .get(name)
+                .and_then(|value| value.as_str())
+                .map(|value| value.to_owned())
+        };
+
+        return Ok(get_profile::Response {
+            avatar_url: field("avatar_url"),
+            displayname: field("displayname"),
+        }
+        .into());
+    }
+
     if !db.users.exists(&body.user_id)? {
         // Return 404 if this user doesn't exist
         return Err(Error::BadRequest(
@@ -231,3 +201,116 @@ pub fn get_profile_route(
     }
     .into())
 }
+
+/// Asks the server of a remote user for their profile, or only one `field` of it.
+async fn remote_profile(
+    db: &Database<'static>,
+    user_id: &UserId,
+    field: Option<&str>,
+) -> Result<serde_json::Value> {
+    let mut path = format!(
+        "/_matrix/federation/v1/query/profile?user_id={}",
+        utils::percent_encode(user_id.as_str())
+    );
+    if let Some(field) = field {
+        path.push_str(&format!("&field={}", field));
+    }
+
+    server_server::send_json_request(
+        db,
+        user_id.server_name().as_str(),
+        reqwest::Method::GET,
+        &path,
+        None,
+    )
+    .await
+    .map_err(|_| {
+        Error::BadRequest(
+            ErrorKind::NotFound,
+            "Profile was not found on the user's server.",
+        )
+    })
+}
+
+/// Sends a new member event with the updated profile and a presence update into all joined
+/// rooms of the user.
+///
+/// The events of all rooms are collected first and then sent to each other server in as few
+/// transactions as possible, so users in many rooms don't cause a request per room and server.
+pub(super) async fn update_member_events(
+    db: &Database<'static>,
+    sender_id: &UserId,
+    update: impl Fn(&mut MemberEventContent),
+) -> Result<()> {
+    let mut server_pdus = BTreeMap::<String, Vec<serde_json::Value>>::new();
+
+    for room_id in db.rooms.rooms_joined(&sender_id) {
+        let room_id = room_id?;
+
+        let mut content = serde_json::from_value::<Raw<MemberEventContent>>(
+            db.rooms
+                .room_state_get(&room_id, &EventType::RoomMember, sender_id.as_str())?
+                .ok_or_else(|| {
+                    Error::bad_database("Tried to send profile update for user not in the room.")
+                })?
+                .content,
+        )
+        .expect("from_value::<Raw<..>> can never fail")
+        .deserialize()
+        .map_err(|_| Error::bad_database("Database contains invalid PDU."))?;
+        update(&mut content);
+
+        let event_id = db.rooms.append_pdu(
+            PduBuilder {
+                room_id: room_id.clone(),
+                sender: sender_id.clone(),
+                event_type: EventType::RoomMember,
+                content: serde_json::to_value(content).expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(sender_id.to_string()),
+                redacts: None,
+            },
+            &db.globals,
+            &db.account_data,
+        )?;
+
+        if let Some(pdu_json) = db.rooms.get_pdu_json(&event_id)? {
+            for server in server_server::room_servers(db, &room_id)? {
+                server_pdus
+                    .entry(server)
+                    .or_default()
+                    .push(pdu_json.clone());
+            }
+        }
+
+        // Presence update
+        if db.globals.allow_presence() {
+            db.rooms.edus.update_presence(
+                &sender_id,
+                &room_id,
+                ruma::events::presence::PresenceEvent {
+                    content: ruma::events::presence::PresenceEventContent {
+                        avatar_url: db.users.avatar_url(&sender_id)?,
+                        currently_active: None,
+                        displayname: db.users.displayname(&sender_id)?,
+                        last_active_ago: Some(
+                            utils::millis_since_unix_epoch()
+                                .try_into()
+                                .expect("time is valid"),
+                        ),
+                        presence: ruma::presence::PresenceState::Online,
+                        status_msg: None,
+                    },
+                    sender: sender_id.clone(),
+                },
+                &db.globals,
+            )?;
+        }
+    }
+
+    for (server, pdus) in server_pdus {
+        server_server::send_pdus(db, &server, pdus).await?;
+    }
+
+    Ok(())
+}
@@ -1,16 +1,21 @@
 use super::State;
-use crate::{ConduitResult, Database, Error, Ruma};
-use log::warn;
+use crate::{push_rules, ConduitResult, Database, Error, Result, Ruma};
+use rocket::response::content::Json;
 use ruma::{
     api::client::{
         error::ErrorKind,
-        r0::push::{get_pushers, get_pushrules_all, set_pushrule, set_pushrule_enabled},
+        r0::push::{
+            delete_pushrule, get_pushers, get_pushrule, get_pushrules_all, set_pusher,
+            set_pushrule, set_pushrule_actions, set_pushrule_enabled,
+        },
     },
     events::EventType,
+    UserId,
 };
+use serde_json::json;
 
 #[cfg(feature = "conduit_bin")]
-use rocket::{get, post, put};
+use rocket::{delete, get, post, put};
 
 #[cfg_attr(
     feature = "conduit_bin",
@@ -36,40 +41,384 @@ pub fn get_pushrules_all_route(
     .into())
 }
 
-#[cfg_attr(feature = "conduit_bin", put(
-    "/_matrix/client/r0/pushrules/<_>/<_>/<_>",
-    //data = "<body>"
-))]
-pub fn set_pushrule_route(//db: State<'_, Database<'_>>,
-    //body: Ruma<set_pushrule::Request>,
+/// # `GET /_matrix/client/r0/pushrules/{scope}/{kind}/{ruleId}`
+///
+/// Returns a single push rule of the user.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get(
+        "/_matrix/client/r0/pushrules/<scope>/<kind>/<rule_id>",
+        data = "<body>"
+    )
+)]
+pub fn get_pushrule_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_pushrule::Request>,
+    scope: String,
+    kind: String,
+    rule_id: String,
+) -> Result<Json<String>> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    let mut rules = pushrules_json(&db, &sender_id)?;
+    let rule = find_rule(&mut rules, &scope, &kind, &rule_id)?;
+
+    Ok(Json(rule.to_string()))
+}
+
+/// # `PUT /_matrix/client/r0/pushrules/{scope}/{kind}/{ruleId}`
+///
+/// Creates or replaces a push rule of the user.
+///
+/// - Rules are added with the highest priority of their kind unless `before` or `after` is given
+/// - Default rules (starting with a dot) can't be replaced
+#[cfg_attr(
+    feature = "conduit_bin",
+    put(
+        "/_matrix/client/r0/pushrules/<scope>/<kind>/<rule_id>?<before>&<after>",
+        data = "<body>"
+    )
+)]
+pub fn set_pushrule_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<set_pushrule::Request>,
+    scope: String,
+    kind: String,
+    rule_id: String,
+    before: Option<String>,
+    after: Option<String>,
 ) -> ConduitResult<set_pushrule::Response> {
-    // TODO
-    warn!("TODO: set_pushrule_route");
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    check_scope_and_kind(&scope, &kind)?;
+
+    if rule_id.starts_with('.') || rule_id.contains('/') || rule_id.contains('\\') {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Invalid rule id.",
+        ));
+    }
+
+    let rule_body = body_json(&body)?;
+
+    let mut rule = serde_json::Map::new();
+    rule.insert("rule_id".to_owned(), rule_id.clone().into());
+    rule.insert("default".to_owned(), false.into());
+    rule.insert("enabled".to_owned(), true.into());
+    rule.insert(
+        "actions".to_owned(),
+        rule_body.get("actions").cloned().ok_or(Error::BadRequest(
+            ErrorKind::MissingParam,
+            "Push rule needs actions.",
+        ))?,
+    );
+    match &*kind {
+        "override" | "underride" => {
+            rule.insert(
+                "conditions".to_owned(),
+                rule_body
+                    .get("conditions")
+                    .cloned()
+                    .unwrap_or_else(|| Vec::<serde_json::Value>::new().into()),
+            );
+        }
+        "content" => {
+            rule.insert(
+                "pattern".to_owned(),
+                rule_body.get("pattern").cloned().ok_or(Error::BadRequest(
+                    ErrorKind::MissingParam,
+                    "Content rules need a pattern.",
+                ))?,
+            );
+        }
+        _ => {}
+    }
+
+    let mut rules = pushrules_json(&db, &sender_id)?;
+    let kind_rules = rules
+        .get_mut(&kind)
+        .and_then(|rules| rules.as_array_mut())
+        .ok_or_else(|| Error::bad_database("Push rules in db are invalid."))?;
+
+    // Replace the rule if it already exists
+    kind_rules.retain(|r| r.get("rule_id").and_then(|id| id.as_str()) != Some(&rule_id));
+
+    let position_of = |kind_rules: &Vec<serde_json::Value>, id: &str| {
+        kind_rules
+            .iter()
+            .position(|r| r.get("rule_id").and_then(|i| i.as_str()) == Some(id))
+            .ok_or(Error::BadRequest(
+                ErrorKind::NotFound,
+                "The rule given in before or after does not exist.",
+            ))
+    };
+
+    let index = if let Some(before) = &before {
+        position_of(kind_rules, before)?
+    } else if let Some(after) = &after {
+        position_of(kind_rules, after)? + 1
+    } else {
+        // The master rule always stays first
+        kind_rules
+            .iter()
+            .take_while(|r| r.get("rule_id").and_then(|id| id.as_str()) == Some(".m.rule.master"))
+            .count()
+    };
+    kind_rules.insert(index, rule.into());
+
+    save_pushrules_json(&db, &sender_id, rules)?;
+
     Ok(set_pushrule::Response.into())
 }
 
+/// # `DELETE /_matrix/client/r0/pushrules/{scope}/{kind}/{ruleId}`
+///
+/// Deletes a push rule of the user. Default rules can only be disabled.
 #[cfg_attr(
     feature = "conduit_bin",
-    put("/_matrix/client/r0/pushrules/<_>/<_>/<_>/enabled")
+    delete(
+        "/_matrix/client/r0/pushrules/<scope>/<kind>/<rule_id>",
+        data = "<body>"
+    )
 )]
-pub fn set_pushrule_enabled_route() -> ConduitResult<set_pushrule_enabled::Response> {
-    // TODO
-    warn!("TODO: set_pushrule_enabled_route");
+pub fn delete_pushrule_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<delete_pushrule::Request>,
+    scope: String,
+    kind: String,
+    rule_id: String,
+) -> ConduitResult<delete_pushrule::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    if rule_id.starts_with('.') {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Default rules can't be deleted.",
+        ));
+    }
+
+    let mut rules = pushrules_json(&db, &sender_id)?;
+    // Make sure the rule exists
+    find_rule(&mut rules, &scope, &kind, &rule_id)?;
+
+    rules
+        .get_mut(&kind)
+        .and_then(|rules| rules.as_array_mut())
+        .ok_or_else(|| Error::bad_database("Push rules in db are invalid."))?
+        .retain(|r| r.get("rule_id").and_then(|id| id.as_str()) != Some(&rule_id));
+
+    save_pushrules_json(&db, &sender_id, rules)?;
+
+    Ok(delete_pushrule::Response.into())
+}
+
+/// # `PUT /_matrix/client/r0/pushrules/{scope}/{kind}/{ruleId}/enabled`
+///
+/// Enables or disables a push rule of the user.
+#[cfg_attr(
+    feature = "conduit_bin",
+    put(
+        "/_matrix/client/r0/pushrules/<scope>/<kind>/<rule_id>/enabled",
+        data = "<body>"
+    )
+)]
+pub fn set_pushrule_enabled_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<set_pushrule_enabled::Request>,
+    scope: String,
+    kind: String,
+    rule_id: String,
+) -> ConduitResult<set_pushrule_enabled::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    let enabled = body_json(&body)?
+        .get("enabled")
+        .and_then(|enabled| enabled.as_bool())
+        .ok_or(Error::BadRequest(
+            ErrorKind::MissingParam,
+            "Missing enabled field.",
+        ))?;
+
+    let mut rules = pushrules_json(&db, &sender_id)?;
+    find_rule(&mut rules, &scope, &kind, &rule_id)?["enabled"] = enabled.into();
+    save_pushrules_json(&db, &sender_id, rules)?;
+
     Ok(set_pushrule_enabled::Response.into())
 }
 
-#[cfg_attr(feature = "conduit_bin", get("/_matrix/client/r0/pushers"))]
-pub fn get_pushers_route() -> ConduitResult<get_pushers::Response> {
-    Ok(get_pushers::Response {
-        pushers: Vec::new(),
+/// # `PUT /_matrix/client/r0/pushrules/{scope}/{kind}/{ruleId}/actions`
+///
+/// Changes the actions of a push rule of the user.
+#[cfg_attr(
+    feature = "conduit_bin",
+    put(
+        "/_matrix/client/r0/pushrules/<scope>/<kind>/<rule_id>/actions",
+        data = "<body>"
+    )
+)]
+pub fn set_pushrule_actions_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<set_pushrule_actions::Request>,
+    scope: String,
+    kind: String,
+    rule_id: String,
+) -> ConduitResult<set_pushrule_actions::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    let actions = body_json(&body)?
+        .get("actions")
+        .cloned()
+        .ok_or(Error::BadRequest(
+            ErrorKind::MissingParam,
+            "Missing actions field.",
+        ))?;
+
+    let mut rules = pushrules_json(&db, &sender_id)?;
+    find_rule(&mut rules, &scope, &kind, &rule_id)?["actions"] = actions;
+    save_pushrules_json(&db, &sender_id, rules)?;
+
+    Ok(set_pushrule_actions::Response.into())
+}
+
+/// Only the global scope exists.
+fn check_scope_and_kind(scope: &str, kind: &str) -> Result<()> {
+    if scope != "global" {
+        return Err(Error::BadRequest(ErrorKind::InvalidParam, "Unknown scope."));
     }
-    .into())
+    if !push_rules::RULE_KINDS.contains(&kind) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Unknown rule kind.",
+        ));
+    }
+
+    Ok(())
+}
+
+fn body_json<T>(body: &Ruma<T>) -> Result<serde_json::Value> {
+    serde_json::from_str(
+        body.json_body
+            .as_ref()
+            .ok_or(Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?
+            .get(),
+    )
+    .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))
+}
+
+/// Returns the push rules of the user in their JSON form, so all kinds can be handled the same
+/// way.
+fn pushrules_json(db: &Database<'_>, user_id: &UserId) -> Result<serde_json::Value> {
+    let event = db
+        .account_data
+        .get::<ruma::events::push_rules::PushRulesEvent>(None, user_id, EventType::PushRules)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "PushRules event not found.",
+        ))?;
+
+    Ok(serde_json::to_value(event.content.global).expect("Ruleset can be serialized"))
+}
+
+fn save_pushrules_json(
+    db: &Database<'_>,
+    user_id: &UserId,
+    rules: serde_json::Value,
+) -> Result<()> {
+    let global = serde_json::from_value(rules)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid push rule."))?;
+
+    db.account_data.update(
+        None,
+        user_id,
+        EventType::PushRules,
+        &ruma::events::push_rules::PushRulesEvent {
+            content: ruma::events::push_rules::PushRulesEventContent { global },
+        },
+        &db.globals,
+    )
+}
+
+fn find_rule<'a>(
+    rules: &'a mut serde_json::Value,
+    scope: &str,
+    kind: &str,
+    rule_id: &str,
+) -> Result<&'a mut serde_json::Value> {
+    check_scope_and_kind(scope, kind)?;
+
+    rules
+        .get_mut(kind)
+        .and_then(|rules| rules.as_array_mut())
+        .into_iter()
+        .flatten()
+        .find(|r| r.get("rule_id").and_then(|id| id.as_str()) == Some(rule_id))
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Push rule not found.",
+        ))
 }
 
-#[cfg_attr(feature = "conduit_bin", post("/_matrix/client/r0/pushers/set"))]
-pub fn set_pushers_route() -> ConduitResult<get_pushers::Response> {
-    Ok(get_pushers::Response {
-        pushers: Vec::new(),
+/// # `GET /_matrix/client/r0/pushers`
+///
+/// Returns the pushers of all devices of the user.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/pushers", data = "<body>")
+)]
+pub fn get_pushers_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_pushers::Request>,
+) -> Result<Json<String>> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    Ok(Json(
+        json!({ "pushers": db.pushers.get_pushers(&sender_id)? }).to_string(),
+    ))
+}
+
+/// # `POST /_matrix/client/r0/pushers/set`
+///
+/// Adds, replaces or removes a pusher of the current device.
+///
+/// - Only pushers of the kind `http` are used to send notifications
+/// - A pusher with the kind `null` is removed
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/pushers/set", data = "<body>")
+)]
+pub fn set_pushers_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<set_pusher::Request>,
+) -> ConduitResult<set_pusher::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+    let device_id = body.device_id.as_ref().expect("user is authenticated");
+
+    let mut pusher = body_json(&body)?;
+
+    if pusher.get("kind").and_then(|k| k.as_str()) == Some("http")
+        && pusher
+            .get("data")
+            .and_then(|d| d.get("url"))
+            .and_then(|u| u.as_str())
+            .is_none()
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::MissingParam,
+            "Http pushers need a url.",
+        ));
     }
-    .into())
+
+    let append = pusher
+        .get("append")
+        .and_then(|a| a.as_bool())
+        .unwrap_or(false);
+
+    if let Some(pusher) = pusher.as_object_mut() {
+        pusher.remove("append");
+    }
+
+    db.pushers
+        .set_pusher(&sender_id, &device_id, &pusher, append)?;
+
+    Ok(set_pusher::Response.into())
 }
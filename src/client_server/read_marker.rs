@@ -1,19 +1,27 @@
 use super::State;
-use crate::{ConduitResult, Database, Error, Ruma};
+use crate::{server_server, ConduitResult, Database, Result, Ruma};
 use ruma::{
-    api::client::{error::ErrorKind, r0::read_marker::set_read_marker},
+    api::client::r0::{read_marker::set_read_marker, receipt::create_receipt},
     events::{AnyEphemeralRoomEvent, AnyEvent, EventType},
+    EventId, RoomId, UserId,
 };
 
 #[cfg(feature = "conduit_bin")]
 use rocket::post;
-use std::{collections::BTreeMap, time::SystemTime};
+use std::{collections::BTreeMap, convert::TryFrom, time::SystemTime};
 
+/// # `POST /_matrix/client/r0/rooms/{roomId}/read_markers`
+///
+/// Sets the fully read marker and optionally the read receipt of the user.
+///
+/// - The fully read marker is room account data, so it is sent to all devices in /sync
+/// - The fully read marker can point to events this server does not know yet
+/// - `m.read.private` moves the read marker without sending a public read receipt
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/rooms/<_>/read_markers", data = "<body>")
 )]
-pub fn set_read_marker_route(
+pub async fn set_read_marker_route(
     db: State<'_, Database<'_>>,
     body: Ruma<set_read_marker::Request>,
 ) -> ConduitResult<set_read_marker::Response> {
@@ -34,42 +42,95 @@ pub fn set_read_marker_route(
     )?;
 
     if let Some(event) = &body.read_receipt {
-        db.rooms.edus.private_read_set(
-            &body.room_id,
-            &sender_id,
-            db.rooms.get_pdu_count(event)?.ok_or(Error::BadRequest(
-                ErrorKind::InvalidParam,
-                "Event does not exist.",
-            ))?,
-            &db.globals,
-        )?;
-
-        let mut user_receipts = BTreeMap::new();
-        user_receipts.insert(
-            sender_id.clone(),
-            ruma::events::receipt::Receipt {
-                ts: Some(SystemTime::now()),
-            },
-        );
-        let mut receipt_content = BTreeMap::new();
-        receipt_content.insert(
-            event.clone(),
-            ruma::events::receipt::Receipts {
-                read: Some(user_receipts),
-            },
-        );
+        set_read_receipt(&db, &body.room_id, &sender_id, event, SystemTime::now())?;
+        server_server::send_receipt_edu(&db, &body.room_id, &sender_id, event).await?;
+    }
 
-        db.rooms.edus.readreceipt_update(
-            &sender_id,
-            &body.room_id,
-            AnyEvent::Ephemeral(AnyEphemeralRoomEvent::Receipt(
-                ruma::events::receipt::ReceiptEvent {
-                    content: ruma::events::receipt::ReceiptEventContent(receipt_content),
-                    room_id: body.room_id.clone(),
-                },
-            )),
-            &db.globals,
-        )?;
+    // Private read receipts only move the read marker of the user, other users don't see them
+    if let Some(count) = body
+        .json_body
+        .as_ref()
+        .and_then(|json| serde_json::from_str::<serde_json::Value>(json.get()).ok())
+        .and_then(|json| json.get("m.read.private")?.as_str().map(|e| e.to_owned()))
+        .and_then(|event_id| EventId::try_from(event_id).ok())
+        .map(|event_id| db.rooms.get_pdu_count(&event_id))
+        .transpose()?
+        .flatten()
+    {
+        db.rooms
+            .edus
+            .private_read_set(&body.room_id, &sender_id, count, &db.globals)?;
     }
+
     Ok(set_read_marker::Response.into())
 }
+
+/// # `POST /_matrix/client/r0/rooms/{roomId}/receipt/{receiptType}/{eventId}`
+///
+/// Sets the public read receipt of the user to the event.
+///
+/// - The event does not have to be known by this server yet
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/rooms/<_>/receipt/<_>/<_>", data = "<body>")
+)]
+pub async fn create_receipt_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<create_receipt::Request>,
+) -> ConduitResult<create_receipt::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    set_read_receipt(
+        &db,
+        &body.room_id,
+        &sender_id,
+        &body.event_id,
+        SystemTime::now(),
+    )?;
+    server_server::send_receipt_edu(&db, &body.room_id, &sender_id, &body.event_id).await?;
+
+    Ok(create_receipt::Response.into())
+}
+
+/// Replaces the read receipt of the user in this room.
+///
+/// The private read marker is only moved if we know the event. Receipts for unknown events are
+/// still saved, the event might be backfilled later.
+pub fn set_read_receipt(
+    db: &Database<'_>,
+    room_id: &RoomId,
+    user_id: &UserId,
+    event_id: &EventId,
+    ts: SystemTime,
+) -> Result<()> {
+    if let Some(count) = db.rooms.get_pdu_count(event_id)? {
+        db.rooms
+            .edus
+            .private_read_set(room_id, user_id, count, &db.globals)?;
+    }
+
+    let mut user_receipts = BTreeMap::new();
+    user_receipts.insert(
+        user_id.clone(),
+        ruma::events::receipt::Receipt { ts: Some(ts) },
+    );
+    let mut receipt_content = BTreeMap::new();
+    receipt_content.insert(
+        event_id.clone(),
+        ruma::events::receipt::Receipts {
+            read: Some(user_receipts),
+        },
+    );
+
+    db.rooms.edus.readreceipt_update(
+        user_id,
+        room_id,
+        AnyEvent::Ephemeral(AnyEphemeralRoomEvent::Receipt(
+            ruma::events::receipt::ReceiptEvent {
+                content: ruma::events::receipt::ReceiptEventContent(receipt_content),
+                room_id: room_id.clone(),
+            },
+        )),
+        &db.globals,
+    )
+}
@@ -1,13 +1,20 @@
 use super::State;
-use crate::{pdu::PduBuilder, ConduitResult, Database, Ruma};
+use crate::{pdu::PduBuilder, ConduitResult, Database, Result, Ruma};
 use ruma::{
     api::client::r0::redact::redact_event,
     events::{room::redaction, EventType},
+    EventId,
 };
 
 #[cfg(feature = "conduit_bin")]
 use rocket::put;
 
+/// # `PUT /_matrix/client/r0/rooms/{roomId}/redact/{eventId}/{txnId}`
+///
+/// Redacts an event of the room.
+///
+/// - Retries with the same transaction id return the event id of the first request, also while
+/// the first request is still running
 #[cfg_attr(
     feature = "conduit_bin",
     put("/_matrix/client/r0/rooms/<_>/redact/<_>/<_>", data = "<body>")
@@ -17,6 +24,32 @@ pub fn redact_event_route(
     body: Ruma<redact_event::Request>,
 ) -> ConduitResult<redact_event::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+    let device_id = body.device_id.as_ref().expect("user is authenticated");
+
+    let event_id = tokio::task::block_in_place(|| {
+        db.transaction_ids
+            .exclusive(sender_id, device_id, &body.txn_id, || {
+                redact_event(&db, &body)
+            })
+    })?;
+
+    Ok(redact_event::Response { event_id }.into())
+}
+
+/// Sends the redaction of the request, unless it was sent with the same transaction id before.
+fn redact_event(db: &Database<'_>, body: &Ruma<redact_event::Request>) -> Result<EventId> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+    let device_id = body.device_id.as_ref().expect("user is authenticated");
+
+    if let Some(event_id) =
+        db.transaction_ids
+            .existing_event_id(sender_id, device_id, &body.txn_id)?
+    {
+        return Ok(event_id);
+    }
+
+    let mut unsigned = serde_json::Map::new();
+    unsigned.insert("transaction_id".to_owned(), body.txn_id.clone().into());
 
     let event_id = db.rooms.append_pdu(
         PduBuilder {
@@ -27,7 +60,7 @@ pub fn redact_event_route(
                 reason: body.reason.clone(),
             })
             .expect("event is valid, we just created it"),
-            unsigned: None,
+            unsigned: Some(unsigned),
             state_key: None,
             redacts: Some(body.event_id.clone()),
         },
@@ -35,5 +68,7 @@ pub fn redact_event_route(
         &db.account_data,
     )?;
 
-    Ok(redact_event::Response { event_id }.into())
+    db.transaction_ids
+        .add_txnid(sender_id, device_id, &body.txn_id, event_id.as_bytes())?;
+    Ok(event_id)
 }
@@ -0,0 +1,258 @@
+use super::State;
+use crate::{Database, Error, Result, Ruma};
+use rocket::response::content::Json;
+use ruma::api::client::{
+    error::ErrorKind,
+    r0::{membership::joined_members, room::get_room_event},
+};
+use serde_json::json;
+
+#[cfg(feature = "conduit_bin")]
+use rocket::get;
+
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 100;
+
+/// # `GET /_matrix/client/v1/rooms/{roomId}/relations/{eventId}`
+///
+/// Returns the events that relate to the event with `m.relates_to`, newest first.
+///
+/// - The request has the same form as [`GET /_matrix/client/r0/rooms/{roomId}/event/{eventId}`](fn.get_room_event_route.html),
+/// so we parse it as that request
+/// - `next_batch` can be passed as `from` to get older relations
+#[cfg_attr(
+    feature = "conduit_bin",
+    get(
+        "/_matrix/client/v1/rooms/<_>/relations/<_>?<from>&<limit>",
+        data = "<body>"
+    )
+)]
+pub fn get_relations_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_room_event::Request>,
+    from: Option<String>,
+    limit: Option<usize>,
+) -> Result<Json<String>> {
+    get_relations(&db, &body, None, None, from, limit)
+}
+
+/// # `GET /_matrix/client/v1/rooms/{roomId}/relations/{eventId}/{relType}`
+///
+/// Returns the events that relate to the event with the relation type, newest first.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get(
+        "/_matrix/client/v1/rooms/<_>/relations/<_>/<rel_type>?<from>&<limit>",
+        data = "<body>"
+    )
+)]
+pub fn get_relations_with_rel_type_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_room_event::Request>,
+    rel_type: String,
+    from: Option<String>,
+    limit: Option<usize>,
+) -> Result<Json<String>> {
+    get_relations(&db, &body, Some(&*rel_type), None, from, limit)
+}
+
+/// # `GET /_matrix/client/v1/rooms/{roomId}/relations/{eventId}/{relType}/{eventType}`
+///
+/// Returns the events of the type that relate to the event with the relation type, newest first.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get(
+        "/_matrix/client/v1/rooms/<_>/relations/<_>/<rel_type>/<event_type>?<from>&<limit>",
+        data = "<body>"
+    )
+)]
+pub fn get_relations_with_rel_type_and_event_type_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_room_event::Request>,
+    rel_type: String,
+    event_type: String,
+    from: Option<String>,
+    limit: Option<usize>,
+) -> Result<Json<String>> {
+    get_relations(
+        &db,
+        &body,
+        Some(&*rel_type),
+        Some(&*event_type),
+        from,
+        limit,
+    )
+}
+
+/// # `GET /_matrix/client/v1/rooms/{roomId}/threads`
+///
+/// Returns the thread roots of the room with their `m.thread` aggregation, the thread with the
+/// newest reply first.
+///
+/// - The request has the same form as [`GET /_matrix/client/r0/rooms/{roomId}/joined_members`](fn.joined_members_route.html),
+/// so we parse it as that request
+/// - `include=participated` only returns threads the user started or replied to
+/// - `next_batch` can be passed as `from` to get older threads
+#[cfg_attr(
+    feature = "conduit_bin",
+    get(
+        "/_matrix/client/v1/rooms/<_>/threads?<include>&<from>&<limit>",
+        data = "<body>"
+    )
+)]
+pub fn get_threads_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<joined_members::Request>,
+    include: Option<String>,
+    from: Option<String>,
+    limit: Option<usize>,
+) -> Result<Json<String>> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    if !db.rooms.is_joined(sender_id, &body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to view this room.",
+        ));
+    }
+
+    let participated_only = match include.as_deref() {
+        None | Some("all") => false,
+        Some("participated") => true,
+        Some(_) => {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "include has to be all or participated.",
+            ))
+        }
+    };
+    let from = match from.map(|from| from.parse::<u64>()) {
+        Some(Ok(from)) => from,
+        Some(Err(_)) => {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Invalid from token.",
+            ))
+        }
+        None => u64::MAX, // Default to the newest thread
+    };
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).max(1).min(MAX_LIMIT);
+
+    let mut chunk = Vec::new();
+    let mut next_batch = None;
+    for (count, root_id) in db.rooms.threads(&body.room_id)? {
+        if count >= from {
+            continue;
+        }
+
+        let mut root = match db.rooms.get_pdu(&root_id)? {
+            Some(root) => root,
+            None => continue,
+        };
+        db.rooms.add_bundled_relations(&mut root, &sender_id)?;
+
+        if participated_only
+            && root
+                .unsigned
+                .get("m.relations")
+                .and_then(|relations| relations.get("m.thread")?.get("current_user_participated"))
+                .and_then(|participated| participated.as_bool())
+                != Some(true)
+        {
+            continue;
+        }
+
+        if chunk.len() == limit {
+            // There is at least one more thread
+            next_batch = Some(count + 1);
+            break;
+        }
+
+        chunk.push(root.to_room_event());
+    }
+
+    Ok(Json(
+        json!({
+            "chunk": chunk,
+            "next_batch": next_batch.map(|count| count.to_string()),
+        })
+        .to_string(),
+    ))
+}
+
+fn get_relations(
+    db: &Database<'_>,
+    body: &Ruma<get_room_event::Request>,
+    rel_type: Option<&str>,
+    event_type: Option<&str>,
+    from: Option<String>,
+    limit: Option<usize>,
+) -> Result<Json<String>> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    if !db.rooms.is_joined(sender_id, &body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to view this room.",
+        ));
+    }
+
+    if db
+        .rooms
+        .get_pdu(&body.event_id)?
+        .map_or(true, |pdu| pdu.room_id != body.room_id)
+    {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Event not found."));
+    }
+
+    let from = match from.map(|from| from.parse::<u64>()) {
+        Some(Ok(from)) => from,
+        Some(Err(_)) => {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Invalid from token.",
+            ))
+        }
+        None => u64::MAX, // Default to the newest relation
+    };
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).max(1).min(MAX_LIMIT);
+
+    // One more than needed tells us if there is a next batch
+    let mut relations = db
+        .rooms
+        .relations(&body.room_id, &body.event_id)
+        .rev()
+        .filter_map(|r| r.ok()) // Filter out buggy events
+        .filter(|(count, _)| *count < from)
+        .filter(|(_, pdu)| {
+            rel_type.map_or(true, |rel_type| {
+                pdu.content
+                    .get("m.relates_to")
+                    .and_then(|relates_to| relates_to.get("rel_type")?.as_str())
+                    == Some(rel_type)
+            })
+        })
+        .filter(|(_, pdu)| event_type.map_or(true, |event_type| pdu.kind.to_string() == event_type))
+        .take(limit + 1)
+        .collect::<Vec<_>>();
+
+    let next_batch = if relations.len() > limit {
+        relations.truncate(limit);
+        relations.last().map(|(count, _)| count.to_string())
+    } else {
+        None
+    };
+
+    let chunk = relations
+        .into_iter()
+        .map(|(_, pdu)| pdu.to_room_event())
+        .collect::<Vec<_>>();
+
+    Ok(Json(
+        json!({
+            "chunk": chunk,
+            "next_batch": next_batch,
+        })
+        .to_string(),
+    ))
+}
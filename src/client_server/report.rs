@@ -0,0 +1,92 @@
+use super::State;
+use crate::{
+    database::{reports::ContentReport, rooms::Viewer},
+    utils, Database, Error, Result, Ruma,
+};
+use rocket::response::content::Json;
+use ruma::api::client::{error::ErrorKind, r0::room::get_room_event};
+use serde_json::json;
+
+#[cfg(feature = "conduit_bin")]
+use rocket::post;
+
+/// # `POST /_matrix/client/r0/rooms/{roomId}/report/{eventId}`
+///
+/// Reports an event to the server admins, who can list the reports with
+/// [`GET /_conduit/admin/reports`](../admin/fn.list_reports_route.html).
+///
+/// - The request has the same form as [`GET /_matrix/client/r0/rooms/{roomId}/event/{eventId}`](fn.get_room_event_route.html),
+/// so we parse it as that request and read `score` and `reason` from the body
+/// - The `score` goes from -100 (most offensive) to 0 (inoffensive)
+/// - Users can only report events they can see, other events are not found
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/rooms/<_>/report/<_>", data = "<body>")
+)]
+pub fn report_event_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_room_event::Request>,
+) -> Result<Json<String>> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    let request = body
+        .json_body
+        .as_ref()
+        .and_then(|json_body| serde_json::from_str::<serde_json::Value>(json_body.get()).ok())
+        .unwrap_or_default();
+
+    let score = match request.get("score") {
+        Some(score) => Some(
+            score
+                .as_i64()
+                .filter(|score| (-100..=0).contains(score))
+                .ok_or(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "The score has to be between -100 and 0.",
+                ))?,
+        ),
+        None => None,
+    };
+    let reason = match request.get("reason") {
+        Some(reason) => Some(
+            reason
+                .as_str()
+                .ok_or(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "The reason has to be a string.",
+                ))?
+                .to_owned(),
+        ),
+        None => None,
+    };
+
+    // Users can't find out which events exist in rooms they can't see
+    let pdu = match db.rooms.get_pdu(&body.event_id)? {
+        Some(pdu)
+            if pdu.room_id == body.room_id
+                && db.rooms.can_see_event(
+                    Viewer::User(&sender_id),
+                    &body.room_id,
+                    &body.event_id,
+                )? =>
+        {
+            pdu
+        }
+        _ => return Err(Error::BadRequest(ErrorKind::NotFound, "Event not found.")),
+    };
+
+    db.reports.add(
+        &ContentReport {
+            reporter: sender_id.clone(),
+            room_id: body.room_id.clone(),
+            event_id: body.event_id.clone(),
+            sender: pdu.sender,
+            score,
+            reason,
+            received_ts: utils::millis_since_unix_epoch(),
+        },
+        &db.globals,
+    )?;
+
+    Ok(Json(json!({}).to_string()))
+}
@@ -1,5 +1,9 @@
-use super::State;
-use crate::{pdu::PduBuilder, ConduitResult, Database, Error, Ruma};
+use super::{invite_remote_user, invite_third_party_id, validate_canonical_alias, State};
+use crate::{
+    database::globals::{supported_room_versions, EncryptionDefault},
+    pdu::PduBuilder,
+    ConduitResult, Database, Error, Result, Ruma,
+};
 use ruma::{
     api::client::{
         error::ErrorKind,
@@ -9,18 +13,33 @@ use ruma::{
         room::{guest_access, history_visibility, join_rules, member, name, topic},
         EventType,
     },
-    Raw, RoomAliasId, RoomId, RoomVersionId,
+    Raw, RoomAliasId, RoomId, RoomVersionId, UserId,
 };
 use std::{cmp::max, collections::BTreeMap, convert::TryFrom};
+use tracing::warn;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, post};
 
+/// # `POST /_matrix/client/r0/createRoom`
+///
+/// Creates a new room.
+///
+/// - The events are sent in the order of the spec: create, creator join, power levels, preset
+/// events, `initial_state`, name and topic, invites
+/// - `trusted_private_chat` gives the invited users the same power level as the creator
+/// - `power_level_content_override` is merged into the levels of the preset, the creator must
+/// still have power level 100
+/// - Fields of `creation_content` are copied into the create event
+/// - Encryption is enabled according to `encryption_enabled_by_default_for_room_type`, unless
+/// `initial_state` has an `m.room.encryption` event. One without `algorithm` keeps the room
+/// unencrypted
+/// - Users of other servers are invited through their server
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/createRoom", data = "<body>")
 )]
-pub fn create_room_route(
+pub async fn create_room_route(
     db: State<'_, Database<'_>>,
     body: Ruma<create_room::Request>,
 ) -> ConduitResult<create_room::Response> {
@@ -47,13 +66,43 @@ pub fn create_room_route(
             }
         })?;
 
+    // Fields of the request ruma doesn't know, like the type of spaces in creation_content
+    let json_body = body
+        .json_body
+        .as_ref()
+        .and_then(|json| serde_json::from_str::<serde_json::Value>(json.get()).ok())
+        .unwrap_or_default();
+
     let mut content = ruma::events::room::create::CreateEventContent::new(sender_id.clone());
     content.federate = body.creation_content.as_ref().map_or(true, |c| c.federate);
     content.predecessor = body
         .creation_content
         .as_ref()
         .and_then(|c| c.predecessor.clone());
-    content.room_version = RoomVersionId::Version6;
+    content.room_version = match &body.room_version {
+        Some(room_version) if supported_room_versions().contains(room_version) => {
+            room_version.clone()
+        }
+        Some(_) => {
+            return Err(Error::BadRequest(
+                ErrorKind::UnsupportedRoomVersion,
+                "This server does not support that room version.",
+            ))
+        }
+        None => db.globals.default_room_version().clone(),
+    };
+    let mut content = serde_json::to_value(content).expect("event is valid, we just created it");
+    for (key, value) in json_body
+        .get("creation_content")
+        .and_then(|creation_content| creation_content.as_object())
+        .into_iter()
+        .flatten()
+    {
+        // The creator and the room version are always set by the server
+        if key != "creator" && key != "room_version" {
+            content[key] = value.clone();
+        }
+    }
 
     // 1. The room create event
     db.rooms.append_pdu(
@@ -61,7 +110,7 @@ pub fn create_room_route(
             room_id: room_id.clone(),
             sender: sender_id.clone(),
             event_type: EventType::RoomCreate,
-            content: serde_json::to_value(content).expect("event is valid, we just created it"),
+            content,
             unsigned: None,
             state_key: Some("".to_owned()),
             redacts: None,
@@ -92,34 +141,29 @@ pub fn create_room_route(
         &db.account_data,
     )?;
 
-    // 3. Power levels
-    let mut users = BTreeMap::new();
-    users.insert(sender_id.clone(), 100.into());
-    for invite_ in &body.invite {
-        users.insert(invite_.clone(), 100.into());
-    }
+    // Figure out preset. We need it for preset specific events
+    let visibility = body.visibility.unwrap_or(room::Visibility::Private);
+    let preset = body.preset.unwrap_or_else(|| match visibility {
+        room::Visibility::Private => create_room::RoomPreset::PrivateChat,
+        room::Visibility::Public => create_room::RoomPreset::PublicChat,
+    });
 
-    let power_levels_content = if let Some(power_levels) = &body.power_level_content_override {
-        serde_json::from_str(power_levels.json().get()).map_err(|_| {
+    // 3. Power levels
+    let power_levels_override = body
+        .power_level_content_override
+        .as_ref()
+        .map(|power_levels| serde_json::from_str::<serde_json::Value>(power_levels.json().get()))
+        .transpose()
+        .map_err(|_| {
             Error::BadRequest(ErrorKind::BadJson, "Invalid power_level_content_override.")
-        })?
-    } else {
-        serde_json::to_value(ruma::events::room::power_levels::PowerLevelsEventContent {
-            ban: 50.into(),
-            events: BTreeMap::new(),
-            events_default: 0.into(),
-            invite: 50.into(),
-            kick: 50.into(),
-            redact: 50.into(),
-            state_default: 50.into(),
-            users,
-            users_default: 0.into(),
-            notifications: ruma::events::room::power_levels::NotificationPowerLevels {
-                room: 50.into(),
-            },
-        })
-        .expect("event is valid, we just created it")
-    };
+        })?;
+    let power_levels_content = initial_power_levels(
+        sender_id,
+        preset,
+        &body.invite,
+        power_levels_override.as_ref(),
+    )?;
+
     db.rooms.append_pdu(
         PduBuilder {
             room_id: room_id.clone(),
@@ -135,13 +179,7 @@ pub fn create_room_route(
     )?;
 
     // 4. Events set by preset
-
-    // Figure out preset. We need it for preset specific events
-    let visibility = body.visibility.unwrap_or(room::Visibility::Private);
-    let preset = body.preset.unwrap_or_else(|| match visibility {
-        room::Visibility::Private => create_room::RoomPreset::PrivateChat,
-        room::Visibility::Public => create_room::RoomPreset::PublicChat,
-    });
+    let (join_rule, history_visibility, guest_access) = preset_state(preset);
 
     // 4.1 Join Rules
     db.rooms.append_pdu(
@@ -149,17 +187,8 @@ pub fn create_room_route(
             room_id: room_id.clone(),
             sender: sender_id.clone(),
             event_type: EventType::RoomJoinRules,
-            content: match preset {
-                create_room::RoomPreset::PublicChat => serde_json::to_value(
-                    join_rules::JoinRulesEventContent::new(join_rules::JoinRule::Public),
-                )
-                .expect("event is valid, we just created it"),
-                // according to spec "invite" is the default
-                _ => serde_json::to_value(join_rules::JoinRulesEventContent::new(
-                    join_rules::JoinRule::Invite,
-                ))
+            content: serde_json::to_value(join_rules::JoinRulesEventContent::new(join_rule))
                 .expect("event is valid, we just created it"),
-            },
             unsigned: None,
             state_key: Some("".to_owned()),
             redacts: None,
@@ -175,7 +204,7 @@ pub fn create_room_route(
             sender: sender_id.clone(),
             event_type: EventType::RoomHistoryVisibility,
             content: serde_json::to_value(history_visibility::HistoryVisibilityEventContent::new(
-                history_visibility::HistoryVisibility::Shared,
+                history_visibility,
             ))
             .expect("event is valid, we just created it"),
             unsigned: None,
@@ -192,18 +221,8 @@ pub fn create_room_route(
             room_id: room_id.clone(),
             sender: sender_id.clone(),
             event_type: EventType::RoomGuestAccess,
-            content: match preset {
-                create_room::RoomPreset::PublicChat => {
-                    serde_json::to_value(guest_access::GuestAccessEventContent::new(
-                        guest_access::GuestAccess::Forbidden,
-                    ))
-                    .expect("event is valid, we just created it")
-                }
-                _ => serde_json::to_value(guest_access::GuestAccessEventContent::new(
-                    guest_access::GuestAccess::CanJoin,
-                ))
+            content: serde_json::to_value(guest_access::GuestAccessEventContent::new(guest_access))
                 .expect("event is valid, we just created it"),
-            },
             unsigned: None,
             state_key: Some("".to_owned()),
             redacts: None,
@@ -212,6 +231,12 @@ pub fn create_room_route(
         &db.account_data,
     )?;
 
+    // The alias already points to the room, so initial_state can contain it in a canonical alias
+    if let Some(alias) = &alias {
+        db.rooms.set_alias(alias, Some(&room_id), &db.globals)?;
+        db.rooms.set_alias_creator(alias, &sender_id)?;
+    }
+
     // 5. Events listed in initial_state
     for create_room::InitialStateEvent {
         event_type,
@@ -224,14 +249,24 @@ pub fn create_room_route(
             continue;
         }
 
+        let content: serde_json::Value = serde_json::from_str(content.get())
+            .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid initial_state content."))?;
+
+        // An encryption event without algorithm means the client doesn't want encryption
+        if event_type == &EventType::RoomEncryption && content.get("algorithm").is_none() {
+            continue;
+        }
+
+        if event_type == &EventType::RoomCanonicalAlias {
+            validate_canonical_alias(&db, &room_id, &content).await?;
+        }
+
         db.rooms.append_pdu(
             PduBuilder {
                 room_id: room_id.clone(),
                 sender: sender_id.clone(),
                 event_type: event_type.clone(),
-                content: serde_json::from_str(content.get()).map_err(|_| {
-                    Error::BadRequest(ErrorKind::BadJson, "Invalid initial_state content.")
-                })?,
+                content,
                 unsigned: None,
                 state_key: state_key.clone(),
                 redacts: None,
@@ -241,6 +276,29 @@ pub fn create_room_route(
         )?;
     }
 
+    // Encryption by default, unless the client configured encryption itself
+    if encrypted_by_default(
+        db.globals.encryption_default(),
+        preset,
+        body.initial_state
+            .iter()
+            .any(|event| event.event_type == EventType::RoomEncryption),
+    ) {
+        db.rooms.append_pdu(
+            PduBuilder {
+                room_id: room_id.clone(),
+                sender: sender_id.clone(),
+                event_type: EventType::RoomEncryption,
+                content: serde_json::json!({ "algorithm": "m.megolm.v1.aes-sha2" }),
+                unsigned: None,
+                state_key: Some("".to_owned()),
+                redacts: None,
+            },
+            &db.globals,
+            &db.account_data,
+        )?;
+    }
+
     // 6. Events implied by name and topic
     if let Some(name) = &body.name {
         db.rooms.append_pdu(
@@ -282,35 +340,68 @@ pub fn create_room_route(
         )?;
     }
 
-    // 7. Events implied by invite (and TODO: invite_3pid)
-    for user in &body.invite {
-        db.rooms.append_pdu(
-            PduBuilder {
-                room_id: room_id.clone(),
-                sender: sender_id.clone(),
-                event_type: EventType::RoomMember,
-                content: serde_json::to_value(member::MemberEventContent {
-                    membership: member::MembershipState::Invite,
-                    displayname: db.users.displayname(&user)?,
-                    avatar_url: db.users.avatar_url(&user)?,
-                    is_direct: body.is_direct,
-                    third_party_invite: None,
-                })
-                .expect("event is valid, we just created it"),
-                unsigned: None,
-                state_key: Some(user.to_string()),
-                redacts: None,
-            },
-            &db.globals,
-            &db.account_data,
-        )?;
+    // 7. Events implied by invite and invite_3pid. Third party ids that already belong to a
+    // user invite that user
+    let mut invitees = body.invite.clone();
+    for third_party_id in &body.invite_3pid {
+        let third_party_id =
+            serde_json::to_value(third_party_id).expect("third party id is valid json");
+        let medium = third_party_id
+            .get("medium")
+            .and_then(|medium| medium.as_str())
+            .unwrap_or_default();
+        let address = third_party_id
+            .get("address")
+            .and_then(|address| address.as_str())
+            .unwrap_or_default();
+
+        if let Some(user_id) =
+            invite_third_party_id(&db, &sender_id, &room_id, medium, address).await?
+        {
+            if !invitees.contains(&user_id) {
+                invitees.push(user_id);
+            }
+        }
     }
 
-    // Homeserver specific stuff
-    if let Some(alias) = alias {
-        db.rooms.set_alias(&alias, Some(&room_id), &db.globals)?;
+    for user in &invitees {
+        let pdu_builder = PduBuilder {
+            room_id: room_id.clone(),
+            sender: sender_id.clone(),
+            event_type: EventType::RoomMember,
+            content: serde_json::to_value(member::MemberEventContent {
+                membership: member::MembershipState::Invite,
+                displayname: db.users.displayname(&user)?,
+                avatar_url: db.users.avatar_url(&user)?,
+                is_direct: body.is_direct,
+                third_party_invite: None,
+            })
+            .expect("event is valid, we just created it"),
+            unsigned: None,
+            state_key: Some(user.to_string()),
+            redacts: None,
+        };
+
+        if user.server_name() == db.globals.server_name() {
+            db.rooms
+                .append_pdu(pdu_builder, &db.globals, &db.account_data)?;
+        } else if let Err(e) = invite_remote_user(&db, &user, pdu_builder).await {
+            // The room exists already, the user can be invited again later
+            warn!(
+                "Failed to invite {} to the new room {}: {}",
+                user, room_id, e
+            );
+            continue;
+        }
+
+        // The invited users add the room to their direct chats when they accept the invite
+        if body.is_direct == Some(true) {
+            db.account_data
+                .add_direct_room(&sender_id, &user, &room_id, &db.globals)?;
+        }
     }
 
+    // Homeserver specific stuff
     if let Some(room::Visibility::Public) = body.visibility {
         db.rooms.set_public(&room_id, true)?;
     }
@@ -349,7 +440,7 @@ pub fn get_room_event_route(
     feature = "conduit_bin",
     post("/_matrix/client/r0/rooms/<_room_id>/upgrade", data = "<body>")
 )]
-pub fn upgrade_room_route(
+pub async fn upgrade_room_route(
     db: State<'_, Database<'_>>,
     body: Ruma<upgrade_room::Request>,
     _room_id: String,
@@ -357,19 +448,31 @@ pub fn upgrade_room_route(
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
     // Validate the room version requested
-    let new_version =
-        RoomVersionId::try_from(body.new_version.clone()).expect("invalid room version id");
+    let new_version = RoomVersionId::try_from(body.new_version.clone()).map_err(|_| {
+        Error::BadRequest(
+            ErrorKind::UnsupportedRoomVersion,
+            "This server does not support that room version.",
+        )
+    })?;
 
-    if !matches!(
-        new_version,
-        RoomVersionId::Version5 | RoomVersionId::Version6
-    ) {
+    if !supported_room_versions().contains(&new_version) {
         return Err(Error::BadRequest(
             ErrorKind::UnsupportedRoomVersion,
             "This server does not support that room version.",
         ));
     }
 
+    if !db.rooms.is_joined(&sender_id, &body.room_id)?
+        || !db
+            .rooms
+            .can_send_state_event(&body.room_id, &sender_id, &EventType::RoomTombstone)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to upgrade the room.",
+        ));
+    }
+
     // Create a replacement room
     let replacement_room = RoomId::new(db.globals.server_name());
 
@@ -455,7 +558,8 @@ pub fn upgrade_room_route(
         &db.account_data,
     )?;
 
-    // Recommended transferable state events list from the specs
+    // Recommended transferable state events list from the specs, the power levels are copied
+    // last, so the upgrader can send all other events
     let transferable_state_events = vec![
         EventType::RoomServerAcl,
         EventType::RoomEncryption,
@@ -465,7 +569,6 @@ pub fn upgrade_room_route(
         EventType::RoomGuestAccess,
         EventType::RoomHistoryVisibility,
         EventType::RoomJoinRules,
-        EventType::RoomPowerLevels,
     ];
 
     // Replicate transferable state events to the new room
@@ -496,6 +599,111 @@ pub fn upgrade_room_route(
             .set_alias(&alias, Some(&replacement_room), &db.globals)?;
     }
 
+    // Moves the canonical alias to the new room, the old room keeps an empty one
+    if let Some(canonical_alias) =
+        db.rooms
+            .room_state_get(&body.room_id, &EventType::RoomCanonicalAlias, "")?
+    {
+        db.rooms.append_pdu(
+            PduBuilder {
+                room_id: replacement_room.clone(),
+                sender: sender_id.clone(),
+                event_type: EventType::RoomCanonicalAlias,
+                content: canonical_alias.content,
+                unsigned: None,
+                state_key: Some("".to_owned()),
+                redacts: None,
+            },
+            &db.globals,
+            &db.account_data,
+        )?;
+
+        db.rooms
+            .append_pdu(
+                PduBuilder {
+                    room_id: body.room_id.clone(),
+                    sender: sender_id.clone(),
+                    event_type: EventType::RoomCanonicalAlias,
+                    content: serde_json::json!({}),
+                    unsigned: None,
+                    state_key: Some("".to_owned()),
+                    redacts: None,
+                },
+                &db.globals,
+                &db.account_data,
+            )
+            .ok();
+    }
+
+    // Members of the old room can always join the new room, even if it is not public
+    let join_rule = db
+        .rooms
+        .room_state_get(&body.room_id, &EventType::RoomJoinRules, "")?
+        .and_then(|pdu| pdu.content.get("join_rule")?.as_str().map(str::to_owned));
+
+    if join_rule.as_deref() != Some("public") {
+        let members = db
+            .rooms
+            .room_members(&body.room_id)
+            .filter_map(|r| r.ok())
+            .filter(|user_id| user_id != sender_id)
+            .collect::<Vec<_>>();
+
+        for user_id in members {
+            let pdu_builder = PduBuilder {
+                room_id: replacement_room.clone(),
+                sender: sender_id.clone(),
+                event_type: EventType::RoomMember,
+                content: serde_json::to_value(member::MemberEventContent {
+                    membership: member::MembershipState::Invite,
+                    displayname: db.users.displayname(&user_id)?,
+                    avatar_url: db.users.avatar_url(&user_id)?,
+                    is_direct: None,
+                    third_party_invite: None,
+                })
+                .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(user_id.to_string()),
+                redacts: None,
+            };
+
+            let result = if user_id.server_name() == db.globals.server_name() {
+                db.rooms
+                    .append_pdu(pdu_builder, &db.globals, &db.account_data)
+                    .map(|_| ())
+            } else {
+                invite_remote_user(&db, &user_id, pdu_builder).await
+            };
+
+            if let Err(e) = result {
+                warn!(
+                    "Failed to invite {} to the upgraded room {}: {}",
+                    user_id, replacement_room, e
+                );
+            }
+        }
+    }
+
+    // Copy the power levels, which keeps the levels of all members
+    if let Some(power_levels) =
+        db.rooms
+            .room_state_get(&body.room_id, &EventType::RoomPowerLevels, "")?
+    {
+        db.rooms.append_pdu(
+            PduBuilder {
+                room_id: replacement_room.clone(),
+                sender: sender_id.clone(),
+                event_type: EventType::RoomPowerLevels,
+                content: power_levels.content,
+                unsigned: None,
+                state_key: Some("".to_owned()),
+                redacts: None,
+            },
+            &db.globals,
+            &db.account_data,
+        )?;
+    }
+
     // Get the old room power levels
     let mut power_levels_event_content =
         serde_json::from_value::<Raw<ruma::events::room::power_levels::PowerLevelsEventContent>>(
@@ -537,3 +745,259 @@ pub fn upgrade_room_route(
     // Return the replacement room id
     Ok(upgrade_room::Response { replacement_room }.into())
 }
+
+/// The join rule, history visibility and guest access of rooms created with the preset.
+fn preset_state(
+    preset: create_room::RoomPreset,
+) -> (
+    join_rules::JoinRule,
+    history_visibility::HistoryVisibility,
+    guest_access::GuestAccess,
+) {
+    match preset {
+        create_room::RoomPreset::PublicChat => (
+            join_rules::JoinRule::Public,
+            history_visibility::HistoryVisibility::Shared,
+            guest_access::GuestAccess::Forbidden,
+        ),
+        // according to spec "invite" is the default
+        _ => (
+            join_rules::JoinRule::Invite,
+            history_visibility::HistoryVisibility::Shared,
+            guest_access::GuestAccess::CanJoin,
+        ),
+    }
+}
+
+/// Checks if a new room gets an encryption event because of
+/// `encryption_enabled_by_default_for_room_type`. An encryption event the client sent in
+/// `initial_state` always wins, also one that disables encryption.
+fn encrypted_by_default(
+    encryption_default: EncryptionDefault,
+    preset: create_room::RoomPreset,
+    configured_by_client: bool,
+) -> bool {
+    if configured_by_client {
+        return false;
+    }
+
+    match encryption_default {
+        EncryptionDefault::Off => false,
+        EncryptionDefault::Invite => match preset {
+            create_room::RoomPreset::PrivateChat | create_room::RoomPreset::TrustedPrivateChat => {
+                true
+            }
+            _ => false,
+        },
+        EncryptionDefault::All => true,
+    }
+}
+
+/// The power levels of a new room. The creator has level 100, the invited users of
+/// `trusted_private_chat` rooms too. The override only replaces the levels it contains, the users
+/// and events are merged.
+fn initial_power_levels(
+    sender_id: &UserId,
+    preset: create_room::RoomPreset,
+    invites: &[UserId],
+    power_levels_override: Option<&serde_json::Value>,
+) -> Result<serde_json::Value> {
+    let mut users = BTreeMap::new();
+    users.insert(sender_id.clone(), 100.into());
+    if let create_room::RoomPreset::TrustedPrivateChat = preset {
+        for invite_ in invites {
+            users.insert(invite_.clone(), 100.into());
+        }
+    }
+
+    let mut power_levels_content =
+        serde_json::to_value(ruma::events::room::power_levels::PowerLevelsEventContent {
+            ban: 50.into(),
+            events: BTreeMap::new(),
+            events_default: 0.into(),
+            invite: 50.into(),
+            kick: 50.into(),
+            redact: 50.into(),
+            state_default: 50.into(),
+            users,
+            users_default: 0.into(),
+            notifications: ruma::events::room::power_levels::NotificationPowerLevels {
+                room: 50.into(),
+            },
+        })
+        .expect("event is valid, we just created it");
+
+    if let Some(power_levels_override) = power_levels_override {
+        merge_json(&mut power_levels_content, power_levels_override);
+    }
+
+    if power_levels_content["users"]
+        .get(sender_id.as_str())
+        .and_then(|level| level.as_i64())
+        .map_or(true, |level| level < 100)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "The room creator must have power level 100.",
+        ));
+    }
+
+    Ok(power_levels_content)
+}
+
+/// Merges `other` into `base`: Objects are merged recursively, all other values of `other`
+/// replace the ones in `base`.
+fn merge_json(base: &mut serde_json::Value, other: &serde_json::Value) {
+    match (base.as_object_mut(), other.as_object()) {
+        (Some(base), Some(other)) => {
+            for (key, value) in other {
+                merge_json(
+                    base.entry(key.clone()).or_insert(serde_json::Value::Null),
+                    value,
+                );
+            }
+        }
+        _ => *base = other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encrypted_by_default, initial_power_levels, preset_state};
+    use crate::database::globals::EncryptionDefault;
+    use ruma::{
+        api::client::r0::room::create_room::RoomPreset,
+        events::room::{
+            guest_access::GuestAccess, history_visibility::HistoryVisibility, join_rules::JoinRule,
+        },
+        UserId,
+    };
+    use serde_json::json;
+    use std::convert::TryFrom;
+
+    fn alice() -> UserId {
+        UserId::try_from("@alice:example.com").unwrap()
+    }
+
+    fn bob() -> UserId {
+        UserId::try_from("@bob:example.com").unwrap()
+    }
+
+    #[test]
+    fn presets_set_join_rule_history_visibility_and_guest_access() {
+        assert_eq!(
+            preset_state(RoomPreset::PrivateChat),
+            (
+                JoinRule::Invite,
+                HistoryVisibility::Shared,
+                GuestAccess::CanJoin
+            )
+        );
+        assert_eq!(
+            preset_state(RoomPreset::TrustedPrivateChat),
+            (
+                JoinRule::Invite,
+                HistoryVisibility::Shared,
+                GuestAccess::CanJoin
+            )
+        );
+        assert_eq!(
+            preset_state(RoomPreset::PublicChat),
+            (
+                JoinRule::Public,
+                HistoryVisibility::Shared,
+                GuestAccess::Forbidden
+            )
+        );
+    }
+
+    #[test]
+    fn only_trusted_private_chats_give_invited_users_level_100() {
+        let invites = [bob()];
+
+        let power_levels =
+            initial_power_levels(&alice(), RoomPreset::TrustedPrivateChat, &invites, None).unwrap();
+        assert_eq!(
+            power_levels["users"],
+            json!({ "@alice:example.com": 100, "@bob:example.com": 100 })
+        );
+
+        for preset in &[RoomPreset::PrivateChat, RoomPreset::PublicChat] {
+            let power_levels = initial_power_levels(&alice(), *preset, &invites, None).unwrap();
+            assert_eq!(power_levels["users"], json!({ "@alice:example.com": 100 }));
+        }
+    }
+
+    #[test]
+    fn power_level_overrides_are_merged_into_the_preset() {
+        let power_levels_override = json!({
+            "ban": 75,
+            "users": { "@bob:example.com": 50 },
+            "events": { "m.room.name": 100 },
+        });
+
+        let power_levels = initial_power_levels(
+            &alice(),
+            RoomPreset::PrivateChat,
+            &[],
+            Some(&power_levels_override),
+        )
+        .unwrap();
+        assert_eq!(power_levels["ban"], 75);
+        assert_eq!(power_levels["kick"], 50);
+        assert_eq!(
+            power_levels["users"],
+            json!({ "@alice:example.com": 100, "@bob:example.com": 50 })
+        );
+        assert_eq!(power_levels["events"], json!({ "m.room.name": 100 }));
+        assert_eq!(power_levels["notifications"], json!({ "room": 50 }));
+    }
+
+    #[test]
+    fn creators_keep_level_100() {
+        let power_levels_override = json!({ "users": { "@alice:example.com": 50 } });
+
+        assert!(initial_power_levels(
+            &alice(),
+            RoomPreset::PrivateChat,
+            &[],
+            Some(&power_levels_override),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn direct_chats_are_encrypted_by_default_with_invite() {
+        // Clients create direct chats with the trusted_private_chat preset
+        for preset in &[RoomPreset::TrustedPrivateChat, RoomPreset::PrivateChat] {
+            assert!(encrypted_by_default(
+                EncryptionDefault::Invite,
+                *preset,
+                false
+            ));
+        }
+        assert!(!encrypted_by_default(
+            EncryptionDefault::Invite,
+            RoomPreset::PublicChat,
+            false
+        ));
+    }
+
+    #[test]
+    fn encryption_by_default_follows_the_setting_and_the_client() {
+        for preset in &[
+            RoomPreset::PrivateChat,
+            RoomPreset::TrustedPrivateChat,
+            RoomPreset::PublicChat,
+        ] {
+            assert!(encrypted_by_default(EncryptionDefault::All, *preset, false));
+            assert!(!encrypted_by_default(
+                EncryptionDefault::Off,
+                *preset,
+                false
+            ));
+            // The client's own m.room.encryption in initial_state wins
+            assert!(!encrypted_by_default(EncryptionDefault::All, *preset, true));
+        }
+    }
+}
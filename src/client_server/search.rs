@@ -1,13 +1,38 @@
 use super::State;
-use crate::{ConduitResult, Database, Error, Ruma};
-use js_int::uint;
-use ruma::api::client::{error::ErrorKind, r0::search::search_events};
+use crate::{database::rooms, utils, Database, Error, PduEvent, Result, Ruma};
+use rocket::response::content::Json;
+use ruma::{
+    api::client::{error::ErrorKind, r0::search::search_events},
+    events::EventType,
+    RoomId, UserId,
+};
+use serde_json::{json, Value};
 
 #[cfg(feature = "conduit_bin")]
 use rocket::post;
-use search_events::{ResultCategories, ResultRoomEvents, SearchResult};
-use std::collections::BTreeMap;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashSet},
+    convert::TryFrom,
+};
 
+/// These fields are searched if the request doesn't specify `keys`
+const DEFAULT_SEARCH_KEYS: [&str; 3] = ["content.body", "content.name", "content.topic"];
+
+struct SearchHit {
+    room_id: RoomId,
+    count: u64,
+    rank: f64,
+    pdu: PduEvent,
+}
+
+/// # `POST /_matrix/client/r0/search`
+///
+/// Searches the messages, names and topics of the rooms the user is joined to.
+///
+/// - Only the `room_events` category is supported
+/// - Events from before the user joined are only found if the history visibility allows it
+/// - Results are ordered by `rank` (how often the search words occur) or by `recent`
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/search", data = "<body>")
@@ -15,22 +40,51 @@ use std::collections::BTreeMap;
 pub fn search_events_route(
     db: State<'_, Database<'_>>,
     body: Ruma<search_events::Request>,
-) -> ConduitResult<search_events::Response> {
+) -> Result<Json<String>> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    let search_criteria = body.search_categories.room_events.as_ref().unwrap();
-    let filter = search_criteria.filter.as_ref().unwrap();
+    let request = serde_json::from_str::<Value>(
+        body.json_body
+            .as_ref()
+            .ok_or(Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?
+            .get(),
+    )
+    .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?;
+
+    let criteria = match request
+        .get("search_categories")
+        .and_then(|categories| categories.get("room_events"))
+    {
+        Some(criteria) => criteria,
+        None => return Ok(Json(json!({ "search_categories": {} }).to_string())),
+    };
 
-    let room_id = filter.rooms.as_ref().unwrap().first().unwrap();
+    let search_term = criteria
+        .get("search_term")
+        .and_then(|term| term.as_str())
+        .ok_or(Error::BadRequest(
+            ErrorKind::MissingParam,
+            "Missing search_term.",
+        ))?;
 
-    let limit = filter.limit.map_or(10, |l| u64::from(l) as usize);
+    let keys = match criteria.get("keys").and_then(|keys| keys.as_array()) {
+        Some(keys) => keys
+            .iter()
+            .filter_map(|key| key.as_str())
+            .filter(|key| DEFAULT_SEARCH_KEYS.contains(key))
+            .map(|key| key.trim_start_matches("content."))
+            .collect::<Vec<_>>(),
+        None => DEFAULT_SEARCH_KEYS
+            .iter()
+            .map(|key| key.trim_start_matches("content."))
+            .collect(),
+    };
 
-    if !db.rooms.is_joined(sender_id, &room_id)? {
-        return Err(Error::BadRequest(
-            ErrorKind::Forbidden,
-            "You don't have permission to view this room.",
-        ));
-    }
+    let filter = criteria.get("filter").cloned().unwrap_or_else(|| json!({}));
+    let limit = filter
+        .get("limit")
+        .and_then(|limit| limit.as_u64())
+        .map_or(10, |limit| limit as usize);
 
     let skip = match body.next_batch.as_ref().map(|s| s.parse()) {
         Some(Ok(s)) => s,
@@ -43,44 +97,232 @@ pub fn search_events_route(
         None => 0, // Default to the start
     };
 
-    let search = db
-        .rooms
-        .search_pdus(&room_id, &search_criteria.search_term)?;
-
-    let results = search
-        .0
-        .map(|result| {
-            Ok::<_, Error>(SearchResult {
-                context: None,
-                rank: None,
-                result: db
-                    .rooms
-                    .get_pdu_from_id(&result)?
-                    .map(|pdu| pdu.to_room_event()),
-            })
-        })
-        .filter_map(|r| r.ok())
+    let room_ids = match filter.get("rooms").and_then(|rooms| rooms.as_array()) {
+        Some(rooms) => rooms
+            .iter()
+            .filter_map(|room_id| RoomId::try_from(room_id.as_str()?).ok())
+            .collect::<Vec<_>>(),
+        None => db
+            .rooms
+            .rooms_joined(&sender_id)
+            .filter_map(|r| r.ok())
+            .collect(),
+    };
+
+    let mut hits = Vec::new();
+    let mut highlights = Vec::new();
+
+    for room_id in room_ids {
+        // Users can only search rooms they are in
+        if !db.rooms.is_joined(sender_id, &room_id)?
+            || !filter_allows(&filter, "rooms", room_id.as_str())
+        {
+            continue;
+        }
+
+        let visible_since = db.rooms.visible_since(&room_id, &sender_id)?;
+
+        let (pdu_ids, words) = db.rooms.search_pdus(&room_id, search_term)?;
+        highlights = words;
+
+        for pdu_id in pdu_ids {
+            let count = utils::u64_from_bytes(&pdu_id[pdu_id.len() - 8..])
+                .map_err(|_| Error::bad_database("Invalid pdu id in search index."))?;
+            if count < visible_since {
+                // The search results are ordered from newest to oldest
+                break;
+            }
+
+            let mut pdu = match db.rooms.get_pdu_from_id(&pdu_id)? {
+                Some(pdu) => pdu,
+                None => continue,
+            };
+
+            if !filter_allows(&filter, "senders", pdu.sender.as_str())
+                || !filter_allows(&filter, "types", &pdu.kind.to_string())
+            {
+                continue;
+            }
+
+            // Every word has to be in one of the requested fields
+            let tokens = keys
+                .iter()
+                .filter_map(|key| pdu.content.get(key)?.as_str())
+                .flat_map(rooms::tokenize)
+                .collect::<Vec<_>>();
+            if !highlights.iter().all(|word| tokens.contains(word)) {
+                continue;
+            }
+
+            let rank = tokens
+                .iter()
+                .filter(|token| highlights.contains(*token))
+                .count() as f64
+                / tokens.len() as f64;
+
+            if pdu.sender != *sender_id {
+                pdu.unsigned.remove("transaction_id");
+            }
+
+            hits.push(SearchHit {
+                room_id: room_id.clone(),
+                count,
+                rank,
+                pdu,
+            });
+        }
+    }
+
+    if criteria.get("order_by").and_then(|o| o.as_str()) == Some("recent") {
+        hits.sort_by(|a, b| b.count.cmp(&a.count));
+    } else {
+        hits.sort_by(|a, b| {
+            b.rank
+                .partial_cmp(&a.rank)
+                .unwrap_or(Ordering::Equal)
+                .then(b.count.cmp(&a.count))
+        });
+    }
+
+    let total = hits.len();
+    let event_context = criteria.get("event_context");
+
+    let results = hits
+        .into_iter()
         .skip(skip)
         .take(limit)
-        .collect::<Vec<_>>();
+        .map(|hit| {
+            let mut result = json!({
+                "rank": hit.rank,
+                "result": hit.pdu.to_room_event(),
+            });
 
-    let next_batch = if results.len() < limit as usize {
-        None
-    } else {
+            if let Some(event_context) = event_context {
+                result["context"] = search_context(&db, &sender_id, &hit, event_context)?;
+            }
+
+            Ok(result)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let next_batch = if skip + limit < total {
         Some((skip + limit).to_string())
+    } else {
+        None
+    };
+
+    Ok(Json(
+        json!({
+            "search_categories": {
+                "room_events": {
+                    "count": total,
+                    "highlights": highlights,
+                    "next_batch": next_batch,
+                    "results": results,
+                }
+            }
+        })
+        .to_string(),
+    ))
+}
+
+/// Checks the `rooms`, `senders` or `types` filter and its `not_` counterpart. Types can end with
+/// a `*` wildcard.
+fn filter_allows(filter: &Value, field: &str, value: &str) -> bool {
+    let matches = |patterns: &Value| {
+        patterns.as_array().map_or(false, |patterns| {
+            patterns.iter().filter_map(|p| p.as_str()).any(|pattern| {
+                if pattern.ends_with('*') {
+                    value.starts_with(pattern.trim_end_matches('*'))
+                } else {
+                    value == pattern
+                }
+            })
+        })
     };
 
-    Ok(search_events::Response {
-        search_categories: ResultCategories {
-            room_events: Some(ResultRoomEvents {
-                count: uint!(0),         // TODO
-                groups: BTreeMap::new(), // TODO
-                next_batch,
-                results,
-                state: BTreeMap::new(), // TODO
-                highlights: search.1,
-            }),
-        },
+    if filter
+        .get(format!("not_{}", field))
+        .map_or(false, |patterns| matches(patterns))
+    {
+        return false;
     }
-    .into())
+
+    filter.get(field).map_or(true, |patterns| matches(patterns))
+}
+
+/// Returns the events around the search result and optionally the profiles of their senders.
+fn search_context(
+    db: &Database<'_>,
+    sender_id: &UserId,
+    hit: &SearchHit,
+    event_context: &Value,
+) -> Result<Value> {
+    let before_limit = event_context
+        .get("before_limit")
+        .and_then(|l| l.as_u64())
+        .unwrap_or(5) as usize;
+    let after_limit = event_context
+        .get("after_limit")
+        .and_then(|l| l.as_u64())
+        .unwrap_or(5) as usize;
+
+    let events_before = db
+        .rooms
+        .pdus_until(sender_id, &hit.room_id, hit.count)
+        .take(before_limit)
+        .filter_map(|r| r.ok()) // Remove buggy events
+        .collect::<Vec<_>>();
+    let events_after = db
+        .rooms
+        .pdus_after(sender_id, &hit.room_id, hit.count)
+        .take(after_limit)
+        .filter_map(|r| r.ok()) // Remove buggy events
+        .collect::<Vec<_>>();
+
+    let mut context = json!({
+        "start": events_before.last().map(|(count, _)| count.to_string()),
+        "end": events_after.last().map(|(count, _)| (count + 1).to_string()),
+        "events_before": events_before
+            .iter()
+            .map(|(_, pdu)| pdu.to_room_event())
+            .collect::<Vec<_>>(),
+        "events_after": events_after
+            .iter()
+            .map(|(_, pdu)| pdu.to_room_event())
+            .collect::<Vec<_>>(),
+    });
+
+    if event_context
+        .get("include_profile")
+        .and_then(|i| i.as_bool())
+        == Some(true)
+    {
+        let senders = events_before
+            .iter()
+            .chain(events_after.iter())
+            .map(|(_, pdu)| &pdu.sender)
+            .chain(Some(&hit.pdu.sender))
+            .collect::<HashSet<_>>();
+
+        let mut profile_info = BTreeMap::new();
+        for sender in senders {
+            if let Some(member) =
+                db.rooms
+                    .room_state_get(&hit.room_id, &EventType::RoomMember, sender.as_str())?
+            {
+                profile_info.insert(
+                    sender.to_string(),
+                    json!({
+                        "displayname": member.content.get("displayname"),
+                        "avatar_url": member.content.get("avatar_url"),
+                    }),
+                );
+            }
+        }
+
+        context["profile_info"] = json!(profile_info);
+    }
+
+    Ok(context)
 }
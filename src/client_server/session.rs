@@ -1,15 +1,32 @@
 use super::State;
-use super::{DEVICE_ID_LENGTH, TOKEN_LENGTH};
-use crate::{utils, ConduitResult, Database, Error, Ruma};
+use super::{
+    DEVICE_ID_LENGTH, LOGIN_TOKEN_LENGTH, LOGIN_TOKEN_LIFETIME, REFRESHABLE_TOKEN_LIFETIME,
+    SESSION_ID_LENGTH, TOKEN_LENGTH,
+};
+use crate::{
+    database::rate_limiter::RateLimitClass, utils, ConduitResult, Database, Error, Result, Ruma,
+};
+use rocket::{response::content::Json, tokio::io::AsyncReadExt, Data};
 use ruma::{
     api::client::{
         error::ErrorKind,
-        r0::session::{get_login_types, login, logout, logout_all},
+        r0::{
+            account::whoami,
+            session::{login, logout, logout_all},
+            uiaa::{AuthData, AuthFlow, UiaaInfo},
+        },
     },
     events::EventType,
-    UserId,
+    DeviceId, UserId,
 };
 use serde::Deserialize;
+use serde_json::{json, value::RawValue};
+use tracing::warn;
+
+#[derive(Deserialize)]
+struct RefreshRequest {
+    refresh_token: String,
+}
 
 #[derive(Debug, Deserialize)]
 struct Claims {
@@ -24,12 +41,53 @@ use rocket::{get, post};
 ///
 /// Get the homeserver's supported login types. One of these should be used as the `type` field
 /// when logging in.
+///
+/// - `m.login.password` is left out if `password_login_disabled` is set
+/// - `m.login.token` accepts login tokens of single sign-on or `/login/get_token` and, if
+/// configured, JWTs
+/// - `m.login.sso` is only supported if a CAS server or an OpenID Connect provider is configured
 #[cfg_attr(feature = "conduit_bin", get("/_matrix/client/r0/login"))]
-pub fn get_login_types_route() -> ConduitResult<get_login_types::Response> {
-    Ok(get_login_types::Response {
-        flows: vec![get_login_types::LoginType::Password],
+pub fn get_login_types_route(db: State<'_, Database<'_>>) -> Result<Json<String>> {
+    let flows = login_flows(
+        !db.globals.password_login_disabled(),
+        db.globals.cas_server_url().is_some(),
+        db.globals
+            .oidc_provider()
+            .map(|provider| provider.issuer.as_str()),
+    );
+
+    Ok(Json(json!({ "flows": flows }).to_string()))
+}
+
+/// Returns the login flows of `GET /login` for the configured login methods.
+fn login_flows(
+    password_login: bool,
+    cas_configured: bool,
+    oidc_issuer: Option<&str>,
+) -> Vec<serde_json::Value> {
+    let mut flows = Vec::new();
+
+    if password_login {
+        flows.push(json!({ "type": "m.login.password" }));
+    }
+
+    let mut identity_providers = Vec::new();
+    if cas_configured {
+        identity_providers.push(json!({ "id": "cas", "name": "CAS" }));
+    }
+    if let Some(issuer) = oidc_issuer {
+        identity_providers.push(json!({ "id": "oidc", "name": issuer }));
     }
-    .into())
+
+    flows.push(json!({ "type": "m.login.token" }));
+    if !identity_providers.is_empty() {
+        flows.push(json!({
+            "type": "m.login.sso",
+            "identity_providers": identity_providers,
+        }));
+    }
+
+    flows
 }
 
 /// # `POST /_matrix/client/r0/login`
@@ -39,6 +97,10 @@ pub fn get_login_types_route() -> ConduitResult<get_login_types::Response> {
 /// - The returned access token is associated with the user and device
 /// - Old access tokens of that device should be invalidated
 /// - If `device_id` is unknown, a new device will be created
+/// - With `refresh_token: true`, the access token expires and the client gets a refresh token
+/// - Without a refresh token, the access token only expires if `access_token_lifetime` is set
+/// - After `login_fail_limit` wrong passwords for a user from the same ip address, password logins
+/// are locked out for `login_fail_window` seconds, and twice as long after every further failure
 ///
 /// Note: You can use [`GET /_matrix/client/r0/login`](fn.get_supported_versions_route.html) to see
 /// supported login types.
@@ -49,10 +111,21 @@ pub fn get_login_types_route() -> ConduitResult<get_login_types::Response> {
 pub fn login_route(
     db: State<'_, Database<'_>>,
     body: Ruma<login::Request>,
-) -> ConduitResult<login::Response> {
+) -> Result<Json<String>> {
+    db.globals
+        .rate_limiter()
+        .check_ip(RateLimitClass::Login, body.client_ip)?;
+
     // Validate login method
     let user_id = match &body.login_info {
         login::LoginInfo::Password { password } => {
+            if db.globals.password_login_disabled() {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "Password login is disabled on this server.",
+                ));
+            }
+
             let username = if let login::UserInfo::MatrixId(matrix_id) = body.user.clone() {
                 matrix_id
             } else {
@@ -62,57 +135,101 @@ pub fn login_route(
                 .map_err(|_| {
                     Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid.")
                 })?;
-            let hash = db.users.password_hash(&user_id)?.ok_or(Error::BadRequest(
-                ErrorKind::Forbidden,
-                "Wrong username or password.",
-            ))?;
+            db.globals
+                .login_throttle()
+                .check(user_id.localpart(), body.client_ip)?;
 
-            if hash.is_empty() {
-                return Err(Error::BadRequest(
-                    ErrorKind::UserDeactivated,
-                    "The user has been deactivated",
-                ));
-            }
+            // Unknown users are checked against a dummy hash, so the response takes as long as
+            // for existing users and doesn't tell which users exist
+            let hash = db.users.password_hash(&user_id)?;
+            let hash_matches = db.users.verify_password(
+                hash.as_deref()
+                    .filter(|hash| !hash.is_empty())
+                    .unwrap_or_else(|| db.globals.dummy_password_hash()),
+                password,
+            );
 
-            let hash_matches = argon2::verify_encoded(&hash, password.as_bytes()).unwrap_or(false);
+            let hash = match hash {
+                Some(hash) if hash_matches => hash,
+                Some(hash) if hash.is_empty() => {
+                    return Err(Error::BadRequest(
+                        ErrorKind::UserDeactivated,
+                        "The user has been deactivated",
+                    ));
+                }
+                _ => {
+                    db.globals
+                        .login_throttle()
+                        .failed(user_id.localpart(), body.client_ip);
+                    return Err(Error::BadRequest(
+                        ErrorKind::Forbidden,
+                        "Wrong username or password.",
+                    ));
+                }
+            };
+            db.globals
+                .login_throttle()
+                .succeeded(user_id.localpart(), body.client_ip);
 
-            if !hash_matches {
-                return Err(Error::BadRequest(
-                    ErrorKind::Forbidden,
-                    "Wrong username or password.",
-                ));
+            // Only now the password is known, so weaker hashes can be replaced
+            if db.users.password_hash_is_outdated(&hash) {
+                db.users.set_password(&user_id, password)?;
             }
 
             user_id
         }
         login::LoginInfo::Token { token } => {
-            let token = jsonwebtoken::decode::<Claims>(
-                &token,
-                &db.globals.jwt_decoding_key(),
-                &jsonwebtoken::Validation::default(),
-            )
-            .map_err(|_| Error::BadRequest(ErrorKind::InvalidUsername, "Token is invalid."))?;
-            let username = token.claims.sub;
-            let user_id = UserId::parse_with_server_name(username, db.globals.server_name()).map_err(|_| {
-                Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid.")
-            })?;
-
-            if !db.users.exists(&user_id)? {
-                db.account_data.update(
-                    None,
-                    &user_id,
-                    EventType::PushRules,
-                    &ruma::events::push_rules::PushRulesEvent {
-                        content: ruma::events::push_rules::PushRulesEventContent {
-                            global: crate::push_rules::default_pushrules(&user_id),
-                        },
-                    },
-                    &db.globals,
-                )?;
-                db.users.create(&user_id, &utils::random_string(40))?;
-            }
+            // Login tokens of single sign-on or /login/get_token are random strings, other tokens are JWTs
+            if let Some(user_id) = db.users.take_login_token(&token)? {
+                if db.users.is_deactivated(&user_id)? {
+                    return Err(Error::BadRequest(
+                        ErrorKind::UserDeactivated,
+                        "The user has been deactivated",
+                    ));
+                }
 
-            user_id
+                user_id
+            } else {
+                // Expired or used login tokens are not JWTs either
+                let header = jsonwebtoken::decode_header(&token)
+                    .ok()
+                    .filter(|_| db.globals.jwt_configured())
+                    .ok_or(Error::BadRequest(
+                        ErrorKind::Forbidden,
+                        "Invalid or expired login token.",
+                    ))?;
+                let (decoding_key, algorithm) = db
+                    .globals
+                    .jwt_decoding_key(header.kid.as_deref())
+                    .ok_or(Error::BadRequest(
+                        ErrorKind::InvalidUsername,
+                        "Token is signed by an unknown key.",
+                    ))?;
+                let token = jsonwebtoken::decode::<Claims>(
+                    &token,
+                    &decoding_key,
+                    &jsonwebtoken::Validation::new(algorithm),
+                )
+                .map_err(|_| Error::BadRequest(ErrorKind::InvalidUsername, "Token is invalid."))?;
+                let username = token.claims.sub;
+                let user_id = UserId::parse_with_server_name(username, db.globals.server_name())
+                    .map_err(|_| {
+                        Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid.")
+                    })?;
+
+                if !db.users.exists(&user_id)? {
+                    create_external_user(&db, &user_id)?;
+                }
+
+                if db.users.is_deactivated(&user_id)? {
+                    return Err(Error::BadRequest(
+                        ErrorKind::UserDeactivated,
+                        "The user has been deactivated",
+                    ));
+                }
+
+                user_id
+            }
         }
     };
 
@@ -132,15 +249,192 @@ pub fn login_route(
         &token,
         body.initial_device_display_name.clone(),
     )?;
+    db.users
+        .update_device_last_seen(&user_id, &device_id, body.client_ip)?;
 
-    Ok(login::Response {
-        user_id,
-        access_token: token,
-        home_server: Some(db.globals.server_name().to_owned()),
-        device_id,
-        well_known: None,
+    let mut response = json!({
+        "user_id": user_id,
+        "access_token": token,
+        "home_server": db.globals.server_name().as_str(),
+        "device_id": device_id,
+    });
+
+    if wants_refresh_token(body.json_body.as_deref()) {
+        let (refresh_token, expires_in_ms) =
+            create_refresh_token(&db, &user_id, &device_id, &token)?;
+        response["refresh_token"] = json!(refresh_token);
+        response["expires_in_ms"] = json!(expires_in_ms);
+    } else if let Some(expires_in_ms) = set_access_token_expiry(&db, &token)? {
+        response["expires_in_ms"] = json!(expires_in_ms);
     }
-    .into())
+
+    Ok(Json(response.to_string()))
+}
+
+/// # `POST /_matrix/client/v1/refresh`
+///
+/// Exchanges a refresh token for a new access token and refresh token.
+///
+/// - The old access token and refresh token can't be used anymore
+/// - If a refresh token is used twice, the device is logged out, because someone else probably
+/// knows the token
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/v1/refresh", data = "<body>")
+)]
+pub async fn refresh_route(db: State<'_, Database<'_>>, body: Data) -> Result<Json<String>> {
+    let mut bytes = Vec::new();
+    body.open()
+        .take(db.globals.max_request_size().into())
+        .read_to_end(&mut bytes)
+        .await
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid request body."))?;
+    let body = serde_json::from_slice::<RefreshRequest>(&bytes)
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid request body."))?;
+
+    let (user_id, device_id, used) = db
+        .users
+        .use_refresh_token(&body.refresh_token)?
+        .ok_or(Error::UnknownToken("Unknown refresh token.", false))?;
+
+    if used {
+        warn!(
+            "Refresh token of device {} of {} was used twice, logging out the device",
+            device_id, user_id
+        );
+        db.users.remove_device(&user_id, &device_id)?;
+        db.pushers.remove_device_pushers(&user_id, &device_id)?;
+        return Err(Error::UnknownToken(
+            "Refresh token was already used.",
+            false,
+        ));
+    }
+
+    let token = utils::random_string(TOKEN_LENGTH);
+    db.users.set_token(&user_id, &device_id, &token)?;
+
+    let (refresh_token, expires_in_ms) = create_refresh_token(&db, &user_id, &device_id, &token)?;
+
+    Ok(Json(
+        json!({
+            "access_token": token,
+            "refresh_token": refresh_token,
+            "expires_in_ms": expires_in_ms,
+        })
+        .to_string(),
+    ))
+}
+
+/// Checks if the client sent `refresh_token: true` when logging in or registering. Ruma doesn't
+/// know the field yet.
+pub fn wants_refresh_token(json_body: Option<&RawValue>) -> bool {
+    json_body
+        .and_then(|json| serde_json::from_str::<serde_json::Value>(json.get()).ok())
+        .and_then(|json| json.get("refresh_token")?.as_bool())
+        .unwrap_or(false)
+}
+
+/// Lets the access token of the device expire and creates a refresh token the client can get a
+/// new access token with. Returns the refresh token and the lifetime of the access token in
+/// millis.
+pub fn create_refresh_token(
+    db: &Database<'_>,
+    user_id: &UserId,
+    device_id: &DeviceId,
+    access_token: &str,
+) -> Result<(String, u64)> {
+    let lifetime = db
+        .globals
+        .access_token_lifetime()
+        .unwrap_or(REFRESHABLE_TOKEN_LIFETIME)
+        .as_millis() as u64;
+    db.users
+        .set_token_expiry(access_token, utils::millis_since_unix_epoch() + lifetime)?;
+    let refresh_token = db
+        .users
+        .create_refresh_token(user_id, device_id, TOKEN_LENGTH)?;
+
+    Ok((refresh_token, lifetime))
+}
+
+/// Lets the access token expire after `access_token_lifetime`, if it is set. Returns the
+/// lifetime in millis. Clients without a refresh token have to log in again afterwards.
+pub fn set_access_token_expiry(db: &Database<'_>, access_token: &str) -> Result<Option<u64>> {
+    let lifetime = match db.globals.access_token_lifetime() {
+        Some(lifetime) => lifetime.as_millis() as u64,
+        None => return Ok(None),
+    };
+    db.users
+        .set_token_expiry(access_token, utils::millis_since_unix_epoch() + lifetime)?;
+
+    Ok(Some(lifetime))
+}
+
+/// # `POST /_matrix/client/v1/login/get_token`
+///
+/// Creates a token another device of the user can log in with using `m.login.token`, e.g. after
+/// scanning a QR code.
+///
+/// - Requires the password of the user
+/// - The token can only be used once and expires after two minutes
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/v1/login/get_token", data = "<body>")
+)]
+pub fn get_login_token_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+) -> Result<Json<String>> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+    let device_id = body.device_id.as_ref().expect("user is authenticated");
+
+    let auth = body
+        .json_body
+        .as_ref()
+        .and_then(|json| serde_json::from_str::<serde_json::Value>(json.get()).ok())
+        .and_then(|json| serde_json::from_value::<AuthData>(json.get("auth")?.clone()).ok());
+
+    let mut uiaainfo = UiaaInfo {
+        flows: vec![AuthFlow {
+            stages: vec!["m.login.password".to_owned()],
+        }],
+        completed: Vec::new(),
+        params: Default::default(),
+        session: None,
+        auth_error: None,
+    };
+
+    if let Some(auth) = &auth {
+        let (worked, uiaainfo) = db.uiaa.try_auth(
+            &sender_id,
+            device_id,
+            auth,
+            &uiaainfo,
+            &db.users,
+            &db.globals,
+        )?;
+        if !worked {
+            return Err(Error::Uiaa(uiaainfo));
+        }
+    // Success!
+    } else {
+        uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
+        db.uiaa.create(&sender_id, &device_id, &uiaainfo)?;
+        return Err(Error::Uiaa(uiaainfo));
+    }
+
+    let expires_at = utils::millis_since_unix_epoch() + LOGIN_TOKEN_LIFETIME.as_millis() as u64;
+    let login_token = db
+        .users
+        .create_login_token(sender_id, LOGIN_TOKEN_LENGTH, expires_at)?;
+
+    Ok(Json(
+        json!({
+            "login_token": login_token,
+            "expires_in_ms": LOGIN_TOKEN_LIFETIME.as_millis() as u64,
+        })
+        .to_string(),
+    ))
 }
 
 /// # `POST /_matrix/client/r0/logout`
@@ -161,6 +455,7 @@ pub fn logout_route(
     let device_id = body.device_id.as_ref().expect("user is authenticated");
 
     db.users.remove_device(&sender_id, device_id)?;
+    db.pushers.remove_device_pushers(&sender_id, device_id)?;
 
     Ok(logout::Response.into())
 }
@@ -187,8 +482,72 @@ pub fn logout_all_route(
     for device_id in db.users.all_device_ids(sender_id) {
         if let Ok(device_id) = device_id {
             db.users.remove_device(&sender_id, &device_id)?;
+            db.pushers.remove_device_pushers(&sender_id, &device_id)?;
         }
     }
 
     Ok(logout_all::Response.into())
 }
+
+/// Creates an account for a user that logs in with an external system like JWT or CAS. The
+/// password is random, so the user can't log in with a password.
+pub fn create_external_user(db: &Database<'_>, user_id: &UserId) -> Result<()> {
+    db.account_data.update(
+        None,
+        user_id,
+        EventType::PushRules,
+        &ruma::events::push_rules::PushRulesEvent {
+            content: ruma::events::push_rules::PushRulesEventContent {
+                global: crate::push_rules::default_pushrules(user_id),
+            },
+        },
+        &db.globals,
+    )?;
+    db.users.create(user_id, &utils::random_string(40))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::login_flows;
+    use serde_json::json;
+
+    #[test]
+    fn login_flows_follow_the_configuration() {
+        assert_eq!(
+            login_flows(true, false, None),
+            vec![
+                json!({ "type": "m.login.password" }),
+                json!({ "type": "m.login.token" }),
+            ]
+        );
+
+        // Without password login only tokens are left
+        assert_eq!(
+            login_flows(false, false, None),
+            vec![json!({ "type": "m.login.token" })]
+        );
+    }
+
+    #[test]
+    fn sso_flow_lists_the_identity_providers() {
+        assert_eq!(
+            login_flows(false, true, Some("https://id.example.com")),
+            vec![
+                json!({ "type": "m.login.token" }),
+                json!({
+                    "type": "m.login.sso",
+                    "identity_providers": [
+                        { "id": "cas", "name": "CAS" },
+                        { "id": "oidc", "name": "https://id.example.com" },
+                    ],
+                }),
+            ]
+        );
+        assert_eq!(
+            login_flows(true, true, None)[2]["identity_providers"],
+            json!([{ "id": "cas", "name": "CAS" }])
+        );
+    }
+}
@@ -0,0 +1,450 @@
+use super::State;
+use crate::{
+    database::{globals::SlidingSyncConnection, rooms::Viewer},
+    Database, Error, Result, Ruma,
+};
+use rocket::response::content::Json;
+use ruma::{
+    api::client::{error::ErrorKind, r0::account::whoami},
+    events::{AnySyncStateEvent, EventType},
+    Raw, RoomId, UserId,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryFrom,
+    time::Duration,
+};
+
+#[cfg(feature = "conduit_bin")]
+use rocket::{post, tokio};
+
+/// How many events of a room are sent if the list or subscription has no `timeline_limit`
+const DEFAULT_TIMELINE_LIMIT: u64 = 10;
+const MAX_TIMELINE_LIMIT: u64 = 100;
+
+/// # `POST /_matrix/client/unstable/org.matrix.msc3575/sync`
+///
+/// Synchronizes windows of the sorted room lists of the user and the rooms the client subscribed
+/// to (sliding sync, MSC3575).
+///
+/// - Ruma has no type for this request, so we parse it as
+/// [`GET /_matrix/client/r0/account/whoami`](fn.whoami_route.html) and read the body ourselves
+/// - Only available if `experimental_sliding_sync` is enabled
+/// - `lists` contain the joined rooms, sorted `by_recency` (newest event first) and/or `by_name`,
+/// and can be filtered with `is_dm` and `is_encrypted`. Every list returns a `SYNC` op with the
+/// rooms of each of its `ranges`
+/// - Rooms in the windows and in `room_subscriptions` get their `required_state` and up to
+/// `timeline_limit` events. `["*", "*"]` matches all state and the state key `$ME` the user
+/// - Lists and subscriptions are sticky: the connection `conn_id` keeps them until they are
+/// changed or `unsubscribe_rooms` removes them
+/// - `pos` is the global count when the last response of the connection was built. With it, only
+/// rooms with new events are sent, only with the new events and without `required_state`. A
+/// request without `pos` or with another `pos` than the last response starts the connection
+/// over, then all rooms are sent with `initial: true`
+/// - If nothing changed, the request waits up to `timeout`, at most 30 seconds
+#[cfg_attr(
+    feature = "conduit_bin",
+    post(
+        "/_matrix/client/unstable/org.matrix.msc3575/sync?<pos>&<timeout>",
+        data = "<body>"
+    )
+)]
+pub async fn sliding_sync_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+    pos: Option<String>,
+    timeout: Option<u64>,
+) -> Result<Json<String>> {
+    if !db.globals.experimental_sliding_sync() {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Sliding sync is disabled.",
+        ));
+    }
+
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+    let device_id = body.device_id.as_ref().expect("user is authenticated");
+
+    let request = serde_json::from_str::<Value>(
+        body.json_body
+            .as_ref()
+            .ok_or(Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?
+            .get(),
+    )
+    .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?;
+    let conn_id = request
+        .get("conn_id")
+        .and_then(|conn_id| conn_id.as_str())
+        .unwrap_or("");
+
+    let pos = pos.and_then(|pos| pos.parse::<u64>().ok());
+    let mut connection = db
+        .globals
+        .sliding_sync_connection(sender_id, device_id, conn_id);
+    if pos.map_or(true, |pos| pos != connection.pos) {
+        connection = SlidingSyncConnection::default();
+    }
+
+    let old_lists = connection.lists.clone();
+    let old_subscriptions = connection.subscriptions.clone();
+
+    // Parameters that are left out keep their value of the last request
+    for (name, list) in request
+        .get("lists")
+        .and_then(|lists| lists.as_object())
+        .into_iter()
+        .flatten()
+    {
+        let sticky = connection
+            .lists
+            .entry(name.clone())
+            .or_insert_with(|| json!({}));
+        if let (Some(sticky), Some(list)) = (sticky.as_object_mut(), list.as_object()) {
+            for (key, value) in list {
+                sticky.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    for (room_id, subscription) in request
+        .get("room_subscriptions")
+        .and_then(|subscriptions| subscriptions.as_object())
+        .into_iter()
+        .flatten()
+    {
+        if let Ok(room_id) = RoomId::try_from(room_id.as_str()) {
+            connection
+                .subscriptions
+                .insert(room_id, subscription.clone());
+        }
+    }
+    for room_id in request
+        .get("unsubscribe_rooms")
+        .and_then(|room_ids| room_ids.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|room_id| RoomId::try_from(room_id.as_str()?).ok())
+    {
+        connection.subscriptions.remove(&room_id);
+    }
+
+    // New parameters are answered right away
+    let params_changed = pos.is_none()
+        || connection.lists != old_lists
+        || connection.subscriptions != old_subscriptions;
+
+    let mut duration = Duration::from_millis(timeout.unwrap_or(0));
+    if duration.as_secs() > 30 {
+        duration = Duration::from_secs(30);
+    }
+    let mut delay = tokio::time::delay_for(duration);
+
+    loop {
+        // Setup watchers before building the response, so no change is missed while waiting
+        let watcher = db.watch(sender_id, device_id);
+
+        let (response, new_connection) =
+            tokio::task::block_in_place(|| sliding_sync_response(&db, sender_id, &connection))?;
+
+        let changed = params_changed
+            || response["rooms"]
+                .as_object()
+                .map_or(false, |rooms| !rooms.is_empty())
+            || new_connection.list_rooms != connection.list_rooms;
+
+        // When something changed, the response is built again. The change can still be
+        // outside of the windows, then the request keeps waiting until the timeout
+        if !changed {
+            let woken = tokio::select! {
+                _ = &mut delay => false,
+                _ = watcher => true,
+                _ = db.globals.shutdown().wait() => false,
+            };
+            if woken {
+                continue;
+            }
+        }
+
+        db.globals
+            .set_sliding_sync_connection(sender_id, device_id, conn_id, new_connection);
+        return Ok(Json(response.to_string()));
+    }
+}
+
+/// Builds the response for the lists and subscriptions of the connection and returns it with
+/// the new state of the connection.
+fn sliding_sync_response(
+    db: &Database<'_>,
+    sender_id: &UserId,
+    connection: &SlidingSyncConnection,
+) -> Result<(Value, SlidingSyncConnection)> {
+    let mut new_connection = connection.clone();
+    new_connection.pos = db.globals.current_count()?;
+    new_connection.list_rooms.clear();
+
+    // Events of ignored users are left out of the timelines
+    let ignored_users = db.account_data.ignored_users(&sender_id)?;
+
+    let direct_rooms = db
+        .account_data
+        .get::<ruma::events::direct::DirectEvent>(None, &sender_id, EventType::Direct)?
+        .map(|direct| {
+            direct
+                .content
+                .0
+                .into_iter()
+                .flat_map(|(_, room_ids)| room_ids)
+                .collect::<HashSet<_>>()
+        })
+        .unwrap_or_default();
+
+    // The count of the last event of every joined room, for sorting and to remember what the
+    // client knows
+    let mut last_counts = HashMap::new();
+    for room_id in db.rooms.rooms_joined(&sender_id) {
+        let room_id = room_id?;
+        let last_count = db
+            .rooms
+            .pdus_until(&sender_id, &room_id, u64::MAX)
+            .filter_map(|r| r.ok())
+            .next()
+            .map_or(0, |(count, _)| count);
+        last_counts.insert(room_id, last_count);
+    }
+
+    // The parameters of the list or subscription every room is sent with
+    let mut room_params = BTreeMap::<RoomId, &Value>::new();
+
+    let mut lists = serde_json::Map::new();
+    for (name, list) in &connection.lists {
+        let filters = list.get("filters");
+        let filter = |key: &str| {
+            filters
+                .and_then(|filters| filters.get(key))
+                .and_then(|value| value.as_bool())
+        };
+        let is_dm = filter("is_dm");
+        let is_encrypted = filter("is_encrypted");
+
+        let mut rooms = Vec::new();
+        for (room_id, last_count) in &last_counts {
+            if is_dm.map_or(false, |is_dm| is_dm != direct_rooms.contains(room_id)) {
+                continue;
+            }
+            if let Some(is_encrypted) = is_encrypted {
+                if is_encrypted
+                    != db
+                        .rooms
+                        .room_state_get(room_id, &EventType::RoomEncryption, "")?
+                        .is_some()
+                {
+                    continue;
+                }
+            }
+
+            let sort_name = room_name(db, room_id)?
+                .unwrap_or_else(|| room_id.to_string())
+                .to_lowercase();
+            rooms.push((room_id, *last_count, sort_name));
+        }
+
+        // Room ids are the last key, so the order is stable. Later sort keys only decide
+        // between rooms that are equal in the earlier ones
+        rooms.sort_by(|a, b| a.0.cmp(b.0));
+        let sort = list
+            .get("sort")
+            .and_then(|sort| sort.as_array())
+            .map(|sort| {
+                sort.iter()
+                    .filter_map(|key| key.as_str())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_else(|| vec!["by_recency"]);
+        for key in sort.iter().rev() {
+            match *key {
+                "by_recency" => rooms.sort_by(|a, b| b.1.cmp(&a.1)),
+                "by_name" => rooms.sort_by(|a, b| a.2.cmp(&b.2)),
+                _ => {}
+            }
+        }
+
+        let mut ops = Vec::new();
+        let mut windows = Vec::new();
+        for range in list
+            .get("ranges")
+            .and_then(|ranges| ranges.as_array())
+            .into_iter()
+            .flatten()
+        {
+            let (start, end) = match (
+                range.get(0).and_then(|start| start.as_u64()),
+                range.get(1).and_then(|end| end.as_u64()),
+            ) {
+                (Some(start), Some(end)) if start <= end => (start as usize, end as usize),
+                _ => continue,
+            };
+
+            let window = rooms
+                .iter()
+                .skip(start)
+                .take((end - start).saturating_add(1))
+                .map(|(room_id, _, _)| (*room_id).clone())
+                .collect::<Vec<_>>();
+            for room_id in &window {
+                room_params.entry(room_id.clone()).or_insert(list);
+            }
+
+            ops.push(json!({
+                "op": "SYNC",
+                "range": [start, end],
+                "room_ids": window,
+            }));
+            windows.push(window);
+        }
+
+        lists.insert(
+            name.clone(),
+            json!({
+                "count": rooms.len(),
+                "ops": ops,
+            }),
+        );
+        new_connection
+            .list_rooms
+            .insert(name.clone(), (rooms.len(), windows));
+    }
+
+    // Subscriptions take precedence over the lists
+    for (room_id, subscription) in &connection.subscriptions {
+        if last_counts.contains_key(room_id) {
+            room_params.insert(room_id.clone(), subscription);
+        }
+    }
+
+    let mut rooms = serde_json::Map::new();
+    for (room_id, params) in room_params {
+        let known = connection.known_rooms.get(&room_id).copied();
+        let initial = known.is_none();
+
+        let timeline_limit = params
+            .get("timeline_limit")
+            .and_then(|limit| limit.as_u64())
+            .unwrap_or(DEFAULT_TIMELINE_LIMIT)
+            .min(MAX_TIMELINE_LIMIT) as usize;
+
+        // Events the user could not see before joining are left out
+        let mut visibility = db
+            .rooms
+            .event_visibility(Viewer::User(&sender_id), &room_id)?;
+        // One more event than needed shows if the timeline is limited
+        let mut timeline = db
+            .rooms
+            .pdus_until(&sender_id, &room_id, u64::MAX)
+            .filter_map(|r| r.ok()) // Filter out buggy events
+            .take_while(|(count, _)| known.map_or(true, |known| *count > known))
+            .filter(|(_, pdu)| !super::sent_by_ignored_user(&ignored_users, pdu))
+            .filter(|(count, pdu)| visibility.can_see(*count, pdu).unwrap_or(false))
+            .take(timeline_limit + 1)
+            .collect::<Vec<_>>();
+        let limited = timeline.len() > timeline_limit;
+        timeline.truncate(timeline_limit);
+        timeline.reverse();
+
+        new_connection.known_rooms.insert(
+            room_id.clone(),
+            last_counts.get(&room_id).copied().unwrap_or_default(),
+        );
+
+        // The client already has everything of known rooms without new events
+        if !initial && timeline.is_empty() {
+            continue;
+        }
+
+        let (notification_count, highlight_count) =
+            db.rooms.edus.notification_counts(&room_id, &sender_id)?;
+
+        let mut room = json!({
+            "initial": initial,
+            "timeline": timeline
+                .iter()
+                .map(|(_, pdu)| pdu.to_sync_room_event())
+                .collect::<Vec<_>>(),
+            "limited": limited,
+            "notification_count": notification_count,
+            "highlight_count": highlight_count,
+            "joined_count": db.rooms.room_members(&room_id).count(),
+            "invited_count": db.rooms.room_members_invited(&room_id).count(),
+        });
+        if let Some((count, _)) = timeline.first() {
+            room["prev_batch"] = count.to_string().into();
+        }
+        if initial {
+            room["name"] = json!(room_name(db, &room_id)?);
+            room["required_state"] = json!(required_state(db, sender_id, &room_id, params)?);
+        }
+
+        rooms.insert(room_id.to_string(), room);
+    }
+
+    Ok((
+        json!({
+            "pos": new_connection.pos.to_string(),
+            "lists": lists,
+            "rooms": rooms,
+        }),
+        new_connection,
+    ))
+}
+
+/// Returns the name of the room or its canonical alias. Clients calculate other names from the
+/// members themselves.
+fn room_name(db: &Database<'_>, room_id: &RoomId) -> Result<Option<String>> {
+    if let Some(name) = db
+        .rooms
+        .room_state_get(room_id, &EventType::RoomName, "")?
+        .and_then(|pdu| pdu.content.get("name")?.as_str().map(str::to_owned))
+        .filter(|name| !name.is_empty())
+    {
+        return Ok(Some(name));
+    }
+
+    Ok(db
+        .rooms
+        .room_state_get(room_id, &EventType::RoomCanonicalAlias, "")?
+        .and_then(|pdu| pdu.content.get("alias")?.as_str().map(str::to_owned)))
+}
+
+/// Returns the state events of the room that match the `required_state` of a list or
+/// subscription.
+fn required_state(
+    db: &Database<'_>,
+    sender_id: &UserId,
+    room_id: &RoomId,
+    params: &Value,
+) -> Result<Vec<Raw<AnySyncStateEvent>>> {
+    let patterns = params
+        .get("required_state")
+        .and_then(|required_state| required_state.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|pattern| Some((pattern.get(0)?.as_str()?, pattern.get(1)?.as_str()?)))
+        .collect::<Vec<_>>();
+    if patterns.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    Ok(db
+        .rooms
+        .room_state_full(room_id)?
+        .into_iter()
+        .filter(|((event_type, state_key), _)| {
+            patterns.iter().any(|(pattern_type, pattern_key)| {
+                (*pattern_type == "*" || *pattern_type == event_type.to_string())
+                    && (*pattern_key == "*"
+                        || *pattern_key == state_key.as_str()
+                        || (*pattern_key == "$ME" && state_key.as_str() == sender_id.as_str()))
+            })
+        })
+        .map(|(_, pdu)| pdu.to_sync_state_event())
+        .collect())
+}
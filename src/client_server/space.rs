@@ -0,0 +1,455 @@
+use super::{remote_alias, State};
+use crate::{server_server, Database, Error, Result, Ruma};
+use rocket::response::content::Json;
+use ruma::{
+    api::client::{
+        error::ErrorKind,
+        r0::{account::whoami, membership::joined_members},
+    },
+    events::EventType,
+    RoomAliasId, RoomId, ServerName, UserId,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+};
+use tracing::warn;
+
+#[cfg(feature = "conduit_bin")]
+use rocket::get;
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 200;
+/// Deeper children are not returned, even if the client asks for them
+const MAX_DEPTH: usize = 10;
+
+/// # `GET /_matrix/client/v1/rooms/{roomId}/hierarchy`
+///
+/// Returns the rooms of a space and, recursively, of its subspaces.
+///
+/// - The request has the same form as [`GET /_matrix/client/r0/rooms/{roomId}/joined_members`](fn.joined_members_route.html),
+/// so we parse it as that request
+/// - Rooms are returned depth first, children are ordered by the `order` of their `m.space.child`
+/// event
+/// - Rooms the user is not allowed to see are skipped together with their children
+/// - Rooms of other servers are requested from the servers in `via`
+/// - `next_batch` is the number of rooms that were already returned
+#[cfg_attr(
+    feature = "conduit_bin",
+    get(
+        "/_matrix/client/v1/rooms/<_>/hierarchy?<from>&<limit>&<max_depth>&<suggested_only>",
+        data = "<body>"
+    )
+)]
+pub async fn get_hierarchy_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<joined_members::Request>,
+    from: Option<String>,
+    limit: Option<usize>,
+    max_depth: Option<usize>,
+    suggested_only: Option<bool>,
+) -> Result<Json<String>> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    let skip = match from.map(|from| from.parse::<usize>()) {
+        Some(Ok(skip)) => skip,
+        Some(Err(_)) => {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Invalid from token.",
+            ))
+        }
+        None => 0, // Default to the start
+    };
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).max(1).min(MAX_LIMIT);
+    let max_depth = max_depth.unwrap_or(MAX_DEPTH).min(MAX_DEPTH);
+    let suggested_only = suggested_only.unwrap_or(false);
+
+    if !db.rooms.exists(&body.room_id)? {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Unknown room."));
+    }
+
+    // Summaries of remote rooms that other servers sent along with their parents
+    let mut remote_summaries = HashMap::new();
+    let mut visited = HashSet::new();
+    let mut rooms = Vec::new();
+
+    let mut stack = vec![(body.room_id.clone(), Vec::new(), 0)];
+    // One more room than needed tells us if there is a next batch
+    while let Some((room_id, via, depth)) = stack.pop() {
+        if rooms.len() > skip + limit {
+            break;
+        }
+
+        // Spaces can contain each other
+        if !visited.insert(room_id.clone()) {
+            continue;
+        }
+
+        let summary = match room_summary_from_anywhere(
+            &db,
+            &room_id,
+            &via,
+            suggested_only,
+            &mut remote_summaries,
+        )
+        .await?
+        {
+            Some(summary) => summary,
+            None => continue,
+        };
+
+        if !is_accessible(&db, Some(&sender_id), &summary)? {
+            if room_id == body.room_id {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "You are not allowed to see this room.",
+                ));
+            }
+            continue;
+        }
+
+        if depth < max_depth {
+            // Reversed, so the first child is visited first
+            for (child_id, via) in space_children(&summary, suggested_only).into_iter().rev() {
+                if !visited.contains(&child_id) {
+                    stack.push((child_id, via, depth + 1));
+                }
+            }
+        }
+
+        rooms.push(summary);
+    }
+
+    let next_batch = if rooms.len() > skip + limit {
+        Some((skip + limit).to_string())
+    } else {
+        None
+    };
+
+    Ok(Json(
+        json!({
+            "rooms": rooms.into_iter().skip(skip).take(limit).collect::<Vec<_>>(),
+            "next_batch": next_batch,
+        })
+        .to_string(),
+    ))
+}
+
+/// # `GET /_matrix/client/unstable/im.nheko.summary/rooms/{roomIdOrAlias}/summary`
+///
+/// Returns the summary of a room, so clients can show a preview before the user joins it.
+///
+/// - The request has no body, so we parse it as a whoami request
+/// - Aliases are resolved first, the servers of the alias are asked for rooms this server
+/// doesn't know, like in [`GET /_matrix/client/v1/rooms/{roomId}/hierarchy`](fn.get_hierarchy_route.html)
+/// - Rooms the user could neither join nor read are not found, unless the user is in them or
+/// invited
+/// - `membership` is the membership of the user in the room, if they have one
+#[cfg_attr(
+    feature = "conduit_bin",
+    get(
+        "/_matrix/client/unstable/im.nheko.summary/rooms/<room_id_or_alias>/summary",
+        data = "<body>"
+    )
+)]
+pub async fn get_room_summary_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+    room_id_or_alias: String,
+) -> Result<Json<String>> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    let (room_id, mut via) = match RoomId::try_from(room_id_or_alias.as_str()) {
+        Ok(room_id) => (room_id, Vec::new()),
+        Err(_) => {
+            let room_alias = RoomAliasId::try_from(room_id_or_alias.as_str()).map_err(|_| {
+                Error::BadRequest(ErrorKind::InvalidParam, "Invalid room id or alias.")
+            })?;
+
+            if room_alias.server_name() == db.globals.server_name() {
+                let room_id = db
+                    .rooms
+                    .id_from_alias(&room_alias)?
+                    .ok_or(Error::BadRequest(
+                        ErrorKind::NotFound,
+                        "Room with alias not found.",
+                    ))?;
+                (room_id, Vec::new())
+            } else {
+                let (room_id, servers) = remote_alias(&db, &room_alias).await?;
+                (
+                    room_id,
+                    servers
+                        .iter()
+                        .filter_map(|server| Box::<ServerName>::try_from(server.as_str()).ok())
+                        .collect(),
+                )
+            }
+        }
+    };
+    via.push(room_id.server_name().to_owned());
+
+    let mut summary =
+        match room_summary_from_anywhere(&db, &room_id, &via, false, &mut HashMap::new()).await? {
+            Some(summary) if is_accessible(&db, Some(&sender_id), &summary)? => summary,
+            // Rooms the user can't see are not found, so they don't tell that the room exists
+            _ => return Err(Error::BadRequest(ErrorKind::NotFound, "Room not found.")),
+        };
+
+    if let Some(summary) = summary.as_object_mut() {
+        summary.remove("children_state");
+    }
+
+    let membership = if db.rooms.exists(&room_id)? {
+        db.rooms
+            .room_state_get(&room_id, &EventType::RoomMember, sender_id.as_str())?
+            .and_then(|pdu| Some(pdu.content.get("membership")?.as_str()?.to_owned()))
+    } else if db.rooms.is_invited(&sender_id, &room_id)? {
+        Some("invite".to_owned())
+    } else {
+        None
+    };
+    if let Some(membership) = membership {
+        summary["membership"] = membership.into();
+    }
+
+    Ok(Json(summary.to_string()))
+}
+
+/// Returns the summary of a local room in the form of the hierarchy endpoints, including the
+/// stripped `m.space.child` events.
+pub fn room_summary(db: &Database<'_>, room_id: &RoomId) -> Result<Value> {
+    let state = db.rooms.room_state_full(room_id)?;
+    let content = |event_type| {
+        state
+            .get(&(event_type, "".to_owned()))
+            .map(|pdu| &pdu.content)
+    };
+    let string = |event_type, field: &str| {
+        content(event_type).and_then(|content| content.get(field)?.as_str().map(str::to_owned))
+    };
+
+    let children_state = state
+        .values()
+        .filter(|pdu| pdu.kind.to_string() == "m.space.child")
+        // Children without via were removed
+        .filter(|pdu| {
+            pdu.content
+                .get("via")
+                .and_then(|via| via.as_array())
+                .map_or(false, |via| !via.is_empty())
+        })
+        .map(|pdu| {
+            json!({
+                "type": pdu.kind,
+                "state_key": pdu.state_key,
+                "content": pdu.content,
+                "sender": pdu.sender,
+                "origin_server_ts": pdu.origin_server_ts,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(json!({
+        "room_id": room_id,
+        "room_type": string(EventType::RoomCreate, "type"),
+        "name": string(EventType::RoomName, "name"),
+        "topic": string(EventType::RoomTopic, "topic"),
+        "canonical_alias": string(EventType::RoomCanonicalAlias, "alias"),
+        "avatar_url": string(EventType::RoomAvatar, "url"),
+        "num_joined_members": db.rooms.room_members(room_id).count(),
+        // Rooms without join rules can't be joined by anyone but the creator
+        "join_rule": string(EventType::RoomJoinRules, "join_rule")
+            .unwrap_or_else(|| "invite".to_owned()),
+        "allowed_room_ids": db.rooms.restricted_join_rooms(room_id)?.unwrap_or_default(),
+        "world_readable": string(EventType::RoomHistoryVisibility, "history_visibility").as_deref()
+            == Some("world_readable"),
+        "guest_can_join": string(EventType::RoomGuestAccess, "guest_access").as_deref()
+            == Some("can_join"),
+        // Room versions before 2 didn't have the field
+        "room_version": string(EventType::RoomCreate, "room_version")
+            .unwrap_or_else(|| "1".to_owned()),
+        "encryption": string(EventType::RoomEncryption, "algorithm"),
+        "children_state": children_state,
+    }))
+}
+
+/// Returns the children of a room summary with the servers they can be found on, in the order
+/// of the `order` field of their `m.space.child` events.
+pub fn space_children(
+    summary: &Value,
+    suggested_only: bool,
+) -> Vec<(RoomId, Vec<Box<ServerName>>)> {
+    let mut children = summary
+        .get("children_state")
+        .and_then(|children| children.as_array())
+        .map_or_else(Vec::new, |children| {
+            children
+                .iter()
+                .filter(|child| {
+                    !suggested_only
+                        || child
+                            .get("content")
+                            .and_then(|content| content.get("suggested")?.as_bool())
+                            == Some(true)
+                })
+                .filter_map(|child| {
+                    let room_id = RoomId::try_from(child.get("state_key")?.as_str()?).ok()?;
+                    let content = child.get("content")?;
+                    let via = content
+                        .get("via")?
+                        .as_array()?
+                        .iter()
+                        .filter_map(|server| Box::<ServerName>::try_from(server.as_str()?).ok())
+                        .collect::<Vec<_>>();
+
+                    // Invalid orders are ignored
+                    let order = content
+                        .get("order")
+                        .and_then(|order| order.as_str())
+                        .filter(|order| {
+                            order.len() <= 50 && order.chars().all(|c| (' '..='~').contains(&c))
+                        })
+                        .map(str::to_owned);
+                    let ts = child
+                        .get("origin_server_ts")
+                        .and_then(|ts| ts.as_u64())
+                        .unwrap_or(0);
+
+                    Some(((order.is_none(), order, ts), room_id, via))
+                })
+                .collect::<Vec<_>>()
+        });
+
+    children.sort_by(|(a, a_id, _), (b, b_id, _)| a.cmp(b).then(a_id.cmp(b_id)));
+
+    children
+        .into_iter()
+        .map(|(_, room_id, via)| (room_id, via))
+        .collect()
+}
+
+/// Checks if the room of the summary can be seen by the user in a space hierarchy. Without a
+/// user, this checks if the room can be seen by other servers.
+///
+/// Rooms are visible to their members and if anyone could join or read them.
+pub fn is_accessible(db: &Database<'_>, user_id: Option<&UserId>, summary: &Value) -> Result<bool> {
+    let room_id = match summary
+        .get("room_id")
+        .and_then(|room_id| RoomId::try_from(room_id.as_str()?).ok())
+    {
+        Some(room_id) => room_id,
+        None => return Ok(false),
+    };
+
+    if let Some(user_id) = user_id {
+        if db.rooms.is_joined(user_id, &room_id)? || db.rooms.is_invited(user_id, &room_id)? {
+            return Ok(true);
+        }
+    }
+
+    if summary.get("world_readable").and_then(|w| w.as_bool()) == Some(true) {
+        return Ok(true);
+    }
+
+    Ok(
+        match summary
+            .get("join_rule")
+            .and_then(|join_rule| join_rule.as_str())
+        {
+            Some("public") | Some("knock") | Some("knock_restricted") => true,
+            // Users of other servers might be in one of the allowed rooms
+            Some("restricted") if user_id.is_none() => true,
+            Some("restricted") => {
+                let user_id = user_id.expect("checked above");
+                let allowed_room_ids = summary
+                    .get("allowed_room_ids")
+                    .and_then(|allowed| allowed.as_array())
+                    .map_or_else(Vec::new, |allowed| {
+                        allowed
+                            .iter()
+                            .filter_map(|room_id| RoomId::try_from(room_id.as_str()?).ok())
+                            .collect()
+                    });
+
+                allowed_room_ids
+                    .iter()
+                    .map(|room_id| db.rooms.is_joined(user_id, room_id))
+                    .collect::<Result<Vec<_>>>()?
+                    .contains(&true)
+            }
+            _ => false,
+        },
+    )
+}
+
+/// Returns the summary of a local room, or asks the servers in `via` for it. The children in the
+/// responses of other servers are remembered, so we don't have to ask again for them.
+async fn room_summary_from_anywhere(
+    db: &crate::Database<'static>,
+    room_id: &RoomId,
+    via: &[Box<ServerName>],
+    suggested_only: bool,
+    remote_summaries: &mut HashMap<RoomId, Value>,
+) -> Result<Option<Value>> {
+    if db.rooms.exists(room_id)? {
+        return room_summary(db, room_id).map(Some);
+    }
+
+    if let Some(summary) = remote_summaries.get(room_id) {
+        return Ok(Some(summary.clone()));
+    }
+
+    for server in via {
+        if &**server == db.globals.server_name() {
+            continue;
+        }
+
+        let response = server_server::send_json_request(
+            db,
+            server.as_str(),
+            reqwest::Method::GET,
+            &format!(
+                "/_matrix/federation/v1/hierarchy/{}?suggested_only={}",
+                room_id, suggested_only
+            ),
+            None,
+        )
+        .await;
+
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                warn!(
+                    "Failed to get hierarchy of {} from {}: {}",
+                    room_id, server, e
+                );
+                continue;
+            }
+        };
+
+        for child in response
+            .get("children")
+            .and_then(|children| children.as_array())
+            .into_iter()
+            .flatten()
+        {
+            if let Some(child_id) = child
+                .get("room_id")
+                .and_then(|room_id| RoomId::try_from(room_id.as_str()?).ok())
+            {
+                remote_summaries
+                    .entry(child_id)
+                    .or_insert_with(|| child.clone());
+            }
+        }
+
+        if let Some(summary) = response.get("room") {
+            return Ok(Some(summary.clone()));
+        }
+    }
+
+    Ok(None)
+}
@@ -0,0 +1,401 @@
+use super::{create_external_user, State, LOGIN_TOKEN_LENGTH, LOGIN_TOKEN_LIFETIME};
+use crate::{utils, Database, Error, Result};
+use rocket::{http::Status, response::Response};
+use ruma::{api::client::error::ErrorKind, UserId};
+
+#[cfg(feature = "conduit_bin")]
+use rocket::get;
+
+const OIDC_STATE_LENGTH: usize = 32;
+const OIDC_NONCE_LENGTH: usize = 32;
+const OIDC_CODE_VERIFIER_LENGTH: usize = 64;
+
+/// # `GET /_matrix/client/r0/login/sso/redirect?redirectUrl=...`
+///
+/// Redirects the browser of the user to the login page of the CAS server or, without one, the
+/// OpenID Connect provider.
+///
+/// - After the login, the user is sent to `redirectUrl` with a `loginToken` that can be used for
+/// `m.login.token`
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/login/sso/redirect?<redirectUrl>")
+)]
+#[allow(non_snake_case)]
+pub async fn sso_redirect_route(
+    db: State<'_, Database<'_>>,
+    redirectUrl: String,
+) -> Result<Response<'static>> {
+    if db.globals.cas_server_url().is_some() {
+        cas_redirect(&db, &redirectUrl)
+    } else {
+        oidc_redirect(&db, &redirectUrl).await
+    }
+}
+
+/// # `GET /_matrix/client/r0/login/sso/redirect/{idpId}?redirectUrl=...`
+///
+/// Like `/login/sso/redirect`, but with the identity provider the user chose from the
+/// `identity_providers` of `m.login.sso`.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/login/sso/redirect/<idp_id>?<redirectUrl>")
+)]
+#[allow(non_snake_case)]
+pub async fn sso_redirect_idp_route(
+    db: State<'_, Database<'_>>,
+    idp_id: String,
+    redirectUrl: String,
+) -> Result<Response<'static>> {
+    match &*idp_id {
+        "cas" => cas_redirect(&db, &redirectUrl),
+        "oidc" => oidc_redirect(&db, &redirectUrl).await,
+        _ => Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Unknown identity provider.",
+        )),
+    }
+}
+
+fn cas_redirect(db: &Database<'_>, redirect_url: &str) -> Result<Response<'static>> {
+    let cas_server_url = db.globals.cas_server_url().ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "Single sign-on is not enabled on this server.",
+    ))?;
+
+    let location = format!(
+        "{}/login?service={}",
+        cas_server_url,
+        utils::percent_encode(&cas_service_url(db, redirect_url))
+    );
+
+    redirect(location)
+}
+
+/// # `GET /_conduit/client/sso/cas/ticket?redirectUrl=...&ticket=...`
+///
+/// The CAS server sends the browser of the user here after the login.
+///
+/// - The ticket is validated with the CAS server and the CAS username becomes the localpart of
+/// the user id
+/// - Users that don't exist yet are only registered if `cas_auto_register` is enabled
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_conduit/client/sso/cas/ticket?<redirectUrl>&<ticket>")
+)]
+#[allow(non_snake_case)]
+pub async fn cas_ticket_route(
+    db: State<'_, Database<'_>>,
+    redirectUrl: String,
+    ticket: String,
+) -> Result<Response<'static>> {
+    let cas_server_url = db.globals.cas_server_url().ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "Single sign-on is not enabled on this server.",
+    ))?;
+
+    let validation = db
+        .globals
+        .reqwest_client()
+        .get(&format!("{}/serviceValidate", cas_server_url))
+        .query(&[
+            ("service", cas_service_url(&db, &redirectUrl)),
+            ("ticket", ticket),
+        ])
+        .send()
+        .await?
+        .text()
+        .await?;
+
+    let username = cas_user(&validation).ok_or(Error::BadRequest(
+        ErrorKind::Forbidden,
+        "The CAS server did not accept the ticket.",
+    ))?;
+
+    let user_id = UserId::parse_with_server_name(username.to_lowercase(), db.globals.server_name())
+        .map_err(|_| {
+            Error::BadRequest(
+                ErrorKind::InvalidUsername,
+                "The CAS username is not a valid user id.",
+            )
+        })?;
+
+    finish_login(&db, &user_id, db.globals.cas_auto_register(), &redirectUrl)
+}
+
+/// The url the CAS server sends the user back to. CAS servers compare it when validating the
+/// ticket, so both requests have to use the same url.
+fn cas_service_url(db: &Database<'_>, redirect_url: &str) -> String {
+    format!(
+        "{}/_conduit/client/sso/cas/ticket?redirectUrl={}",
+        db.globals.public_baseurl(),
+        utils::percent_encode(redirect_url)
+    )
+}
+
+/// Returns the username of a successful `serviceValidate` response.
+fn cas_user(validation: &str) -> Option<&str> {
+    let start = validation.find("<cas:user>")? + "<cas:user>".len();
+    let end = start + validation[start..].find("</cas:user>")?;
+
+    Some(validation[start..end].trim()).filter(|user| !user.is_empty())
+}
+
+async fn oidc_redirect(db: &Database<'_>, redirect_url: &str) -> Result<Response<'static>> {
+    let provider = db.globals.oidc_provider().ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "Single sign-on is not enabled on this server.",
+    ))?;
+    let metadata = oidc_metadata(db, provider).await?;
+    let authorization_endpoint = metadata
+        .get("authorization_endpoint")
+        .and_then(|endpoint| endpoint.as_str())
+        .ok_or(Error::BadServerResponse(
+            "OpenID Connect provider has no authorization endpoint.",
+        ))?;
+
+    let state = utils::random_string(OIDC_STATE_LENGTH);
+    let session = OidcSession {
+        code_verifier: utils::random_string(OIDC_CODE_VERIFIER_LENGTH),
+        nonce: utils::random_string(OIDC_NONCE_LENGTH),
+        redirect_url: redirect_url.to_owned(),
+    };
+    let code_challenge = base64::encode_config(
+        digest::digest(&digest::SHA256, session.code_verifier.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    );
+
+    let location = reqwest::Url::parse_with_params(
+        authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", provider.client_id.as_str()),
+            ("redirect_uri", &*oidc_redirect_uri(db)),
+            ("scope", provider.scopes.as_str()),
+            ("state", state.as_str()),
+            ("nonce", session.nonce.as_str()),
+            ("code_challenge", code_challenge.as_str()),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(|_| {
+        Error::BadServerResponse("OpenID Connect provider has an invalid authorization endpoint.")
+    })?;
+
+    db.globals.add_oidc_session(state, session);
+
+    redirect(location.to_string())
+}
+
+/// # `GET /_conduit/client/sso/oidc/callback?code=...&state=...`
+///
+/// The OpenID Connect provider sends the browser of the user here after the login.
+///
+/// - The `state` has to belong to a login that was started on this server, which prevents CSRF
+/// - The code is exchanged for an ID token, whose signature, issuer, audience and nonce are checked
+/// - `preferred_username` becomes the localpart of the user id, or `sub` if the preferred username
+/// is not a valid localpart
+/// - Users that don't exist yet are only registered if `oidc_auto_register` is enabled
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_conduit/client/sso/oidc/callback?<code>&<state>")
+)]
+pub async fn oidc_callback_route(
+    db: State<'_, Database<'_>>,
+    code: Option<String>,
+    state: String,
+) -> Result<Response<'static>> {
+    let provider = db.globals.oidc_provider().ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "Single sign-on is not enabled on this server.",
+    ))?;
+    let session = db
+        .globals
+        .take_oidc_session(&state)
+        .ok_or(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Unknown or expired login session.",
+        ))?;
+    // Without a code the provider sends an error, e.g. because the user denied the login
+    let code = code.ok_or(Error::BadRequest(
+        ErrorKind::Forbidden,
+        "The login was not successful.",
+    ))?;
+
+    let metadata = oidc_metadata(&db, provider).await?;
+    let endpoint = |name: &'static str| {
+        metadata
+            .get(name)
+            .and_then(|endpoint| endpoint.as_str())
+            .ok_or(Error::BadServerResponse(
+                "OpenID Connect provider metadata is incomplete.",
+            ))
+    };
+    let token_endpoint = endpoint("token_endpoint")?;
+    let jwks_uri = reqwest::Url::parse(endpoint("jwks_uri")?).map_err(|_| {
+        Error::BadServerResponse("OpenID Connect provider has an invalid JWKS uri.")
+    })?;
+    let issuer = endpoint("issuer")?;
+
+    let redirect_uri = oidc_redirect_uri(&db);
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code.as_str()),
+        ("redirect_uri", redirect_uri.as_str()),
+        ("client_id", provider.client_id.as_str()),
+        ("code_verifier", session.code_verifier.as_str()),
+    ];
+    if let Some(client_secret) = &provider.client_secret {
+        form.push(("client_secret", client_secret.as_str()));
+    }
+
+    let tokens = db
+        .globals
+        .reqwest_client()
+        .post(token_endpoint)
+        .form(&form)
+        .send()
+        .await?;
+    if !tokens.status().is_success() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The OpenID Connect provider did not accept the code.",
+        ));
+    }
+    let tokens = tokens.json::<Value>().await?;
+    let id_token = tokens
+        .get("id_token")
+        .and_then(|id_token| id_token.as_str())
+        .ok_or(Error::BadServerResponse(
+            "OpenID Connect provider did not send an ID token.",
+        ))?;
+
+    let header = jsonwebtoken::decode_header(id_token)
+        .map_err(|_| Error::BadServerResponse("ID token is invalid."))?;
+    let keys = globals::fetch_jwks(db.globals.reqwest_client(), jwks_uri).await?;
+    let (decoding_key, algorithm) = match &header.kid {
+        Some(kid) => keys.get(kid),
+        None if keys.len() == 1 => keys.values().next(),
+        None => None,
+    }
+    .ok_or(Error::BadServerResponse(
+        "ID token is signed by an unknown key.",
+    ))?;
+
+    let mut validation = jsonwebtoken::Validation::new(*algorithm);
+    validation.iss = Some(issuer.to_owned());
+    validation.set_audience(&[&provider.client_id]);
+    let claims = jsonwebtoken::decode::<Value>(id_token, decoding_key, &validation)
+        .map_err(|_| Error::BadServerResponse("ID token is invalid."))?
+        .claims;
+
+    if claims.get("nonce").and_then(|nonce| nonce.as_str()) != Some(session.nonce.as_str()) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The ID token belongs to another login.",
+        ));
+    }
+
+    let parse_claim = |name: &str| {
+        claims
+            .get(name)
+            .and_then(|claim| claim.as_str())
+            .and_then(|localpart| {
+                UserId::parse_with_server_name(localpart.to_lowercase(), db.globals.server_name())
+                    .ok()
+            })
+    };
+    let user_id = parse_claim("preferred_username")
+        .or_else(|| parse_claim("sub"))
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidUsername,
+            "The OpenID Connect user has no valid username.",
+        ))?;
+
+    finish_login(&db, &user_id, provider.auto_register, &session.redirect_url)
+}
+
+/// The url OpenID Connect providers send the user back to. It has to be registered at the
+/// provider.
+fn oidc_redirect_uri(db: &Database<'_>) -> String {
+    format!(
+        "{}/_conduit/client/sso/oidc/callback",
+        db.globals.public_baseurl()
+    )
+}
+
+/// Downloads the discovery document of the provider, which contains its endpoints.
+async fn oidc_metadata(db: &Database<'_>, provider: &OidcProvider) -> Result<Value> {
+    let metadata = db
+        .globals
+        .reqwest_client()
+        .get(&format!(
+            "{}/.well-known/openid-configuration",
+            provider.issuer
+        ))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Value>()
+        .await?;
+
+    // Tokens of other issuers must not be accepted, even if the document links to them
+    if metadata
+        .get("issuer")
+        .and_then(|issuer| issuer.as_str())
+        .map(|issuer| issuer.trim_end_matches('/'))
+        != Some(provider.issuer.as_str())
+    {
+        return Err(Error::BadServerResponse(
+            "OpenID Connect provider metadata belongs to another issuer.",
+        ));
+    }
+
+    Ok(metadata)
+}
+
+/// Registers the user if allowed and sends the browser back to the client with a login token.
+fn finish_login(
+    db: &Database<'_>,
+    user_id: &UserId,
+    auto_register: bool,
+    redirect_url: &str,
+) -> Result<Response<'static>> {
+    if !db.users.exists(user_id)? {
+        if !auto_register {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "There is no account for this user.",
+            ));
+        }
+
+        create_external_user(db, user_id)?;
+    }
+
+    if db.users.is_deactivated(user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::UserDeactivated,
+            "The user has been deactivated",
+        ));
+    }
+
+    let expires_at = utils::millis_since_unix_epoch() + LOGIN_TOKEN_LIFETIME.as_millis() as u64;
+    let login_token = db
+        .users
+        .create_login_token(user_id, LOGIN_TOKEN_LENGTH, expires_at)?;
+
+    let separator = if redirect_url.contains('?') { '&' } else { '?' };
+    redirect(format!(
+        "{}{}loginToken={}",
+        redirect_url,
+        separator,
+        utils::percent_encode(&login_token)
+    ))
+}
+
+fn redirect(location: String) -> Result<Response<'static>> {
+    Ok(Response::build()
+        .status(Status::Found)
+        .raw_header("Location", location)
+        .finalize())
+}
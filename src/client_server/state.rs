@@ -1,5 +1,5 @@
-use super::State;
-use crate::{pdu::PduBuilder, ConduitResult, Database, Error, Ruma};
+use super::{validate_canonical_alias, State};
+use crate::{pdu::PduBuilder, ConduitResult, Database, Error, PduEvent, Result, Ruma};
 use ruma::{
     api::client::{
         error::ErrorKind,
@@ -8,8 +8,8 @@ use ruma::{
             send_state_event_for_empty_key, send_state_event_for_key,
         },
     },
-    events::{room::canonical_alias, EventType},
-    Raw,
+    events::EventType,
+    RoomId, UserId,
 };
 
 #[cfg(feature = "conduit_bin")]
@@ -19,7 +19,7 @@ use rocket::{get, put};
     feature = "conduit_bin",
     put("/_matrix/client/r0/rooms/<_>/state/<_>/<_>", data = "<body>")
 )]
-pub fn send_state_event_for_key_route(
+pub async fn send_state_event_for_key_route(
     db: State<'_, Database<'_>>,
     body: Ruma<send_state_event_for_key::IncomingRequest>,
 ) -> ConduitResult<send_state_event_for_key::Response> {
@@ -34,34 +34,7 @@ pub fn send_state_event_for_key_route(
     .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?;
 
     if body.event_type == EventType::RoomCanonicalAlias {
-        let canonical_alias = serde_json::from_value::<
-            Raw<canonical_alias::CanonicalAliasEventContent>,
-        >(content.clone())
-        .expect("from_value::<Raw<..>> can never fail")
-        .deserialize()
-        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid canonical alias."))?;
-
-        let mut aliases = canonical_alias.alt_aliases;
-
-        if let Some(alias) = canonical_alias.alias {
-            aliases.push(alias);
-        }
-
-        for alias in aliases {
-            if alias.server_name() != db.globals.server_name()
-                || db
-                    .rooms
-                    .id_from_alias(&alias)?
-                    .filter(|room| room == &body.room_id) // Make sure it's the right room
-                    .is_none()
-            {
-                return Err(Error::BadRequest(
-                    ErrorKind::Forbidden,
-                    "You are only allowed to send canonical_alias \
-                    events when it's aliases already exists",
-                ));
-            }
-        }
+        validate_canonical_alias(&db, &body.room_id, &content).await?;
     }
 
     let event_id = db.rooms.append_pdu(
@@ -78,6 +51,14 @@ pub fn send_state_event_for_key_route(
         &db.account_data,
     )?;
 
+    db.pushers.queue_notifications(
+        &event_id,
+        &db.rooms,
+        &db.users,
+        &db.account_data,
+        &db.globals,
+    )?;
+
     Ok(send_state_event_for_key::Response { event_id }.into())
 }
 
@@ -85,7 +66,7 @@ pub fn send_state_event_for_key_route(
     feature = "conduit_bin",
     put("/_matrix/client/r0/rooms/<_>/state/<_>", data = "<body>")
 )]
-pub fn send_state_event_for_empty_key_route(
+pub async fn send_state_event_for_empty_key_route(
     db: State<'_, Database<'_>>,
     body: Ruma<send_state_event_for_empty_key::IncomingRequest>,
 ) -> ConduitResult<send_state_event_for_empty_key::Response> {
@@ -100,6 +81,8 @@ pub fn send_state_event_for_empty_key_route(
         sender_id,
         device_id,
         json_body,
+        client_ip,
+        appservice_id,
     } = body;
 
     Ok(send_state_event_for_empty_key::Response {
@@ -115,14 +98,22 @@ pub fn send_state_event_for_empty_key_route(
                 sender_id,
                 device_id,
                 json_body,
+                client_ip,
+                appservice_id,
             },
-        )?
+        )
+        .await?
         .0
         .event_id,
     }
     .into())
 }
 
+/// # `GET /_matrix/client/r0/rooms/{roomId}/state`
+///
+/// Returns the state of the room.
+///
+/// - Members see the current state, users that left see the state right after they left
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/rooms/<_>/state", data = "<body>")
@@ -133,24 +124,22 @@ pub fn get_state_events_route(
 ) -> ConduitResult<get_state_events::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    if !db.rooms.is_joined(sender_id, &body.room_id)? {
-        return Err(Error::BadRequest(
-            ErrorKind::Forbidden,
-            "You don't have permission to view the room state.",
-        ));
-    }
+    let state = match left_at(&db, sender_id, &body.room_id)? {
+        Some(count) => db.rooms.room_state_at(&body.room_id, count)?,
+        None => db.rooms.room_state_full(&body.room_id)?,
+    };
 
     Ok(get_state_events::Response {
-        room_state: db
-            .rooms
-            .room_state_full(&body.room_id)?
-            .values()
-            .map(|pdu| pdu.to_state_event())
-            .collect(),
+        room_state: state.values().map(|pdu| pdu.to_state_event()).collect(),
     }
     .into())
 }
 
+/// # `GET /_matrix/client/r0/rooms/{roomId}/state/{eventType}/{stateKey}`
+///
+/// Returns the content of a state event of the room.
+///
+/// - Members see the current state, users that left see the state right after they left
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/rooms/<_>/state/<_>/<_>", data = "<body>")
@@ -161,20 +150,17 @@ pub fn get_state_events_for_key_route(
 ) -> ConduitResult<get_state_events_for_key::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    if !db.rooms.is_joined(sender_id, &body.room_id)? {
-        return Err(Error::BadRequest(
-            ErrorKind::Forbidden,
-            "You don't have permission to view the room state.",
-        ));
-    }
-
-    let event = db
-        .rooms
-        .room_state_get(&body.room_id, &body.event_type, &body.state_key)?
-        .ok_or(Error::BadRequest(
-            ErrorKind::NotFound,
-            "State event not found.",
-        ))?;
+    let event = state_get(
+        &db,
+        sender_id,
+        &body.room_id,
+        &body.event_type,
+        &body.state_key,
+    )?
+    .ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "State event not found.",
+    ))?;
 
     Ok(get_state_events_for_key::Response {
         content: serde_json::value::to_raw_value(&event.content)
@@ -183,6 +169,11 @@ pub fn get_state_events_for_key_route(
     .into())
 }
 
+/// # `GET /_matrix/client/r0/rooms/{roomId}/state/{eventType}`
+///
+/// Returns the content of a state event of the room.
+///
+/// - Members see the current state, users that left see the state right after they left
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/rooms/<_>/state/<_>", data = "<body>")
@@ -193,20 +184,9 @@ pub fn get_state_events_for_empty_key_route(
 ) -> ConduitResult<get_state_events_for_empty_key::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    if !db.rooms.is_joined(sender_id, &body.room_id)? {
-        return Err(Error::BadRequest(
-            ErrorKind::Forbidden,
-            "You don't have permission to view the room state.",
-        ));
-    }
-
-    let event = db
-        .rooms
-        .room_state_get(&body.room_id, &body.event_type, "")?
-        .ok_or(Error::BadRequest(
-            ErrorKind::NotFound,
-            "State event not found.",
-        ))?;
+    let event = state_get(&db, sender_id, &body.room_id, &body.event_type, "")?.ok_or(
+        Error::BadRequest(ErrorKind::NotFound, "State event not found."),
+    )?;
 
     Ok(get_state_events_for_empty_key::Response {
         content: serde_json::value::to_raw_value(&event)
@@ -214,3 +194,81 @@ pub fn get_state_events_for_empty_key_route(
     }
     .into())
 }
+
+/// Returns the count of the leave event if the user left the room, because they can only see the
+/// state until then. Members see the current state and get `None`, other users are forbidden.
+fn left_at(db: &Database<'_>, user_id: &UserId, room_id: &RoomId) -> Result<Option<u64>> {
+    let is_joined = db.rooms.is_joined(user_id, room_id)?;
+
+    let leave_count = if !is_joined && db.rooms.is_left(user_id, room_id)? {
+        match db
+            .rooms
+            .room_state_get(room_id, &EventType::RoomMember, user_id.as_str())?
+        {
+            Some(pdu) => db.rooms.get_pdu_count(&pdu.event_id)?,
+            None => None,
+        }
+    } else {
+        None
+    };
+
+    visible_state_until(is_joined, leave_count)
+}
+
+/// See [`left_at`](fn.left_at.html), `leave_count` is the count of the leave event of a user
+/// that left.
+fn visible_state_until(is_joined: bool, leave_count: Option<u64>) -> Result<Option<u64>> {
+    if is_joined {
+        return Ok(None);
+    }
+
+    leave_count.map(Some).ok_or(Error::BadRequest(
+        ErrorKind::Forbidden,
+        "You don't have permission to view the room state.",
+    ))
+}
+
+/// Returns a state event the user can see. The current state is looked up directly, only users
+/// that left need the state at their leave.
+fn state_get(
+    db: &Database<'_>,
+    user_id: &UserId,
+    room_id: &RoomId,
+    event_type: &EventType,
+    state_key: &str,
+) -> Result<Option<PduEvent>> {
+    match left_at(db, user_id, room_id)? {
+        Some(count) => Ok(db
+            .rooms
+            .room_state_at(room_id, count)?
+            .remove(&(event_type.clone(), state_key.to_owned()))),
+        None => db.rooms.room_state_get(room_id, event_type, state_key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::visible_state_until;
+    use crate::Error;
+    use ruma::api::client::error::ErrorKind;
+
+    #[test]
+    fn members_see_the_current_state() {
+        assert_eq!(visible_state_until(true, None).unwrap(), None);
+        // Users that joined again see the current state, not the one of their old leave
+        assert_eq!(visible_state_until(true, Some(7)).unwrap(), None);
+    }
+
+    #[test]
+    fn users_that_left_see_the_state_at_their_leave() {
+        assert_eq!(visible_state_until(false, Some(7)).unwrap(), Some(7));
+    }
+
+    #[test]
+    fn other_users_are_forbidden() {
+        assert!(matches!(
+            visible_state_until(false, None),
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+    }
+}
@@ -1,19 +1,27 @@
 use super::State;
-use crate::{ConduitResult, Database, Error, Ruma};
+use crate::{database::rooms::Viewer, utils, ConduitResult, Database, Error, Result, Ruma};
+use rocket::response::content::Json;
 use ruma::{
-    api::client::r0::sync::sync_events,
+    api::client::{
+        error::ErrorKind,
+        r0::{membership::joined_members, sync::sync_events},
+    },
     events::{room::member::MembershipState, AnySyncEphemeralRoomEvent, EventType},
-    Raw, RoomId, UserId,
+    DeviceId, Raw, RoomId, UserId,
 };
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, tokio};
+use serde_json::json;
 use std::{
     collections::{hash_map, BTreeMap, HashMap, HashSet},
-    convert::TryFrom,
+    convert::{TryFrom, TryInto},
     time::Duration,
 };
 
+/// How many events `/rooms/{roomId}/initialSync` returns
+const INITIAL_SYNC_LIMIT: usize = 20;
+
 /// # `GET /_matrix/client/r0/sync`
 ///
 /// Synchronize the client's state with the latest state on the server.
@@ -25,32 +33,149 @@ use std::{
 /// - To get incremental updates, you can call this endpoint with a `since` parameter. This will
 /// return all recent events, state updates and more data that happened since the last /sync
 /// request.
+/// - The `filter` is the id of a stored filter or a filter as JSON. It can limit the rooms, the
+/// timeline and the state by types, senders and rooms, set the timeline `limit` and request the
+/// events in the `federation` `event_format`
+/// - With `lazy_load_members` in the room state filter, only the member events of the timeline
+/// senders and the user are sent, and only if the device didn't get them yet or
+/// `include_redundant_members` is true
+/// - The state of joined rooms only contains the state events that changed since `since` and are
+/// not in the timeline, unless the user joined since then or `full_state` is true
+/// - If nothing changed, the request waits up to `timeout`, at most 30 seconds, and returns the
+/// new data as soon as something for the device changes
 #[cfg_attr(
     feature = "conduit_bin",
-    get("/_matrix/client/r0/sync", data = "<body>")
+    get("/_matrix/client/r0/sync?<filter>", data = "<body>")
 )]
 pub async fn sync_events_route(
     db: State<'_, Database<'_>>,
     body: Ruma<sync_events::Request>,
+    filter: Option<String>,
 ) -> ConduitResult<sync_events::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
     let device_id = body.device_id.as_ref().expect("user is authenticated");
 
-    // TODO: match body.set_presence {
-    db.rooms.edus.ping_presence(&sender_id)?;
+    // Counted until the response is sent
+    let _active_sync = db.globals.metrics().map(|metrics| metrics.sync_started());
 
-    // Setup watchers, so if there's no response, we can wait for them
-    let watcher = db.watch(sender_id, device_id);
+    if db.globals.allow_presence() {
+        // TODO: match body.set_presence {
+        db.rooms.edus.ping_presence(&sender_id)?;
 
-    let next_batch = db.globals.current_count()?.to_string();
+        // Users that were set unavailable automatically are back now
+        if db.rooms.edus.is_idle(&sender_id)? {
+            let status_msg = db
+                .rooms
+                .edus
+                .current_presence(&sender_id)?
+                .and_then(|p| p.content.status_msg);
+
+            for room_id in db.rooms.rooms_joined(&sender_id) {
+                db.rooms.edus.update_presence(
+                    &sender_id,
+                    &room_id?,
+                    ruma::events::presence::PresenceEvent {
+                        content: ruma::events::presence::PresenceEventContent {
+                            avatar_url: db.users.avatar_url(&sender_id)?,
+                            currently_active: None,
+                            displayname: db.users.displayname(&sender_id)?,
+                            last_active_ago: Some(
+                                utils::millis_since_unix_epoch()
+                                    .try_into()
+                                    .expect("time is valid"),
+                            ),
+                            presence: ruma::presence::PresenceState::Online,
+                            status_msg: status_msg.clone(),
+                        },
+                        sender: sender_id.clone(),
+                    },
+                    &db.globals,
+                )?;
+            }
+        }
+    }
 
-    let mut joined_rooms = BTreeMap::new();
     let since = body
         .since
         .clone()
         .and_then(|string| string.parse().ok())
         .unwrap_or(0);
 
+    let filter = super::load_filter(&db, sender_id, filter.as_deref())?;
+
+    // Hang up to 30 seconds if there is nothing new, so requests are not spammed
+    let mut duration = body.timeout.unwrap_or_default();
+    if duration.as_secs() > 30 {
+        duration = Duration::from_secs(30);
+    }
+    let mut delay = tokio::time::delay_for(duration);
+
+    loop {
+        // Setup watchers before building the response, so no change is missed while waiting
+        let watcher = db.watch(sender_id, device_id);
+
+        // Building the response reads a lot from the database, the executor can run other
+        // requests on other threads meanwhile
+        let response = tokio::task::block_in_place(|| {
+            sync_response(&db, sender_id, device_id, since, body.full_state, &filter)
+        })?;
+
+        if body.full_state
+            || !response.rooms.is_empty()
+            || !response.presence.is_empty()
+            || !response.account_data.is_empty()
+            || !response.device_lists.is_empty()
+            || !response.device_one_time_keys_count.is_empty()
+            || !response.to_device.is_empty()
+        {
+            return Ok(response.into());
+        }
+
+        // When something changed, the response is built again. The change can still be
+        // filtered out, then the request keeps waiting until the timeout
+        tokio::select! {
+            _ = &mut delay => return Ok(response.into()),
+            _ = watcher => {}
+            _ = db.globals.shutdown().wait() => return Ok(response.into()),
+        }
+    }
+}
+
+/// Collects everything that happened since `since` for the device. With `full_state`, the whole
+/// state of every joined room is sent instead of the state that changed.
+fn sync_response(
+    db: &Database<'_>,
+    sender_id: &UserId,
+    device_id: &DeviceId,
+    since: u64,
+    full_state: bool,
+    filter: &serde_json::Value,
+) -> Result<sync_events::Response> {
+    let next_batch = db.globals.current_count()?.to_string();
+
+    let empty_filter = serde_json::json!({});
+    let room_filter = filter.get("room").unwrap_or(&empty_filter);
+    let timeline_filter = room_filter.get("timeline").unwrap_or(&empty_filter);
+    let state_filter = room_filter.get("state").unwrap_or(&empty_filter);
+
+    let timeline_limit = super::timeline_limit(timeline_filter);
+    let lazy_load_members = state_filter
+        .get("lazy_load_members")
+        .and_then(|lazy_load| lazy_load.as_bool())
+        .unwrap_or(false);
+    let include_redundant_members = state_filter
+        .get("include_redundant_members")
+        .and_then(|include| include.as_bool())
+        .unwrap_or(false);
+    let federation_format = super::federation_format(filter);
+
+    // An initial sync starts over, so the client needs all member events again
+    if lazy_load_members && since == 0 {
+        db.rooms.lazy_load_reset(sender_id, device_id, None)?;
+    }
+
+    let mut joined_rooms = BTreeMap::new();
+
     let mut presence_updates = HashMap::new();
     let mut left_encrypted_users = HashSet::new(); // Users that have left any encrypted rooms the sender was in
     let mut device_list_updates = HashSet::new();
@@ -63,22 +188,38 @@ pub async fn sync_events_route(
             .filter_map(|r| r.ok()),
     );
 
+    // Events of ignored users are left out of the timelines
+    let ignored_users = db.account_data.ignored_users(&sender_id)?;
+
     for room_id in db.rooms.rooms_joined(&sender_id) {
         let room_id = room_id?;
 
-        let mut non_timeline_pdus = db
+        // Take the last events for the timeline. The /sync response doesn't always return all
+        // messages, so we say the output is limited if there are more events before them
+        let (timeline_pdus, limited) = super::last_events(
+            db.rooms
+                .pdus_since(&sender_id, &room_id, since)?
+                .filter_map(|r| r.ok()) // Filter out buggy events
+                .filter(|pdu| super::filter_allows_event(timeline_filter, pdu))
+                .filter(|pdu| !super::sent_by_ignored_user(&ignored_users, pdu)),
+            timeline_limit,
+        );
+
+        // Events the user could not see before joining are left out
+        let mut visibility = db
             .rooms
-            .pdus_since(&sender_id, &room_id, since)?
-            .filter_map(|r| r.ok()); // Filter out buggy events
-
-        // Take the last 10 events for the timeline
-        let timeline_pdus = non_timeline_pdus
-            .by_ref()
-            .rev()
-            .take(10)
-            .collect::<Vec<_>>()
+            .event_visibility(Viewer::User(&sender_id), &room_id)?;
+        let timeline_pdus = timeline_pdus
             .into_iter()
-            .rev()
+            .filter(|pdu| {
+                db.rooms
+                    .get_pdu_count(&pdu.event_id)
+                    .ok()
+                    .flatten()
+                    .map_or(false, |count| {
+                        visibility.can_see(count, pdu).unwrap_or(false)
+                    })
+            })
             .collect::<Vec<_>>();
 
         let send_notification_counts = !timeline_pdus.is_empty()
@@ -88,18 +229,6 @@ pub async fn sync_events_route(
                 .last_privateread_update(&sender_id, &room_id)?
                 > since;
 
-        // They /sync response doesn't always return all messages, so we say the output is
-        // limited unless there are events in non_timeline_pdus
-        let mut limited = false;
-
-        let mut state_pdus = Vec::new();
-        for pdu in non_timeline_pdus {
-            if pdu.state_key.is_some() {
-                state_pdus.push(pdu);
-            }
-            limited = true;
-        }
-
         let encrypted_room = db
             .rooms
             .room_state_get(&room_id, &EventType::RoomEncryption, "")?
@@ -137,8 +266,12 @@ pub async fn sync_events_route(
                     if !share_encrypted_room(&db, &sender_id, &user_id, &room_id) {
                         device_list_updates.insert(user_id);
                     }
-                } else if encrypted_room && content.membership == MembershipState::Leave {
-                    // Write down users that have left encrypted rooms we are in
+                } else if encrypted_room
+                    && (content.membership == MembershipState::Leave
+                        || content.membership == MembershipState::Ban)
+                {
+                    // Write down users that have left (or were banned from) encrypted rooms we
+                    // are in
                     left_encrypted_users.insert(
                         UserId::try_from(state_key)
                             .map_err(|_| Error::bad_database("Invalid UserId in member PDU."))?,
@@ -246,26 +379,15 @@ pub async fn sync_events_route(
             (None, None, Vec::new())
         };
 
-        let notification_count = if send_notification_counts {
-            if let Some(last_read) = db.rooms.edus.private_read_get(&room_id, &sender_id)? {
-                Some(
-                    (db.rooms
-                        .pdus_since(&sender_id, &room_id, last_read)?
-                        .filter_map(|pdu| pdu.ok()) // Filter out buggy events
-                        .filter(|pdu| {
-                            matches!(
-                                pdu.kind.clone(),
-                                EventType::RoomMessage | EventType::RoomEncrypted
-                            )
-                        })
-                        .count() as u32)
-                        .into(),
-                )
-            } else {
-                None
-            }
+        let (notification_count, highlight_count) = if send_notification_counts {
+            let (notification_count, highlight_count) =
+                db.rooms.edus.notification_counts(&room_id, &sender_id)?;
+            (
+                Some(notification_count.into()),
+                Some(highlight_count.into()),
+            )
         } else {
-            None
+            (None, None)
         };
 
         let prev_batch = timeline_pdus.first().map_or(Ok::<_, Error>(None), |e| {
@@ -277,17 +399,127 @@ pub async fn sync_events_route(
             ))
         })?;
 
+        let mut room_state_pdus = Vec::new();
+        if joined_since_last_sync || full_state {
+            room_state_pdus.extend(
+                db.rooms
+                    .room_state_full(&room_id)?
+                    .into_iter()
+                    .filter(|((event_type, _), _)| {
+                        !lazy_load_members || *event_type != EventType::RoomMember
+                    })
+                    .map(|(_, pdu)| pdu)
+                    .filter(|pdu| super::filter_allows_event(state_filter, pdu)),
+            );
+        } else {
+            // Only the state that changed since the last sync and is not in the timeline is
+            // sent, the newest event of every state key. This includes state events in the gap of
+            // a limited timeline, like a tombstone, and state events that are hidden from the
+            // timeline by the filter or ignored users, the client would miss them otherwise
+            let timeline_event_ids = timeline_pdus
+                .iter()
+                .map(|pdu| &pdu.event_id)
+                .collect::<HashSet<_>>();
+            let mut gap_state = HashMap::new();
+            for pdu in db
+                .rooms
+                .pdus_since(&sender_id, &room_id, since)?
+                .filter_map(|r| r.ok())
+                .filter(|pdu| pdu.state_key.is_some())
+                .filter(|pdu| !timeline_event_ids.contains(&pdu.event_id))
+            {
+                if lazy_load_members && pdu.kind == EventType::RoomMember {
+                    continue;
+                }
+                let state_key = pdu
+                    .state_key
+                    .clone()
+                    .expect("only state events are collected");
+                gap_state.insert((pdu.kind.clone(), state_key), pdu);
+            }
+
+            room_state_pdus.extend(
+                gap_state
+                    .into_iter()
+                    .map(|(_, pdu)| pdu)
+                    .filter(|pdu| super::filter_allows_event(state_filter, pdu)),
+            );
+        }
+
+        if lazy_load_members {
+            // After a new join or with full_state the client starts over with the state of the
+            // room
+            if (joined_since_last_sync || full_state) && since != 0 {
+                db.rooms
+                    .lazy_load_reset(sender_id, device_id, Some(&room_id))?;
+            }
+
+            // Member events in the timeline don't have to be sent again
+            for pdu in timeline_pdus
+                .iter()
+                .filter(|pdu| pdu.kind == EventType::RoomMember)
+            {
+                db.rooms.lazy_load_mark_sent(sender_id, device_id, pdu)?;
+            }
+
+            let members = timeline_pdus
+                .iter()
+                .map(|pdu| &pdu.sender)
+                .chain(std::iter::once(sender_id))
+                .collect::<HashSet<_>>();
+            for member in members {
+                if let Some(member_event) =
+                    db.rooms
+                        .room_state_get(&room_id, &EventType::RoomMember, member.as_str())?
+                {
+                    if super::filter_allows_event(state_filter, &member_event)
+                        && (include_redundant_members
+                            || !db
+                                .rooms
+                                .lazy_load_was_sent(sender_id, device_id, &member_event)?)
+                    {
+                        db.rooms
+                            .lazy_load_mark_sent(sender_id, device_id, &member_event)?;
+                        room_state_pdus.push(member_event);
+                    }
+                }
+            }
+        }
+
+        let state_events = room_state_pdus
+            .into_iter()
+            .map(|pdu| {
+                if federation_format {
+                    pdu.to_federation_event()
+                } else {
+                    pdu.to_sync_state_event()
+                }
+            })
+            .collect();
+
         let room_events = timeline_pdus
             .into_iter()
-            .map(|pdu| pdu.to_sync_room_event())
-            .collect::<Vec<_>>();
+            .map(|mut pdu| {
+                db.rooms.add_bundled_relations(&mut pdu, &sender_id)?;
+                Ok(if federation_format {
+                    pdu.to_federation_event()
+                } else {
+                    pdu.to_sync_room_event()
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut edus = Vec::new();
 
-        let mut edus = db
+        let receipts = db
             .rooms
             .edus
             .readreceipts_since(&room_id, since)?
             .filter_map(|r| r.ok()) // Filter out buggy events
-            .collect::<Vec<_>>();
+            .filter_map(|r| serde_json::from_str::<serde_json::Value>(r.json().get()).ok());
+        if let Some(receipt) = aggregate_receipts(receipts) {
+            edus.push(serde_json::from_value(receipt).expect("event is valid, we just created it"));
+        }
 
         if db.rooms.edus.last_typing_update(&room_id, &db.globals)? > since {
             edus.push(
@@ -320,7 +552,7 @@ pub async fn sync_events_route(
                 invited_member_count: invited_member_count.map(|n| (n as u32).into()),
             },
             unread_notifications: sync_events::UnreadNotificationsCount {
-                highlight_count: None,
+                highlight_count,
                 notification_count,
             },
             timeline: sync_events::Timeline {
@@ -330,29 +562,24 @@ pub async fn sync_events_route(
             },
             // TODO: state before timeline
             state: sync_events::State {
-                events: if joined_since_last_sync {
-                    db.rooms
-                        .room_state_full(&room_id)?
-                        .into_iter()
-                        .map(|(_, pdu)| pdu.to_sync_state_event())
-                        .collect()
-                } else {
-                    Vec::new()
-                },
+                events: state_events,
             },
             ephemeral: sync_events::Ephemeral { events: edus },
         };
 
-        if !joined_room.is_empty() {
+        if !joined_room.is_empty() && super::filter_allows_room(room_filter, &room_id) {
             joined_rooms.insert(room_id.clone(), joined_room);
         }
 
         // Take presence updates from this room
-        for (user_id, presence) in
+        let room_presence_updates = if db.globals.allow_presence() {
             db.rooms
                 .edus
                 .presence_since(&room_id, since, &db.rooms, &db.globals)?
-        {
+        } else {
+            HashMap::new()
+        };
+        for (user_id, presence) in room_presence_updates {
             match presence_updates.entry(user_id) {
                 hash_map::Entry::Vacant(v) => {
                     v.insert(presence);
@@ -382,69 +609,120 @@ pub async fn sync_events_route(
         }
     }
 
+    let include_leave = room_filter
+        .get("include_leave")
+        .and_then(|include| include.as_bool())
+        .unwrap_or(false);
+
     let mut left_rooms = BTreeMap::new();
     for room_id in db.rooms.rooms_left(&sender_id) {
         let room_id = room_id?;
-        let pdus = db.rooms.pdus_since(&sender_id, &room_id, since)?;
-        let room_events = pdus
-            .filter_map(|pdu| pdu.ok()) // Filter out buggy events
-            .map(|pdu| pdu.to_sync_room_event())
-            .collect();
+
+        // The current member event of the user is the leave (or ban)
+        let leave_count = match db
+            .rooms
+            .room_state_get(&room_id, &EventType::RoomMember, sender_id.as_str())?
+            .map(|pdu| db.rooms.get_pdu_count(&pdu.event_id))
+            .transpose()?
+            .flatten()
+        {
+            Some(leave_count) => leave_count,
+            None => continue,
+        };
+
+        // Leaves are only sent once, rooms left before the initial sync only with include_leave
+        let left_since_last_sync = leave_count > since;
+        if !left_since_last_sync || (since == 0 && !include_leave) {
+            continue;
+        }
+
+        // The timeline ends with the leave, the user can't see the events after it
+        let mut visibility = db
+            .rooms
+            .event_visibility(Viewer::User(&sender_id), &room_id)?;
+        let mut timeline_pdus = db
+            .rooms
+            .pdus_until(&sender_id, &room_id, leave_count + 1)
+            .filter_map(|r| r.ok()) // Filter out buggy events
+            .take_while(|&(count, _)| count > since)
+            .filter(|(_, pdu)| super::filter_allows_event(timeline_filter, pdu))
+            .filter(|(_, pdu)| !super::sent_by_ignored_user(&ignored_users, pdu))
+            .filter(|(count, pdu)| visibility.can_see(*count, pdu).unwrap_or(false))
+            .take(timeline_limit + 1)
+            .collect::<Vec<_>>();
+        let limited = timeline_pdus.len() > timeline_limit;
+        timeline_pdus.truncate(timeline_limit);
+        timeline_pdus.reverse();
+
+        // The client doesn't know the state before the timeline if it skips events
+        let state_events = match timeline_pdus.first() {
+            Some((first_count, _)) if limited || since == 0 => db
+                .rooms
+                .room_state_at(&room_id, first_count - 1)?
+                .into_iter()
+                .map(|(_, pdu)| pdu)
+                .filter(|pdu| super::filter_allows_event(state_filter, pdu))
+                .map(|pdu| {
+                    if federation_format {
+                        pdu.to_federation_event()
+                    } else {
+                        pdu.to_sync_state_event()
+                    }
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let prev_batch = timeline_pdus.first().map(|(count, _)| count.to_string());
+
+        let room_events = timeline_pdus
+            .into_iter()
+            .map(|(_, mut pdu)| {
+                db.rooms.add_bundled_relations(&mut pdu, &sender_id)?;
+                Ok(if federation_format {
+                    pdu.to_federation_event()
+                } else {
+                    pdu.to_sync_room_event()
+                })
+            })
+            .collect::<Result<_>>()?;
 
         let left_room = sync_events::LeftRoom {
             account_data: sync_events::AccountData { events: Vec::new() },
             timeline: sync_events::Timeline {
-                limited: false,
-                prev_batch: Some(next_batch.clone()),
+                limited,
+                prev_batch,
                 events: room_events,
             },
-            state: sync_events::State { events: Vec::new() },
+            state: sync_events::State {
+                events: state_events,
+            },
         };
 
-        let mut left_since_last_sync = false;
-        for pdu in db.rooms.pdus_since(&sender_id, &room_id, since)? {
-            let pdu = pdu?;
-            if pdu.kind == EventType::RoomMember && pdu.state_key == Some(sender_id.to_string()) {
-                let content = serde_json::from_value::<
-                    Raw<ruma::events::room::member::MemberEventContent>,
-                >(pdu.content.clone())
-                .expect("Raw::from_value always works")
-                .deserialize()
-                .map_err(|_| Error::bad_database("Invalid PDU in database."))?;
-
-                if content.membership == MembershipState::Leave {
-                    left_since_last_sync = true;
-                    break;
-                }
-            }
-        }
-
-        if left_since_last_sync {
-            device_list_left.extend(
-                db.rooms
-                    .room_members(&room_id)
-                    .filter_map(|user_id| {
-                        Some(
-                            UserId::try_from(user_id.ok()?.clone())
-                                .map_err(|_| {
-                                    Error::bad_database("Invalid member event state key in db.")
-                                })
-                                .ok()?,
-                        )
-                    })
-                    .filter(|user_id| {
-                        // Don't send key updates from the sender to the sender
-                        sender_id != user_id
-                    })
-                    .filter(|user_id| {
-                        // Only send if the sender doesn't share any encrypted room with the target
-                        // anymore
-                        !share_encrypted_room(&db, sender_id, user_id, &room_id)
-                    }),
-            );
-        }
+        device_list_left.extend(
+            db.rooms
+                .room_members(&room_id)
+                .filter_map(|user_id| {
+                    Some(
+                        UserId::try_from(user_id.ok()?.clone())
+                            .map_err(|_| {
+                                Error::bad_database("Invalid member event state key in db.")
+                            })
+                            .ok()?,
+                    )
+                })
+                .filter(|user_id| {
+                    // Don't send key updates from the sender to the sender
+                    sender_id != user_id
+                })
+                .filter(|user_id| {
+                    // Only send if the sender doesn't share any encrypted room with the target
+                    // anymore
+                    !share_encrypted_room(&db, sender_id, user_id, &room_id)
+                }),
+        );
 
-        if !left_room.is_empty() {
+        if super::filter_allows_room(room_filter, &room_id) {
             left_rooms.insert(room_id.clone(), left_room);
         }
     }
@@ -452,6 +730,28 @@ pub async fn sync_events_route(
     let mut invited_rooms = BTreeMap::new();
     for room_id in db.rooms.rooms_invited(&sender_id) {
         let room_id = room_id?;
+
+        // Invites of other servers to rooms we don't know only have the stripped state they sent
+        if let Some((invite_count, invite_state)) = db.rooms.remote_invite(&sender_id, &room_id)? {
+            if invite_count > since && super::filter_allows_room(room_filter, &room_id) {
+                invited_rooms.insert(
+                    room_id.clone(),
+                    sync_events::InvitedRoom {
+                        invite_state: sync_events::InviteState {
+                            events: invite_state
+                                .into_iter()
+                                .map(|event| {
+                                    serde_json::from_value(event)
+                                        .expect("Raw::from_value always works")
+                                })
+                                .collect(),
+                        },
+                    },
+                );
+            }
+            continue;
+        }
+
         let mut invited_since_last_sync = false;
         for pdu in db.rooms.pdus_since(&sender_id, &room_id, since)? {
             let pdu = pdu?;
@@ -470,7 +770,7 @@ pub async fn sync_events_route(
             }
         }
 
-        if !invited_since_last_sync {
+        if !invited_since_last_sync || !super::filter_allows_room(room_filter, &room_id) {
             continue;
         }
 
@@ -511,6 +811,18 @@ pub async fn sync_events_route(
         }
     }
 
+    // A user can't be changed and left at the same time, the client stops tracking left users
+    for user_id in &device_list_left {
+        device_list_updates.remove(user_id);
+    }
+
+    // Device list changes are only part of incremental syncs, clients download all keys they need
+    // after the initial sync
+    if since == 0 {
+        device_list_updates.clear();
+        device_list_left.clear();
+    }
+
     // Remove all to-device events the device received *last time*
     db.users
         .remove_to_device_events(sender_id, device_id, since)?;
@@ -554,29 +866,132 @@ pub async fn sync_events_route(
         },
     };
 
-    // TODO: Retry the endpoint instead of returning (waiting for #118)
-    if !body.full_state
-        && response.rooms.is_empty()
-        && response.presence.is_empty()
-        && response.account_data.is_empty()
-        && response.device_lists.is_empty()
-        && response.device_one_time_keys_count.is_empty()
-        && response.to_device.is_empty()
-    {
-        // Hang a few seconds so requests are not spammed
-        // Stop hanging if new info arrives
-        let mut duration = body.timeout.unwrap_or_default();
-        if duration.as_secs() > 30 {
-            duration = Duration::from_secs(30);
-        }
-        let mut delay = tokio::time::delay_for(duration);
-        tokio::select! {
-            _ = &mut delay => {}
-            _ = watcher => {}
+    Ok(response)
+}
+
+/// # `GET /_matrix/client/r0/rooms/{roomId}/initialSync`
+///
+/// Returns the newest events and the state of a room.
+///
+/// - World readable rooms can be peeked into without joining them
+/// - Former members see the room as it was when they left
+/// - Events the user could not see when they were sent are left out
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/rooms/<_>/initialSync", data = "<body>")
+)]
+pub fn room_initial_sync_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<joined_members::Request>,
+) -> Result<Json<String>> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    let member_event =
+        db.rooms
+            .room_state_get(&body.room_id, &EventType::RoomMember, sender_id.as_str())?;
+    let membership = member_event
+        .as_ref()
+        .and_then(|pdu| pdu.content.get("membership")?.as_str().map(str::to_owned));
+    let world_readable = db.rooms.is_world_readable(&body.room_id)?;
+
+    if member_event.is_none() && !world_readable {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to view this room.",
+        ));
+    }
+
+    // The room ends at the last membership event, unless the user can still see new events
+    let until = match &member_event {
+        Some(pdu) if membership.as_deref() != Some("join") && !world_readable => db
+            .rooms
+            .get_pdu_count(&pdu.event_id)?
+            .ok_or_else(|| Error::bad_database("Member event has no count."))?,
+        _ => db.globals.current_count()?,
+    };
+
+    let mut visibility = db
+        .rooms
+        .event_visibility(Viewer::User(&sender_id), &body.room_id)?;
+    let mut events = db
+        .rooms
+        .pdus_until(&sender_id, &body.room_id, until + 1)
+        .filter_map(|r| r.ok()) // Filter out buggy events
+        .filter(|(count, pdu)| visibility.can_see(*count, pdu).unwrap_or(false))
+        .take(INITIAL_SYNC_LIMIT)
+        .collect::<Vec<_>>();
+    events.reverse();
+
+    let start = events.first().map_or(until, |(count, _)| *count);
+
+    Ok(Json(
+        json!({
+            "room_id": body.room_id,
+            "membership": membership,
+            "messages": {
+                "chunk": events
+                    .into_iter()
+                    .map(|(_, pdu)| pdu.to_room_event())
+                    .collect::<Vec<_>>(),
+                "start": start.to_string(),
+                "end": (until + 1).to_string(),
+            },
+            "state": db
+                .rooms
+                .state_at(&body.room_id, until)?
+                .values()
+                .map(|pdu| pdu.to_state_event())
+                .collect::<Vec<_>>(),
+            "visibility": if db.rooms.is_public_room(&body.room_id)? {
+                "public"
+            } else {
+                "private"
+            },
+        })
+        .to_string(),
+    ))
+}
+
+/// Combines the receipts of all users into one m.receipt event. Returns `None` if there are no
+/// receipts.
+fn aggregate_receipts(
+    receipts: impl Iterator<Item = serde_json::Value>,
+) -> Option<serde_json::Value> {
+    let mut receipt_content = serde_json::Map::new();
+    for receipt in receipts {
+        for (event_id, receipts) in receipt
+            .get("content")
+            .and_then(|content| content.as_object())
+            .into_iter()
+            .flatten()
+        {
+            let event_receipts = receipt_content
+                .entry(event_id.clone())
+                .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+
+            for (receipt_type, users) in receipts.as_object().into_iter().flatten() {
+                if let (Some(event_receipts), Some(users)) =
+                    (event_receipts.as_object_mut(), users.as_object().cloned())
+                {
+                    event_receipts
+                        .entry(receipt_type.clone())
+                        .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()))
+                        .as_object_mut()
+                        .expect("we only insert objects")
+                        .extend(users);
+                }
+            }
         }
     }
 
-    Ok(response.into())
+    if receipt_content.is_empty() {
+        None
+    } else {
+        Some(serde_json::json!({
+            "type": "m.receipt",
+            "content": receipt_content,
+        }))
+    }
 }
 
 fn share_encrypted_room(
@@ -599,3 +1014,93 @@ fn share_encrypted_room(
         })
         .any(|encrypted| encrypted)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::aggregate_receipts;
+    use serde_json::json;
+    use std::time::{Duration, Instant};
+
+    fn receipt(event_id: &str, user_id: &str, ts: u64) -> serde_json::Value {
+        json!({
+            "type": "m.receipt",
+            "content": { event_id: { "m.read": { user_id: { "ts": ts } } } },
+        })
+    }
+
+    #[test]
+    fn receipts_of_two_users_are_combined_into_one_event() {
+        let receipts = vec![
+            receipt("$a:example.com", "@alice:example.com", 1),
+            receipt("$a:example.com", "@bob:example.com", 2),
+            receipt("$b:example.com", "@carol:example.com", 3),
+        ];
+
+        assert_eq!(
+            aggregate_receipts(receipts.into_iter()),
+            Some(json!({
+                "type": "m.receipt",
+                "content": {
+                    "$a:example.com": {
+                        "m.read": {
+                            "@alice:example.com": { "ts": 1 },
+                            "@bob:example.com": { "ts": 2 },
+                        },
+                    },
+                    "$b:example.com": {
+                        "m.read": { "@carol:example.com": { "ts": 3 } },
+                    },
+                },
+            }))
+        );
+    }
+
+    #[test]
+    fn no_receipts_give_no_event() {
+        assert_eq!(aggregate_receipts(Vec::new().into_iter()), None);
+    }
+
+    #[test]
+    fn syncs_dont_wait_for_a_slow_write() {
+        // With a single executor thread, everything would run after the write without
+        // block_in_place
+        let mut runtime = tokio::runtime::Builder::new()
+            .threaded_scheduler()
+            .core_threads(1)
+            .enable_time()
+            .build()
+            .unwrap();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("pduid_pdu").unwrap();
+        tree.insert(b"pdu", b"{}").unwrap();
+
+        runtime.block_on(async {
+            let started = Instant::now();
+            let write = tokio::spawn({
+                let tree = tree.clone();
+                async move {
+                    tokio::task::block_in_place(|| {
+                        std::thread::sleep(Duration::from_secs(2));
+                        tree.insert(b"pdu2", b"{}").unwrap();
+                    });
+                }
+            });
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+
+            let syncs = (0..100)
+                .map(|_| {
+                    let tree = tree.clone();
+                    tokio::spawn(async move {
+                        tokio::task::block_in_place(|| tree.get(b"pdu").unwrap().is_some())
+                    })
+                })
+                .collect::<Vec<_>>();
+            for sync in syncs {
+                assert!(sync.await.unwrap());
+            }
+            assert!(started.elapsed() < Duration::from_secs(1));
+
+            write.await.unwrap();
+        });
+    }
+}
@@ -1,7 +1,10 @@
 use super::State;
-use crate::{ConduitResult, Database, Ruma};
+use crate::{ConduitResult, Database, Error, Ruma};
 use ruma::{
-    api::client::r0::tag::{create_tag, delete_tag, get_tags},
+    api::client::{
+        error::ErrorKind,
+        r0::tag::{create_tag, delete_tag, get_tags},
+    },
     events::EventType,
 };
 use std::collections::BTreeMap;
@@ -9,6 +12,12 @@ use std::collections::BTreeMap;
 #[cfg(feature = "conduit_bin")]
 use rocket::{delete, get, put};
 
+/// # `PUT /_matrix/client/r0/user/{userId}/rooms/{roomId}/tags/{tag}`
+///
+/// Adds a tag to the room or replaces its order.
+///
+/// - Tags are stored in the `m.tag` room account data, so `/sync` sends the changed event
+/// - The `order` has to be between 0 and 1
 #[cfg_attr(
     feature = "conduit_bin",
     put("/_matrix/client/r0/user/<_>/rooms/<_>/tags/<_>", data = "<body>")
@@ -19,6 +28,22 @@ pub fn update_tag_route(
 ) -> ConduitResult<create_tag::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
+    if sender_id != &body.user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You can only tag rooms for yourself.",
+        ));
+    }
+
+    if let Some(order) = body.tag_info.order {
+        if !(0.0..=1.0).contains(&order) {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "The order has to be between 0 and 1.",
+            ));
+        }
+    }
+
     let mut tags_event = db
         .account_data
         .get::<ruma::events::tag::TagEvent>(Some(&body.room_id), sender_id, EventType::Tag)?
@@ -43,6 +68,11 @@ pub fn update_tag_route(
     Ok(create_tag::Response.into())
 }
 
+/// # `DELETE /_matrix/client/r0/user/{userId}/rooms/{roomId}/tags/{tag}`
+///
+/// Removes a tag from the room.
+///
+/// - The `m.tag` event without the tag is sent with the next `/sync`
 #[cfg_attr(
     feature = "conduit_bin",
     delete("/_matrix/client/r0/user/<_>/rooms/<_>/tags/<_>", data = "<body>")
@@ -53,6 +83,13 @@ pub fn delete_tag_route(
 ) -> ConduitResult<delete_tag::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
+    if sender_id != &body.user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You can only remove tags of your own rooms.",
+        ));
+    }
+
     let mut tags_event = db
         .account_data
         .get::<ruma::events::tag::TagEvent>(Some(&body.room_id), sender_id, EventType::Tag)?
@@ -74,6 +111,9 @@ pub fn delete_tag_route(
     Ok(delete_tag::Response.into())
 }
 
+/// # `GET /_matrix/client/r0/user/{userId}/rooms/{roomId}/tags`
+///
+/// Returns the tags of the room.
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/user/<_>/rooms/<_>/tags", data = "<body>")
@@ -84,6 +124,13 @@ pub fn get_tags_route(
 ) -> ConduitResult<get_tags::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
+    if sender_id != &body.user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You can only get the tags of your own rooms.",
+        ));
+    }
+
     Ok(get_tags::Response {
         tags: db
             .account_data
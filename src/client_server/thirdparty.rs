@@ -1,10 +1,10 @@
 use crate::ConduitResult;
 use ruma::api::client::r0::thirdparty::get_protocols;
 
-use log::warn;
 #[cfg(feature = "conduit_bin")]
 use rocket::get;
 use std::collections::BTreeMap;
+use tracing::warn;
 
 #[cfg_attr(
     feature = "conduit_bin",
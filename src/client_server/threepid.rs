@@ -0,0 +1,523 @@
+use super::State;
+use crate::{server_server, utils, ConduitResult, Database, Error, Result, Ruma};
+use http::header::AUTHORIZATION;
+use lettre::{smtp::authentication::Credentials, SmtpClient, Transport};
+use lettre_email::EmailBuilder;
+use rocket::response::content::Json;
+use ruma::{
+    api::client::{
+        error::ErrorKind,
+        r0::account::{
+            request_password_change_token_via_email, request_registration_token_via_email, whoami,
+        },
+    },
+    UserId,
+};
+use serde_json::{json, Value};
+use tracing::warn;
+
+#[cfg(feature = "conduit_bin")]
+use rocket::{get, post};
+
+const SID_LENGTH: usize = 32;
+const EMAIL_TOKEN_LENGTH: usize = 32;
+
+/// # `POST /_matrix/client/r0/register/email/requestToken`
+///
+/// Sends a validation link to an email address that is not used by any account yet.
+///
+/// - The session can be used for the `m.login.email.identity` stage of registration
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/register/email/requestToken", data = "<body>")
+)]
+pub async fn request_registration_token_via_email_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<request_registration_token_via_email::Request>,
+) -> ConduitResult<request_registration_token_via_email::Response> {
+    if db.users.find_from_email(&body.email)?.is_some() {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidInUse,
+            "Email address is already in use.",
+        ));
+    }
+
+    let sid = send_validation_email(&db, &body.email, &body.client_secret).await?;
+
+    Ok(request_registration_token_via_email::Response {
+        sid,
+        submit_url: None,
+    }
+    .into())
+}
+
+/// # `POST /_matrix/client/r0/account/password/email/requestToken`
+///
+/// Sends a validation link to an email address that belongs to an account.
+///
+/// - The session can be used to reset the password of the account without logging in
+#[cfg_attr(
+    feature = "conduit_bin",
+    post(
+        "/_matrix/client/r0/account/password/email/requestToken",
+        data = "<body>"
+    )
+)]
+pub async fn request_password_change_token_via_email_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<request_password_change_token_via_email::Request>,
+) -> ConduitResult<request_password_change_token_via_email::Response> {
+    if db.users.find_from_email(&body.email)?.is_none() {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidNotFound,
+            "Email address is not used by any account.",
+        ));
+    }
+
+    let sid = send_validation_email(&db, &body.email, &body.client_secret).await?;
+
+    Ok(request_password_change_token_via_email::Response {
+        sid,
+        submit_url: None,
+    }
+    .into())
+}
+
+/// # `GET /_conduit/client/email/submitToken`
+///
+/// The link in validation emails points here. Marks the session as validated.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_conduit/client/email/submitToken?<sid>&<client_secret>&<token>")
+)]
+pub fn submit_email_token_route(
+    db: State<'_, Database<'_>>,
+    sid: String,
+    client_secret: String,
+    token: String,
+) -> Result<String> {
+    if db
+        .threepid_sessions
+        .validate(&sid, &client_secret, &token)?
+    {
+        Ok("Your email address has been validated. You can now return to your client.".to_owned())
+    } else {
+        Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The link is invalid or expired.",
+        ))
+    }
+}
+
+/// # `GET /_matrix/client/r0/account/3pid`
+///
+/// Lists the email addresses and phone numbers of the user.
+///
+/// - The endpoints of third party ids have no ruma types, so their requests are parsed as
+/// [`GET /_matrix/client/r0/account/whoami`](fn.whoami_route.html), which only checks the access
+/// token
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/account/3pid", data = "<body>")
+)]
+pub fn get_threepids_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+) -> Result<Json<String>> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    let threepids = db
+        .users
+        .threepids(sender_id)
+        .map(|threepid| {
+            let threepid = threepid?;
+            Ok(json!({
+                "medium": threepid.get("medium"),
+                "address": threepid.get("address"),
+                "validated_at": threepid.get("validated_at"),
+                "added_at": threepid.get("added_at"),
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Json(json!({ "threepids": threepids }).to_string()))
+}
+
+/// # `POST /_matrix/client/r0/account/3pid`
+///
+/// Adds a third party id that was validated by an identity server to the account and publishes
+/// it on the identity server if `bind` is true.
+///
+/// - Deprecated in favor of `POST /_matrix/client/r0/account/3pid/bind`
+/// - Forbidden if `threepid_changes_disabled` is set
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/account/3pid", data = "<body>")
+)]
+pub async fn add_threepid_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+) -> Result<Json<String>> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+    check_threepid_changes_allowed(&db)?;
+    let request = json_body(&body)?;
+    let creds = request.get("three_pid_creds").ok_or(Error::BadRequest(
+        ErrorKind::MissingParam,
+        "Missing three_pid_creds.",
+    ))?;
+
+    add_threepid_of_session(
+        &db,
+        sender_id,
+        creds,
+        request.get("bind").and_then(|bind| bind.as_bool()) == Some(true),
+    )
+    .await?;
+
+    Ok(Json(json!({}).to_string()))
+}
+
+/// # `POST /_matrix/client/r0/account/3pid/bind`
+///
+/// Publishes a third party id on an identity server, so other users can find the user by it. The
+/// id is also added to the account.
+///
+/// - The identity server has to be trusted and has to have validated the session
+/// - Forbidden if `threepid_changes_disabled` is set
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/account/3pid/bind", data = "<body>")
+)]
+pub async fn bind_threepid_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+) -> Result<Json<String>> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+    check_threepid_changes_allowed(&db)?;
+    let request = json_body(&body)?;
+
+    add_threepid_of_session(&db, sender_id, &request, true).await?;
+
+    Ok(Json(json!({}).to_string()))
+}
+
+/// # `POST /_matrix/client/r0/account/3pid/unbind`
+///
+/// Removes a third party id from an identity server. The id stays on the account.
+///
+/// - Without `id_server` the id is removed from the identity server it was bound to
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/account/3pid/unbind", data = "<body>")
+)]
+pub async fn unbind_threepid_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+) -> Result<Json<String>> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+    let request = json_body(&body)?;
+    let (medium, address) = medium_and_address(&request)?;
+
+    let result = unbind_threepid(
+        &db,
+        sender_id,
+        medium,
+        address,
+        request
+            .get("id_server")
+            .and_then(|id_server| id_server.as_str()),
+    )
+    .await?;
+
+    Ok(Json(
+        json!({ "id_server_unbind_result": result }).to_string(),
+    ))
+}
+
+/// # `POST /_matrix/client/r0/account/3pid/delete`
+///
+/// Removes a third party id from the account and from the identity server it was bound to.
+///
+/// - Forbidden if `threepid_changes_disabled` is set
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/account/3pid/delete", data = "<body>")
+)]
+pub async fn delete_threepid_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+) -> Result<Json<String>> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+    check_threepid_changes_allowed(&db)?;
+    let request = json_body(&body)?;
+    let (medium, address) = medium_and_address(&request)?;
+
+    let result = unbind_threepid(
+        &db,
+        sender_id,
+        medium,
+        address,
+        request
+            .get("id_server")
+            .and_then(|id_server| id_server.as_str()),
+    )
+    .await?;
+    db.users.remove_threepid(sender_id, medium, address)?;
+
+    Ok(Json(
+        json!({ "id_server_unbind_result": result }).to_string(),
+    ))
+}
+
+/// Checks the session `sid` of `creds` on the identity server `id_server` of `creds` and adds the
+/// validated third party id to the account. If `bind` is true, the identity server publishes it.
+async fn add_threepid_of_session(
+    db: &Database<'_>,
+    user_id: &UserId,
+    creds: &Value,
+    bind: bool,
+) -> Result<()> {
+    let field = |name: &'static str| {
+        creds
+            .get(name)
+            .and_then(|value| value.as_str())
+            .ok_or(Error::BadRequest(ErrorKind::MissingParam, name))
+    };
+    let sid = field("sid")?;
+    let client_secret = field("client_secret")?;
+    let id_server = field("id_server")?;
+    let base_url = identity_server_url(db, id_server)?;
+
+    let validated = db
+        .globals
+        .reqwest_client()
+        .get(&format!(
+            "{}/_matrix/identity/api/v1/3pid/getValidated3pid",
+            base_url
+        ))
+        .query(&[("sid", sid), ("client_secret", client_secret)])
+        .send()
+        .await?;
+    if !validated.status().is_success() {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidAuthFailed,
+            "The third party id was not validated.",
+        ));
+    }
+    let validated = validated.json::<Value>().await?;
+    let (medium, address) = medium_and_address(&validated)
+        .map_err(|_| Error::BadServerResponse("Invalid validated third party id."))?;
+
+    if medium == "email" {
+        if let Some(owner) = db.users.find_from_email(address)? {
+            if &owner != user_id {
+                return Err(Error::BadRequest(
+                    ErrorKind::ThreepidInUse,
+                    "Email address is already in use.",
+                ));
+            }
+        }
+    }
+
+    if bind {
+        let response = db
+            .globals
+            .reqwest_client()
+            .post(&format!("{}/_matrix/identity/api/v1/3pid/bind", base_url))
+            .json(&json!({
+                "sid": sid,
+                "client_secret": client_secret,
+                "mxid": user_id,
+            }))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            warn!(
+                "{} did not bind a third party id: {}",
+                id_server,
+                response.status()
+            );
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidAuthFailed,
+                "The identity server did not bind the third party id.",
+            ));
+        }
+    }
+
+    db.users.add_threepid(
+        user_id,
+        medium,
+        address,
+        if bind { Some(id_server) } else { None },
+    )
+}
+
+/// Asks the identity server to forget the third party id of the user. Returns `success` or
+/// `no-support` like the spec wants.
+async fn unbind_threepid(
+    db: &Database<'_>,
+    user_id: &UserId,
+    medium: &str,
+    address: &str,
+    id_server: Option<&str>,
+) -> Result<&'static str> {
+    let threepid = db.users.threepid(user_id, medium, address)?;
+    let bound_to = threepid
+        .as_ref()
+        .and_then(|threepid| threepid.get("bound_to"))
+        .and_then(|bound_to| bound_to.as_str())
+        .map(|bound_to| bound_to.to_owned());
+
+    let id_server = match id_server.map(|id_server| id_server.to_owned()).or(bound_to) {
+        Some(id_server) => id_server,
+        None => return Ok("no-support"),
+    };
+    let base_url = identity_server_url(db, &id_server)?;
+
+    // Identity servers check that the request comes from the server of the user
+    let path = "/_matrix/identity/api/v1/3pid/unbind";
+    let content = json!({
+        "mxid": user_id,
+        "threepid": {
+            "medium": medium,
+            "address": address,
+        },
+    });
+    let authorization =
+        server_server::x_matrix_authorization(db, &id_server, "POST", path, Some(content.clone()));
+
+    let response = db
+        .globals
+        .reqwest_client()
+        .post(&format!("{}{}", base_url, path))
+        .header(AUTHORIZATION, authorization)
+        .json(&content)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        warn!(
+            "{} did not unbind a third party id: {}",
+            id_server,
+            response.status()
+        );
+        return Ok("no-support");
+    }
+
+    if threepid.is_some() {
+        db.users.add_threepid(user_id, medium, address, None)?;
+    }
+
+    Ok("success")
+}
+
+/// Returns the base url of the identity server, if users may use it.
+fn identity_server_url(db: &Database<'_>, id_server: &str) -> Result<String> {
+    if !db.globals.is_trusted_identity_server(id_server) {
+        return Err(Error::BadRequest(
+            ErrorKind::ServerNotTrusted,
+            "The identity server is not trusted.",
+        ));
+    }
+
+    Ok(format!("https://{}", id_server))
+}
+
+/// Fails if `threepid_changes_disabled` is set.
+fn check_threepid_changes_allowed(db: &Database<'_>) -> Result<()> {
+    if db.globals.threepid_changes_disabled() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Third party id changes are disabled on this server.",
+        ));
+    }
+
+    Ok(())
+}
+
+fn medium_and_address(json: &Value) -> Result<(&str, &str)> {
+    let medium = json
+        .get("medium")
+        .and_then(|medium| medium.as_str())
+        .ok_or(Error::BadRequest(
+            ErrorKind::MissingParam,
+            "Missing medium.",
+        ))?;
+    let address = json
+        .get("address")
+        .and_then(|address| address.as_str())
+        .ok_or(Error::BadRequest(
+            ErrorKind::MissingParam,
+            "Missing address.",
+        ))?;
+
+    Ok((medium, address))
+}
+
+fn json_body(body: &Ruma<whoami::Request>) -> Result<Value> {
+    serde_json::from_str(
+        body.json_body
+            .as_ref()
+            .ok_or(Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?
+            .get(),
+    )
+    .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))
+}
+
+/// Creates a new threepid session and sends the validation link to the email address. The SMTP
+/// transport blocks, so the email is sent on a thread for blocking tasks.
+async fn send_validation_email(
+    db: &Database<'_>,
+    email: &str,
+    client_secret: &str,
+) -> Result<String> {
+    let (smtp_server, credentials) = db.globals.smtp_server().ok_or(Error::BadRequest(
+        ErrorKind::ThreepidDenied,
+        "This server does not support email addresses.",
+    ))?;
+    let from = db
+        .globals
+        .smtp_from()
+        .expect("smtp_from is required for smtp_server");
+
+    let (sid, token) =
+        db.threepid_sessions
+            .create(email, client_secret, SID_LENGTH, EMAIL_TOKEN_LENGTH)?;
+
+    let link = format!(
+        "{}/_conduit/client/email/submitToken?sid={}&client_secret={}&token={}",
+        db.globals.public_baseurl(),
+        sid,
+        utils::percent_encode(client_secret),
+        token
+    );
+
+    let message = EmailBuilder::new()
+        .to(email)
+        .from(from)
+        .subject(format!(
+            "Validate your email for {}",
+            db.globals.server_name()
+        ))
+        .text(format!(
+            "Someone requested to use this email address on {}.\n\n\
+            If this was you, open this link to continue:\n{}\n\n\
+            If this was not you, you can ignore this email.",
+            db.globals.server_name(),
+            link
+        ))
+        .build()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid email address."))?;
+
+    let mut client = SmtpClient::new_simple(smtp_server)
+        .map_err(|_| Error::BadConfig("Invalid smtp_server."))?;
+    if let Some((username, password)) = credentials {
+        client = client.credentials(Credentials::new(username.to_owned(), password.to_owned()));
+    }
+
+    tokio::task::spawn_blocking(move || client.transport().send(message.into()))
+        .await
+        .map_err(|_| Error::BadServerResponse("Failed to send email."))?
+        .map_err(|_| Error::BadServerResponse("Failed to send email."))?;
+
+    Ok(sid)
+}
@@ -1,18 +1,26 @@
 use super::State;
-use crate::{ConduitResult, Database, Error, Ruma};
+use crate::{server_server, ConduitResult, Database, Error, Ruma};
 use ruma::api::client::{
     error::ErrorKind,
     r0::to_device::{self, send_event_to_device},
 };
+use std::collections::BTreeMap;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::put;
 
+/// # `PUT /_matrix/client/r0/sendToDevice/{eventType}/{txnId}`
+///
+/// Sends an event to devices of other users.
+///
+/// - `*` sends the event to all devices of the user
+/// - Events for users of other servers are sent to their server in m.direct_to_device EDUs
+/// - Requests that reuse a transaction id of the device are ignored
 #[cfg_attr(
     feature = "conduit_bin",
     put("/_matrix/client/r0/sendToDevice/<_>/<_>", data = "<body>")
 )]
-pub fn send_event_to_device_route(
+pub async fn send_event_to_device_route(
     db: State<'_, Database<'_>>,
     body: Ruma<send_event_to_device::IncomingRequest>,
 ) -> ConduitResult<send_event_to_device::Response> {
@@ -28,8 +36,31 @@ pub fn send_event_to_device_route(
         return Ok(send_event_to_device::Response.into());
     }
 
+    // Server -> user id -> device id or * -> content
+    let mut remote_messages = BTreeMap::<_, BTreeMap<_, BTreeMap<_, _>>>::new();
+
     for (target_user_id, map) in &body.messages {
         for (target_device_id_maybe, event) in map {
+            let content = serde_json::from_str::<serde_json::Value>(event.get())
+                .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Event is invalid"))?;
+
+            if target_user_id.server_name() != db.globals.server_name() {
+                let target_device = match target_device_id_maybe {
+                    to_device::DeviceIdOrAllDevices::DeviceId(target_device_id) => {
+                        target_device_id.to_string()
+                    }
+                    to_device::DeviceIdOrAllDevices::AllDevices => "*".to_owned(),
+                };
+
+                remote_messages
+                    .entry(target_user_id.server_name().to_string())
+                    .or_default()
+                    .entry(target_user_id.to_string())
+                    .or_default()
+                    .insert(target_device, content);
+                continue;
+            }
+
             match target_device_id_maybe {
                 to_device::DeviceIdOrAllDevices::DeviceId(target_device_id) => {
                     db.users.add_to_device_event(
@@ -37,9 +68,7 @@ pub fn send_event_to_device_route(
                         &target_user_id,
                         &target_device_id,
                         &body.event_type,
-                        serde_json::from_str(event.get()).map_err(|_| {
-                            Error::BadRequest(ErrorKind::InvalidParam, "Event is invalid")
-                        })?,
+                        content,
                         &db.globals,
                     )?
                 }
@@ -51,9 +80,7 @@ pub fn send_event_to_device_route(
                             &target_user_id,
                             &target_device_id?,
                             &body.event_type,
-                            serde_json::from_str(event.get()).map_err(|_| {
-                                Error::BadRequest(ErrorKind::InvalidParam, "Event is invalid")
-                            })?,
+                            content.clone(),
                             &db.globals,
                         )?;
                     }
@@ -66,5 +93,19 @@ pub fn send_event_to_device_route(
     db.transaction_ids
         .add_txnid(sender_id, device_id, &body.txn_id, &[])?;
 
+    // Transaction ids are only unique per device
+    let message_id = format!("{}_{}", device_id, body.txn_id);
+    for (server, messages) in remote_messages {
+        server_server::send_to_device_edu(
+            &db,
+            server,
+            sender_id,
+            &body.event_type,
+            &message_id,
+            messages,
+        )
+        .await?;
+    }
+
     Ok(send_event_to_device::Response.into())
 }
@@ -1,5 +1,5 @@
 use super::State;
-use crate::{utils, ConduitResult, Database, Ruma};
+use crate::{server_server, utils, ConduitResult, Database, Ruma};
 use ruma::api::client::r0::typing::create_typing_event;
 
 #[cfg(feature = "conduit_bin")]
@@ -9,7 +9,7 @@ use rocket::put;
     feature = "conduit_bin",
     put("/_matrix/client/r0/rooms/<_>/typing/<_>", data = "<body>")
 )]
-pub fn create_typing_event_route(
+pub async fn create_typing_event_route(
     db: State<'_, Database<'_>>,
     body: Ruma<create_typing_event::Request>,
 ) -> ConduitResult<create_typing_event::Response> {
@@ -29,5 +29,7 @@ pub fn create_typing_event_route(
             .typing_remove(&sender_id, &body.room_id, &db.globals)?;
     }
 
+    server_server::send_typing_edu(&db, &body.room_id, &sender_id, body.typing).await?;
+
     Ok(create_typing_event::Response.into())
 }
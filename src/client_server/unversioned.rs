@@ -1,6 +1,11 @@
-use crate::ConduitResult;
+use super::State;
+use crate::{ConduitResult, Database};
+use rocket::{
+    http::ContentType,
+    response::{self, Response},
+};
 use ruma::api::client::unversioned::get_supported_versions;
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, io::Cursor};
 
 #[cfg(feature = "conduit_bin")]
 use rocket::get;
@@ -27,3 +32,20 @@ pub fn get_supported_versions_route() -> ConduitResult<get_supported_versions::R
     }
     .into())
 }
+
+/// # `GET /.well-known/matrix/client`
+///
+/// Tells clients the url of this server, so users only have to enter the server name.
+///
+/// - The url is `well_known_client` or the public base url, `well_known_identity` adds an identity
+/// server and the fields of `well_known_client_extra` are added as they are
+/// - Browser clients on `cors_allow_origin` are allowed to read the response
+#[cfg_attr(feature = "conduit_bin", get("/.well-known/matrix/client"))]
+pub fn well_known_client_route(db: State<'_, Database<'_>>) -> response::Result<'static> {
+    let body = db.globals.well_known_client().to_string();
+
+    Response::build()
+        .header(ContentType::JSON)
+        .sized_body(body.len(), Cursor::new(body))
+        .ok()
+}
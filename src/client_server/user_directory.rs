@@ -1,10 +1,24 @@
 use super::State;
 use crate::{ConduitResult, Database, Ruma};
-use ruma::api::client::r0::user_directory::search_users;
+use ruma::{
+    api::client::r0::user_directory::search_users,
+    events::{room::member::MemberEventContent, EventType},
+    RoomId, UserId,
+};
+use std::collections::HashSet;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::post;
 
+/// # `POST /_matrix/client/r0/user_directory/search`
+///
+/// Searches users by their user id and display name.
+///
+/// - Only users that share a room with the sender or are in a public room are found, unless
+/// `user_directory_show_all` is set
+/// - The search is case-insensitive, users whose id or name starts with the search term come
+/// first
+/// - The sender is never part of the results
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/user_directory/search", data = "<body>")
@@ -13,36 +27,202 @@ pub fn search_users_route(
     db: State<'_, Database<'_>>,
     body: Ruma<search_users::IncomingRequest>,
 ) -> ConduitResult<search_users::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
     let limit = u64::from(body.limit) as usize;
+    let search_term = body.search_term.to_lowercase();
 
-    let mut users = db.users.iter().filter_map(|user_id| {
+    // The membership trees of the rooms are the index of the directory
+    let mut seen = HashSet::new();
+    let mut candidates = Vec::new();
+    for room_id in db
+        .rooms
+        .rooms_joined(sender_id)
+        .chain(db.rooms.public_rooms())
+        .filter_map(|r| r.ok())
+    {
+        for user_id in db.rooms.room_members(&room_id).filter_map(|r| r.ok()) {
+            if seen.insert(user_id.clone()) {
+                candidates.push((user_id, Some(room_id.clone())));
+            }
+        }
+    }
+    if db.globals.user_directory_show_all() {
+        for user_id in db.users.iter().filter_map(|r| r.ok()) {
+            if seen.insert(user_id.clone()) {
+                candidates.push((user_id, None));
+            }
+        }
+    }
+
+    let (results, limited) = search_results(
+        sender_id,
+        &search_term,
+        candidates
+            .into_iter()
+            .filter_map(|(user_id, room_id)| profile(&db, user_id, room_id.as_ref())),
+        limit,
+    );
+
+    Ok(search_users::Response { results, limited }.into())
+}
+
+/// Returns the users whose id or display name contains the lowercase search term, users whose
+/// localpart or name starts with it first, and if more users than `limit` matched. The sender is
+/// left out.
+fn search_results(
+    sender_id: &UserId,
+    search_term: &str,
+    users: impl Iterator<Item = search_users::User>,
+    limit: usize,
+) -> (Vec<search_users::User>, bool) {
+    let mut results = users
+        .filter(|user| &user.user_id != sender_id)
+        .filter_map(|user| {
+            let id = user.user_id.as_str().to_lowercase();
+            let name = user
+                .display_name
+                .as_ref()
+                .map(|name| name.to_lowercase())
+                .unwrap_or_default();
+
+            // Ids start with @, the localpart should be enough to match
+            let prefix_match = id[1..].starts_with(search_term) || name.starts_with(search_term);
+            if !prefix_match && !id.contains(search_term) && !name.contains(search_term) {
+                return None;
+            }
+
+            Some((!prefix_match, user))
+        })
+        .collect::<Vec<_>>();
+
+    results.sort_by(|(a_rank, a), (b_rank, b)| {
+        a_rank
+            .cmp(b_rank)
+            .then_with(|| a.user_id.as_str().cmp(b.user_id.as_str()))
+    });
+
+    let limited = results.len() > limit;
+    let results = results
+        .into_iter()
+        .take(limit)
+        .map(|(_, user)| user)
+        .collect();
+
+    (results, limited)
+}
+
+/// Returns the profile of local users and the member event profile of remote users.
+fn profile(
+    db: &Database<'_>,
+    user_id: UserId,
+    room_id: Option<&RoomId>,
+) -> Option<search_users::User> {
+    if user_id.server_name() == db.globals.server_name() {
         // Filter out buggy users (they should not exist, but you never know...)
-        let user_id = user_id.ok()?;
         if db.users.is_deactivated(&user_id).ok()? {
             return None;
         }
 
-        let user = search_users::User {
-            user_id: user_id.clone(),
+        return Some(search_users::User {
             display_name: db.users.displayname(&user_id).ok()?,
             avatar_url: db.users.avatar_url(&user_id).ok()?,
-        };
+            user_id,
+        });
+    }
 
-        if !user.user_id.to_string().contains(&body.search_term)
-            && user
-                .display_name
-                .as_ref()
-                .filter(|name| name.contains(&body.search_term))
-                .is_none()
-        {
-            return None;
+    let content = db
+        .rooms
+        .room_state_get(room_id?, &EventType::RoomMember, user_id.as_str())
+        .ok()??
+        .content;
+    let content = serde_json::from_value::<MemberEventContent>(content).ok()?;
+
+    Some(search_users::User {
+        display_name: content.displayname,
+        avatar_url: content.avatar_url,
+        user_id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::search_results;
+    use ruma::{api::client::r0::user_directory::search_users, UserId};
+    use std::convert::TryFrom;
+
+    fn user(user_id: &str, display_name: Option<&str>) -> search_users::User {
+        search_users::User {
+            user_id: UserId::try_from(user_id).unwrap(),
+            display_name: display_name.map(|name| name.to_owned()),
+            avatar_url: None,
         }
+    }
 
-        Some(user)
-    });
+    fn search(term: &str, limit: usize) -> (Vec<String>, bool) {
+        let sender_id = UserId::try_from("@sender:example.com").unwrap();
+        let users = vec![
+            user("@sender:example.com", Some("Alice's friend")),
+            user("@bob:example.com", Some("Alice Bobson")),
+            user("@carol:example.com", Some("Carol, Alice's sister")),
+            user("@alice:example.com", None),
+            user("@dave:remote.example", Some("Dave")),
+        ];
 
-    let results = users.by_ref().take(limit).collect();
-    let limited = users.next().is_some();
+        let (results, limited) = search_results(&sender_id, term, users.into_iter(), limit);
+        (
+            results
+                .into_iter()
+                .map(|user| user.user_id.to_string())
+                .collect(),
+            limited,
+        )
+    }
 
-    Ok(search_users::Response { results, limited }.into())
+    #[test]
+    fn display_names_match_substrings() {
+        // Users whose id or name starts with the term come first
+        assert_eq!(
+            search("alice", 10),
+            (
+                vec![
+                    "@alice:example.com".to_owned(),
+                    "@bob:example.com".to_owned(),
+                    "@carol:example.com".to_owned(),
+                ],
+                false
+            )
+        );
+        assert_eq!(
+            search("sister", 10),
+            (vec!["@carol:example.com".to_owned()], false)
+        );
+        assert_eq!(
+            search("remote", 10),
+            (vec!["@dave:remote.example".to_owned()], false)
+        );
+        assert_eq!(search("eve", 10), (Vec::new(), false));
+    }
+
+    #[test]
+    fn searching_user_is_left_out() {
+        let (results, _) = search("sender", 10);
+        assert!(results.is_empty());
+        let (results, _) = search("friend", 10);
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn results_are_limited() {
+        assert_eq!(
+            search("alice", 2),
+            (
+                vec![
+                    "@alice:example.com".to_owned(),
+                    "@bob:example.com".to_owned()
+                ],
+                true
+            )
+        );
+        assert!(!search("alice", 3).1);
+    }
 }
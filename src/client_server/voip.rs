@@ -1,13 +1,97 @@
-use crate::{ConduitResult, Error};
-use ruma::api::client::{error::ErrorKind, r0::message::send_message_event};
+use super::State;
+use crate::{utils, Database, Result, Ruma};
+use ring::hmac;
+use rocket::response::content::Json;
+use ruma::{api::client::r0::account::whoami, UserId};
+use serde_json::json;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::get;
 
-#[cfg_attr(feature = "conduit_bin", get("/_matrix/client/r0/voip/turnServer"))]
-pub fn turn_server_route() -> ConduitResult<send_message_event::Response> {
-    Err(Error::BadRequest(
-        ErrorKind::NotFound,
-        "There is no turn server yet.",
+/// # `GET /_matrix/client/r0/voip/turnServer`
+///
+/// Returns the TURN servers clients can use for calls, together with credentials.
+///
+/// - With `turn_secret`, the credentials are generated for the user and expire after `turn_ttl`,
+/// otherwise `turn_username` and `turn_password` are returned
+/// - The response is empty if no TURN server is configured
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/voip/turnServer", data = "<body>")
+)]
+pub fn turn_server_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<whoami::Request>,
+) -> Result<Json<String>> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    let uris = db.globals.turn_uris();
+    if uris.is_empty() {
+        return Ok(Json(json!({}).to_string()));
+    }
+
+    let ttl = db.globals.turn_ttl();
+    let (username, password) = match db.globals.turn_secret() {
+        Some(secret) => {
+            let expires_at = utils::millis_since_unix_epoch() / 1000 + ttl.as_secs();
+            turn_credentials(secret, sender_id, expires_at)
+        }
+        None => {
+            let (username, password) = db.globals.turn_credentials();
+            (username.to_owned(), password.to_owned())
+        }
+    };
+
+    Ok(Json(
+        json!({
+            "username": username,
+            "password": password,
+            "uris": uris,
+            "ttl": ttl.as_secs(),
+        })
+        .to_string(),
     ))
 }
+
+/// Generates credentials for the TURN REST API: The username is the expiry (seconds since the
+/// unix epoch) and the user id, the password is the base64 encoded HMAC-SHA1 of the username with
+/// the shared secret. The TURN server checks both without knowing the user.
+pub fn turn_credentials(secret: &str, user_id: &UserId, expires_at: u64) -> (String, String) {
+    let username = format!("{}:{}", expires_at, user_id);
+
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret.as_bytes());
+    let password = base64::encode(hmac::sign(&key, username.as_bytes()).as_ref());
+
+    (username, password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::turn_credentials;
+    use ruma::UserId;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn turn_credentials_are_derived_from_the_secret() {
+        let user_id = UserId::try_from("@alice:example.com").unwrap();
+
+        let (username, password) = turn_credentials("secret", &user_id, 1_600_000_000);
+        assert_eq!(username, "1600000000:@alice:example.com");
+        assert_eq!(password, "Ry1+ypIdf93w3jJke5Z+0x0GCUc=");
+
+        // Other secrets give other passwords, so the TURN server can check them
+        let (_, other_password) = turn_credentials("other", &user_id, 1_600_000_000);
+        assert_eq!(other_password, "o8hTI76iV+9TNyl8lz9m87Ylb0M=");
+    }
+
+    #[test]
+    fn turn_credentials_expire() {
+        let user_id = UserId::try_from("@alice:example.com").unwrap();
+
+        let (username, password) = turn_credentials("secret", &user_id, 1_600_000_000);
+        let (later_username, later_password) = turn_credentials("secret", &user_id, 1_600_086_400);
+        assert_eq!(later_username, "1600086400:@alice:example.com");
+        assert_ne!(later_username, username);
+        assert_ne!(later_password, password);
+    }
+}
@@ -1,30 +1,57 @@
+pub mod abstraction;
 pub mod account_data;
+pub mod appservice;
+pub mod counter;
+pub mod export;
 pub mod globals;
 pub mod key_backups;
 pub mod media;
+pub mod metrics;
+pub mod outgoing;
+pub mod pushers;
+pub mod rate_limiter;
+pub mod reports;
 pub mod rooms;
+pub mod sending;
+pub mod shutdown;
+pub mod threepid_sessions;
 pub mod transaction_ids;
 pub mod uiaa;
 pub mod users;
 
-use crate::{Error, Result};
+use crate::{pdu::PduBuilder, utils, Error, Result};
 use directories::ProjectDirs;
-use log::info;
-use std::fs::remove_dir_all;
+use std::{
+    collections::HashMap,
+    fs::remove_dir_all,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+use tracing::info;
 
-use futures::StreamExt;
+use futures::{Future, StreamExt};
 use rocket::{futures, Config};
-use ruma::{DeviceId, UserId};
+use ruma::{
+    api::client::error::ErrorKind,
+    events::{room::create::CreateEventContent, EventType},
+    DeviceId, EventId, RoomAliasId, RoomId, RoomVersionId, UserId,
+};
+use serde_json::{json, Value};
 
 pub struct Database<'a> {
     pub globals: globals::Globals<'a>,
     pub users: users::Users,
     pub uiaa: uiaa::Uiaa,
     pub rooms: rooms::Rooms,
+    pub reports: reports::Reports,
+    pub outgoing: outgoing::Outgoing,
     pub account_data: account_data::AccountData,
     pub media: media::Media,
+    pub pushers: pushers::Pushers,
     pub key_backups: key_backups::KeyBackups,
     pub transaction_ids: transaction_ids::TransactionIds,
+    pub threepid_sessions: threepid_sessions::ThreepidSessions,
     pub _db: sled::Db,
 }
 
@@ -43,11 +70,11 @@ impl<'a> Database<'a> {
         Ok(())
     }
 
-    /// Load an existing database or create a new one.
-    pub fn load_or_create(config: &Config) -> Result<Self> {
+    /// Returns the path of the database from the config.
+    fn path(config: &Config) -> Result<String> {
         let server_name = config.get_str("server_name").unwrap_or("localhost");
 
-        let path = config
+        config
             .get_str("database_path")
             .map(|x| Ok::<_, Error>(x.to_owned()))
             .unwrap_or_else(|_| {
@@ -62,13 +89,79 @@ impl<'a> Database<'a> {
                     .to_str()
                     .ok_or(Error::BadConfig("Database path contains invalid unicode."))?
                     .to_owned())
-            })?;
+            })
+    }
+
+    /// Writes all trees of the database into a new archive file and returns the number of
+    /// entries. This fails while the server is running, because sled locks the database.
+    pub fn export(config: &Config, archive_path: &Path) -> Result<u64> {
+        let db = sled::open(Self::path(config)?)?;
+        export::export(&db, archive_path)
+    }
+
+    /// Restores the database from an archive of `export` and returns the number of entries.
+    /// The archive is checked completely before anything is written, and the database has to be
+    /// empty.
+    pub fn import(config: &Config, archive_path: &Path) -> Result<u64> {
+        export::verify(archive_path)?;
+
+        let db = sled::open(Self::path(config)?)?;
+        for name in db.tree_names() {
+            if !db.open_tree(name)?.is_empty() {
+                return Err(Error::BadArchive(
+                    "The database already contains data, remove it before importing.",
+                ));
+            }
+        }
+
+        let entries = export::import(&db, archive_path)?;
+        db.flush()?;
+
+        Ok(entries)
+    }
+
+    /// Load an existing database or create a new one.
+    pub fn load_or_create(config: &Config) -> Result<Self> {
+        let path = Self::path(config)?;
 
         let db = sled::open(&path)?;
         info!("Opened sled database at {}", path);
 
-        Ok(Self {
-            globals: globals::Globals::load(db.open_tree("global")?, config)?,
+        Self::load(db, config)
+    }
+
+    /// Creates an empty database that is removed when it is dropped, with `server_name`
+    /// example.com and the extra config values. Has to be called within a tokio runtime.
+    #[cfg(test)]
+    pub fn load_temporary(extras: Vec<(&str, rocket::config::Value)>) -> Self {
+        // Cheap password hashes keep the tests fast
+        let mut config = Config::build(rocket::config::Environment::Development)
+            .extra("server_name", "example.com")
+            .extra("argon2_mem_cost", 64)
+            .extra("argon2_time_cost", 1);
+        for (name, value) in extras {
+            config = config.extra(name, value);
+        }
+
+        Self::load(
+            sled::Config::new().temporary(true).open().unwrap(),
+            &config.finalize().unwrap(),
+        )
+        .unwrap()
+    }
+
+    /// Opens the trees of the database and starts its background tasks.
+    fn load(db: sled::Db, config: &Config) -> Result<Self> {
+        let globals = globals::Globals::load(
+            db.open_tree("global")?,
+            db.open_tree("keyid_oldkeypair")?,
+            db.open_tree("server_signingkeys")?,
+            config,
+        )?;
+        let password_hashing = globals.password_hashing().clone();
+
+        let database = Self {
+            globals,
             users: users::Users {
                 userid_password: db.open_tree("userid_password")?,
                 userid_displayname: db.open_tree("userid_displayname")?,
@@ -78,12 +171,25 @@ impl<'a> Database<'a> {
                 token_userdeviceid: db.open_tree("token_userdeviceid")?,
                 onetimekeyid_onetimekeys: db.open_tree("onetimekeyid_onetimekeys")?,
                 userid_lastonetimekeyupdate: db.open_tree("userid_lastonetimekeyupdate")?,
+                fallbackkeyid_fallbackkey: db.open_tree("fallbackkeyid_fallbackkey")?,
                 keychangeid_userid: db.open_tree("devicekeychangeid_userid")?,
                 keyid_key: db.open_tree("keyid_key")?,
                 userid_masterkeyid: db.open_tree("userid_masterkeyid")?,
                 userid_selfsigningkeyid: db.open_tree("userid_selfsigningkeyid")?,
                 userid_usersigningkeyid: db.open_tree("userid_usersigningkeyid")?,
                 todeviceid_events: db.open_tree("todeviceid_events")?,
+                registrationnonce_expiresat: db.open_tree("registrationnonce_expiresat")?,
+                registration_tokens: db.open_tree("registration_tokens")?,
+                email_userid: db.open_tree("email_userid")?,
+                user_threepids: db.open_tree("user_threepids")?,
+                userid_guest: db.open_tree("userid_guest")?,
+                userid_admin: db.open_tree("userid_admin")?,
+                filters: db.open_tree("filters")?,
+                openid_tokens: db.open_tree("openid_tokens")?,
+                login_tokens: db.open_tree("login_tokens")?,
+                token_expiresat: db.open_tree("token_expiresat")?,
+                refreshtoken_userdeviceid: db.open_tree("refreshtoken_userdeviceid")?,
+                password_hashing,
             },
             uiaa: uiaa::Uiaa {
                 userdeviceid_uiaainfo: db.open_tree("userdeviceid_uiaainfo")?,
@@ -94,21 +200,27 @@ impl<'a> Database<'a> {
                     roomuserid_privateread: db.open_tree("roomuserid_privateread")?, // "Private" read receipt
                     roomuserid_lastprivatereadupdate: db
                         .open_tree("roomid_lastprivatereadupdate")?,
-                    typingid_userid: db.open_tree("typingid_userid")?,
+                    roomusercount_notification: db.open_tree("roomusercount_notification")?,
+                    roomid_typing: RwLock::new(HashMap::new()),
                     roomid_lasttypingupdate: db.open_tree("roomid_lasttypingupdate")?,
                     presenceid_presence: db.open_tree("presenceid_presence")?,
                     userid_lastpresenceupdate: db.open_tree("userid_lastpresenceupdate")?,
+                    userid_presence: db.open_tree("userid_presence")?,
                 },
                 pduid_pdu: db.open_tree("pduid_pdu")?,
                 eventid_pduid: db.open_tree("eventid_pduid")?,
                 roomid_pduleaves: db.open_tree("roomid_pduleaves")?,
                 roomstateid_pdu: db.open_tree("roomstateid_pdu")?,
+                eventid_softfailedpdu: db.open_tree("eventid_softfailedpdu")?,
+                eventid_outlierpdu: db.open_tree("eventid_outlierpdu")?,
 
                 alias_roomid: db.open_tree("alias_roomid")?,
-                aliasid_alias: db.open_tree("alias_roomid")?,
+                aliasid_alias: db.open_tree("aliasid_alias")?,
+                alias_userid: db.open_tree("alias_userid")?,
                 publicroomids: db.open_tree("publicroomids")?,
 
-                tokenids: db.open_tree("tokenids")?,
+                search_index: db.open_tree("search_index")?,
+                parentid_relations: db.open_tree("parentid_relations")?,
 
                 userroomid_joined: db.open_tree("userroomid_joined")?,
                 roomuserid_joined: db.open_tree("roomuserid_joined")?,
@@ -116,12 +228,31 @@ impl<'a> Database<'a> {
                 userroomid_invited: db.open_tree("userroomid_invited")?,
                 roomuserid_invited: db.open_tree("roomuserid_invited")?,
                 userroomid_left: db.open_tree("userroomid_left")?,
+                userroomid_invitestate: db.open_tree("userroomid_invitestate")?,
+
+                lazy_load_sent: db.open_tree("lazy_load_sent")?,
+
+                servicepdus: db.open_tree("servicepdus")?,
+            },
+            reports: reports::Reports {
+                content_reports: db.open_tree("content_reports")?,
+            },
+            outgoing: outgoing::Outgoing {
+                outgoing: db.open_tree("outgoing")?,
+                destination_state: db.open_tree("destination_state")?,
             },
             account_data: account_data::AccountData {
                 roomuserdataid_accountdata: db.open_tree("roomuserdataid_accountdata")?,
             },
             media: media::Media {
                 mediaid_file: db.open_tree("mediaid_file")?,
+                thumbnailid_file: db.open_tree("thumbnailid_file")?,
+                mxc_lastaccessed: db.open_tree("mxc_lastaccessed")?,
+                url_previews: db.open_tree("url_previews")?,
+            },
+            pushers: pushers::Pushers {
+                senderkey_pusher: db.open_tree("senderkey_pusher")?,
+                userid_pendingnotification: Arc::new(Mutex::new(HashMap::new())),
             },
             key_backups: key_backups::KeyBackups {
                 backupid_algorithm: db.open_tree("backupid_algorithm")?,
@@ -130,12 +261,257 @@ impl<'a> Database<'a> {
             },
             transaction_ids: transaction_ids::TransactionIds {
                 userdevicetxnid_response: db.open_tree("userdevicetxnid_response")?,
+                addedat_userdevicetxnid: db.open_tree("addedat_userdevicetxnid")?,
+                txnid_locks: Mutex::new(HashMap::new()),
+            },
+            threepid_sessions: threepid_sessions::ThreepidSessions {
+                sid_session: db.open_tree("threepid_sessions")?,
+                email_lasttokenrequest: db.open_tree("email_lasttokenrequest")?,
             },
             _db: db,
-        })
+        };
+
+        // The first admin is named in the config
+        if let Some(admin_user) = database.globals.admin_user() {
+            if database.users.exists(admin_user)? {
+                database.users.make_admin(admin_user)?;
+            }
+        }
+
+        sending::start_appservice_senders(
+            database.globals.appservices(),
+            database.rooms.servicepdus.clone(),
+            database.rooms.pduid_pdu.clone(),
+            database.globals.reqwest_client().clone(),
+        );
+
+        database.transaction_ids.start_txnid_retention();
+
+        // Local media is never removed automatically
+        if let Some(days) = database.globals.media_retention_remote_days() {
+            database.media.start_remote_media_retention(
+                database.globals.server_name().to_owned(),
+                Duration::from_secs(days * 24 * 60 * 60),
+            );
+        }
+
+        Ok(database)
     }
 
-    pub async fn watch(&self, user_id: &UserId, device_id: &DeviceId) {
+    /// Sends a message from the server notices user to a local user. Notices are sent in a
+    /// private room that is tagged with `m.server_notice` and created for the first notice.
+    ///
+    /// Only the server notices user can kick or ban in that room. If the user left it, they are
+    /// invited again, so later notices still reach them.
+    pub fn send_server_notice(&self, user_id: &UserId, content: Value) -> Result<EventId> {
+        let notices_user = self
+            .globals
+            .server_notices_user()
+            .ok_or(Error::BadConfig("server_notices_user is not set."))?
+            .clone();
+
+        if user_id.server_name() != self.globals.server_name() || !self.users.exists(user_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "User does not exist.",
+            ));
+        }
+
+        if !self.users.exists(&notices_user)? {
+            // Nobody knows the password, so nobody can log in as the server notices user
+            self.users
+                .create(&notices_user, &utils::random_string(40))?;
+            self.users
+                .set_displayname(&notices_user, Some("Server Notices".to_owned()))?;
+        }
+
+        // The notices room is the one room of the server notices user the user was ever in
+        let mut notices_room = None;
+        for room_id in self.rooms.rooms_joined(&notices_user) {
+            let room_id = room_id?;
+            if self
+                .rooms
+                .room_state_get(&room_id, &EventType::RoomMember, user_id.as_str())?
+                .is_some()
+            {
+                notices_room = Some(room_id);
+                break;
+            }
+        }
+
+        let room_id = match notices_room {
+            Some(room_id) => room_id,
+            None => self.create_server_notices_room(&notices_user)?,
+        };
+
+        if !self.rooms.is_joined(user_id, &room_id)? && !self.rooms.is_invited(user_id, &room_id)? {
+            self.rooms.append_pdu(
+                PduBuilder {
+                    room_id: room_id.clone(),
+                    sender: notices_user.clone(),
+                    event_type: EventType::RoomMember,
+                    content: json!({
+                        "membership": "invite",
+                        "displayname": self.users.displayname(user_id)?,
+                        "avatar_url": self.users.avatar_url(user_id)?,
+                        "is_direct": true,
+                    }),
+                    unsigned: None,
+                    state_key: Some(user_id.to_string()),
+                    redacts: None,
+                },
+                &self.globals,
+                &self.account_data,
+            )?;
+        }
+
+        // Clients show rooms with this tag as server notices
+        let mut tags_event = self
+            .account_data
+            .get::<Value>(Some(&room_id), user_id, EventType::Tag)?
+            .unwrap_or_else(|| json!({ "type": "m.tag", "content": { "tags": {} } }));
+        if tags_event["content"]["tags"]
+            .get("m.server_notice")
+            .is_none()
+        {
+            tags_event["content"]["tags"]["m.server_notice"] = json!({});
+            self.account_data.update(
+                Some(&room_id),
+                user_id,
+                EventType::Tag,
+                &tags_event,
+                &self.globals,
+            )?;
+        }
+
+        self.rooms.append_pdu(
+            PduBuilder {
+                room_id,
+                sender: notices_user,
+                event_type: EventType::RoomMessage,
+                content,
+                unsigned: None,
+                state_key: None,
+                redacts: None,
+            },
+            &self.globals,
+            &self.account_data,
+        )
+    }
+
+    /// Creates an empty invite-only room in which only the server notices user has power.
+    fn create_server_notices_room(&self, notices_user: &UserId) -> Result<RoomId> {
+        let room_id = RoomId::new(self.globals.server_name());
+
+        let mut create_content = CreateEventContent::new(notices_user.clone());
+        create_content.federate = false;
+        create_content.room_version = self.globals.default_room_version().clone();
+
+        let state = vec![
+            (
+                EventType::RoomCreate,
+                "".to_owned(),
+                serde_json::to_value(create_content).expect("event is valid, we just created it"),
+            ),
+            (
+                EventType::RoomMember,
+                notices_user.to_string(),
+                json!({
+                    "membership": "join",
+                    "displayname": self.users.displayname(notices_user)?,
+                }),
+            ),
+            (
+                EventType::RoomPowerLevels,
+                "".to_owned(),
+                json!({
+                    "users": { notices_user.to_string(): 100 },
+                    "users_default": 0,
+                    "events_default": 0,
+                    "state_default": 100,
+                    "invite": 100,
+                    "kick": 100,
+                    "ban": 100,
+                    "redact": 100,
+                }),
+            ),
+            (
+                EventType::RoomJoinRules,
+                "".to_owned(),
+                json!({ "join_rule": "invite" }),
+            ),
+            (
+                EventType::RoomHistoryVisibility,
+                "".to_owned(),
+                json!({ "history_visibility": "shared" }),
+            ),
+            (
+                EventType::RoomName,
+                "".to_owned(),
+                json!({ "name": "Server Notices" }),
+            ),
+        ];
+
+        for (event_type, state_key, content) in state {
+            self.rooms.append_pdu(
+                PduBuilder {
+                    room_id: room_id.clone(),
+                    sender: notices_user.clone(),
+                    event_type,
+                    content,
+                    unsigned: None,
+                    state_key: Some(state_key),
+                    redacts: None,
+                },
+                &self.globals,
+                &self.account_data,
+            )?;
+        }
+
+        Ok(room_id)
+    }
+
+    /// Creates a public room with the local alias for `auto_join_rooms`. The creator is the only
+    /// user with power in it.
+    pub fn create_auto_join_room(&self, alias: &RoomAliasId, creator: &UserId) -> Result<RoomId> {
+        let room_id = RoomId::new(self.globals.server_name());
+
+        let state = auto_join_room_state(
+            alias,
+            creator,
+            self.globals.default_room_version(),
+            self.users.displayname(creator)?,
+            self.users.avatar_url(creator)?,
+        );
+
+        for (event_type, state_key, content) in state {
+            self.rooms.append_pdu(
+                PduBuilder {
+                    room_id: room_id.clone(),
+                    sender: creator.clone(),
+                    event_type,
+                    content,
+                    unsigned: None,
+                    state_key: Some(state_key),
+                    redacts: None,
+                },
+                &self.globals,
+                &self.account_data,
+            )?;
+        }
+
+        self.rooms.set_alias(alias, Some(&room_id), &self.globals)?;
+        self.rooms.set_alias_creator(alias, creator)?;
+
+        Ok(room_id)
+    }
+
+    /// Returns a future that finishes when something for the sync of the device changes, like
+    /// new events, to-device events, account data or keys.
+    ///
+    /// The watchers are registered before this returns, not when the future is polled, so
+    /// changes while the caller builds its response are not missed.
+    pub fn watch(&self, user_id: &UserId, device_id: &DeviceId) -> impl Future<Output = ()> {
         let userid_bytes = user_id.to_string().as_bytes().to_vec();
         let mut userid_prefix = userid_bytes.clone();
         userid_prefix.push(0xff);
@@ -216,6 +592,107 @@ impl<'a> Database<'a> {
         );
 
         // Wait until one of them finds something
-        futures.next().await;
+        async move {
+            futures.next().await;
+        }
+    }
+}
+
+/// The initial state of a room created for `auto_join_rooms`: a public room with the alias as
+/// canonical alias, in which only the creator has power.
+fn auto_join_room_state(
+    alias: &RoomAliasId,
+    creator: &UserId,
+    room_version: &RoomVersionId,
+    displayname: Option<String>,
+    avatar_url: Option<String>,
+) -> Vec<(EventType, String, Value)> {
+    let mut create_content = CreateEventContent::new(creator.clone());
+    create_content.room_version = room_version.clone();
+
+    vec![
+        (
+            EventType::RoomCreate,
+            "".to_owned(),
+            serde_json::to_value(create_content).expect("event is valid, we just created it"),
+        ),
+        (
+            EventType::RoomMember,
+            creator.to_string(),
+            json!({
+                "membership": "join",
+                "displayname": displayname,
+                "avatar_url": avatar_url,
+            }),
+        ),
+        (
+            EventType::RoomPowerLevels,
+            "".to_owned(),
+            json!({ "users": { creator.to_string(): 100 } }),
+        ),
+        (
+            EventType::RoomJoinRules,
+            "".to_owned(),
+            json!({ "join_rule": "public" }),
+        ),
+        (
+            EventType::RoomHistoryVisibility,
+            "".to_owned(),
+            json!({ "history_visibility": "shared" }),
+        ),
+        (
+            EventType::RoomCanonicalAlias,
+            "".to_owned(),
+            json!({ "alias": alias }),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::auto_join_room_state;
+    use ruma::{events::EventType, RoomAliasId, RoomVersionId, UserId};
+    use serde_json::json;
+    use std::{collections::HashMap, convert::TryFrom};
+
+    #[test]
+    fn auto_join_rooms_are_public_rooms_of_the_admin_user() {
+        let alias = RoomAliasId::try_from("#welcome:example.com").unwrap();
+        let admin = UserId::try_from("@admin:example.com").unwrap();
+
+        let state = auto_join_room_state(
+            &alias,
+            &admin,
+            &RoomVersionId::Version6,
+            Some("Admin".to_owned()),
+            None,
+        )
+        .into_iter()
+        .map(|(event_type, state_key, content)| ((event_type, state_key), content))
+        .collect::<HashMap<_, _>>();
+        let get = |event_type: EventType, state_key: &str| {
+            state[&(event_type, state_key.to_owned())].clone()
+        };
+
+        let create = get(EventType::RoomCreate, "");
+        assert_eq!(create["creator"], "@admin:example.com");
+        assert_eq!(create["room_version"], "6");
+        assert_eq!(
+            get(EventType::RoomMember, "@admin:example.com"),
+            json!({ "membership": "join", "displayname": "Admin", "avatar_url": null })
+        );
+        assert_eq!(
+            get(EventType::RoomPowerLevels, ""),
+            json!({ "users": { "@admin:example.com": 100 } })
+        );
+        // New users can join without an invite
+        assert_eq!(
+            get(EventType::RoomJoinRules, ""),
+            json!({ "join_rule": "public" })
+        );
+        assert_eq!(
+            get(EventType::RoomCanonicalAlias, ""),
+            json!({ "alias": "#welcome:example.com" })
+        );
     }
 }
@@ -0,0 +1,266 @@
+//! The key-value trees the database modules are built on. Code that uses `KvTree` instead of
+//! `sled::Tree` works with every backend that implements it.
+
+use crate::{Error, Result};
+use sled::{
+    transaction::{ConflictableTransactionResult, TransactionError, TransactionalTree},
+    Transactional,
+};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+pub type TreeIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// Computes the new value of an entry from the old one, see `KvTree::update_and_fetch`.
+pub type UpdateFn = dyn Fn(Option<&[u8]>) -> Option<Vec<u8>>;
+
+pub trait KvTree: Send + Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()>;
+
+    fn remove(&self, key: &[u8]) -> Result<()>;
+
+    /// Iterates over all entries in the order of their keys.
+    fn iter<'a>(&'a self) -> TreeIter<'a>;
+
+    /// Iterates over all entries whose keys start with the prefix, in the order of their keys.
+    fn scan_prefix<'a>(&'a self, prefix: Vec<u8>) -> TreeIter<'a>;
+
+    /// Atomically replaces the value with the result of `f` and returns the new value. `None`
+    /// removes the entry.
+    fn update_and_fetch(&self, key: &[u8], f: &UpdateFn) -> Result<Option<Vec<u8>>>;
+}
+
+/// Applies either all writes of `f` to the trees or none of them, even if the server crashes in
+/// between. `f` runs again if a concurrent write conflicts with it and returning
+/// `ConflictableTransactionError::Abort` cancels the transaction.
+pub fn transaction<T>(
+    trees: &[&sled::Tree],
+    f: impl Fn(&[TransactionalTree]) -> ConflictableTransactionResult<T, Error>,
+) -> Result<T> {
+    trees.transaction(|trees| f(trees)).map_err(|e| match e {
+        TransactionError::Abort(error) => error,
+        TransactionError::Storage(error) => error.into(),
+    })
+}
+
+impl KvTree for sled::Tree {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(sled::Tree::get(self, key)?.map(|value| value.to_vec()))
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        sled::Tree::insert(self, key, value)?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        sled::Tree::remove(self, key)?;
+        Ok(())
+    }
+
+    fn iter<'a>(&'a self) -> TreeIter<'a> {
+        Box::new(sled::Tree::iter(self).map(|r| {
+            let (key, value) = r?;
+            Ok((key.to_vec(), value.to_vec()))
+        }))
+    }
+
+    fn scan_prefix<'a>(&'a self, prefix: Vec<u8>) -> TreeIter<'a> {
+        Box::new(sled::Tree::scan_prefix(self, prefix).map(|r| {
+            let (key, value) = r?;
+            Ok((key.to_vec(), value.to_vec()))
+        }))
+    }
+
+    fn update_and_fetch(&self, key: &[u8], f: &UpdateFn) -> Result<Option<Vec<u8>>> {
+        Ok(sled::Tree::update_and_fetch(self, key, f)?.map(|value| value.to_vec()))
+    }
+}
+
+/// A tree that only lives in memory, for example for tests that should not touch the disk.
+#[derive(Default)]
+pub struct MemoryTree {
+    map: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryTree {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+}
+
+impl KvTree for MemoryTree {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.map.read().unwrap().get(key).cloned())
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.map
+            .write()
+            .unwrap()
+            .insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        self.map.write().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn iter<'a>(&'a self) -> TreeIter<'a> {
+        // The lock can't be held by the iterator, so it iterates over a snapshot like sled does
+        let entries = self
+            .map
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect::<Vec<_>>();
+        Box::new(entries.into_iter())
+    }
+
+    fn scan_prefix<'a>(&'a self, prefix: Vec<u8>) -> TreeIter<'a> {
+        let entries = self
+            .map
+            .read()
+            .unwrap()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect::<Vec<_>>();
+        Box::new(entries.into_iter())
+    }
+
+    fn update_and_fetch(&self, key: &[u8], f: &UpdateFn) -> Result<Option<Vec<u8>>> {
+        let mut map = self.map.write().unwrap();
+        let new = f(map.get(key).map(|value| &value[..]));
+        match &new {
+            Some(value) => map.insert(key.to_vec(), value.clone()),
+            None => map.remove(key),
+        };
+        Ok(new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{transaction, KvTree, MemoryTree};
+    use crate::Error;
+    use sled::transaction::ConflictableTransactionError;
+
+    fn keys(tree: &MemoryTree, prefix: &[u8]) -> Vec<Vec<u8>> {
+        tree.scan_prefix(prefix.to_vec())
+            .map(|r| r.unwrap().0)
+            .collect()
+    }
+
+    #[test]
+    fn memory_tree_inserts_and_removes() {
+        let tree = MemoryTree::new();
+        assert_eq!(tree.get(b"a").unwrap(), None);
+
+        tree.insert(b"a", b"1").unwrap();
+        tree.insert(b"a", b"2").unwrap();
+        assert_eq!(tree.get(b"a").unwrap(), Some(b"2".to_vec()));
+
+        tree.remove(b"a").unwrap();
+        assert_eq!(tree.get(b"a").unwrap(), None);
+        // Removing a missing key is not an error, like in sled
+        tree.remove(b"a").unwrap();
+    }
+
+    #[test]
+    fn memory_tree_iterates_in_key_order() {
+        let tree = MemoryTree::new();
+        for key in &[&b"b"[..], b"a\xff2", b"a", b"a\xff1", b"c"] {
+            tree.insert(key, b"").unwrap();
+        }
+
+        let all = tree.iter().map(|r| r.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(
+            all,
+            vec![
+                b"a".to_vec(),
+                b"a\xff1".to_vec(),
+                b"a\xff2".to_vec(),
+                b"b".to_vec(),
+                b"c".to_vec()
+            ]
+        );
+
+        assert_eq!(
+            keys(&tree, b"a\xff"),
+            vec![b"a\xff1".to_vec(), b"a\xff2".to_vec()]
+        );
+        assert_eq!(keys(&tree, b"a").len(), 3);
+        assert!(keys(&tree, b"d").is_empty());
+    }
+
+    #[test]
+    fn memory_tree_iterators_see_a_snapshot() {
+        let tree = MemoryTree::new();
+        tree.insert(b"a", b"1").unwrap();
+
+        let mut iter = tree.iter();
+        tree.insert(b"b", b"2").unwrap();
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            (b"a".to_vec(), b"1".to_vec())
+        );
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn memory_tree_updates_and_fetches() {
+        let tree = MemoryTree::new();
+        let increment = |old: Option<&[u8]>| Some(vec![old.map_or(0, |old| old[0]) + 1]);
+
+        assert_eq!(
+            tree.update_and_fetch(b"n", &increment).unwrap(),
+            Some(vec![1])
+        );
+        assert_eq!(
+            tree.update_and_fetch(b"n", &increment).unwrap(),
+            Some(vec![2])
+        );
+        assert_eq!(tree.get(b"n").unwrap(), Some(vec![2]));
+
+        assert_eq!(tree.update_and_fetch(b"n", &|_| None).unwrap(), None);
+        assert_eq!(tree.get(b"n").unwrap(), None);
+    }
+
+    #[test]
+    fn transactions_write_all_trees_or_none() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let pdus = db.open_tree("pduid_pdu").unwrap();
+        let state = db.open_tree("roomstateid_pdu").unwrap();
+
+        // The failure happens after both trees were written to
+        let result = transaction(&[&pdus, &state], |trees| {
+            trees[0].insert(b"pdu", b"{}")?;
+            trees[1].insert(b"state", b"{}")?;
+            Err::<(), _>(ConflictableTransactionError::Abort(Error::bad_database(
+                "Injected failure.",
+            )))
+        });
+        assert!(matches!(
+            result,
+            Err(Error::BadDatabase("Injected failure."))
+        ));
+        assert!(pdus.is_empty());
+        assert!(state.is_empty());
+
+        transaction(&[&pdus, &state], |trees| {
+            trees[0].insert(b"pdu", b"{}")?;
+            trees[1].insert(b"state", b"{}")?;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pdus.get(b"pdu").unwrap().as_deref(), Some(&b"{}"[..]));
+        assert_eq!(state.get(b"state").unwrap().as_deref(), Some(&b"{}"[..]));
+    }
+}
@@ -1,19 +1,24 @@
 use crate::{utils, Error, Result};
 use ruma::{
     api::client::error::ErrorKind,
-    events::{AnyEvent as EduEvent, EventType},
+    events::{ignored_user_list, AnyEvent as EduEvent, EventType},
     Raw, RoomId, UserId,
 };
 use serde::{de::DeserializeOwned, Serialize};
+use serde_json::json;
 use sled::IVec;
-use std::{collections::HashMap, convert::TryFrom};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+};
 
 pub struct AccountData {
-    pub(super) roomuserdataid_accountdata: sled::Tree, // RoomUserDataId = Room + User + Count + Type
+    pub(super) roomuserdataid_accountdata: sled::Tree, // RoomUserDataId = Room + User + Count + Type, Count is when the event last changed
 }
 
 impl AccountData {
-    /// Places one event in the account data of the user and removes the previous entry.
+    /// Places one event in the account data of the user and removes the previous entry. The new
+    /// entry gets a new count, so incremental syncs send only this event.
     pub fn update<T: Serialize>(
         &self,
         room_id: Option<&RoomId>,
@@ -71,6 +76,54 @@ impl AccountData {
             .transpose()
     }
 
+    /// Returns the users of the `m.ignored_user_list` of the user. Looking up a sender in the set
+    /// is fast, so it's loaded once and used for all events of a request.
+    pub fn ignored_users(&self, user_id: &UserId) -> Result<HashSet<UserId>> {
+        Ok(self
+            .get::<ignored_user_list::IgnoredUserListEvent>(
+                None,
+                user_id,
+                EventType::IgnoredUserList,
+            )?
+            .map(|event| event.content.ignored_users.into_iter().collect())
+            .unwrap_or_default())
+    }
+
+    /// Adds the room to the direct chats with the other user in the `m.direct` account data of
+    /// the user. Rooms that are already in the list are not added again.
+    pub fn add_direct_room(
+        &self,
+        user_id: &UserId,
+        other_user_id: &UserId,
+        room_id: &RoomId,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<()> {
+        let mut direct_event = self
+            .get::<serde_json::Value>(None, user_id, EventType::Direct)?
+            .filter(|event| event["content"].is_object())
+            .unwrap_or_else(|| json!({ "type": "m.direct", "content": {} }));
+
+        let room_ids = direct_event["content"]
+            .as_object_mut()
+            .expect("content was checked to be an object")
+            .entry(other_user_id.as_str())
+            .or_insert_with(|| json!([]));
+        if !room_ids.is_array() {
+            *room_ids = json!([]);
+        }
+        let room_ids = room_ids.as_array_mut().expect("room_ids is an array");
+
+        if room_ids
+            .iter()
+            .any(|r| r.as_str() == Some(room_id.as_str()))
+        {
+            return Ok(());
+        }
+        room_ids.push(room_id.as_str().into());
+
+        self.update(None, user_id, EventType::Direct, &direct_event, globals)
+    }
+
     /// Returns all changes to the account data that happened after `since`.
     pub fn changes_since(
         &self,
@@ -0,0 +1,155 @@
+//! Registrations of application services, which are read from the `appservices` directory at
+//! startup. Every `.yaml` file in the directory is one registration.
+
+use crate::{Error, Result};
+use regex::Regex;
+use ruma::{RoomAliasId, RoomId, ServerName, UserId};
+use serde::Deserialize;
+use std::{collections::HashSet, convert::TryFrom, fs, path::Path};
+use tracing::warn;
+
+/// The users, room aliases or room ids of a namespace. Exclusive namespaces can only be used by
+/// the appservice.
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "RawNamespace")]
+pub struct Namespace {
+    pub exclusive: bool,
+    pub regex: Regex,
+}
+
+#[derive(Deserialize)]
+struct RawNamespace {
+    #[serde(default)]
+    exclusive: bool,
+    regex: String,
+}
+
+impl TryFrom<RawNamespace> for Namespace {
+    type Error = regex::Error;
+
+    fn try_from(raw: RawNamespace) -> std::result::Result<Self, Self::Error> {
+        Ok(Self {
+            exclusive: raw.exclusive,
+            // Like in Synapse, the regex has to match at the start of the id
+            regex: Regex::new(&format!("^(?:{})", raw.regex))?,
+        })
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct Namespaces {
+    #[serde(default)]
+    pub users: Vec<Namespace>,
+    #[serde(default)]
+    pub aliases: Vec<Namespace>,
+    #[serde(default)]
+    pub rooms: Vec<Namespace>,
+}
+
+#[derive(Deserialize)]
+struct RawRegistration {
+    id: String,
+    url: Option<String>,
+    as_token: String,
+    hs_token: String,
+    sender_localpart: String,
+    #[serde(default)]
+    namespaces: Namespaces,
+    #[serde(default)]
+    rate_limited: Option<bool>,
+    #[serde(default)]
+    protocols: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct Registration {
+    pub id: String,
+    pub url: Option<String>, // Appservices without url don't receive events
+    pub as_token: String,    // Used by the appservice to authenticate at this server
+    pub hs_token: String,    // Used by this server to authenticate at the appservice
+    pub sender: UserId,      // The user the appservice acts as by default
+    pub namespaces: Namespaces,
+    pub rate_limited: bool,
+    pub protocols: Vec<String>,
+}
+
+impl Registration {
+    /// Checks if the user is the sender of the appservice or in one of its user namespaces.
+    pub fn is_user_match(&self, user_id: &UserId) -> bool {
+        user_id == &self.sender || is_match(&self.namespaces.users, user_id.as_str(), false)
+    }
+
+    /// Checks if the user is the sender of the appservice or in one of its exclusive user
+    /// namespaces. Other users and appservices can't use these user ids.
+    pub fn is_exclusive_user_match(&self, user_id: &UserId) -> bool {
+        user_id == &self.sender || is_match(&self.namespaces.users, user_id.as_str(), true)
+    }
+
+    /// Checks if the alias is in one of the alias namespaces.
+    pub fn is_room_alias_match(&self, alias: &RoomAliasId) -> bool {
+        is_match(&self.namespaces.aliases, alias.as_str(), false)
+    }
+
+    /// Checks if the alias is in one of the exclusive alias namespaces.
+    pub fn is_exclusive_room_alias_match(&self, alias: &RoomAliasId) -> bool {
+        is_match(&self.namespaces.aliases, alias.as_str(), true)
+    }
+
+    /// Checks if the room id is in one of the room namespaces.
+    pub fn is_room_match(&self, room_id: &RoomId) -> bool {
+        is_match(&self.namespaces.rooms, room_id.as_str(), false)
+    }
+}
+
+fn is_match(namespaces: &[Namespace], id: &str, exclusive_only: bool) -> bool {
+    namespaces
+        .iter()
+        .filter(|namespace| namespace.exclusive || !exclusive_only)
+        .any(|namespace| namespace.regex.is_match(id))
+}
+
+/// Reads all registrations in the directory. Ids and `as_token`s have to be unique.
+pub fn load_registrations(dir: &Path, server_name: &ServerName) -> Result<Vec<Registration>> {
+    let mut paths = fs::read_dir(dir)
+        .map_err(|_| Error::BadConfig("Can't read the appservices directory."))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.retain(|path| {
+        path.extension()
+            .map_or(false, |extension| extension == "yaml" || extension == "yml")
+    });
+    paths.sort();
+
+    let mut registrations = Vec::new();
+    let mut ids = HashSet::new();
+    let mut as_tokens = HashSet::new();
+    for path in paths {
+        let raw =
+            serde_yaml::from_str::<RawRegistration>(&fs::read_to_string(&path)?).map_err(|e| {
+                warn!("Invalid appservice registration {}: {}", path.display(), e);
+                Error::BadConfig("Invalid appservice registration.")
+            })?;
+
+        let sender = UserId::parse_with_server_name(raw.sender_localpart, server_name)
+            .map_err(|_| Error::BadConfig("Invalid sender_localpart of appservice."))?;
+
+        if !ids.insert(raw.id.clone()) || !as_tokens.insert(raw.as_token.clone()) {
+            return Err(Error::BadConfig(
+                "Ids and as_tokens of appservices have to be unique.",
+            ));
+        }
+
+        registrations.push(Registration {
+            id: raw.id,
+            url: raw.url.map(|url| url.trim_end_matches('/').to_owned()),
+            as_token: raw.as_token,
+            hs_token: raw.hs_token,
+            sender,
+            namespaces: raw.namespaces,
+            rate_limited: raw.rate_limited.unwrap_or(true),
+            protocols: raw.protocols,
+        });
+    }
+
+    Ok(registrations)
+}
@@ -0,0 +1,142 @@
+use super::abstraction::KvTree;
+use crate::{utils, Error, Result};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+pub const COUNTER: &str = "c";
+
+/// How many counts are reserved in the database at once
+const RESERVATION: u64 = 1000;
+
+/// The global counter, which is the position of everything in the event stream.
+///
+/// Counts are handed out from memory. The database stores an upper bound of the handed out
+/// counts, so after a crash the counter continues behind all counts that could have been used.
+pub struct Counter {
+    tree: Arc<dyn KvTree>,
+    count: AtomicU64,
+    reserved: AtomicU64,
+    reserve_lock: Mutex<()>,
+}
+
+impl Counter {
+    pub fn load(tree: Arc<dyn KvTree>) -> Result<Self> {
+        let stored = tree.get(COUNTER.as_bytes())?.map_or(Ok(0_u64), |bytes| {
+            utils::u64_from_bytes(&bytes)
+                .map_err(|_| Error::bad_database("Count has invalid bytes."))
+        })?;
+
+        Ok(Self {
+            tree,
+            count: AtomicU64::new(stored),
+            reserved: AtomicU64::new(stored),
+            reserve_lock: Mutex::new(()),
+        })
+    }
+
+    pub fn next(&self) -> Result<u64> {
+        let count = self.count.fetch_add(1, Ordering::SeqCst) + 1;
+
+        // A count is only handed out after the database knows that it could be used
+        if count > self.reserved.load(Ordering::SeqCst) {
+            let _lock = self.reserve_lock.lock().unwrap();
+            if count > self.reserved.load(Ordering::SeqCst) {
+                let reserved = count + RESERVATION;
+                self.tree
+                    .insert(COUNTER.as_bytes(), &reserved.to_be_bytes())?;
+                self.reserved.store(reserved, Ordering::SeqCst);
+            }
+        }
+
+        Ok(count)
+    }
+
+    pub fn current(&self) -> u64 {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    /// Stores the current count instead of the reservation, so the counter doesn't skip the
+    /// unused counts after a restart. Used when the server shuts down.
+    pub fn store(&self) -> Result<()> {
+        let _lock = self.reserve_lock.lock().unwrap();
+        let count = self.current();
+        self.tree.insert(COUNTER.as_bytes(), &count.to_be_bytes())?;
+        self.reserved.store(count, Ordering::SeqCst);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Counter, COUNTER, RESERVATION};
+    use crate::{
+        database::abstraction::{KvTree, MemoryTree},
+        utils,
+    };
+    use std::{sync::Arc, thread};
+
+    fn stored(tree: &MemoryTree) -> u64 {
+        utils::u64_from_bytes(&tree.get(COUNTER.as_bytes()).unwrap().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn concurrent_counts_are_unique_and_increasing() {
+        let tree = MemoryTree::new();
+        let counter = Arc::new(Counter::load(tree.clone()).unwrap());
+
+        let threads = (0..8)
+            .map(|_| {
+                let counter = Arc::clone(&counter);
+                thread::spawn(move || {
+                    (0..125_000)
+                        .map(|_| counter.next().unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut counts = Vec::new();
+        for thread in threads {
+            let thread_counts = thread.join().unwrap();
+            assert!(thread_counts.windows(2).all(|pair| pair[0] < pair[1]));
+            counts.extend(thread_counts);
+        }
+
+        counts.sort_unstable();
+        assert!(counts.iter().copied().eq(1..=1_000_000));
+        assert_eq!(counter.current(), 1_000_000);
+        assert!(stored(&tree) >= 1_000_000);
+    }
+
+    #[test]
+    fn counter_continues_behind_reserved_counts_after_a_crash() {
+        let tree = MemoryTree::new();
+        let counter = Counter::load(tree.clone()).unwrap();
+        for _ in 0..10 {
+            counter.next().unwrap();
+        }
+        assert_eq!(stored(&tree), 1 + RESERVATION);
+
+        // Without `store` the unused part of the reservation is skipped
+        let counter = Counter::load(tree.clone()).unwrap();
+        assert_eq!(counter.current(), 1 + RESERVATION);
+        assert_eq!(counter.next().unwrap(), 2 + RESERVATION);
+    }
+
+    #[test]
+    fn stored_counter_continues_at_the_current_count() {
+        let tree = MemoryTree::new();
+        let counter = Counter::load(tree.clone()).unwrap();
+        for _ in 0..10 {
+            counter.next().unwrap();
+        }
+        counter.store().unwrap();
+        assert_eq!(stored(&tree), 10);
+
+        let counter = Counter::load(tree.clone()).unwrap();
+        assert_eq!(counter.next().unwrap(), 11);
+    }
+}
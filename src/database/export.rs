@@ -0,0 +1,201 @@
+//! Archives of all trees of a sled database for `conduit export` and `conduit import`.
+//!
+//! An archive starts with `MAGIC`, followed by the collections of the database. A collection is
+//! `1`, its type and name and its entries, each entry is `1` followed by its fields, and `0`
+//! ends the entries. `0` ends the collections and the archive ends with the SHA-256 digest of
+//! everything before it. Byte strings are written with their length as u64.
+
+use crate::{Error, Result};
+use ring::digest;
+use std::{
+    convert::TryInto,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+const MAGIC: &[u8] = b"conduit export 1\n";
+
+/// A collection of the database: its type, its name and its entries.
+type Collection = (Vec<u8>, Vec<u8>, Vec<Vec<Vec<u8>>>);
+
+/// Writes everything to the file and hashes it along the way.
+struct HashingWriter {
+    file: BufWriter<File>,
+    context: digest::Context,
+}
+
+impl HashingWriter {
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.context.update(bytes);
+        self.file.write_all(bytes)?;
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.write(&(bytes.len() as u64).to_be_bytes())?;
+        self.write(bytes)
+    }
+}
+
+/// Writes all trees of the database into a new archive and returns the number of entries. The
+/// archive must not exist yet.
+pub fn export(db: &sled::Db, archive_path: &Path) -> Result<u64> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(archive_path)?;
+    let mut writer = HashingWriter {
+        file: BufWriter::new(file),
+        context: digest::Context::new(&digest::SHA256),
+    };
+
+    writer.write(MAGIC)?;
+
+    let mut entries = 0;
+    for (collection_type, name, collection_entries) in db.export() {
+        writer.write(&[1])?;
+        writer.write_bytes(&collection_type)?;
+        writer.write_bytes(&name)?;
+
+        for fields in collection_entries {
+            writer.write(&[1])?;
+            writer.write(&(fields.len() as u64).to_be_bytes())?;
+            for field in fields {
+                writer.write_bytes(&field)?;
+            }
+            entries += 1;
+        }
+        writer.write(&[0])?;
+    }
+    writer.write(&[0])?;
+
+    let HashingWriter { mut file, context } = writer;
+    file.write_all(context.finish().as_ref())?;
+    file.flush()?;
+    file.get_ref().sync_all()?;
+
+    Ok(entries)
+}
+
+/// Checks that the archive is complete and was not modified, without reading it into memory.
+pub fn verify(archive_path: &Path) -> Result<()> {
+    let length = fs::metadata(archive_path)?.len();
+    let digest_length = digest::SHA256.output_len as u64;
+    if length < MAGIC.len() as u64 + 1 + digest_length {
+        return Err(Error::BadArchive("The archive is too short."));
+    }
+
+    let mut reader = BufReader::new(File::open(archive_path)?);
+    let mut context = digest::Context::new(&digest::SHA256);
+    let mut remaining = length - digest_length;
+    let mut buffer = vec![0; 64 * 1024];
+    while remaining > 0 {
+        let chunk = remaining.min(buffer.len() as u64) as usize;
+        reader.read_exact(&mut buffer[..chunk])?;
+        context.update(&buffer[..chunk]);
+        remaining -= chunk as u64;
+    }
+
+    let mut expected = vec![0; digest_length as usize];
+    reader.read_exact(&mut expected)?;
+    if context.finish().as_ref() != &expected[..] {
+        return Err(Error::BadArchive(
+            "The checksum of the archive is wrong, the archive is corrupted.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Imports all collections of a verified archive into the database and returns the number of
+/// imported entries.
+pub fn import(db: &sled::Db, archive_path: &Path) -> Result<u64> {
+    let mut reader = BufReader::new(File::open(archive_path)?);
+
+    let mut magic = vec![0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(Error::BadArchive("This is not a Conduit export."));
+    }
+
+    let mut entries = 0;
+    // Collections are imported one after another, so only one has to fit in memory
+    while let Some((collection_type, name, collection_entries)) = read_collection(&mut reader)? {
+        let count = collection_entries.len();
+        entries += count as u64;
+
+        // The global counter and the keypair are in this tree, the server can't work without them
+        let globals = if name == b"global" {
+            Some(collection_entries.clone())
+        } else {
+            None
+        };
+
+        db.import(vec![(
+            collection_type,
+            name.clone(),
+            collection_entries.into_iter(),
+        )]);
+
+        let tree = db.open_tree(&name)?;
+        if tree.len() != count {
+            return Err(Error::BadArchive(
+                "A tree of the archive was not imported completely.",
+            ));
+        }
+
+        for fields in globals.into_iter().flatten() {
+            if let [key, value] = &fields[..] {
+                if tree.get(key)?.as_deref() != Some(&value[..]) {
+                    return Err(Error::BadArchive(
+                        "The global counter or keypair was not imported correctly.",
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+fn read_collection(reader: &mut impl Read) -> Result<Option<Collection>> {
+    if read_u8(reader)? == 0 {
+        return Ok(None);
+    }
+
+    let collection_type = read_bytes(reader)?;
+    let name = read_bytes(reader)?;
+
+    let mut entries = Vec::new();
+    while read_u8(reader)? == 1 {
+        let field_count = read_u64(reader)?;
+        let fields = (0..field_count)
+            .map(|_| read_bytes(reader))
+            .collect::<Result<Vec<_>>>()?;
+        entries.push(fields);
+    }
+
+    Ok(Some((collection_type, name, entries)))
+}
+
+fn read_u8(reader: &mut impl Read) -> Result<u8> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn read_bytes(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let length = read_u64(reader)?
+        .try_into()
+        .map_err(|_| Error::BadArchive("The archive contains an entry that is too big."))?;
+    let mut bytes = vec![0; length];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
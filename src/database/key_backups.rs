@@ -59,6 +59,21 @@ impl KeyBackups {
         Ok(())
     }
 
+    /// Deletes all backups of the user and the keys in them.
+    pub fn delete_all_backups(&self, user_id: &UserId) -> Result<()> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        for key in self.backupid_algorithm.scan_prefix(&prefix).keys() {
+            let key = key?;
+            let version = utils::string_from_bytes(&key[prefix.len()..])
+                .map_err(|_| Error::bad_database("backupid_algorithm key is invalid."))?;
+            self.delete_backup(user_id, &version)?;
+        }
+
+        Ok(())
+    }
+
     pub fn update_backup(
         &self,
         user_id: &UserId,
@@ -122,6 +137,10 @@ impl KeyBackups {
         })
     }
 
+    /// Adds the key of a session to the backup.
+    ///
+    /// If the backup already contains a key for the session, it is only replaced if the new key
+    /// is better (see `is_better_key`).
     pub fn add_key(
         &self,
         user_id: &UserId,
@@ -131,30 +150,40 @@ impl KeyBackups {
         key_data: &KeyData,
         globals: &super::globals::Globals<'_>,
     ) -> Result<()> {
-        let mut key = user_id.to_string().as_bytes().to_vec();
-        key.push(0xff);
-        key.extend_from_slice(version.as_bytes());
+        let mut backup_key = user_id.to_string().as_bytes().to_vec();
+        backup_key.push(0xff);
+        backup_key.extend_from_slice(version.as_bytes());
 
-        if self.backupid_algorithm.get(&key)?.is_none() {
+        if self.backupid_algorithm.get(&backup_key)?.is_none() {
             return Err(Error::BadRequest(
                 ErrorKind::NotFound,
                 "Tried to update nonexistent backup.",
             ));
         }
 
-        self.backupid_etag
-            .insert(&key, &globals.next_count()?.to_be_bytes())?;
-
+        let mut key = backup_key.clone();
         key.push(0xff);
         key.extend_from_slice(room_id.to_string().as_bytes());
         key.push(0xff);
         key.extend_from_slice(session_id.as_bytes());
 
+        if let Some(old_key_data) = self.backupkeyid_backup.get(&key)? {
+            let old_key_data = serde_json::from_slice::<KeyData>(&old_key_data)
+                .map_err(|_| Error::bad_database("KeyData in backupkeyid_backup is invalid."))?;
+
+            if !is_better_key(key_data, &old_key_data) {
+                return Ok(());
+            }
+        }
+
         self.backupkeyid_backup.insert(
             &key,
             &*serde_json::to_string(&key_data).expect("KeyData::to_string always works"),
         )?;
 
+        self.backupid_etag
+            .insert(&backup_key, &globals.next_count()?.to_be_bytes())?;
+
         Ok(())
     }
 
@@ -162,10 +191,29 @@ impl KeyBackups {
         let mut prefix = user_id.to_string().as_bytes().to_vec();
         prefix.push(0xff);
         prefix.extend_from_slice(version.as_bytes());
+        prefix.push(0xff);
 
         Ok(self.backupkeyid_backup.scan_prefix(&prefix).count())
     }
 
+    fn bump_etag(
+        &self,
+        user_id: &UserId,
+        version: &str,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<()> {
+        let mut key = user_id.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&version.as_bytes());
+
+        if self.backupid_etag.contains_key(&key)? {
+            self.backupid_etag
+                .insert(&key, &globals.next_count()?.to_be_bytes())?;
+        }
+
+        Ok(())
+    }
+
     pub fn get_etag(&self, user_id: &UserId, version: &str) -> Result<String> {
         let mut key = user_id.to_string().as_bytes().to_vec();
         key.push(0xff);
@@ -289,7 +337,12 @@ impl KeyBackups {
             .transpose()
     }
 
-    pub fn delete_all_keys(&self, user_id: &UserId, version: &str) -> Result<()> {
+    pub fn delete_all_keys(
+        &self,
+        user_id: &UserId,
+        version: &str,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<()> {
         let mut key = user_id.to_string().as_bytes().to_vec();
         key.push(0xff);
         key.extend_from_slice(&version.as_bytes());
@@ -304,7 +357,7 @@ impl KeyBackups {
             self.backupkeyid_backup.remove(outdated_key)?;
         }
 
-        Ok(())
+        self.bump_etag(user_id, version, globals)
     }
 
     pub fn delete_room_keys(
@@ -312,6 +365,7 @@ impl KeyBackups {
         user_id: &UserId,
         version: &str,
         room_id: &RoomId,
+        globals: &super::globals::Globals<'_>,
     ) -> Result<()> {
         let mut key = user_id.to_string().as_bytes().to_vec();
         key.push(0xff);
@@ -329,7 +383,7 @@ impl KeyBackups {
             self.backupkeyid_backup.remove(outdated_key)?;
         }
 
-        Ok(())
+        self.bump_etag(user_id, version, globals)
     }
 
     pub fn delete_room_key(
@@ -338,6 +392,7 @@ impl KeyBackups {
         version: &str,
         room_id: &RoomId,
         session_id: &str,
+        globals: &super::globals::Globals<'_>,
     ) -> Result<()> {
         let mut key = user_id.to_string().as_bytes().to_vec();
         key.push(0xff);
@@ -356,6 +411,95 @@ impl KeyBackups {
             self.backupkeyid_backup.remove(outdated_key)?;
         }
 
-        Ok(())
+        self.bump_etag(user_id, version, globals)
+    }
+}
+
+/// Compares two keys of the same session as described in the spec: Verified keys are better than
+/// unverified keys, then the key with the lower `first_message_index` and then the key with the
+/// lower `forwarded_count` wins. Equal keys are not replaced.
+fn is_better_key(new: &KeyData, old: &KeyData) -> bool {
+    (
+        !new.is_verified,
+        new.first_message_index,
+        new.forwarded_count,
+    ) < (
+        !old.is_verified,
+        old.first_message_index,
+        old.forwarded_count,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_better_key, KeyBackups};
+    use ruma::{api::client::r0::backup::KeyData, UserId};
+    use serde_json::json;
+    use std::convert::TryFrom;
+
+    fn key_data(is_verified: bool, first_message_index: u64, forwarded_count: u64) -> KeyData {
+        serde_json::from_value(json!({
+            "first_message_index": first_message_index,
+            "forwarded_count": forwarded_count,
+            "is_verified": is_verified,
+            "session_data": {
+                "ephemeral": "ephemeral",
+                "ciphertext": "ciphertext",
+                "mac": "mac",
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn better_keys_are_verified_older_and_less_forwarded() {
+        // Verified keys win, even if they start at a later message
+        assert!(is_better_key(&key_data(true, 5, 3), &key_data(false, 0, 0)));
+        assert!(!is_better_key(
+            &key_data(false, 0, 0),
+            &key_data(true, 5, 3)
+        ));
+
+        assert!(is_better_key(&key_data(true, 0, 3), &key_data(true, 1, 0)));
+        assert!(is_better_key(&key_data(true, 1, 0), &key_data(true, 1, 1)));
+        // Equal keys are not replaced
+        assert!(!is_better_key(&key_data(true, 1, 1), &key_data(true, 1, 1)));
+    }
+
+    #[test]
+    fn all_backups_of_a_user_are_deleted() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let key_backups = KeyBackups {
+            backupid_algorithm: db.open_tree("backupid_algorithm").unwrap(),
+            backupid_etag: db.open_tree("backupid_etag").unwrap(),
+            backupkeyid_backup: db.open_tree("backupkeyid_backup").unwrap(),
+        };
+        let alice = UserId::try_from("@alice:example.com").unwrap();
+        let bob = UserId::try_from("@bob:example.com").unwrap();
+
+        for (user_id, version) in &[(&alice, "1"), (&alice, "2"), (&bob, "3")] {
+            let mut key = user_id.to_string().as_bytes().to_vec();
+            key.push(0xff);
+            key.extend_from_slice(version.as_bytes());
+            key_backups.backupid_algorithm.insert(&key, "{}").unwrap();
+            key_backups
+                .backupid_etag
+                .insert(&key, &1_u64.to_be_bytes())
+                .unwrap();
+
+            key.push(0xff);
+            key.extend_from_slice(b"!room:example.com");
+            key.push(0xff);
+            key.extend_from_slice(b"session");
+            key_backups.backupkeyid_backup.insert(&key, "{}").unwrap();
+        }
+
+        key_backups.delete_all_backups(&alice).unwrap();
+
+        assert_eq!(key_backups.count_keys(&alice, "1").unwrap(), 0);
+        assert_eq!(key_backups.count_keys(&alice, "2").unwrap(), 0);
+        assert_eq!(key_backups.backupid_algorithm.len(), 1);
+        assert_eq!(key_backups.backupid_etag.len(), 1);
+        assert_eq!(key_backups.count_keys(&bob, "3").unwrap(), 1);
     }
 }
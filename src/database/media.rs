@@ -1,5 +1,14 @@
 use crate::{utils, Error, Result};
-use std::mem;
+use image::{imageops::FilterType, GenericImageView};
+use ruma::{api::client::r0::media::get_content_thumbnail::Method, ServerName};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Thumbnail requests larger than this in either dimension are clamped
+const MAX_THUMBNAIL_SIZE: u32 = 1024;
+
+/// How often the remote media retention task scans the media store
+const RETENTION_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 pub struct FileMeta {
     pub filename: Option<String>,
@@ -9,6 +18,9 @@ pub struct FileMeta {
 
 pub struct Media {
     pub(super) mediaid_file: sled::Tree, // MediaId = MXC + WidthHeight + Filename + ContentType
+    pub(super) thumbnailid_file: sled::Tree, // ThumbnailId = MXC + WidthHeight + Crop (u8) + Filename + ContentType
+    pub(super) mxc_lastaccessed: sled::Tree, // LastAccessed = Millis since unix epoch (u64)
+    pub(super) url_previews: sled::Tree,     // Url -> ExpiresAt (u64) + preview json
 }
 
 impl Media {
@@ -30,6 +42,110 @@ impl Media {
         key.extend_from_slice(content_type.as_bytes());
 
         self.mediaid_file.insert(key, file)?;
+        self.update_last_accessed(&mxc)?;
+
+        Ok(())
+    }
+
+    /// Remembers that the file was used now, so it is not removed by the retention task.
+    fn update_last_accessed(&self, mxc: &str) -> Result<()> {
+        self.mxc_lastaccessed
+            .insert(mxc, &utils::millis_since_unix_epoch().to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Removes all remote files (and their thumbnails) that were not accessed since
+    /// `older_than` (millis since unix epoch). Files of this server are never removed.
+    ///
+    /// Returns how many files were removed.
+    pub fn purge_remote_media(&self, server_name: &ServerName, older_than: u64) -> Result<usize> {
+        let local_prefix = format!("mxc://{}/", server_name);
+        let mut purged = 0;
+
+        for r in self.mxc_lastaccessed.iter() {
+            let (mxc, last_accessed) = r?;
+
+            if mxc.starts_with(local_prefix.as_bytes()) {
+                continue;
+            }
+
+            let last_accessed = utils::u64_from_bytes(&last_accessed)
+                .map_err(|_| Error::bad_database("Invalid last accessed time in db."))?;
+            if last_accessed >= older_than {
+                continue;
+            }
+
+            let mut prefix = mxc.to_vec();
+            prefix.push(0xff);
+            for key in self.mediaid_file.scan_prefix(&prefix).keys() {
+                self.mediaid_file.remove(key?)?;
+            }
+            for key in self.thumbnailid_file.scan_prefix(&prefix).keys() {
+                self.thumbnailid_file.remove(key?)?;
+            }
+            self.mxc_lastaccessed.remove(&mxc)?;
+
+            purged += 1;
+        }
+
+        Ok(purged)
+    }
+
+    /// Periodically removes remote media that was not accessed within `retention`.
+    pub fn start_remote_media_retention(&self, server_name: Box<ServerName>, retention: Duration) {
+        let media = Self {
+            mediaid_file: self.mediaid_file.clone(),
+            thumbnailid_file: self.thumbnailid_file.clone(),
+            mxc_lastaccessed: self.mxc_lastaccessed.clone(),
+            url_previews: self.url_previews.clone(),
+        };
+
+        tokio::spawn(async move {
+            loop {
+                let older_than =
+                    utils::millis_since_unix_epoch().saturating_sub(retention.as_millis() as u64);
+
+                match media.purge_remote_media(&server_name, older_than) {
+                    Ok(0) => {}
+                    Ok(purged) => info!("Removed {} remote media files", purged),
+                    Err(e) => warn!("Failed to remove old remote media: {}", e),
+                }
+
+                tokio::time::delay_for(RETENTION_SWEEP_INTERVAL).await;
+            }
+        });
+    }
+
+    /// Returns the cached preview of the url, unless it expired.
+    pub fn url_preview(&self, url: &str) -> Result<Option<serde_json::Value>> {
+        let value = match self.url_previews.get(url)? {
+            Some(value) if value.len() >= 8 => value,
+            Some(_) => return Err(Error::bad_database("Invalid url preview in db.")),
+            None => return Ok(None),
+        };
+
+        let expires_at = utils::u64_from_bytes(&value[..8])
+            .map_err(|_| Error::bad_database("Invalid url preview expiry in db."))?;
+        if expires_at < utils::millis_since_unix_epoch() {
+            self.url_previews.remove(url)?;
+            return Ok(None);
+        }
+
+        serde_json::from_slice(&value[8..])
+            .map(Some)
+            .map_err(|_| Error::bad_database("Invalid url preview in db."))
+    }
+
+    /// Caches the preview of the url until `expires_at` (millis since unix epoch).
+    pub fn set_url_preview(
+        &self,
+        url: &str,
+        preview: &serde_json::Value,
+        expires_at: u64,
+    ) -> Result<()> {
+        let mut value = expires_at.to_be_bytes().to_vec();
+        value.extend_from_slice(preview.to_string().as_bytes());
+        self.url_previews.insert(url, value)?;
 
         Ok(())
     }
@@ -43,139 +159,221 @@ impl Media {
         prefix.push(0xff);
 
         if let Some(r) = self.mediaid_file.scan_prefix(&prefix).next() {
+            self.update_last_accessed(&mxc)?;
+
             let (key, file) = r?;
-            let mut parts = key.rsplit(|&b| b == 0xff);
-
-            let content_type = utils::string_from_bytes(
-                parts
-                    .next()
-                    .ok_or_else(|| Error::bad_database("Media ID in db is invalid."))?,
-            )
-            .map_err(|_| Error::bad_database("Content type in mediaid_file is invalid unicode."))?;
-
-            let filename_bytes = parts
-                .next()
-                .ok_or_else(|| Error::bad_database("Media ID in db is invalid."))?;
-
-            let filename = if filename_bytes.is_empty() {
-                None
-            } else {
-                Some(utils::string_from_bytes(filename_bytes).map_err(|_| {
-                    Error::bad_database("Filename in mediaid_file is invalid unicode.")
-                })?)
-            };
-
-            Ok(Some(FileMeta {
-                filename,
-                content_type,
-                file: file.to_vec(),
-            }))
+            parse_file_meta(&key, &file).map(Some)
         } else {
             Ok(None)
         }
     }
 
+    /// Checks if the file is stored, without reading it.
+    pub fn exists(&self, mxc: &str) -> Result<bool> {
+        let mut prefix = mxc.as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(&0_u32.to_be_bytes()); // Width = 0 if it's not a thumbnail
+        prefix.extend_from_slice(&0_u32.to_be_bytes()); // Height = 0 if it's not a thumbnail
+        prefix.push(0xff);
+
+        Ok(self.mediaid_file.scan_prefix(&prefix).next().is_some())
+    }
+
     /// Downloads a file's thumbnail.
-    pub fn get_thumbnail(&self, mxc: String, width: u32, height: u32) -> Result<Option<FileMeta>> {
-        let mut main_prefix = mxc.as_bytes().to_vec();
-        main_prefix.push(0xff);
+    ///
+    /// Generated thumbnails are cached, so they only have to be generated once. Files that are
+    /// not images, animated images and images that are already small enough are returned as they
+    /// are.
+    pub fn get_thumbnail(
+        &self,
+        mxc: String,
+        width: u32,
+        height: u32,
+        method: &Method,
+    ) -> Result<Option<FileMeta>> {
+        let (width, height, crop) = thumbnail_properties(width, height, method);
 
-        let mut thumbnail_prefix = main_prefix.clone();
+        let mut thumbnail_prefix = mxc.as_bytes().to_vec();
+        thumbnail_prefix.push(0xff);
         thumbnail_prefix.extend_from_slice(&width.to_be_bytes());
         thumbnail_prefix.extend_from_slice(&height.to_be_bytes());
+        thumbnail_prefix.push(crop as u8);
         thumbnail_prefix.push(0xff);
 
-        let mut original_prefix = main_prefix;
-        original_prefix.extend_from_slice(&0_u32.to_be_bytes()); // Width = 0 if it's not a thumbnail
-        original_prefix.extend_from_slice(&0_u32.to_be_bytes()); // Height = 0 if it's not a thumbnail
-        original_prefix.push(0xff);
-
-        if let Some(r) = self.mediaid_file.scan_prefix(&thumbnail_prefix).next() {
+        if let Some(r) = self.thumbnailid_file.scan_prefix(&thumbnail_prefix).next() {
             // Using saved thumbnail
+            self.update_last_accessed(&mxc)?;
+
             let (key, file) = r?;
-            let mut parts = key.rsplit(|&b| b == 0xff);
-
-            let content_type = utils::string_from_bytes(
-                parts
-                    .next()
-                    .ok_or_else(|| Error::bad_database("Invalid Media ID in db"))?,
-            )
-            .map_err(|_| Error::bad_database("Content type in mediaid_file is invalid unicode."))?;
-
-            let filename_bytes = parts
-                .next()
-                .ok_or_else(|| Error::bad_database("Media ID in db is invalid."))?;
-
-            let filename = if filename_bytes.is_empty() {
-                None
-            } else {
-                Some(
-                    utils::string_from_bytes(filename_bytes)
-                        .map_err(|_| Error::bad_database("Filename in db is invalid."))?,
-                )
-            };
-
-            Ok(Some(FileMeta {
-                filename,
-                content_type,
-                file: file.to_vec(),
-            }))
-        } else if let Some(r) = self.mediaid_file.scan_prefix(&original_prefix).next() {
-            // Generate a thumbnail
-            let (key, file) = r?;
-            let mut parts = key.rsplit(|&b| b == 0xff);
-
-            let content_type = utils::string_from_bytes(
-                parts
-                    .next()
-                    .ok_or_else(|| Error::bad_database("Media ID in db is invalid."))?,
-            )
-            .map_err(|_| Error::bad_database("Content type in mediaid_file is invalid unicode."))?;
-
-            let filename_bytes = parts
-                .next()
-                .ok_or_else(|| Error::bad_database("Media ID in db is invalid."))?;
-
-            let filename = if filename_bytes.is_empty() {
-                None
-            } else {
-                Some(utils::string_from_bytes(filename_bytes).map_err(|_| {
-                    Error::bad_database("Filename in mediaid_file is invalid unicode.")
-                })?)
-            };
-
-            if let Ok(image) = image::load_from_memory(&file) {
-                let thumbnail = image.thumbnail(width, height);
-                let mut thumbnail_bytes = Vec::new();
-                thumbnail.write_to(&mut thumbnail_bytes, image::ImageOutputFormat::Png)?;
-
-                // Save thumbnail in database so we don't have to generate it again next time
-                let mut thumbnail_key = key.to_vec();
-                let width_index = thumbnail_key
-                    .iter()
-                    .position(|&b| b == 0xff)
-                    .ok_or_else(|| Error::bad_database("Media in db is invalid."))?
-                    + 1;
-                let mut widthheight = width.to_be_bytes().to_vec();
-                widthheight.extend_from_slice(&height.to_be_bytes());
-
-                thumbnail_key.splice(
-                    width_index..width_index + 2 * mem::size_of::<u32>(),
-                    widthheight,
-                );
-
-                self.mediaid_file.insert(thumbnail_key, &*thumbnail_bytes)?;
-
-                Ok(Some(FileMeta {
-                    filename,
-                    content_type,
-                    file: thumbnail_bytes.to_vec(),
-                }))
-            } else {
-                Ok(None)
-            }
+            return parse_file_meta(&key, &file).map(Some);
+        }
+
+        let original = match self.get(mxc)? {
+            Some(original) => original,
+            None => return Ok(None),
+        };
+
+        // Thumbnails of animated images would only show the first frame
+        if original.content_type == "image/gif" {
+            return Ok(Some(original));
+        }
+
+        let image = match image::load_from_memory(&original.file) {
+            Ok(image) => image,
+            Err(_) => return Ok(Some(original)),
+        };
+
+        let (original_width, original_height) = image.dimensions();
+        if original_width <= width && original_height <= height {
+            return Ok(Some(original));
+        }
+
+        let thumbnail = if crop {
+            image.resize_to_fill(width, height, FilterType::CatmullRom)
         } else {
-            Ok(None)
+            image.thumbnail(width, height)
+        };
+
+        let mut thumbnail_bytes = Vec::new();
+        thumbnail.write_to(&mut thumbnail_bytes, image::ImageOutputFormat::Png)?;
+
+        // Save thumbnail in database so we don't have to generate it again next time
+        let mut thumbnail_key = thumbnail_prefix;
+        thumbnail_key.extend_from_slice(
+            original
+                .filename
+                .as_ref()
+                .map(|f| f.as_bytes())
+                .unwrap_or_default(),
+        );
+        thumbnail_key.push(0xff);
+        thumbnail_key.extend_from_slice(b"image/png");
+
+        self.thumbnailid_file
+            .insert(thumbnail_key, &*thumbnail_bytes)?;
+
+        Ok(Some(FileMeta {
+            filename: original.filename,
+            content_type: "image/png".to_owned(),
+            file: thumbnail_bytes,
+        }))
+    }
+}
+
+/// Returns the width, height and whether the thumbnail should be cropped.
+///
+/// Absurd sizes are clamped to the largest thumbnail size recommended by the spec.
+fn thumbnail_properties(width: u32, height: u32, method: &Method) -> (u32, u32, bool) {
+    if width > MAX_THUMBNAIL_SIZE || height > MAX_THUMBNAIL_SIZE {
+        (800, 600, false)
+    } else {
+        (width.max(1), height.max(1), matches!(method, Method::Crop))
+    }
+}
+
+/// Reads the filename and content type from the end of the media id.
+fn parse_file_meta(key: &[u8], file: &[u8]) -> Result<FileMeta> {
+    let mut parts = key.rsplit(|&b| b == 0xff);
+
+    let content_type = utils::string_from_bytes(
+        parts
+            .next()
+            .ok_or_else(|| Error::bad_database("Media ID in db is invalid."))?,
+    )
+    .map_err(|_| Error::bad_database("Content type in db is invalid unicode."))?;
+
+    let filename_bytes = parts
+        .next()
+        .ok_or_else(|| Error::bad_database("Media ID in db is invalid."))?;
+
+    let filename = if filename_bytes.is_empty() {
+        None
+    } else {
+        Some(
+            utils::string_from_bytes(filename_bytes)
+                .map_err(|_| Error::bad_database("Filename in db is invalid unicode."))?,
+        )
+    };
+
+    Ok(FileMeta {
+        filename,
+        content_type,
+        file: file.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Media;
+    use crate::utils;
+    use ruma::ServerName;
+    use std::convert::TryFrom;
+
+    fn media() -> Media {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        Media {
+            mediaid_file: db.open_tree("mediaid_file").unwrap(),
+            thumbnailid_file: db.open_tree("thumbnailid_file").unwrap(),
+            mxc_lastaccessed: db.open_tree("mxc_lastaccessed").unwrap(),
+            url_previews: db.open_tree("url_previews").unwrap(),
         }
     }
+
+    #[test]
+    fn retention_removes_old_remote_media_only() {
+        let media = media();
+        let server_name = Box::<ServerName>::try_from("local.example").unwrap();
+
+        let old = ["mxc://local.example/old", "mxc://remote.example/old"];
+        let fresh = "mxc://remote.example/fresh";
+        for mxc in old.iter().chain(&[fresh]) {
+            media
+                .create(mxc.to_string(), None, "text/plain", b"file")
+                .unwrap();
+        }
+        let mut thumbnail_key = b"mxc://remote.example/old".to_vec();
+        thumbnail_key.push(0xff);
+        media.thumbnailid_file.insert(thumbnail_key, b"").unwrap();
+        // The old files were last accessed a day after the unix epoch
+        for mxc in &old {
+            media
+                .mxc_lastaccessed
+                .insert(mxc, &(24 * 60 * 60 * 1000_u64).to_be_bytes())
+                .unwrap();
+        }
+
+        let older_than = utils::millis_since_unix_epoch() - 60 * 60 * 1000;
+        assert_eq!(
+            media.purge_remote_media(&server_name, older_than).unwrap(),
+            1
+        );
+
+        assert!(media.exists("mxc://local.example/old").unwrap());
+        assert!(!media.exists("mxc://remote.example/old").unwrap());
+        assert!(media.thumbnailid_file.is_empty());
+        assert!(media.exists(fresh).unwrap());
+    }
+
+    #[test]
+    fn downloads_keep_remote_media_from_being_removed() {
+        let media = media();
+        let server_name = Box::<ServerName>::try_from("local.example").unwrap();
+
+        let mxc = "mxc://remote.example/file";
+        media
+            .create(mxc.to_owned(), None, "text/plain", b"file")
+            .unwrap();
+        media
+            .mxc_lastaccessed
+            .insert(mxc, &0_u64.to_be_bytes())
+            .unwrap();
+
+        assert!(media.get(mxc.to_owned()).unwrap().is_some());
+        let older_than = utils::millis_since_unix_epoch() - 60 * 60 * 1000;
+        assert_eq!(
+            media.purge_remote_media(&server_name, older_than).unwrap(),
+            0
+        );
+        assert!(media.exists(mxc).unwrap());
+    }
 }
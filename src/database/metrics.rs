@@ -0,0 +1,154 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// Upper bounds of the federation latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// The metrics that are collected while the server runs. This only exists if metrics are
+/// enabled, so nothing is counted otherwise.
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(String, String, u16), u64>>, // Method, route and status code
+    federation_latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    federation_latency_sum_micros: AtomicU64,
+    federation_latency_count: AtomicU64,
+    active_syncs: AtomicU64,
+}
+
+/// Counts a running `/sync` request until it is dropped.
+pub struct ActiveSync<'a>(&'a AtomicU64);
+
+impl Drop for ActiveSync<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            requests: Mutex::new(BTreeMap::new()),
+            federation_latency_buckets: Default::default(),
+            federation_latency_sum_micros: AtomicU64::new(0),
+            federation_latency_count: AtomicU64::new(0),
+            active_syncs: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts a request that was answered. The route is the path pattern, so requests for
+    /// different rooms or users are counted together.
+    pub fn count_request(&self, method: &str, route: &str, status: u16) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((method.to_owned(), route.to_owned(), status))
+            .or_insert(0) += 1;
+    }
+
+    /// Records how long another server took to answer a request.
+    pub fn observe_federation_latency(&self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        for (bucket, upper_bound) in self
+            .federation_latency_buckets
+            .iter()
+            .zip(LATENCY_BUCKETS.iter())
+        {
+            if seconds <= *upper_bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        self.federation_latency_sum_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        self.federation_latency_count
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a `/sync` request as active until the returned value is dropped.
+    pub fn sync_started(&self) -> ActiveSync<'_> {
+        self.active_syncs.fetch_add(1, Ordering::Relaxed);
+        ActiveSync(&self.active_syncs)
+    }
+
+    /// Returns the collected metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        output.push_str("# HELP conduit_requests_total Answered requests by endpoint.\n");
+        output.push_str("# TYPE conduit_requests_total counter\n");
+        for ((method, route, status), count) in self.requests.lock().unwrap().iter() {
+            writeln!(
+                output,
+                "conduit_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                method,
+                escape_label(route),
+                status,
+                count
+            )
+            .expect("writing to a string always works");
+        }
+
+        output.push_str(
+            "# HELP conduit_federation_request_duration_seconds Time until other servers answered.\n",
+        );
+        output.push_str("# TYPE conduit_federation_request_duration_seconds histogram\n");
+        for (bucket, upper_bound) in self
+            .federation_latency_buckets
+            .iter()
+            .zip(LATENCY_BUCKETS.iter())
+        {
+            writeln!(
+                output,
+                "conduit_federation_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+                upper_bound,
+                bucket.load(Ordering::Relaxed)
+            )
+            .expect("writing to a string always works");
+        }
+        let count = self.federation_latency_count.load(Ordering::Relaxed);
+        writeln!(
+            output,
+            "conduit_federation_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            count
+        )
+        .expect("writing to a string always works");
+        writeln!(
+            output,
+            "conduit_federation_request_duration_seconds_sum {}",
+            self.federation_latency_sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        )
+        .expect("writing to a string always works");
+        writeln!(
+            output,
+            "conduit_federation_request_duration_seconds_count {}",
+            count
+        )
+        .expect("writing to a string always works");
+
+        output.push_str("# HELP conduit_active_syncs Running /sync requests.\n");
+        output.push_str("# TYPE conduit_active_syncs gauge\n");
+        writeln!(
+            output,
+            "conduit_active_syncs {}",
+            self.active_syncs.load(Ordering::Relaxed)
+        )
+        .expect("writing to a string always works");
+
+        output
+    }
+}
+
+/// Escapes a value for a label of the Prometheus text format.
+pub fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
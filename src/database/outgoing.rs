@@ -0,0 +1,187 @@
+//! Queues the PDUs and EDUs for other servers. Events stay queued while a server can't be
+//! reached and are sent in order once it can be reached again. Every failed transaction doubles
+//! the time until the server is tried again.
+
+use crate::{utils, Error, Result};
+use serde::{Deserialize, Serialize};
+use std::{cmp, convert::TryInto, time::Duration};
+
+/// How long the first retry waits after a failed transaction
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Serialize, Deserialize)]
+pub enum OutgoingEvent {
+    Pdu(serde_json::Value),
+    Edu(serde_json::Value),
+}
+
+/// The state of a server that failed to accept a transaction.
+#[derive(Serialize, Deserialize)]
+pub struct DestinationState {
+    pub failures: u32, // Failed transactions in a row
+    pub next_retry_ts: u64,
+}
+
+pub struct Outgoing {
+    pub(super) outgoing: sled::Tree, // OutgoingId = ServerName + 0xff + Count
+    pub(super) destination_state: sled::Tree, // Only servers with queued events that failed
+}
+
+impl Outgoing {
+    /// Adds the event to the end of the queue of the server.
+    pub fn queue(
+        &self,
+        destination: &str,
+        event: &OutgoingEvent,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<()> {
+        let mut key = destination.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&globals.next_count()?.to_be_bytes());
+
+        self.outgoing.insert(
+            key,
+            serde_json::to_vec(event).expect("OutgoingEvent can be serialized"),
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the oldest `limit` queued events of the server with their ids.
+    pub fn queued(
+        &self,
+        destination: &str,
+        limit: usize,
+    ) -> Result<Vec<(sled::IVec, OutgoingEvent)>> {
+        let mut prefix = destination.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.outgoing
+            .scan_prefix(prefix)
+            .take(limit)
+            .map(|r| {
+                let (key, value) = r?;
+                Ok((
+                    key,
+                    serde_json::from_slice(&value)
+                        .map_err(|_| Error::bad_database("Invalid outgoing event in db."))?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Returns how many events are queued for the server.
+    pub fn queue_len(&self, destination: &str) -> usize {
+        let mut prefix = destination.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.outgoing.scan_prefix(prefix).keys().count()
+    }
+
+    /// Removes events the server accepted from its queue. If the queue is empty, the server is
+    /// not tracked anymore.
+    pub fn remove_sent(&self, destination: &str, ids: &[sled::IVec]) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for id in ids {
+            batch.remove(id);
+        }
+        self.outgoing.apply_batch(batch)?;
+
+        if self.queue_len(destination) == 0 {
+            self.destination_state.remove(destination.as_bytes())?;
+        } else {
+            self.reset_backoff(destination)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the state of the server, or None if it didn't fail since its queue was empty.
+    pub fn state(&self, destination: &str) -> Result<Option<DestinationState>> {
+        self.destination_state
+            .get(destination.as_bytes())?
+            .map_or(Ok(None), |state| {
+                serde_json::from_slice(&state)
+                    .map(Some)
+                    .map_err(|_| Error::bad_database("Invalid destination state in db."))
+            })
+    }
+
+    /// Checks if the server failed recently, so nothing should be sent to it yet.
+    pub fn is_backing_off(&self, destination: &str) -> Result<bool> {
+        Ok(self.state(destination)?.map_or(false, |state| {
+            state.next_retry_ts > utils::millis_since_unix_epoch()
+        }))
+    }
+
+    /// Counts a failed transaction and returns when the server should be tried again.
+    pub fn failed(&self, destination: &str) -> Result<DestinationState> {
+        let failures = self
+            .state(destination)?
+            .map_or(0, |state| state.failures)
+            .saturating_add(1);
+
+        let delay = MIN_RETRY_DELAY
+            .checked_mul(2_u32.checked_pow(failures - 1).unwrap_or(u32::MAX))
+            .map_or(MAX_RETRY_DELAY, |delay| cmp::min(delay, MAX_RETRY_DELAY));
+
+        let state = DestinationState {
+            failures,
+            next_retry_ts: utils::millis_since_unix_epoch().saturating_add(
+                delay
+                    .as_millis()
+                    .try_into()
+                    .expect("MAX_RETRY_DELAY fits in u64"),
+            ),
+        };
+        self.destination_state.insert(
+            destination.as_bytes(),
+            serde_json::to_vec(&state).expect("DestinationState can be serialized"),
+        )?;
+
+        Ok(state)
+    }
+
+    /// Allows sending to the server right away, because it could be contacted.
+    pub fn reset_backoff(&self, destination: &str) -> Result<()> {
+        if self.state(destination)?.is_some() {
+            self.destination_state.insert(
+                destination.as_bytes(),
+                serde_json::to_vec(&DestinationState {
+                    failures: 0,
+                    next_retry_ts: 0,
+                })
+                .expect("DestinationState can be serialized"),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns all servers that have queued events and failed, by server name.
+    pub fn destinations(&self) -> impl Iterator<Item = Result<(String, DestinationState)>> {
+        self.destination_state.iter().map(|r| {
+            let (key, value) = r?;
+            Ok((
+                utils::string_from_bytes(&key)
+                    .map_err(|_| Error::bad_database("Invalid server name in db."))?,
+                serde_json::from_slice(&value)
+                    .map_err(|_| Error::bad_database("Invalid destination state in db."))?,
+            ))
+        })
+    }
+
+    /// Returns the servers with queued events whose backoff is over.
+    pub fn due_destinations(&self) -> Result<Vec<String>> {
+        let now = utils::millis_since_unix_epoch();
+
+        self.destinations()
+            .filter(|r| {
+                r.as_ref()
+                    .map_or(true, |(_, state)| state.next_retry_ts <= now)
+            })
+            .map(|r| r.map(|(destination, _)| destination))
+            .collect()
+    }
+}
@@ -0,0 +1,336 @@
+use crate::{push_rules, utils, Error, Result};
+use ruma::{
+    events::{presence::PresenceState, EventType},
+    DeviceId, EventId, UserId,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::warn;
+
+/// Notifications for a user are collected for this long before they are sent to the push
+/// gateway, so a burst of messages only results in one request
+const PUSH_DEBOUNCE: Duration = Duration::from_secs(2);
+
+pub struct Pushers {
+    pub(super) senderkey_pusher: sled::Tree,
+    /// The newest notification of every user that is waiting for the debounce timer
+    pub(super) userid_pendingnotification: Arc<Mutex<HashMap<UserId, Value>>>,
+}
+
+impl Pushers {
+    /// Adds or replaces a pusher of the device. Pushers with the kind `null` are removed.
+    ///
+    /// If `append` is false, the pusher replaces all other pushers with the same app id and
+    /// pushkey, even those of other users.
+    pub fn set_pusher(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        pusher: &Value,
+        append: bool,
+    ) -> Result<()> {
+        let app_id = pusher.get("app_id").and_then(|a| a.as_str());
+        let pushkey = pusher.get("pushkey").and_then(|p| p.as_str());
+        let (app_id, pushkey) = match (app_id, pushkey) {
+            (Some(app_id), Some(pushkey)) => (app_id, pushkey),
+            _ => {
+                return Err(Error::BadRequest(
+                    ruma::api::client::error::ErrorKind::MissingParam,
+                    "Pusher needs an app_id and a pushkey.",
+                ))
+            }
+        };
+
+        let mut key = user_id.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(device_id.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(app_id.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(pushkey.as_bytes());
+
+        if pusher.get("kind").map_or(true, |kind| kind.is_null()) {
+            self.senderkey_pusher.remove(key)?;
+            return Ok(());
+        }
+
+        if !append {
+            for (other_key, other_pusher) in self.senderkey_pusher.iter().filter_map(|r| r.ok()) {
+                let other_pusher = serde_json::from_slice::<Value>(&other_pusher)
+                    .map_err(|_| Error::bad_database("Invalid pusher in db."))?;
+
+                if other_pusher.get("app_id").and_then(|a| a.as_str()) == Some(app_id)
+                    && other_pusher.get("pushkey").and_then(|p| p.as_str()) == Some(pushkey)
+                {
+                    self.senderkey_pusher.remove(other_key)?;
+                }
+            }
+        }
+
+        self.senderkey_pusher.insert(key, &*pusher.to_string())?;
+
+        Ok(())
+    }
+
+    /// Returns all pushers of all devices of the user.
+    pub fn get_pushers(&self, user_id: &UserId) -> Result<Vec<Value>> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.senderkey_pusher
+            .scan_prefix(prefix)
+            .values()
+            .map(|pusher| {
+                serde_json::from_slice(&pusher?)
+                    .map_err(|_| Error::bad_database("Invalid pusher in db."))
+            })
+            .collect()
+    }
+
+    /// Removes all pushers of a device, for example when it logs out.
+    pub fn remove_device_pushers(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(device_id.as_bytes());
+        prefix.push(0xff);
+
+        for key in self.senderkey_pusher.scan_prefix(prefix).keys() {
+            self.senderkey_pusher.remove(key?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Queues push notifications for all local members of the room that are offline, have a
+    /// pusher and whose push rules say they should be notified about the event.
+    pub fn queue_notifications(
+        &self,
+        event_id: &EventId,
+        rooms: &super::rooms::Rooms,
+        users: &super::users::Users,
+        account_data: &super::account_data::AccountData,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<()> {
+        let pdu = match rooms.get_pdu(event_id)? {
+            Some(pdu) => pdu,
+            None => return Ok(()),
+        };
+        let event = serde_json::to_value(&pdu).expect("PduEvent can be serialized");
+
+        let power_levels = rooms
+            .room_state_get(&pdu.room_id, &EventType::RoomPowerLevels, "")?
+            .map(|pdu| pdu.content);
+        let member_count = rooms.room_members(&pdu.room_id).count() as u64;
+
+        for user_id in rooms.room_members(&pdu.room_id) {
+            let user_id = user_id?;
+
+            if user_id == pdu.sender
+                || user_id.server_name() != globals.server_name()
+                || self.get_pushers(&user_id)?.is_empty()
+            {
+                continue;
+            }
+
+            // Users that are online see the event in their client anyway
+            if rooms
+                .edus
+                .current_presence(&user_id)?
+                .map_or(false, |p| p.content.presence == PresenceState::Online)
+            {
+                continue;
+            }
+
+            let ruleset = match account_data.get::<ruma::events::push_rules::PushRulesEvent>(
+                None,
+                &user_id,
+                EventType::PushRules,
+            )? {
+                Some(event) => event.content.global,
+                None => continue,
+            };
+            let displayname = users.displayname(&user_id)?;
+            let context = push_rules::PushContext {
+                user_id: &user_id,
+                user_display_name: displayname.as_deref(),
+                room_id: &pdu.room_id,
+                member_count,
+                power_levels: power_levels.as_ref(),
+            };
+
+            let actions = push_rules::evaluate(&ruleset, &event, &context);
+            if !actions.notify {
+                continue;
+            }
+
+            let (unread, _) = rooms.edus.notification_counts(&pdu.room_id, &user_id)?;
+
+            let sender_display_name = rooms
+                .room_state_get(&pdu.room_id, &EventType::RoomMember, pdu.sender.as_str())?
+                .and_then(|member| {
+                    member
+                        .content
+                        .get("displayname")?
+                        .as_str()
+                        .map(str::to_owned)
+                });
+            let room_name = rooms
+                .room_state_get(&pdu.room_id, &EventType::RoomName, "")?
+                .and_then(|name| name.content.get("name")?.as_str().map(str::to_owned));
+
+            let mut tweaks = serde_json::Map::new();
+            if actions.highlight {
+                tweaks.insert("highlight".to_owned(), true.into());
+            }
+            if let Some(sound) = actions.sound {
+                tweaks.insert("sound".to_owned(), sound.into());
+            }
+
+            let notification = json!({
+                "event_id": pdu.event_id,
+                "room_id": pdu.room_id,
+                "type": pdu.kind,
+                "sender": pdu.sender,
+                "sender_display_name": sender_display_name,
+                "room_name": room_name,
+                "prio": if actions.highlight { "high" } else { "low" },
+                "counts": { "unread": unread },
+                "tweaks": tweaks,
+            });
+
+            self.queue(user_id, notification, globals.reqwest_client().clone());
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the pending notification of the user. If there was none, the notification is
+    /// sent after the debounce timer.
+    fn queue(&self, user_id: UserId, notification: Value, client: reqwest::Client) {
+        let mut pending = self.userid_pendingnotification.lock().unwrap();
+        if pending.insert(user_id.clone(), notification).is_some() {
+            // The timer is already running
+            return;
+        }
+
+        let senderkey_pusher = self.senderkey_pusher.clone();
+        let userid_pendingnotification = Arc::clone(&self.userid_pendingnotification);
+
+        tokio::spawn(async move {
+            tokio::time::delay_for(PUSH_DEBOUNCE).await;
+
+            let notification = match userid_pendingnotification.lock().unwrap().remove(&user_id) {
+                Some(notification) => notification,
+                None => return,
+            };
+
+            let pushers = Self {
+                senderkey_pusher,
+                userid_pendingnotification,
+            };
+            if let Err(e) = pushers.send(&user_id, notification, &client).await {
+                warn!("Failed to send push notification to {}: {}", user_id, e);
+            }
+        });
+    }
+
+    /// Sends the notification to every http pusher of the user and removes pushers that were
+    /// rejected by their push gateway.
+    async fn send(
+        &self,
+        user_id: &UserId,
+        mut notification: Value,
+        client: &reqwest::Client,
+    ) -> Result<()> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let tweaks = notification
+            .as_object_mut()
+            .and_then(|n| n.remove("tweaks"))
+            .unwrap_or_else(|| json!({}));
+
+        // Collected first because the requests below are awaited
+        let pushers = self
+            .senderkey_pusher
+            .scan_prefix(prefix)
+            .filter_map(|r| r.ok())
+            .collect::<Vec<_>>();
+
+        for (key, pusher) in pushers {
+            let pusher = serde_json::from_slice::<Value>(&pusher)
+                .map_err(|_| Error::bad_database("Invalid pusher in db."))?;
+
+            if pusher.get("kind").and_then(|k| k.as_str()) != Some("http") {
+                continue;
+            }
+
+            let url = match pusher
+                .get("data")
+                .and_then(|d| d.get("url"))
+                .and_then(|u| u.as_str())
+                .and_then(|u| reqwest::Url::parse(u).ok())
+            {
+                Some(url) => url,
+                None => continue,
+            };
+
+            // The push gateway gets the data without the url
+            let mut data = pusher.get("data").cloned().unwrap_or_else(|| json!({}));
+            if let Some(data) = data.as_object_mut() {
+                data.remove("url");
+            }
+
+            let mut body = notification.clone();
+            body["devices"] = json!([{
+                "app_id": pusher.get("app_id"),
+                "pushkey": pusher.get("pushkey"),
+                "pushkey_ts": utils::millis_since_unix_epoch() / 1000,
+                "data": data,
+                "tweaks": tweaks,
+            }]);
+
+            let response = client
+                .post(url)
+                .json(&json!({ "notification": body }))
+                .send()
+                .await;
+
+            match response {
+                Ok(response) if response.status() == reqwest::StatusCode::GONE => {
+                    // The push gateway does not know the pushkey anymore
+                    warn!(
+                        "Push gateway rejected pusher, removing it: {}",
+                        String::from_utf8_lossy(&key)
+                    );
+                    self.senderkey_pusher.remove(key)?;
+                }
+                Ok(response) if !response.status().is_success() => {
+                    warn!("Push gateway returned {}", response.status());
+                }
+                Ok(response) => {
+                    // Gateways can also reject single pushkeys in the response body
+                    let rejected = response
+                        .json::<Value>()
+                        .await
+                        .ok()
+                        .and_then(|r| r.get("rejected").cloned());
+                    if rejected.map_or(false, |rejected| {
+                        rejected
+                            .as_array()
+                            .map_or(false, |r| r.contains(&pusher["pushkey"]))
+                    }) {
+                        self.senderkey_pusher.remove(key)?;
+                    }
+                }
+                Err(e) => warn!("Failed to reach push gateway: {}", e),
+            }
+        }
+
+        Ok(())
+    }
+}
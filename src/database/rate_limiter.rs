@@ -0,0 +1,433 @@
+use crate::{Error, Result};
+use ruma::api::client::error::ErrorKind;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Buckets are garbage collected at most this often
+const GC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The endpoints that share a bucket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RateLimitClass {
+    Login,
+    Register,
+    Message,
+    Profile,
+}
+
+/// How many requests per second are allowed and how many requests can be made at once.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst_count: f64,
+}
+
+impl RateLimit {
+    /// Parses a config value of the form "per_second,burst_count", e.g. "0.17,3".
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.split(',').map(|part| part.trim().parse::<f64>());
+        let per_second = parts.next()?.ok()?;
+        let burst_count = parts.next()?.ok()?;
+
+        if parts.next().is_some() || per_second <= 0.0 || burst_count < 1.0 {
+            return None;
+        }
+
+        Some(Self {
+            per_second,
+            burst_count,
+        })
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last_update: Instant,
+}
+
+pub struct RateLimiter {
+    login: RateLimit,
+    register: RateLimit,
+    message: RateLimit,
+    profile: RateLimit,
+    buckets: Mutex<HashMap<(RateLimitClass, String), Bucket>>,
+    last_gc: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(
+        login: RateLimit,
+        register: RateLimit,
+        message: RateLimit,
+        profile: RateLimit,
+    ) -> Self {
+        Self {
+            login,
+            register,
+            message,
+            profile,
+            buckets: Mutex::new(HashMap::new()),
+            last_gc: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn limit(&self, class: RateLimitClass) -> RateLimit {
+        match class {
+            RateLimitClass::Login => self.login,
+            RateLimitClass::Register => self.register,
+            RateLimitClass::Message => self.message,
+            RateLimitClass::Profile => self.profile,
+        }
+    }
+
+    /// Takes a token from the bucket of the key (a user id or ip address) and class.
+    ///
+    /// Returns `Error::RateLimited` with the time until the next token is available if the
+    /// bucket is empty.
+    pub fn check(&self, class: RateLimitClass, key: &str) -> Result<()> {
+        self.check_at(class, key, Instant::now())
+    }
+
+    /// Takes a token from the bucket of the ip address of the connection. Requests without an
+    /// address are rejected, they would all share one bucket.
+    pub fn check_ip(&self, class: RateLimitClass, ip: Option<IpAddr>) -> Result<()> {
+        let ip = ip.ok_or(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The address of the client is unknown.",
+        ))?;
+        self.check(class, &ip.to_string())
+    }
+
+    fn check_at(&self, class: RateLimitClass, key: &str, now: Instant) -> Result<()> {
+        let limit = self.limit(class);
+
+        self.gc(now);
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets
+            .entry((class, key.to_owned()))
+            .or_insert_with(|| Bucket {
+                tokens: limit.burst_count,
+                last_update: now,
+            });
+
+        let elapsed = now.duration_since(bucket.last_update).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(limit.burst_count);
+        bucket.last_update = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let retry_after_ms = ((1.0 - bucket.tokens) / limit.per_second * 1000.0).ceil();
+            Err(Error::RateLimited(retry_after_ms as u64))
+        }
+    }
+
+    /// Removes all buckets that would be full again, they are the same as a new bucket.
+    fn gc(&self, now: Instant) {
+        let mut last_gc = self.last_gc.lock().unwrap();
+        if now.duration_since(*last_gc) < GC_INTERVAL {
+            return;
+        }
+        *last_gc = now;
+
+        self.buckets.lock().unwrap().retain(|(class, _), bucket| {
+            let limit = self.limit(*class);
+            let elapsed = now.duration_since(bucket.last_update).as_secs_f64();
+            bucket.tokens + elapsed * limit.per_second < limit.burst_count
+        });
+    }
+}
+
+/// Lockouts don't grow beyond this many windows
+const MAX_LOCKOUT_WINDOWS: u32 = 64;
+
+struct FailedLogins {
+    count: u32,
+    last_failure: Instant,
+}
+
+/// Counts failed password logins per localpart and client address. After `limit` failures in a
+/// row, each less than `window` after the previous one, further attempts are rejected for one
+/// window. Every failure after a lockout doubles the next lockout.
+///
+/// The address is the one of the connection, or of X-Real-IP if the connection comes from a
+/// trusted proxy. Logins without an address share the lockout of the localpart.
+pub struct LoginThrottle {
+    limit: u32, // 0 disables the throttle
+    window: Duration,
+    failures: Mutex<HashMap<(String, Option<IpAddr>), FailedLogins>>,
+    last_gc: Mutex<Instant>,
+}
+
+impl LoginThrottle {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            failures: Mutex::new(HashMap::new()),
+            last_gc: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// How long logins are rejected after this many failures.
+    fn lockout(&self, count: u32) -> Duration {
+        if count < self.limit {
+            return Duration::from_secs(0);
+        }
+
+        let windows = 2_u32
+            .checked_pow(count - self.limit)
+            .unwrap_or(MAX_LOCKOUT_WINDOWS)
+            .min(MAX_LOCKOUT_WINDOWS);
+        self.window * windows
+    }
+
+    /// Returns `Error::RateLimited` with the remaining time if logins of the localpart from the
+    /// ip address are locked out.
+    pub fn check(&self, localpart: &str, ip: Option<IpAddr>) -> Result<()> {
+        self.check_at(localpart, ip, Instant::now())
+    }
+
+    fn check_at(&self, localpart: &str, ip: Option<IpAddr>, now: Instant) -> Result<()> {
+        if self.limit == 0 {
+            return Ok(());
+        }
+
+        self.gc(now);
+
+        let failures = self.failures.lock().unwrap();
+        if let Some(failed) = failures.get(&(localpart.to_owned(), ip)) {
+            let locked_until = failed.last_failure + self.lockout(failed.count);
+            if now < locked_until {
+                let retry_after_ms = locked_until.duration_since(now).as_millis();
+                return Err(Error::RateLimited(retry_after_ms as u64));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Counts a failed login. Failures more than one window after the previous one start over.
+    pub fn failed(&self, localpart: &str, ip: Option<IpAddr>) {
+        self.failed_at(localpart, ip, Instant::now())
+    }
+
+    fn failed_at(&self, localpart: &str, ip: Option<IpAddr>, now: Instant) {
+        if self.limit == 0 {
+            return;
+        }
+
+        let mut failures = self.failures.lock().unwrap();
+        let failed = failures
+            .entry((localpart.to_owned(), ip))
+            .or_insert(FailedLogins {
+                count: 0,
+                last_failure: now,
+            });
+
+        let expired = failed.last_failure + self.lockout(failed.count).max(self.window);
+        if now > expired {
+            failed.count = 0;
+        }
+        failed.count += 1;
+        failed.last_failure = now;
+    }
+
+    /// Forgets the failures after a successful login.
+    pub fn succeeded(&self, localpart: &str, ip: Option<IpAddr>) {
+        self.failures
+            .lock()
+            .unwrap()
+            .remove(&(localpart.to_owned(), ip));
+    }
+
+    /// Removes all failures that would start over at the next failure.
+    fn gc(&self, now: Instant) {
+        let mut last_gc = self.last_gc.lock().unwrap();
+        if now.duration_since(*last_gc) < GC_INTERVAL {
+            return;
+        }
+        *last_gc = now;
+
+        self.failures.lock().unwrap().retain(|_, failed| {
+            now <= failed.last_failure + self.lockout(failed.count).max(self.window)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LoginThrottle, RateLimit, RateLimitClass, RateLimiter};
+    use crate::Error;
+    use std::time::{Duration, Instant};
+
+    fn limiter() -> RateLimiter {
+        let limit = RateLimit {
+            per_second: 0.5,
+            burst_count: 3.0,
+        };
+        RateLimiter::new(limit, limit, limit, limit)
+    }
+
+    fn retry_after(result: crate::Result<()>) -> Option<u64> {
+        match result {
+            Err(Error::RateLimited(retry_after_ms)) => Some(retry_after_ms),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn parses_per_second_and_burst_count() {
+        let limit = RateLimit::parse("0.17, 3").unwrap();
+        assert!((limit.per_second - 0.17).abs() < f64::EPSILON);
+        assert!((limit.burst_count - 3.0).abs() < f64::EPSILON);
+
+        for invalid in &["", "1", "1,2,3", "0,3", "1,0.5", "a,b"] {
+            assert!(RateLimit::parse(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn bucket_allows_the_burst_and_refills_over_time() {
+        let limiter = limiter();
+        let start = Instant::now();
+        let login = RateLimitClass::Login;
+
+        for _ in 0..3 {
+            assert!(limiter.check_at(login, "1.2.3.4", start).is_ok());
+        }
+        // One token comes back every two seconds
+        assert_eq!(
+            retry_after(limiter.check_at(login, "1.2.3.4", start)),
+            Some(2000)
+        );
+        let later = start + Duration::from_secs(1);
+        assert_eq!(
+            retry_after(limiter.check_at(login, "1.2.3.4", later)),
+            Some(1000)
+        );
+
+        let refilled = start + Duration::from_secs(2);
+        assert!(limiter.check_at(login, "1.2.3.4", refilled).is_ok());
+        assert!(limiter.check_at(login, "1.2.3.4", refilled).is_err());
+    }
+
+    #[test]
+    fn buckets_are_separate_per_key_and_class() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            limiter
+                .check_at(RateLimitClass::Login, "1.2.3.4", now)
+                .unwrap();
+        }
+
+        assert!(limiter
+            .check_at(RateLimitClass::Login, "1.2.3.4", now)
+            .is_err());
+        assert!(limiter
+            .check_at(RateLimitClass::Login, "5.6.7.8", now)
+            .is_ok());
+        assert!(limiter
+            .check_at(RateLimitClass::Register, "1.2.3.4", now)
+            .is_ok());
+    }
+
+    #[test]
+    fn buckets_never_hold_more_than_the_burst() {
+        let limiter = limiter();
+        let start = Instant::now();
+        let login = RateLimitClass::Login;
+
+        limiter.check_at(login, "1.2.3.4", start).unwrap();
+        let much_later = start + Duration::from_secs(30);
+        for _ in 0..3 {
+            assert!(limiter.check_at(login, "1.2.3.4", much_later).is_ok());
+        }
+        assert!(limiter.check_at(login, "1.2.3.4", much_later).is_err());
+    }
+
+    #[test]
+    fn requests_without_an_address_are_rejected() {
+        assert!(limiter().check_ip(RateLimitClass::Login, None).is_err());
+        assert!(limiter()
+            .check_ip(RateLimitClass::Login, Some([1, 2, 3, 4].into()))
+            .is_ok());
+    }
+
+    #[test]
+    fn logins_are_locked_out_after_the_limit() {
+        let throttle = LoginThrottle::new(3, Duration::from_secs(60));
+        let start = Instant::now();
+        let ip = Some([1, 2, 3, 4].into());
+
+        for _ in 0..3 {
+            assert!(throttle.check_at("alice", ip, start).is_ok());
+            throttle.failed_at("alice", ip, start);
+        }
+        assert_eq!(
+            retry_after(throttle.check_at("alice", ip, start)),
+            Some(60_000)
+        );
+
+        let unlocked = start + Duration::from_secs(60);
+        assert!(throttle.check_at("alice", ip, unlocked).is_ok());
+
+        // The next failure doubles the lockout
+        throttle.failed_at("alice", ip, unlocked);
+        assert_eq!(
+            retry_after(throttle.check_at("alice", ip, unlocked)),
+            Some(120_000)
+        );
+    }
+
+    #[test]
+    fn lockouts_are_separate_per_localpart_and_address() {
+        let throttle = LoginThrottle::new(1, Duration::from_secs(60));
+        let now = Instant::now();
+        let ip = Some([1, 2, 3, 4].into());
+
+        throttle.failed_at("alice", ip, now);
+
+        assert!(throttle.check_at("alice", ip, now).is_err());
+        assert!(throttle
+            .check_at("alice", Some([5, 6, 7, 8].into()), now)
+            .is_ok());
+        assert!(throttle.check_at("bob", ip, now).is_ok());
+        assert!(throttle.check_at("alice", None, now).is_ok());
+    }
+
+    #[test]
+    fn failures_start_over_after_a_window_or_a_success() {
+        let throttle = LoginThrottle::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        let ip = Some([1, 2, 3, 4].into());
+
+        throttle.failed_at("alice", ip, start);
+        let later = start + Duration::from_secs(61);
+        throttle.failed_at("alice", ip, later);
+        assert!(throttle.check_at("alice", ip, later).is_ok());
+
+        throttle.succeeded("alice", ip);
+        throttle.failed_at("alice", ip, later);
+        assert!(throttle.check_at("alice", ip, later).is_ok());
+    }
+
+    #[test]
+    fn a_limit_of_zero_disables_the_throttle() {
+        let throttle = LoginThrottle::new(0, Duration::from_secs(60));
+        let now = Instant::now();
+
+        for _ in 0..10 {
+            throttle.failed_at("alice", None, now);
+        }
+        assert!(throttle.check_at("alice", None, now).is_ok());
+    }
+}
@@ -0,0 +1,58 @@
+use crate::{utils, Error, Result};
+use ruma::{EventId, RoomId, UserId};
+use serde::{Deserialize, Serialize};
+
+/// A report of an event by a user. It contains the sender of the event, so admins can act on it
+/// without looking up the event.
+#[derive(Serialize, Deserialize)]
+pub struct ContentReport {
+    pub reporter: UserId,
+    pub room_id: RoomId,
+    pub event_id: EventId,
+    pub sender: UserId,
+    pub score: Option<i64>, // -100 is the most offensive, 0 is inoffensive
+    pub reason: Option<String>,
+    pub received_ts: u64,
+}
+
+pub struct Reports {
+    pub(super) content_reports: sled::Tree, // ReportId = Count
+}
+
+impl Reports {
+    /// Stores a new report. Reports are never changed or removed.
+    pub fn add(
+        &self,
+        report: &ContentReport,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<u64> {
+        let report_id = globals.next_count()?;
+
+        self.content_reports.insert(
+            &report_id.to_be_bytes(),
+            serde_json::to_vec(report).expect("ContentReport can be serialized"),
+        )?;
+
+        Ok(report_id)
+    }
+
+    /// Returns the reports with their ids, oldest first, starting after the report with the id
+    /// `since`.
+    pub fn since(&self, since: u64) -> impl Iterator<Item = Result<(u64, ContentReport)>> {
+        self.content_reports
+            .range(since.saturating_add(1).to_be_bytes()..)
+            .map(|r| {
+                let (key, value) = r?;
+                Ok((
+                    utils::u64_from_bytes(&key)
+                        .map_err(|_| Error::bad_database("Invalid report id in db."))?,
+                    serde_json::from_slice(&value)
+                        .map_err(|_| Error::bad_database("Invalid report in db."))?,
+                ))
+            })
+    }
+
+    pub fn count(&self) -> usize {
+        self.content_reports.len()
+    }
+}
@@ -2,26 +2,30 @@ mod edus;
 
 pub use edus::RoomEdus;
 
-use crate::{pdu::PduBuilder, utils, Error, PduEvent, Result};
-use log::error;
+use super::abstraction;
+use crate::{pdu::PduBuilder, push_rules, state_res, utils, Error, PduEvent, Result};
+use js_int::Int;
 use ruma::{
     api::client::error::ErrorKind,
     events::{
         ignored_user_list,
         room::{
-            join_rules, member,
+            canonical_alias::CanonicalAliasEventContent,
+            history_visibility, member,
             power_levels::{self, PowerLevelsEventContent},
         },
         EventType,
     },
-    EventId, Raw, RoomAliasId, RoomId, UserId,
+    DeviceId, EventId, Raw, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
 };
+use serde_json::json;
 use sled::IVec;
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, HashMap, HashSet},
     convert::{TryFrom, TryInto},
     mem,
 };
+use tracing::error;
 
 pub struct Rooms {
     pub edus: edus::RoomEdus,
@@ -29,12 +33,16 @@ pub struct Rooms {
     pub(super) eventid_pduid: sled::Tree,
     pub(super) roomid_pduleaves: sled::Tree,
     pub(super) roomstateid_pdu: sled::Tree, // RoomStateId = Room + StateType + StateKey
+    pub(super) eventid_softfailedpdu: sled::Tree, // Soft-failed events are not in the timeline
+    pub(super) eventid_outlierpdu: sled::Tree, // Auth events that were fetched from other servers
 
-    pub(super) alias_roomid: sled::Tree,
-    pub(super) aliasid_alias: sled::Tree, // AliasId = RoomId + Count
+    pub(super) alias_roomid: sled::Tree, // Alias = Localpart of the alias
+    pub(super) aliasid_alias: sled::Tree, // AliasId = RoomId + Count, value is the full alias
+    pub(super) alias_userid: sled::Tree, // The user who created the alias
     pub(super) publicroomids: sled::Tree,
 
-    pub(super) tokenids: sled::Tree, // TokenId = RoomId + Token + PduId
+    pub(super) search_index: sled::Tree, // SearchId = Token + PduId, value is the EventId
+    pub(super) parentid_relations: sled::Tree, // ParentId = RoomId + EventId + Count, value is the PduId
 
     pub(super) userroomid_joined: sled::Tree,
     pub(super) roomuserid_joined: sled::Tree,
@@ -42,6 +50,120 @@ pub struct Rooms {
     pub(super) userroomid_invited: sled::Tree,
     pub(super) roomuserid_invited: sled::Tree,
     pub(super) userroomid_left: sled::Tree,
+    pub(super) userroomid_invitestate: sled::Tree, // InviteState = Count + stripped state json of invites from other servers
+
+    pub(super) lazy_load_sent: sled::Tree, // LazyLoadId = UserId + DeviceId + RoomId + UserId, value is the EventId of the sent member event
+
+    pub(super) servicepdus: sled::Tree, // ServicePdu = AppserviceId + 0xff + Count, value is the PduId of an event the appservice has not received yet
+}
+
+/// How many events are visited at most to find out if a state event of another server was sent
+/// after the current state event
+const MAX_ANCESTOR_SEARCH: usize = 1000;
+
+/// The result of checking an event of another server, see
+/// [`Rooms::auth_incoming_pdu`](struct.Rooms.html#method.auth_incoming_pdu).
+pub enum PduAuth {
+    Allowed,
+    SoftFailed, // Allowed by the auth events, but not by the current state
+    Rejected(&'static str),
+}
+
+/// Who wants to see the events of a room: a user of this server or another server.
+#[derive(Clone, Copy)]
+pub enum Viewer<'a> {
+    User(&'a UserId),
+    Server(&'a ServerName),
+}
+
+/// Checks which events of a room a viewer can see, based on the `m.room.history_visibility` and
+/// the memberships of the viewer when the event was sent:
+///
+/// - `world_readable` events can be seen by everyone, also without being in the room
+/// - `shared` events can be seen by current members and by those who were joined at the time
+/// - `invited` events can be seen by those who were invited or joined at the time
+/// - `joined` events can only be seen by those who were joined at the time
+///
+/// Servers see what at least one of their users could see. Users always see their own
+/// membership events.
+///
+/// The history of the room is only read at the first event that is older than the current join
+/// of the user, so checking new events is cheap.
+pub struct EventVisibility<'a> {
+    rooms: &'a Rooms,
+    viewer: Viewer<'a>,
+    room_id: &'a RoomId,
+    joined_since: Option<u64>, // The count of the member event of the current join
+    currently_joined: bool,
+    history: Option<VisibilityHistory>,
+}
+
+struct VisibilityHistory {
+    visibilities: Vec<(u64, String)>,
+    memberships: Vec<(u64, String, String)>, // Count, user id and membership
+}
+
+impl EventVisibility<'_> {
+    /// Checks if the viewer can see the event with this count.
+    pub fn can_see(&mut self, count: u64, pdu: &PduEvent) -> Result<bool> {
+        // The membership can't have changed since the current join
+        if self
+            .joined_since
+            .map_or(false, |joined_since| count >= joined_since)
+        {
+            return Ok(true);
+        }
+
+        if let Viewer::User(user_id) = self.viewer {
+            if pdu.kind == EventType::RoomMember
+                && pdu.state_key.as_deref() == Some(user_id.as_str())
+            {
+                return Ok(true);
+            }
+        }
+
+        if self.history.is_none() {
+            self.history = Some(self.rooms.visibility_history(self.viewer, self.room_id)?);
+        }
+        let history = self.history.as_ref().expect("history was loaded above");
+
+        Ok(history.allows(count, self.currently_joined))
+    }
+}
+
+impl VisibilityHistory {
+    /// Checks the history visibility and the memberships of the viewer at the event with this
+    /// count.
+    fn allows(&self, count: u64, currently_joined: bool) -> bool {
+        // Rooms without history visibility are shared
+        let visibility = self
+            .visibilities
+            .iter()
+            .rev()
+            .find(|(visibility_count, _)| *visibility_count <= count)
+            .map_or("shared", |(_, visibility)| visibility.as_str());
+
+        let mut memberships = HashMap::new();
+        for (_, user_id, membership) in self
+            .memberships
+            .iter()
+            .take_while(|(membership_count, _, _)| *membership_count <= count)
+        {
+            memberships.insert(user_id, membership.as_str());
+        }
+        let had_membership = |allowed: &[&str]| {
+            memberships
+                .values()
+                .any(|membership| allowed.contains(membership))
+        };
+
+        match visibility {
+            "world_readable" => true,
+            "shared" => currently_joined || had_membership(&["join"]),
+            "invited" => had_membership(&["join", "invite"]),
+            _ => had_membership(&["join"]),
+        }
+    }
 }
 
 impl Rooms {
@@ -63,10 +185,14 @@ impl Rooms {
         &self,
         room_id: &RoomId,
     ) -> Result<HashMap<(EventType, String), PduEvent>> {
+        // The separator keeps rooms whose ids start with this room id out
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
         let mut hashmap = HashMap::new();
         for pdu in self
             .roomstateid_pdu
-            .scan_prefix(&room_id.to_string().as_bytes())
+            .scan_prefix(&prefix)
             .values()
             .map(|value| {
                 Ok::<_, Error>(
@@ -84,6 +210,30 @@ impl Rooms {
         Ok(hashmap)
     }
 
+    /// Returns the room state after the event with the count `until`. Only the current state is
+    /// stored, so this goes through all events of the room before it.
+    pub fn room_state_at(
+        &self,
+        room_id: &RoomId,
+        until: u64,
+    ) -> Result<HashMap<(EventType, String), PduEvent>> {
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let mut end = prefix.clone();
+        end.extend_from_slice(&(until + 1).to_be_bytes());
+
+        let mut hashmap = HashMap::new();
+        for value in self.pduid_pdu.range(prefix..end).values() {
+            let pdu = serde_json::from_slice::<PduEvent>(&value?)
+                .map_err(|_| Error::bad_database("Invalid PDU in db."))?;
+            if let Some(state_key) = pdu.state_key.clone() {
+                hashmap.insert((pdu.kind.clone(), state_key), pdu);
+            }
+        }
+        Ok(hashmap)
+    }
+
     /// Returns the all state entries for this type.
     pub fn room_state_type(
         &self,
@@ -122,11 +272,7 @@ impl Rooms {
         event_type: &EventType,
         state_key: &str,
     ) -> Result<Option<PduEvent>> {
-        let mut key = room_id.to_string().as_bytes().to_vec();
-        key.push(0xff);
-        key.extend_from_slice(&event_type.to_string().as_bytes());
-        key.push(0xff);
-        key.extend_from_slice(&state_key.as_bytes());
+        let key = room_state_id(room_id, event_type, state_key);
 
         self.roomstateid_pdu.get(&key)?.map_or(Ok(None), |value| {
             Ok::<_, Error>(Some(
@@ -136,6 +282,265 @@ impl Rooms {
         })
     }
 
+    /// Returns the version from the create event of the room. Version 1 rooms may not have one.
+    pub fn room_version(&self, room_id: &RoomId) -> Result<RoomVersionId> {
+        Ok(self
+            .room_state_get(room_id, &EventType::RoomCreate, "")?
+            .and_then(|create| {
+                RoomVersionId::try_from(create.content.get("room_version")?.as_str()?).ok()
+            })
+            .unwrap_or(RoomVersionId::Version1))
+    }
+
+    /// Checks if the `m.room.server_acl` of the room bans the server.
+    ///
+    /// Servers are denied if they match a `deny` glob, don't match any `allow` glob or are IP
+    /// literals while `allow_ip_literals` is false. Callers have to exempt this server themselves.
+    pub fn is_acl_denied(&self, room_id: &RoomId, server_name: &ServerName) -> Result<bool> {
+        Ok(self
+            .room_state_get(room_id, &EventType::RoomServerAcl, "")?
+            .map_or(false, |acl| acl_denies(&acl.content, server_name)))
+    }
+
+    /// Returns the first pdu count the user is allowed to see in this room.
+    ///
+    /// Rooms with `shared` or `world_readable` history can be read completely, in all other rooms
+    /// only events after the user joined can be seen.
+    pub fn visible_since(&self, room_id: &RoomId, user_id: &UserId) -> Result<u64> {
+        let visibility = self
+            .room_state_get(room_id, &EventType::RoomHistoryVisibility, "")?
+            .map(|pdu| {
+                serde_json::from_value::<Raw<history_visibility::HistoryVisibilityEventContent>>(
+                    pdu.content,
+                )
+                .expect("Raw::from_value always works")
+                .deserialize()
+                .map_err(|_| Error::bad_database("Invalid history visibility event in database."))
+            })
+            .transpose()?
+            .map(|content| content.history_visibility);
+
+        if matches!(
+            visibility,
+            Some(history_visibility::HistoryVisibility::Shared)
+                | Some(history_visibility::HistoryVisibility::WorldReadable)
+        ) {
+            return Ok(0);
+        }
+
+        Ok(self
+            .room_state_get(room_id, &EventType::RoomMember, user_id.as_str())?
+            .map(|member| self.get_pdu_count(&member.event_id))
+            .transpose()?
+            .flatten()
+            .unwrap_or(u64::MAX))
+    }
+
+    /// Checks if the room is currently `world_readable`, so it can be read without joining it.
+    pub fn is_world_readable(&self, room_id: &RoomId) -> Result<bool> {
+        Ok(self
+            .room_state_get(room_id, &EventType::RoomHistoryVisibility, "")?
+            .and_then(|pdu| {
+                pdu.content
+                    .get("history_visibility")?
+                    .as_str()
+                    .map(|visibility| visibility == "world_readable")
+            })
+            .unwrap_or(false))
+    }
+
+    /// Prepares checking which events of the room the viewer can see, see [`EventVisibility`].
+    pub fn event_visibility<'a>(
+        &'a self,
+        viewer: Viewer<'a>,
+        room_id: &'a RoomId,
+    ) -> Result<EventVisibility<'a>> {
+        let (joined_since, currently_joined) = match viewer {
+            Viewer::User(user_id) => {
+                let joined_since = if self.is_joined(user_id, room_id)? {
+                    self.room_state_get(room_id, &EventType::RoomMember, user_id.as_str())?
+                        .map(|member| self.get_pdu_count(&member.event_id))
+                        .transpose()?
+                        .flatten()
+                } else {
+                    None
+                };
+                (joined_since, joined_since.is_some())
+            }
+            Viewer::Server(server_name) => (
+                None,
+                self.room_members(room_id)
+                    .filter_map(|r| r.ok())
+                    .any(|user_id| user_id.server_name() == server_name),
+            ),
+        };
+
+        Ok(EventVisibility {
+            rooms: self,
+            viewer,
+            room_id,
+            joined_since,
+            currently_joined,
+            history: None,
+        })
+    }
+
+    /// Checks if the viewer can see the event, see [`EventVisibility`].
+    pub fn can_see_event(
+        &self,
+        viewer: Viewer<'_>,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<bool> {
+        let pdu = match self.get_pdu(event_id)? {
+            Some(pdu) if &pdu.room_id == room_id => pdu,
+            _ => return Ok(false),
+        };
+        let count = self
+            .get_pdu_count(event_id)?
+            .ok_or_else(|| Error::bad_database("Event has no count."))?;
+
+        self.event_visibility(viewer, room_id)?.can_see(count, &pdu)
+    }
+
+    /// Collects the history visibility changes of the room and the membership changes of the
+    /// viewer, ordered by their count.
+    fn visibility_history(
+        &self,
+        viewer: Viewer<'_>,
+        room_id: &RoomId,
+    ) -> Result<VisibilityHistory> {
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let mut history = VisibilityHistory {
+            visibilities: Vec::new(),
+            memberships: Vec::new(),
+        };
+
+        for (key, pdu) in self.pduid_pdu.scan_prefix(&prefix).filter_map(|r| r.ok()) {
+            let pdu = serde_json::from_slice::<PduEvent>(&pdu)
+                .map_err(|_| Error::bad_database("PDU in db is invalid."))?;
+            let state_key = match &pdu.state_key {
+                Some(state_key) => state_key,
+                None => continue,
+            };
+            let count = utils::u64_from_bytes(&key[prefix.len()..])
+                .map_err(|_| Error::bad_database("Invalid pdu id in db."))?;
+            let content_str = |field: &str| pdu.content.get(field)?.as_str().map(str::to_owned);
+
+            if pdu.kind == EventType::RoomHistoryVisibility && state_key.is_empty() {
+                if let Some(visibility) = content_str("history_visibility") {
+                    history.visibilities.push((count, visibility));
+                }
+            } else if pdu.kind == EventType::RoomMember {
+                let is_viewer = match viewer {
+                    Viewer::User(user_id) => state_key == user_id.as_str(),
+                    Viewer::Server(server_name) => UserId::try_from(&**state_key)
+                        .map_or(false, |user_id| user_id.server_name() == server_name),
+                };
+
+                if let (true, Some(membership)) = (is_viewer, content_str("membership")) {
+                    history
+                        .memberships
+                        .push((count, state_key.clone(), membership));
+                }
+            }
+        }
+
+        Ok(history)
+    }
+
+    /// Returns the state of the room right after the event with this count.
+    ///
+    /// Only the current state is stored, so this goes back through the events of the room.
+    pub fn state_at(
+        &self,
+        room_id: &RoomId,
+        count: u64,
+    ) -> Result<HashMap<(EventType, String), PduEvent>> {
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let mut until = prefix.clone();
+        until.extend_from_slice(&count.to_be_bytes());
+
+        let mut state = HashMap::new();
+        for (_, pdu) in self
+            .pduid_pdu
+            .range(&*prefix..=&*until)
+            .rev()
+            .filter_map(|r| r.ok())
+        {
+            let pdu = serde_json::from_slice::<PduEvent>(&pdu)
+                .map_err(|_| Error::bad_database("PDU in db is invalid."))?;
+
+            // Newer state events replace older ones
+            if let Some(state_key) = &pdu.state_key {
+                state
+                    .entry((pdu.kind.clone(), state_key.clone()))
+                    .or_insert(pdu);
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Returns the rooms whose members may join this room if the join rule is `restricted` or
+    /// `knock_restricted`, or `None` for all other join rules.
+    pub fn restricted_join_rooms(&self, room_id: &RoomId) -> Result<Option<Vec<RoomId>>> {
+        Ok(self
+            .room_state_get(room_id, &EventType::RoomJoinRules, "")?
+            .and_then(|join_rules| restricted_join_rooms(&join_rules.content)))
+    }
+
+    /// Finds a joined member of this server that is allowed to invite users, so it can authorise
+    /// a restricted join.
+    pub fn join_authoriser(
+        &self,
+        room_id: &RoomId,
+        server_name: &ServerName,
+    ) -> Result<Option<UserId>> {
+        for user_id in self.room_members(room_id) {
+            let user_id = user_id?;
+            if user_id.server_name() != server_name {
+                continue;
+            }
+
+            let (user_level, invite_level) =
+                self.power_levels_for(room_id, &user_id, "invite", 0)?;
+            if user_level >= invite_level {
+                return Ok(Some(user_id));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Checks the `join_authorised_via_users_server` of a restricted join: The user has to be
+    /// joined and be allowed to invite users.
+    fn is_valid_join_authoriser(
+        &self,
+        room_id: &RoomId,
+        authoriser: Option<&serde_json::Value>,
+        power_levels: &PowerLevelsEventContent,
+    ) -> Result<bool> {
+        let authoriser = match authoriser
+            .and_then(|authoriser| authoriser.as_str())
+            .and_then(|authoriser| UserId::try_from(authoriser).ok())
+        {
+            Some(authoriser) => authoriser,
+            None => return Ok(false),
+        };
+
+        Ok(self.is_joined(&authoriser, room_id)?
+            && power_levels
+                .users
+                .get(&authoriser)
+                .unwrap_or(&power_levels.users_default)
+                >= &power_levels.invite)
+    }
+
     /// Returns the `count` of this pdu's id.
     pub fn get_pdu_count(&self, event_id: &EventId) -> Result<Option<u64>> {
         self.eventid_pduid
@@ -236,30 +641,350 @@ impl Rooms {
         Ok(events)
     }
 
-    /// Replace the leaves of a room with a new event.
-    pub fn replace_pdu_leaves(&self, room_id: &RoomId, event_id: &EventId) -> Result<()> {
-        let mut prefix = room_id.to_string().as_bytes().to_vec();
-        prefix.push(0xff);
+    /// Checks if the event is allowed by the auth rules and the power levels of the room.
+    #[allow(clippy::too_many_arguments)]
+    fn auth_check(
+        &self,
+        room_id: &RoomId,
+        sender: &UserId,
+        event_type: &EventType,
+        state_key: Option<&String>,
+        content: &serde_json::Value,
+        redacts: Option<&EventId>,
+        prev_events: &[EventId],
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<bool> {
+        let power_levels_event = self.room_state_get(&room_id, &EventType::RoomPowerLevels, "")?;
+        // The first power levels event can set any levels
+        let is_power_levels_change = power_levels_event.is_some();
+        let power_levels = power_levels_event.map_or_else(
+            || {
+                Ok::<_, Error>(power_levels::PowerLevelsEventContent {
+                    ban: 50.into(),
+                    events: BTreeMap::new(),
+                    events_default: 0.into(),
+                    invite: 50.into(),
+                    kick: 50.into(),
+                    redact: 50.into(),
+                    state_default: 0.into(),
+                    users: BTreeMap::new(),
+                    users_default: 0.into(),
+                    notifications: ruma::events::room::power_levels::NotificationPowerLevels {
+                        room: 50.into(),
+                    },
+                })
+            },
+            |power_levels| {
+                Ok(
+                    serde_json::from_value::<Raw<PowerLevelsEventContent>>(power_levels.content)
+                        .expect("Raw::from_value always works.")
+                        .deserialize()
+                        .map_err(|_| Error::bad_database("Invalid PowerLevels event in db."))?,
+                )
+            },
+        )?;
+        let sender_membership = self
+            .room_state_get(&room_id, &EventType::RoomMember, &sender.to_string())?
+            .map_or(Ok::<_, Error>(member::MembershipState::Leave), |pdu| {
+                Ok(
+                    serde_json::from_value::<Raw<member::MemberEventContent>>(pdu.content)
+                        .expect("Raw::from_value always works.")
+                        .deserialize()
+                        .map_err(|_| Error::bad_database("Invalid Member event in db."))?
+                        .membership,
+                )
+            })?;
+
+        let sender_power = power_levels.users.get(sender).map_or_else(
+            || {
+                if sender_membership != member::MembershipState::Join {
+                    None
+                } else {
+                    Some(&power_levels.users_default)
+                }
+            },
+            // If it's okay, wrap with Some(_)
+            Some,
+        );
+
+        let sender_level = *sender_power.unwrap_or(&power_levels.users_default);
+        // The level needed to send this type of event
+        let default_level = if state_key.is_some() {
+            &power_levels.state_default
+        } else {
+            &power_levels.events_default
+        };
+        let required_level = *power_levels.events.get(event_type).unwrap_or(default_level);
+
+        let state_key = match state_key {
+            Some(state_key) => state_key,
+            None => {
+                return Ok(sender_membership == member::MembershipState::Join
+                    && sender_level >= required_level
+                    && (*event_type != EventType::RoomRedaction
+                        || self.is_redaction_allowed(room_id, sender, redacts)?));
+            }
+        };
+
+        Ok(match event_type {
+            EventType::RoomMember => {
+                let target_user_id = UserId::try_from(&**state_key).map_err(|_| {
+                    Error::BadRequest(
+                        ErrorKind::InvalidParam,
+                        "State key of member event does not contain user id.",
+                    )
+                })?;
+
+                let current_membership = self
+                    .room_state_get(
+                        &room_id,
+                        &EventType::RoomMember,
+                        &target_user_id.to_string(),
+                    )?
+                    .map_or(Ok::<_, Error>(member::MembershipState::Leave), |pdu| {
+                        Ok(
+                            serde_json::from_value::<Raw<member::MemberEventContent>>(pdu.content)
+                                .expect("Raw::from_value always works.")
+                                .deserialize()
+                                .map_err(|_| Error::bad_database("Invalid Member event in db."))?
+                                .membership,
+                        )
+                    })?;
+
+                let target_membership =
+                    serde_json::from_value::<Raw<member::MemberEventContent>>(content.clone())
+                        .expect("Raw::from_value always works.")
+                        .deserialize()
+                        .map_err(|_| Error::bad_database("Invalid Member event in db."))?
+                        .membership;
+
+                let target_power = power_levels.users.get(&target_user_id).map_or_else(
+                    || {
+                        if target_membership != member::MembershipState::Join {
+                            None
+                        } else {
+                            Some(&power_levels.users_default)
+                        }
+                    },
+                    // If it's okay, wrap with Some(_)
+                    Some,
+                );
+
+                // Read as a string because ruma doesn't know all join rules (e.g.
+                // knock_restricted)
+                let join_rule = self
+                    .room_state_get(&room_id, &EventType::RoomJoinRules, "")?
+                    .map_or(Ok::<_, Error>("public".to_owned()), |pdu| {
+                        Ok(pdu
+                            .content
+                            .get("join_rule")
+                            .and_then(|join_rule| join_rule.as_str())
+                            .ok_or_else(|| {
+                                Error::bad_database("Database contains invalid JoinRules event")
+                            })?
+                            .to_owned())
+                    })?;
+                // Join rules of newer room versions allow nothing in older rooms
+                let join_rule = if super::globals::join_rule_supported(
+                    &self.room_version(&room_id)?,
+                    &join_rule,
+                ) {
+                    join_rule
+                } else {
+                    "unsupported".to_owned()
+                };
+
+                if target_membership == member::MembershipState::Join {
+                    let mut prev_events = prev_events.iter();
+                    let prev_event = self
+                        .get_pdu(prev_events.next().ok_or(Error::BadRequest(
+                            ErrorKind::Unknown,
+                            "Membership can't be the first event",
+                        ))?)?
+                        .ok_or_else(|| Error::bad_database("PDU leaf points to invalid event!"))?;
+                    if prev_event.kind == EventType::RoomCreate && prev_event.prev_events.is_empty()
+                    {
+                        true
+                    } else if *sender != target_user_id {
+                        false
+                    } else if let member::MembershipState::Ban = current_membership {
+                        false
+                    } else {
+                        let is_restricted =
+                            join_rule == "restricted" || join_rule == "knock_restricted";
+
+                        (join_rule == "invite" || join_rule == "knock" || is_restricted)
+                            && (current_membership == member::MembershipState::Join
+                                || current_membership == member::MembershipState::Invite)
+                            || join_rule == "public"
+                            || is_restricted
+                                && self.is_valid_join_authoriser(
+                                    &room_id,
+                                    content.get("join_authorised_via_users_server"),
+                                    &power_levels,
+                                )?
+                    }
+                } else if target_membership == member::MembershipState::Invite {
+                    if let Some(third_party_invite) = content.get("third_party_invite") {
+                        let invite_event = match third_party_invite
+                            .get("signed")
+                            .and_then(|signed| signed.get("token"))
+                            .and_then(|token| token.as_str())
+                        {
+                            Some(token) => self.room_state_get(
+                                &room_id,
+                                &EventType::RoomThirdPartyInvite,
+                                token,
+                            )?,
+                            None => None,
+                        };
 
-        for key in self.roomid_pduleaves.scan_prefix(&prefix).keys() {
-            self.roomid_pduleaves.remove(key?)?;
+                        current_membership != member::MembershipState::Ban
+                            && crate::state_res::third_party_invite_allowed(
+                                &target_user_id,
+                                sender,
+                                third_party_invite,
+                                invite_event
+                                    .as_ref()
+                                    .map(|invite| (&invite.sender, &invite.content)),
+                            )
+                    } else if sender_membership != member::MembershipState::Join
+                        || current_membership == member::MembershipState::Join
+                        || current_membership == member::MembershipState::Ban
+                    {
+                        false
+                    } else {
+                        sender_power
+                            .filter(|&p| p >= &power_levels.invite)
+                            .is_some()
+                    }
+                } else if target_membership == member::MembershipState::Knock {
+                    // Knocking users ask to be invited, so they can't be in the room already
+                    *sender == target_user_id
+                        && (join_rule == "knock" || join_rule == "knock_restricted")
+                        && current_membership != member::MembershipState::Ban
+                        && current_membership != member::MembershipState::Invite
+                        && current_membership != member::MembershipState::Join
+                } else if target_membership == member::MembershipState::Leave {
+                    if *sender == target_user_id {
+                        current_membership == member::MembershipState::Join
+                            || current_membership == member::MembershipState::Invite
+                            || current_membership == member::MembershipState::Knock
+                    } else if sender_membership != member::MembershipState::Join
+                        || current_membership == member::MembershipState::Ban
+                            && sender_power.filter(|&p| p < &power_levels.ban).is_some()
+                    {
+                        false
+                    } else {
+                        sender_power.filter(|&p| p >= &power_levels.kick).is_some()
+                            && target_power < sender_power
+                    }
+                } else if target_membership == member::MembershipState::Ban {
+                    if sender_membership != member::MembershipState::Join {
+                        false
+                    } else {
+                        sender_power.filter(|&p| p >= &power_levels.ban).is_some()
+                            && target_power < sender_power
+                    }
+                } else {
+                    false
+                }
+            }
+            EventType::RoomCreate => prev_events.is_empty(),
+            // Not allow any of the following events if the sender is not joined.
+            _ if sender_membership != member::MembershipState::Join => false,
+            // Don't allow encryption events when it's disabled
+            EventType::RoomEncryption if globals.encryption_disabled() => false,
+            // Third party invites need the level of invites instead of the level of state events
+            EventType::RoomThirdPartyInvite => sender_level >= power_levels.invite,
+            _ if sender_level < required_level => false,
+            EventType::RoomPowerLevels if is_power_levels_change => {
+                self.is_power_levels_change_allowed(&power_levels, content, sender, sender_level)?
+            }
+            _ => true,
+        })
+    }
+
+    /// Checks the changes of a new `m.room.power_levels` event: The sender can only change levels
+    /// that are not higher than their own (before and after the change) and can't change the
+    /// levels of other users with the same level.
+    fn is_power_levels_change_allowed(
+        &self,
+        current: &PowerLevelsEventContent,
+        new_content: &serde_json::Value,
+        sender: &UserId,
+        sender_level: Int,
+    ) -> Result<bool> {
+        let new = serde_json::from_value::<Raw<PowerLevelsEventContent>>(new_content.clone())
+            .expect("Raw::from_value always works.")
+            .deserialize()
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid power levels."))?;
+
+        let too_high = |old: Option<&Int>, new: Option<&Int>| {
+            old != new
+                && (old.map_or(false, |&l| l > sender_level)
+                    || new.map_or(false, |&l| l > sender_level))
+        };
+
+        let levels = [
+            (&current.ban, &new.ban),
+            (&current.events_default, &new.events_default),
+            (&current.invite, &new.invite),
+            (&current.kick, &new.kick),
+            (&current.redact, &new.redact),
+            (&current.state_default, &new.state_default),
+            (&current.users_default, &new.users_default),
+            (&current.notifications.room, &new.notifications.room),
+        ];
+        if levels
+            .iter()
+            .any(|&(old, new)| too_high(Some(old), Some(new)))
+        {
+            return Ok(false);
         }
 
-        prefix.extend_from_slice(event_id.to_string().as_bytes());
-        self.roomid_pduleaves
-            .insert(&prefix, &*event_id.to_string())?;
+        if current
+            .events
+            .keys()
+            .chain(new.events.keys())
+            .any(|event_type| too_high(current.events.get(event_type), new.events.get(event_type)))
+        {
+            return Ok(false);
+        }
 
-        Ok(())
+        Ok(
+            !current.users.keys().chain(new.users.keys()).any(|user_id| {
+                let old = current.users.get(user_id);
+                let new = new.users.get(user_id);
+
+                too_high(old, new)
+                    // Users with the same level can't demote each other
+                    || user_id != sender
+                        && old != new
+                        && old.map_or(false, |&l| l >= sender_level)
+            }),
+        )
     }
 
     /// Creates a new persisted data unit and adds it to a room.
-    #[allow(clippy::blocks_in_if_conditions)]
     pub fn append_pdu(
         &self,
         pdu_builder: PduBuilder,
         globals: &super::globals::Globals<'_>,
         account_data: &super::account_data::AccountData,
     ) -> Result<EventId> {
+        let (pdu, pdu_json) = self.build_pdu(pdu_builder, globals)?;
+        self.append_built_pdu(pdu, pdu_json, globals, account_data)
+    }
+
+    /// Creates and signs a new persisted data unit without adding it to the room yet. Used when
+    /// another server has to sign the event first, like invites of remote users.
+    #[allow(clippy::blocks_in_if_conditions)]
+    pub fn build_pdu(
+        &self,
+        pdu_builder: PduBuilder,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<(PduEvent, serde_json::Value)> {
         let PduBuilder {
             room_id,
             sender,
@@ -273,217 +998,18 @@ impl Rooms {
         let prev_events = self.get_pdu_leaves(&room_id)?;
 
         // Is the event authorized?
-        if let Some(state_key) = &state_key {
-            let power_levels = self
-                .room_state_get(&room_id, &EventType::RoomPowerLevels, "")?
-                .map_or_else(
-                    || {
-                        Ok::<_, Error>(power_levels::PowerLevelsEventContent {
-                            ban: 50.into(),
-                            events: BTreeMap::new(),
-                            events_default: 0.into(),
-                            invite: 50.into(),
-                            kick: 50.into(),
-                            redact: 50.into(),
-                            state_default: 0.into(),
-                            users: BTreeMap::new(),
-                            users_default: 0.into(),
-                            notifications:
-                                ruma::events::room::power_levels::NotificationPowerLevels {
-                                    room: 50.into(),
-                                },
-                        })
-                    },
-                    |power_levels| {
-                        Ok(serde_json::from_value::<Raw<PowerLevelsEventContent>>(
-                            power_levels.content,
-                        )
-                        .expect("Raw::from_value always works.")
-                        .deserialize()
-                        .map_err(|_| Error::bad_database("Invalid PowerLevels event in db."))?)
-                    },
-                )?;
-            let sender_membership = self
-                .room_state_get(&room_id, &EventType::RoomMember, &sender.to_string())?
-                .map_or(Ok::<_, Error>(member::MembershipState::Leave), |pdu| {
-                    Ok(
-                        serde_json::from_value::<Raw<member::MemberEventContent>>(pdu.content)
-                            .expect("Raw::from_value always works.")
-                            .deserialize()
-                            .map_err(|_| Error::bad_database("Invalid Member event in db."))?
-                            .membership,
-                    )
-                })?;
-
-            let sender_power = power_levels.users.get(&sender).map_or_else(
-                || {
-                    if sender_membership != member::MembershipState::Join {
-                        None
-                    } else {
-                        Some(&power_levels.users_default)
-                    }
-                },
-                // If it's okay, wrap with Some(_)
-                Some,
-            );
-
-            // Is the event allowed?
-            if !match event_type {
-                EventType::RoomEncryption => {
-                    // Don't allow encryption events when it's disabled
-                    !globals.encryption_disabled()
-                }
-                EventType::RoomMember => {
-                    let target_user_id = UserId::try_from(&**state_key).map_err(|_| {
-                        Error::BadRequest(
-                            ErrorKind::InvalidParam,
-                            "State key of member event does not contain user id.",
-                        )
-                    })?;
-
-                    let current_membership = self
-                        .room_state_get(
-                            &room_id,
-                            &EventType::RoomMember,
-                            &target_user_id.to_string(),
-                        )?
-                        .map_or(Ok::<_, Error>(member::MembershipState::Leave), |pdu| {
-                            Ok(serde_json::from_value::<Raw<member::MemberEventContent>>(
-                                pdu.content,
-                            )
-                            .expect("Raw::from_value always works.")
-                            .deserialize()
-                            .map_err(|_| Error::bad_database("Invalid Member event in db."))?
-                            .membership)
-                        })?;
-
-                    let target_membership =
-                        serde_json::from_value::<Raw<member::MemberEventContent>>(content.clone())
-                            .expect("Raw::from_value always works.")
-                            .deserialize()
-                            .map_err(|_| Error::bad_database("Invalid Member event in db."))?
-                            .membership;
-
-                    let target_power = power_levels.users.get(&target_user_id).map_or_else(
-                        || {
-                            if target_membership != member::MembershipState::Join {
-                                None
-                            } else {
-                                Some(&power_levels.users_default)
-                            }
-                        },
-                        // If it's okay, wrap with Some(_)
-                        Some,
-                    );
-
-                    let join_rules =
-                        self.room_state_get(&room_id, &EventType::RoomJoinRules, "")?
-                            .map_or(Ok::<_, Error>(join_rules::JoinRule::Public), |pdu| {
-                                Ok(serde_json::from_value::<
-                                    Raw<join_rules::JoinRulesEventContent>,
-                                >(pdu.content)
-                                .expect("Raw::from_value always works.")
-                                .deserialize()
-                                .map_err(|_| {
-                                    Error::bad_database("Database contains invalid JoinRules event")
-                                })?
-                                .join_rule)
-                            })?;
-
-                    if target_membership == member::MembershipState::Join {
-                        let mut prev_events = prev_events.iter();
-                        let prev_event = self
-                            .get_pdu(prev_events.next().ok_or(Error::BadRequest(
-                                ErrorKind::Unknown,
-                                "Membership can't be the first event",
-                            ))?)?
-                            .ok_or_else(|| {
-                                Error::bad_database("PDU leaf points to invalid event!")
-                            })?;
-                        if prev_event.kind == EventType::RoomCreate
-                            && prev_event.prev_events.is_empty()
-                        {
-                            true
-                        } else if sender != target_user_id {
-                            false
-                        } else if let member::MembershipState::Ban = current_membership {
-                            false
-                        } else {
-                            join_rules == join_rules::JoinRule::Invite
-                                && (current_membership == member::MembershipState::Join
-                                    || current_membership == member::MembershipState::Invite)
-                                || join_rules == join_rules::JoinRule::Public
-                        }
-                    } else if target_membership == member::MembershipState::Invite {
-                        if let Some(third_party_invite_json) = content.get("third_party_invite") {
-                            if current_membership == member::MembershipState::Ban {
-                                false
-                            } else {
-                                let _third_party_invite =
-                                    serde_json::from_value::<member::ThirdPartyInvite>(
-                                        third_party_invite_json.clone(),
-                                    )
-                                    .map_err(|_| {
-                                        Error::BadRequest(
-                                            ErrorKind::InvalidParam,
-                                            "ThirdPartyInvite is invalid",
-                                        )
-                                    })?;
-                                todo!("handle third party invites");
-                            }
-                        } else if sender_membership != member::MembershipState::Join
-                            || current_membership == member::MembershipState::Join
-                            || current_membership == member::MembershipState::Ban
-                        {
-                            false
-                        } else {
-                            sender_power
-                                .filter(|&p| p >= &power_levels.invite)
-                                .is_some()
-                        }
-                    } else if target_membership == member::MembershipState::Leave {
-                        if sender == target_user_id {
-                            current_membership == member::MembershipState::Join
-                                || current_membership == member::MembershipState::Invite
-                        } else if sender_membership != member::MembershipState::Join
-                            || current_membership == member::MembershipState::Ban
-                                && sender_power.filter(|&p| p < &power_levels.ban).is_some()
-                        {
-                            false
-                        } else {
-                            sender_power.filter(|&p| p >= &power_levels.kick).is_some()
-                                && target_power < sender_power
-                        }
-                    } else if target_membership == member::MembershipState::Ban {
-                        if sender_membership != member::MembershipState::Join {
-                            false
-                        } else {
-                            sender_power.filter(|&p| p >= &power_levels.ban).is_some()
-                                && target_power < sender_power
-                        }
-                    } else {
-                        false
-                    }
-                }
-                EventType::RoomCreate => prev_events.is_empty(),
-                // Not allow any of the following events if the sender is not joined.
-                _ if sender_membership != member::MembershipState::Join => false,
-                _ => {
-                    // TODO
-                    sender_power.unwrap_or(&power_levels.users_default)
-                        >= &power_levels.state_default
-                }
-            } {
-                error!("Unauthorized");
-                // Not authorized
-                return Err(Error::BadRequest(
-                    ErrorKind::Forbidden,
-                    "Event is not authorized",
-                ));
-            }
-        } else if !self.is_joined(&sender, &room_id)? {
-            // TODO: auth rules apply to all events, not only those with a state key
+        if !self.auth_check(
+            &room_id,
+            &sender,
+            &event_type,
+            state_key.as_ref(),
+            &content,
+            redacts.as_ref(),
+            &prev_events,
+            globals,
+        )? {
             error!("Unauthorized");
+            // Not authorized
             return Err(Error::BadRequest(
                 ErrorKind::Forbidden,
                 "Event is not authorized",
@@ -509,123 +1035,636 @@ impl Rooms {
             }
         }
 
-        let mut pdu = PduEvent {
-            event_id: EventId::try_from("$thiswillbefilledinlater").expect("we know this is valid"),
-            room_id: room_id.clone(),
-            sender: sender.clone(),
-            origin: globals.server_name().to_owned(),
-            origin_server_ts: utils::millis_since_unix_epoch()
-                .try_into()
-                .expect("time is valid"),
-            kind: event_type.clone(),
-            content: content.clone(),
-            state_key: state_key.clone(),
-            prev_events,
-            depth: depth
-                .try_into()
-                .map_err(|_| Error::bad_database("Depth is invalid"))?,
-            auth_events: Vec::new(),
-            redacts: redacts.clone(),
-            unsigned,
-            hashes: ruma::events::pdu::EventHash {
-                sha256: "aaa".to_owned(),
-            },
-            signatures: HashMap::new(),
-        };
+        let mut pdu = PduEvent {
+            event_id: EventId::try_from("$thiswillbefilledinlater").expect("we know this is valid"),
+            room_id,
+            sender,
+            origin: globals.server_name().to_owned(),
+            origin_server_ts: utils::millis_since_unix_epoch()
+                .try_into()
+                .expect("time is valid"),
+            kind: event_type,
+            content,
+            state_key,
+            prev_events,
+            depth: depth
+                .try_into()
+                .map_err(|_| Error::bad_database("Depth is invalid"))?,
+            auth_events: Vec::new(),
+            redacts,
+            unsigned,
+            hashes: ruma::events::pdu::EventHash {
+                sha256: "aaa".to_owned(),
+            },
+            signatures: HashMap::new(),
+        };
+
+        // Generate event id
+        pdu.event_id = EventId::try_from(&*format!(
+            "${}",
+            ruma::signatures::reference_hash(
+                &serde_json::to_value(&pdu).expect("event is valid, we just created it")
+            )
+            .expect("ruma can calculate reference hashes")
+        ))
+        .expect("ruma's reference hashes are valid event ids");
+
+        let mut pdu_json = serde_json::to_value(&pdu).expect("event is valid, we just created it");
+        ruma::signatures::hash_and_sign_event(
+            globals.server_name().as_str(),
+            &*globals.keypair(),
+            &mut pdu_json,
+        )
+        .expect("event is valid, we just created it");
+
+        Ok((pdu, pdu_json))
+    }
+
+    /// Adds an event of `build_pdu` to the room. `pdu_json` can contain more signatures than the
+    /// event had when it was built.
+    #[tracing::instrument(
+        level = "debug",
+        skip(self, pdu, pdu_json, globals, account_data),
+        fields(room_id = %pdu.room_id, event_id = %pdu.event_id, event_type = %pdu.kind)
+    )]
+    pub fn append_built_pdu(
+        &self,
+        pdu: PduEvent,
+        pdu_json: serde_json::Value,
+        globals: &super::globals::Globals<'_>,
+        account_data: &super::account_data::AccountData,
+    ) -> Result<EventId> {
+        let pdu_string = pdu_json.to_string();
+
+        let mut leaf_prefix = pdu.room_id.to_string().as_bytes().to_vec();
+        leaf_prefix.push(0xff);
+
+        let state_id = pdu
+            .state_key
+            .as_ref()
+            .map(|state_key| room_state_id(&pdu.room_id, &pdu.kind, state_key));
+
+        // Increment the last index and use that
+        // This is also the next_batch/since value
+        let index = globals.next_count()?;
+
+        let mut pdu_id = leaf_prefix.clone();
+        pdu_id.extend_from_slice(&index.to_be_bytes());
+
+        // The event and the new state and leaves of the room are written together, so a crash
+        // can't store an event that isn't in the room. If the transaction fails, the count is
+        // skipped, which is fine because counts are only compared
+        abstraction::transaction(
+            &[
+                &self.pduid_pdu,
+                &self.eventid_pduid,
+                &self.roomstateid_pdu,
+                &self.roomid_pduleaves,
+            ],
+            |trees| {
+                let (pduid_pdu, eventid_pduid, roomstateid_pdu, roomid_pduleaves) =
+                    (&trees[0], &trees[1], &trees[2], &trees[3]);
+
+                pduid_pdu.insert(&*pdu_id, pdu_string.as_bytes())?;
+                eventid_pduid.insert(pdu.event_id.as_bytes(), &*pdu_id)?;
+
+                if let Some(state_id) = &state_id {
+                    roomstateid_pdu.insert(&**state_id, pdu_string.as_bytes())?;
+                }
+
+                // The new event replaces the old leaves of the room
+                for prev_event in &pdu.prev_events {
+                    let mut leaf_id = leaf_prefix.clone();
+                    leaf_id.extend_from_slice(prev_event.as_bytes());
+                    roomid_pduleaves.remove(leaf_id)?;
+                }
+                let mut leaf_id = leaf_prefix.clone();
+                leaf_id.extend_from_slice(pdu.event_id.as_bytes());
+                roomid_pduleaves.insert(leaf_id, pdu.event_id.as_bytes())?;
+
+                Ok(())
+            },
+        )?;
+
+        self.index_relation(&pdu_id, &pdu)?;
+
+        match pdu.kind {
+            EventType::RoomRedaction => {
+                if let Some(redact_id) = &pdu.redacts {
+                    self.redact_event(&pdu.room_id, &redact_id, &pdu)?;
+                }
+            }
+            EventType::RoomMember => {
+                if let Some(state_key) = &pdu.state_key {
+                    // if the state_key fails
+                    let target_user_id = UserId::try_from(&**state_key)
+                        .expect("This state_key was previously validated");
+                    // Update our membership info, we do this here incase a user is invited
+                    // and immediately leaves we need the DB to record the invite event for auth
+                    self.update_membership(
+                        &pdu.room_id,
+                        &target_user_id,
+                        serde_json::from_value::<member::MemberEventContent>(pdu.content.clone())
+                            .map_err(|_| {
+                            Error::BadRequest(
+                                ErrorKind::InvalidParam,
+                                "Invalid redaction event content.",
+                            )
+                        })?,
+                        &pdu.sender,
+                        account_data,
+                        globals,
+                    )?;
+                }
+            }
+            EventType::RoomMessage | EventType::RoomName | EventType::RoomTopic => {
+                self.index_pdu(&pdu_id, &pdu)?;
+            }
+            _ => {}
+        }
+        self.edus
+            .private_read_set(&pdu.room_id, &pdu.sender, index, &globals)?;
+
+        self.add_notifications(&pdu, index, globals, account_data)?;
+        self.queue_for_appservices(&pdu_id, &pdu, globals)?;
+
+        Ok(pdu.event_id)
+    }
+
+    /// Adds an event of another server that was already verified to the room. Events that are
+    /// already known are skipped.
+    ///
+    /// `is_leaf` makes the event the new leaf of the room. State events that were received
+    /// during a join are not leaves, only the join event is. State events of a fork of the room
+    /// only replace the current state if they win state resolution against it.
+    pub fn append_remote_pdu(
+        &self,
+        pdu_json: &serde_json::Value,
+        is_leaf: bool,
+        globals: &super::globals::Globals<'_>,
+        account_data: &super::account_data::AccountData,
+    ) -> Result<EventId> {
+        let pdu = serde_json::from_value::<PduEvent>(pdu_json.clone())
+            .map_err(|_| Error::BadServerResponse("Received event is invalid."))?;
+
+        if self.get_pdu_id(&pdu.event_id)?.is_some() {
+            return Ok(pdu.event_id);
+        }
+
+        let pdu_string = pdu_json.to_string();
+
+        let mut leaf_prefix = pdu.room_id.to_string().as_bytes().to_vec();
+        leaf_prefix.push(0xff);
+
+        let replaces_state = !is_leaf || self.replaces_current_state(&pdu)?;
+        let state_id = pdu
+            .state_key
+            .as_ref()
+            .filter(|_| replaces_state)
+            .map(|state_key| room_state_id(&pdu.room_id, &pdu.kind, state_key));
+
+        let index = globals.next_count()?;
+
+        let mut pdu_id = leaf_prefix.clone();
+        pdu_id.extend_from_slice(&index.to_be_bytes());
+
+        abstraction::transaction(
+            &[
+                &self.pduid_pdu,
+                &self.eventid_pduid,
+                &self.roomstateid_pdu,
+                &self.roomid_pduleaves,
+            ],
+            |trees| {
+                let (pduid_pdu, eventid_pduid, roomstateid_pdu, roomid_pduleaves) =
+                    (&trees[0], &trees[1], &trees[2], &trees[3]);
+
+                pduid_pdu.insert(&*pdu_id, pdu_string.as_bytes())?;
+                eventid_pduid.insert(pdu.event_id.as_bytes(), &*pdu_id)?;
+
+                if let Some(state_id) = &state_id {
+                    roomstateid_pdu.insert(&**state_id, pdu_string.as_bytes())?;
+                }
+
+                if is_leaf {
+                    for prev_event in &pdu.prev_events {
+                        let mut leaf_id = leaf_prefix.clone();
+                        leaf_id.extend_from_slice(prev_event.as_bytes());
+                        roomid_pduleaves.remove(leaf_id)?;
+                    }
+                    let mut leaf_id = leaf_prefix.clone();
+                    leaf_id.extend_from_slice(pdu.event_id.as_bytes());
+                    roomid_pduleaves.insert(leaf_id, pdu.event_id.as_bytes())?;
+                }
+
+                Ok(())
+            },
+        )?;
+
+        if pdu.kind == EventType::RoomMember && replaces_state {
+            if let Some(target_user_id) = pdu
+                .state_key
+                .as_ref()
+                .and_then(|state_key| UserId::try_from(&**state_key).ok())
+            {
+                self.update_membership(
+                    &pdu.room_id,
+                    &target_user_id,
+                    serde_json::from_value::<member::MemberEventContent>(pdu.content.clone())
+                        .map_err(|_| {
+                            Error::BadServerResponse("Received member event is invalid.")
+                        })?,
+                    &pdu.sender,
+                    account_data,
+                    globals,
+                )?;
+            }
+        }
+
+        self.add_notifications(&pdu, index, globals, account_data)?;
+        self.queue_for_appservices(&pdu_id, &pdu, globals)?;
+
+        Ok(pdu.event_id)
+    }
+
+    /// Checks if a state event of another server replaces the current state event of its type
+    /// and state key. Events that were sent after the current state event replace it. Events of a
+    /// fork of the room that doesn't contain the current state event are resolved against it with
+    /// state resolution.
+    fn replaces_current_state(&self, pdu: &PduEvent) -> Result<bool> {
+        let state_key = match &pdu.state_key {
+            Some(state_key) => state_key,
+            None => return Ok(false),
+        };
+        let current = match self.room_state_get(&pdu.room_id, &pdu.kind, state_key)? {
+            Some(current) => current,
+            None => return Ok(true),
+        };
+
+        if self.is_ancestor(&current, pdu)? {
+            return Ok(true);
+        }
+
+        let mut events = HashMap::new();
+        let mut current_state = state_res::StateMap::new();
+        for (key, state_pdu) in self.room_state_full(&pdu.room_id)? {
+            current_state.insert(key, state_pdu.event_id.clone());
+            events.insert(state_pdu.event_id.clone(), state_pdu);
+        }
+        let key = (pdu.kind.clone(), state_key.clone());
+        let mut fork_state = current_state.clone();
+        fork_state.insert(key.clone(), pdu.event_id.clone());
+        events.insert(pdu.event_id.clone(), pdu.clone());
+
+        let auth_chains = [
+            self.auth_chain(current_state.values(), &mut events)?,
+            self.auth_chain(fork_state.values(), &mut events)?,
+        ];
+        let resolved = state_res::resolve(
+            &self.room_version(&pdu.room_id)?,
+            &[current_state, fork_state],
+            &auth_chains,
+            &events,
+        )?;
+
+        Ok(resolved.get(&key) == Some(&pdu.event_id))
+    }
+
+    /// Checks if `ancestor` is one of the prev events of the event or of their prev events.
+    /// Events with a lower depth than the ancestor can't be its descendants, so the search stops
+    /// there.
+    fn is_ancestor(&self, ancestor: &PduEvent, pdu: &PduEvent) -> Result<bool> {
+        let mut visited = HashSet::new();
+        let mut stack = pdu.prev_events.clone();
+        while let Some(event_id) = stack.pop() {
+            if event_id == ancestor.event_id {
+                return Ok(true);
+            }
+            if visited.len() >= MAX_ANCESTOR_SEARCH || !visited.insert(event_id.clone()) {
+                continue;
+            }
+            if let Some(prev_pdu) = self.get_known_pdu(&event_id)? {
+                if prev_pdu.depth >= ancestor.depth {
+                    stack.extend(prev_pdu.prev_events);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Returns the auth chain of the events: their auth events, the auth events of those and so
+    /// on. The events are added to `events`.
+    fn auth_chain<'a>(
+        &self,
+        event_ids: impl Iterator<Item = &'a EventId>,
+        events: &mut HashMap<EventId, PduEvent>,
+    ) -> Result<HashSet<EventId>> {
+        let mut auth_chain = HashSet::new();
+        let mut stack = Vec::new();
+        for event_id in event_ids {
+            if let Some(pdu) = events.get(event_id) {
+                stack.extend(pdu.auth_events.iter().cloned());
+            }
+        }
+
+        while let Some(event_id) = stack.pop() {
+            if auth_chain.contains(&event_id) {
+                continue;
+            }
+            let pdu = match events.get(&event_id) {
+                Some(pdu) => pdu.clone(),
+                None => match self.get_known_pdu(&event_id)? {
+                    Some(pdu) => pdu,
+                    None => continue,
+                },
+            };
+            stack.extend(pdu.auth_events.iter().cloned());
+            events.insert(event_id.clone(), pdu);
+            auth_chain.insert(event_id);
+        }
+
+        Ok(auth_chain)
+    }
+
+    /// Checks an event of another server against the auth rules, first with its own auth events
+    /// and then with the current state of the room.
+    ///
+    /// Auth events have to be known already, they can be soft-failed or outliers themselves.
+    pub fn auth_incoming_pdu(&self, pdu: &PduEvent) -> Result<PduAuth> {
+        if let Some(reason) = self.rejection_reason(pdu)? {
+            return Ok(PduAuth::Rejected(reason));
+        }
+
+        if !state_res::allowed_by_state(pdu, &self.room_state_full(&pdu.room_id)?) {
+            return Ok(PduAuth::SoftFailed);
+        }
+
+        Ok(PduAuth::Allowed)
+    }
+
+    /// Checks an event of another server against the auth rules with only its own auth events
+    /// and returns why it has to be rejected.
+    pub fn rejection_reason(&self, pdu: &PduEvent) -> Result<Option<&'static str>> {
+        let mut auth_events = HashMap::new();
+        for auth_event_id in &pdu.auth_events {
+            let auth_event = match self.get_known_pdu(auth_event_id)? {
+                Some(auth_event) => auth_event,
+                None => return Ok(Some("Auth events of the event are unknown.")),
+            };
+            if auth_event.room_id != pdu.room_id {
+                return Ok(Some("Auth events of the event are in another room."));
+            }
+            auth_events.insert(auth_event_id.clone(), auth_event);
+        }
+
+        if !state_res::allowed_by_auth_events(pdu, &auth_events) {
+            return Ok(Some("Event is not allowed by its auth events."));
+        }
+
+        Ok(None)
+    }
+
+    /// Returns an event of the timeline, a soft-failed event or an outlier.
+    pub fn get_known_pdu(&self, event_id: &EventId) -> Result<Option<PduEvent>> {
+        if let Some(pdu) = self.get_pdu(event_id)? {
+            return Ok(Some(pdu));
+        }
+        if let Some(pdu) = self.get_soft_failed_pdu(event_id)? {
+            return Ok(Some(pdu));
+        }
+        self.get_outlier_pdu(event_id)
+    }
+
+    /// Stores an event that was fetched as auth event of another event. Like soft-failed events,
+    /// outliers are not part of the timeline, the state or the leaves of the room.
+    pub fn append_outlier_pdu(
+        &self,
+        event_id: &EventId,
+        pdu_json: &serde_json::Value,
+    ) -> Result<()> {
+        self.eventid_outlierpdu
+            .insert(event_id.as_bytes(), pdu_json.to_string().as_bytes())?;
+        Ok(())
+    }
+
+    pub fn get_outlier_pdu(&self, event_id: &EventId) -> Result<Option<PduEvent>> {
+        self.eventid_outlierpdu
+            .get(event_id.as_bytes())?
+            .map_or(Ok(None), |pdu| {
+                serde_json::from_slice(&pdu)
+                    .map(Some)
+                    .map_err(|_| Error::bad_database("Invalid outlier PDU in db."))
+            })
+    }
+
+    /// Stores a soft-failed event of another server. It is not added to the timeline, the state
+    /// or the leaves of the room, so clients never get it and new events don't reference it.
+    pub fn append_soft_failed_pdu(
+        &self,
+        event_id: &EventId,
+        pdu_json: &serde_json::Value,
+    ) -> Result<()> {
+        self.eventid_softfailedpdu
+            .insert(event_id.as_bytes(), pdu_json.to_string().as_bytes())?;
+        Ok(())
+    }
 
-        // Generate event id
-        pdu.event_id = EventId::try_from(&*format!(
-            "${}",
-            ruma::signatures::reference_hash(
-                &serde_json::to_value(&pdu).expect("event is valid, we just created it")
-            )
-            .expect("ruma can calculate reference hashes")
-        ))
-        .expect("ruma's reference hashes are valid event ids");
+    /// Checks if the event was soft-failed.
+    pub fn is_soft_failed(&self, event_id: &EventId) -> Result<bool> {
+        Ok(self
+            .eventid_softfailedpdu
+            .contains_key(event_id.as_bytes())?)
+    }
 
-        let mut pdu_json = serde_json::to_value(&pdu).expect("event is valid, we just created it");
-        ruma::signatures::hash_and_sign_event(
-            globals.server_name().as_str(),
-            globals.keypair(),
-            &mut pdu_json,
-        )
-        .expect("event is valid, we just created it");
+    /// Returns a soft-failed event. Other events can still reference it, e.g. as auth event.
+    pub fn get_soft_failed_pdu(&self, event_id: &EventId) -> Result<Option<PduEvent>> {
+        self.eventid_softfailedpdu
+            .get(event_id.as_bytes())?
+            .map_or(Ok(None), |pdu| {
+                serde_json::from_slice(&pdu)
+                    .map(Some)
+                    .map_err(|_| Error::bad_database("Invalid soft-failed PDU in db."))
+            })
+    }
 
-        self.replace_pdu_leaves(&room_id, &pdu.event_id)?;
+    /// Remembers the event with the `count` for every local member of the room whose push rules
+    /// say it notifies them, so /sync can send the notification counts of the room. The sender
+    /// is never notified.
+    fn add_notifications(
+        &self,
+        pdu: &PduEvent,
+        count: u64,
+        globals: &super::globals::Globals<'_>,
+        account_data: &super::account_data::AccountData,
+    ) -> Result<()> {
+        let event = serde_json::to_value(pdu).expect("PduEvent can be serialized");
 
-        // Increment the last index and use that
-        // This is also the next_batch/since value
-        let index = globals.next_count()?;
+        let power_levels = self
+            .room_state_get(&pdu.room_id, &EventType::RoomPowerLevels, "")?
+            .map(|pdu| pdu.content);
+        let member_count = self.room_members(&pdu.room_id).count() as u64;
 
-        let mut pdu_id = room_id.to_string().as_bytes().to_vec();
-        pdu_id.push(0xff);
-        pdu_id.extend_from_slice(&index.to_be_bytes());
+        for user_id in self.room_members(&pdu.room_id) {
+            let user_id = user_id?;
 
-        self.pduid_pdu.insert(&pdu_id, &*pdu_json.to_string())?;
+            if user_id == pdu.sender || user_id.server_name() != globals.server_name() {
+                continue;
+            }
 
-        self.eventid_pduid
-            .insert(pdu.event_id.to_string(), pdu_id.clone())?;
+            let ruleset = match account_data.get::<ruma::events::push_rules::PushRulesEvent>(
+                None,
+                &user_id,
+                EventType::PushRules,
+            )? {
+                Some(event) => event.content.global,
+                None => continue,
+            };
+            // The display name of the user in this room is the one others mention
+            let displayname = self
+                .room_state_get(&pdu.room_id, &EventType::RoomMember, user_id.as_str())?
+                .and_then(|member| {
+                    member
+                        .content
+                        .get("displayname")?
+                        .as_str()
+                        .map(str::to_owned)
+                });
+            let context = push_rules::PushContext {
+                user_id: &user_id,
+                user_display_name: displayname.as_deref(),
+                room_id: &pdu.room_id,
+                member_count,
+                power_levels: power_levels.as_ref(),
+            };
+
+            let actions = push_rules::evaluate(&ruleset, &event, &context);
+            if actions.notify {
+                self.edus
+                    .notification_add(&pdu.room_id, &user_id, count, actions.highlight)?;
+            }
+        }
 
-        if let Some(state_key) = &pdu.state_key {
-            let mut key = room_id.to_string().as_bytes().to_vec();
-            key.push(0xff);
-            key.extend_from_slice(pdu.kind.to_string().as_bytes());
+        Ok(())
+    }
+
+    /// Queues the event for every appservice with a url that is interested in it. The sending
+    /// tasks of the appservices send the queue in order.
+    fn queue_for_appservices(
+        &self,
+        pdu_id: &[u8],
+        pdu: &PduEvent,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<()> {
+        for appservice in globals.appservices() {
+            if appservice.url.is_none() || !self.is_appservice_interested(appservice, pdu)? {
+                continue;
+            }
+
+            let mut key = appservice.id.as_bytes().to_vec();
             key.push(0xff);
-            key.extend_from_slice(state_key.as_bytes());
-            self.roomstateid_pdu.insert(key, &*pdu_json.to_string())?;
+            key.extend_from_slice(&globals.next_count()?.to_be_bytes());
+            self.servicepdus.insert(key, pdu_id)?;
         }
 
-        match event_type {
-            EventType::RoomRedaction => {
-                if let Some(redact_id) = &redacts {
-                    self.redact_pdu(&redact_id, &pdu)?;
+        Ok(())
+    }
+
+    /// Appservices are interested in events of rooms in their namespaces, of rooms with an alias
+    /// in their namespaces and of rooms with a member in their namespaces. Membership events of
+    /// users in their namespaces are always sent, even if the user is not in the room anymore.
+    fn is_appservice_interested(
+        &self,
+        appservice: &super::appservice::Registration,
+        pdu: &PduEvent,
+    ) -> Result<bool> {
+        if appservice.is_room_match(&pdu.room_id) || appservice.is_user_match(&pdu.sender) {
+            return Ok(true);
+        }
+
+        if pdu.kind == EventType::RoomMember {
+            if let Some(user_id) = pdu
+                .state_key
+                .as_ref()
+                .and_then(|state_key| UserId::try_from(&**state_key).ok())
+            {
+                if appservice.is_user_match(&user_id) {
+                    return Ok(true);
                 }
             }
-            EventType::RoomMember => {
-                if let Some(state_key) = state_key {
-                    // if the state_key fails
-                    let target_user_id = UserId::try_from(state_key)
-                        .expect("This state_key was previously validated");
-                    // Update our membership info, we do this here incase a user is invited
-                    // and immediately leaves we need the DB to record the invite event for auth
-                    self.update_membership(
-                        &room_id,
-                        &target_user_id,
-                        serde_json::from_value::<member::MemberEventContent>(content).map_err(
-                            |_| {
-                                Error::BadRequest(
-                                    ErrorKind::InvalidParam,
-                                    "Invalid redaction event content.",
-                                )
-                            },
-                        )?,
-                        &sender,
-                        account_data,
-                        globals,
-                    )?;
-                }
+        }
+
+        for alias in self.room_aliases(&pdu.room_id) {
+            if appservice.is_room_alias_match(&alias?) {
+                return Ok(true);
             }
-            EventType::RoomMessage => {
-                if let Some(body) = content.get("body").and_then(|b| b.as_str()) {
-                    for word in body
-                        .split_terminator(|c: char| !c.is_alphanumeric())
-                        .map(str::to_lowercase)
-                    {
-                        let mut key = room_id.to_string().as_bytes().to_vec();
-                        key.push(0xff);
-                        key.extend_from_slice(word.as_bytes());
-                        key.push(0xff);
-                        key.extend_from_slice(&pdu_id);
-                        self.tokenids.insert(key, &[])?;
-                    }
-                }
+        }
+
+        for user_id in self.room_members(&pdu.room_id) {
+            if appservice.is_user_match(&user_id?) {
+                return Ok(true);
             }
-            _ => {}
         }
-        self.edus
-            .private_read_set(&room_id, &sender, index, &globals)?;
 
-        Ok(pdu.event_id)
+        Ok(false)
+    }
+
+    /// Returns the count and the event id of the oldest event of the room this server knows.
+    pub fn first_pdu(&self, room_id: &RoomId) -> Result<Option<(u64, EventId)>> {
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.pduid_pdu
+            .scan_prefix(&prefix)
+            .next()
+            .map(|r| {
+                let (key, value) = r?;
+                let pdu = serde_json::from_slice::<PduEvent>(&value)
+                    .map_err(|_| Error::bad_database("PDU in db is invalid."))?;
+                Ok((
+                    utils::u64_from_bytes(&key[prefix.len()..])
+                        .map_err(|_| Error::bad_database("Invalid pdu id in db."))?,
+                    pdu.event_id,
+                ))
+            })
+            .transpose()
+    }
+
+    /// Adds a verified event of another server before all events the room has. Used for
+    /// backfilling, so it doesn't change the state, the leaves or memberships of the room.
+    ///
+    /// Counts of backfilled events go down from the oldest event of the room, which is fine
+    /// because pdu ids contain the room. Returns false if the event was already known or there is
+    /// no count left.
+    pub fn append_backfilled_pdu(&self, pdu_json: &serde_json::Value) -> Result<bool> {
+        let pdu = serde_json::from_value::<PduEvent>(pdu_json.clone())
+            .map_err(|_| Error::BadServerResponse("Received event is invalid."))?;
+
+        if self.get_pdu_id(&pdu.event_id)?.is_some() {
+            return Ok(false);
+        }
+
+        let count = match self.first_pdu(&pdu.room_id)? {
+            Some((first_count, _)) if first_count > 1 => first_count - 1,
+            _ => return Ok(false),
+        };
+
+        let mut pdu_id = pdu.room_id.to_string().as_bytes().to_vec();
+        pdu_id.push(0xff);
+        pdu_id.extend_from_slice(&count.to_be_bytes());
+
+        abstraction::transaction(&[&self.pduid_pdu, &self.eventid_pduid], |trees| {
+            trees[0].insert(&*pdu_id, pdu_json.to_string().as_bytes())?;
+            trees[1].insert(pdu.event_id.as_bytes(), &*pdu_id)?;
+            Ok(())
+        })?;
+
+        self.index_relation(&pdu_id, &pdu)?;
+        if pdu.kind == EventType::RoomMessage {
+            self.index_pdu(&pdu_id, &pdu)?;
+        }
+
+        Ok(true)
     }
 
     /// Returns an iterator over all PDUs in a room.
@@ -708,6 +1747,35 @@ impl Rooms {
             })
     }
 
+    /// Returns the events of a room before the event with the count `until` as they are stored,
+    /// newest first. Used to send events to other servers.
+    pub fn pdu_jsons_until(
+        &self,
+        room_id: &RoomId,
+        until: u64,
+    ) -> impl Iterator<Item = Result<(u64, serde_json::Value)>> {
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let mut current = prefix.clone();
+        current.extend_from_slice(&until.to_be_bytes());
+
+        let prefixlen = prefix.len();
+        self.pduid_pdu
+            .range(..current)
+            .rev()
+            .filter_map(|r| r.ok())
+            .take_while(move |(k, _)| k.starts_with(&prefix))
+            .map(move |(k, v)| {
+                Ok((
+                    utils::u64_from_bytes(&k[prefixlen..])
+                        .map_err(|_| Error::bad_database("Invalid pdu id in db."))?,
+                    serde_json::from_slice(&v)
+                        .map_err(|_| Error::bad_database("PDU in db is invalid."))?,
+                ))
+            })
+    }
+
     /// Returns an iterator over all events and their token in a room that happened after the event
     /// with id `from` in chronological order.
     pub fn pdus_after(
@@ -745,21 +1813,108 @@ impl Rooms {
             })
     }
 
-    /// Replace a PDU with the redacted form.
-    pub fn redact_pdu(&self, event_id: &EventId, reason: &PduEvent) -> Result<()> {
-        if let Some(pdu_id) = self.get_pdu_id(event_id)? {
-            let mut pdu = self
-                .get_pdu_from_id(&pdu_id)?
-                .ok_or_else(|| Error::bad_database("PDU ID points to invalid PDU."))?;
-            pdu.redact(&reason)?;
-            self.replace_pdu(&pdu_id, &pdu)?;
-            Ok(())
-        } else {
-            Err(Error::BadRequest(
-                ErrorKind::NotFound,
-                "Event ID does not exist.",
-            ))
+    /// Strips the event down to the keys the redaction algorithm of the room version keeps and
+    /// records the redaction in `unsigned.redacted_because`.
+    ///
+    /// The event stays stored, so its reference hash and signatures can still be verified. Events
+    /// we don't know (yet) or of other rooms are ignored.
+    pub fn redact_event(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+        reason: &PduEvent,
+    ) -> Result<()> {
+        let pdu_id = match self.get_pdu_id(event_id)? {
+            Some(pdu_id) => pdu_id,
+            None => return Ok(()),
+        };
+        let mut pdu = self
+            .get_pdu_from_id(&pdu_id)?
+            .ok_or_else(|| Error::bad_database("PDU ID points to invalid PDU."))?;
+        if &pdu.room_id != room_id {
+            return Ok(());
+        }
+
+        // Redacted events can't be found anymore and lose their relation
+        self.unindex_pdu(&pdu_id, &pdu)?;
+        self.unindex_relation(&pdu_id, &pdu)?;
+        pdu.redact(&reason, &self.room_version(&pdu.room_id)?)?;
+        self.replace_pdu(&pdu_id, &pdu)?;
+
+        // The current state has its own copy of the event
+        if let Some(state_key) = &pdu.state_key {
+            if self
+                .room_state_get(room_id, &pdu.kind, state_key)?
+                .map_or(false, |state| state.event_id == *event_id)
+            {
+                self.roomstateid_pdu.insert(
+                    room_state_id(room_id, &pdu.kind, state_key),
+                    &*serde_json::to_string(&pdu).expect("PduEvent::to_string always works"),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Users can redact their own events and, with the `redact` power level, those of others.
+    fn is_redaction_allowed(
+        &self,
+        room_id: &RoomId,
+        sender: &UserId,
+        redacts: Option<&EventId>,
+    ) -> Result<bool> {
+        let redacts = match redacts {
+            Some(redacts) => redacts,
+            None => return Ok(false),
+        };
+
+        if let Some(target) = self.get_pdu(redacts)? {
+            if &target.room_id != room_id {
+                return Ok(false);
+            }
+            if &target.sender == sender {
+                return Ok(true);
+            }
         }
+
+        let (sender_level, redact_level) = self.power_levels_for(room_id, sender, "redact", 50)?;
+        Ok(sender_level >= redact_level)
+    }
+
+    /// Returns the power level of the user and the level that is needed for `action` (e.g.
+    /// `invite` or `redact`), which defaults to `action_default`.
+    fn power_levels_for(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        action: &str,
+        action_default: i64,
+    ) -> Result<(i64, i64)> {
+        let power_levels = match self.room_state_get(room_id, &EventType::RoomPowerLevels, "")? {
+            Some(power_levels) => power_levels.content,
+            None => {
+                // Without power levels only the creator has power
+                let is_creator = self
+                    .room_state_get(room_id, &EventType::RoomCreate, "")?
+                    .and_then(|pdu| pdu.content.get("creator")?.as_str().map(str::to_owned))
+                    == Some(user_id.to_string());
+                return Ok((if is_creator { 100 } else { 0 }, action_default));
+            }
+        };
+
+        let user_level = power_levels
+            .get("users")
+            .and_then(|users| users.get(user_id.as_str()))
+            .or_else(|| power_levels.get("users_default"))
+            .and_then(|level| level.as_i64())
+            .unwrap_or(0);
+        let action_level = power_levels
+            .get(action)
+            .and_then(|level| level.as_i64())
+            .unwrap_or(action_default);
+
+        Ok((user_level, action_level))
     }
 
     /// Update current membership data.
@@ -873,6 +2028,7 @@ impl Rooms {
                 self.userroomid_invited.remove(&userroom_id)?;
                 self.roomuserid_invited.remove(&roomuser_id)?;
                 self.userroomid_left.remove(&userroom_id)?;
+                self.userroomid_invitestate.remove(&userroom_id)?;
             }
             member::MembershipState::Invite => {
                 // We want to know if the sender is ignored by the receiver
@@ -913,11 +2069,15 @@ impl Rooms {
                 self.userroomid_left.remove(&userroom_id)?;
             }
             member::MembershipState::Leave | member::MembershipState::Ban => {
+                // Users that left can't be typing anymore
+                self.edus.typing_remove(&user_id, &room_id, globals)?;
+
                 self.userroomid_left.insert(&userroom_id, &[])?;
                 self.userroomid_joined.remove(&userroom_id)?;
                 self.roomuserid_joined.remove(&roomuser_id)?;
                 self.userroomid_invited.remove(&userroom_id)?;
                 self.roomuserid_invited.remove(&roomuser_id)?;
+                self.userroomid_invitestate.remove(&userroom_id)?;
             }
             _ => {}
         }
@@ -936,37 +2096,169 @@ impl Rooms {
         Ok(())
     }
 
+    /// Removes the room with its events, state, memberships and aliases from the database.
+    ///
+    /// Local users leave the room first, so other servers know that they are gone. Their clients
+    /// won't see the room again, even in the rooms they left.
+    #[tracing::instrument(level = "debug", skip(self, globals, account_data))]
+    pub fn purge_room(
+        &self,
+        room_id: &RoomId,
+        globals: &super::globals::Globals<'_>,
+        account_data: &super::account_data::AccountData,
+    ) -> Result<()> {
+        let local_members = self
+            .room_members(room_id)
+            .chain(self.room_members_invited(room_id))
+            .filter_map(|r| r.ok())
+            .filter(|user_id| user_id.server_name() == globals.server_name())
+            .collect::<Vec<_>>();
+
+        for user_id in local_members {
+            self.append_pdu(
+                PduBuilder {
+                    room_id: room_id.clone(),
+                    sender: user_id.clone(),
+                    event_type: EventType::RoomMember,
+                    content: json!({ "membership": "leave" }),
+                    unsigned: None,
+                    state_key: Some(user_id.to_string()),
+                    redacts: None,
+                },
+                globals,
+                account_data,
+            )?;
+        }
+
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        for (pdu_id, pdu) in self.pduid_pdu.scan_prefix(&prefix).filter_map(|r| r.ok()) {
+            let pdu = serde_json::from_slice::<PduEvent>(&pdu)
+                .map_err(|_| Error::bad_database("Invalid PDU in db."))?;
+            self.unindex_pdu(&pdu_id, &pdu)?;
+            self.eventid_pduid.remove(pdu.event_id.to_string())?;
+            self.pduid_pdu.remove(pdu_id)?;
+        }
+
+        for tree in &[
+            &self.roomstateid_pdu,
+            &self.roomid_pduleaves,
+            &self.parentid_relations,
+        ] {
+            for key in tree.scan_prefix(&prefix).keys() {
+                tree.remove(key?)?;
+            }
+        }
+
+        // Memberships are also indexed by user
+        for (room_user_tree, user_room_tree) in &[
+            (&self.roomuserid_joined, &self.userroomid_joined),
+            (&self.roomuserid_invited, &self.userroomid_invited),
+        ] {
+            for roomuser_id in room_user_tree.scan_prefix(&prefix).keys() {
+                let roomuser_id = roomuser_id?;
+                let mut userroom_id = roomuser_id[prefix.len()..].to_vec();
+                userroom_id.push(0xff);
+                userroom_id.extend_from_slice(room_id.to_string().as_bytes());
+
+                user_room_tree.remove(userroom_id)?;
+                room_user_tree.remove(roomuser_id)?;
+            }
+        }
+
+        let mut suffix = vec![0xff];
+        suffix.extend_from_slice(room_id.to_string().as_bytes());
+        for tree in &[&self.userroomid_left, &self.roomuseroncejoinedids] {
+            for key in tree.iter().keys() {
+                let key = key?;
+                if key.ends_with(&suffix) {
+                    tree.remove(key)?;
+                }
+            }
+        }
+
+        for alias in self.room_aliases(room_id).filter_map(|r| r.ok()) {
+            self.set_alias(&alias, None, globals)?;
+        }
+
+        self.publicroomids.remove(room_id.to_string())?;
+
+        Ok(())
+    }
+
+    /// Points the local alias to the room, or removes it if `room_id` is `None`.
+    ///
+    /// - If the alias pointed to another room before, it is removed from the aliases of that room
     pub fn set_alias(
         &self,
         alias: &RoomAliasId,
         room_id: Option<&RoomId>,
         globals: &super::globals::Globals<'_>,
     ) -> Result<()> {
+        let old_room_id = self.id_from_alias(alias)?;
+
+        if let Some(old_room_id) = &old_room_id {
+            // Only remove this alias from the old room, the room can have more aliases
+            let mut prefix = old_room_id.to_string().as_bytes().to_vec();
+            prefix.push(0xff);
+
+            for (aliasid, old_alias) in self
+                .aliasid_alias
+                .scan_prefix(prefix)
+                .filter_map(|r| r.ok())
+            {
+                if &*old_alias == alias.as_str().as_bytes() {
+                    self.aliasid_alias.remove(aliasid)?;
+                }
+            }
+        }
+
         if let Some(room_id) = room_id {
             // New alias
             self.alias_roomid
                 .insert(alias.alias(), &*room_id.to_string())?;
             let mut aliasid = room_id.to_string().as_bytes().to_vec();
+            aliasid.push(0xff);
             aliasid.extend_from_slice(&globals.next_count()?.to_be_bytes());
-            self.aliasid_alias.insert(aliasid, &*alias.alias())?;
+            self.aliasid_alias.insert(aliasid, alias.as_str())?;
         } else {
             // room_id=None means remove alias
-            let room_id = self
-                .alias_roomid
-                .remove(alias.alias())?
-                .ok_or(Error::BadRequest(
+            if old_room_id.is_none() {
+                return Err(Error::BadRequest(
                     ErrorKind::NotFound,
                     "Alias does not exist.",
-                ))?;
-
-            for key in self.aliasid_alias.scan_prefix(room_id).keys() {
-                self.aliasid_alias.remove(key?)?;
+                ));
             }
+
+            self.alias_roomid.remove(alias.alias())?;
+            self.alias_userid.remove(alias.alias())?;
         }
 
         Ok(())
     }
 
+    /// Remembers who created the alias, they are allowed to delete it later.
+    pub fn set_alias_creator(&self, alias: &RoomAliasId, user_id: &UserId) -> Result<()> {
+        self.alias_userid.insert(alias.alias(), user_id.as_str())?;
+
+        Ok(())
+    }
+
+    /// Returns the user who created the alias, if it was created by a user.
+    pub fn alias_creator(&self, alias: &RoomAliasId) -> Result<Option<UserId>> {
+        self.alias_userid
+            .get(alias.alias())?
+            .map_or(Ok(None), |bytes| {
+                Ok(Some(
+                    UserId::try_from(utils::string_from_bytes(&bytes).map_err(|_| {
+                        Error::bad_database("User ID in alias_userid is invalid unicode.")
+                    })?)
+                    .map_err(|_| Error::bad_database("User ID in alias_userid is invalid."))?,
+                ))
+            })
+    }
+
     pub fn id_from_alias(&self, alias: &RoomAliasId) -> Result<Option<RoomId>> {
         self.alias_roomid
             .get(alias.alias())?
@@ -980,17 +2272,96 @@ impl Rooms {
             })
     }
 
-    pub fn room_aliases(&self, room_id: &RoomId) -> impl Iterator<Item = Result<RoomAliasId>> {
-        let mut prefix = room_id.to_string().as_bytes().to_vec();
-        prefix.push(0xff);
+    pub fn room_aliases(&self, room_id: &RoomId) -> impl Iterator<Item = Result<RoomAliasId>> {
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.aliasid_alias
+            .scan_prefix(prefix)
+            .values()
+            .map(|bytes| {
+                Ok(
+                    RoomAliasId::try_from(utils::string_from_bytes(&bytes?).map_err(|_| {
+                        Error::bad_database("Alias in aliasid_alias is invalid unicode.")
+                    })?)
+                    .map_err(|_| Error::bad_database("Alias in aliasid_alias is invalid."))?,
+                )
+            })
+    }
+
+    /// Checks that the local aliases in the content of an `m.room.canonical_alias` event point to
+    /// the room.
+    ///
+    /// Returns the aliases of other servers, they can only be checked by asking their server.
+    pub fn validate_canonical_alias(
+        &self,
+        room_id: &RoomId,
+        content: &serde_json::Value,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<Vec<RoomAliasId>> {
+        let content = serde_json::from_value::<Raw<CanonicalAliasEventContent>>(content.clone())
+            .expect("from_value::<Raw<..>> can never fail")
+            .deserialize()
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid canonical alias."))?;
+
+        let mut remote_aliases = Vec::new();
+
+        for alias in content.alias.into_iter().chain(content.alt_aliases) {
+            if alias.server_name() != globals.server_name() {
+                remote_aliases.push(alias);
+            } else if self.id_from_alias(&alias)?.as_ref() != Some(room_id) {
+                return Err(Error::BadAlias(
+                    "Aliases of the canonical alias event have to point to the room.",
+                ));
+            }
+        }
+
+        Ok(remote_aliases)
+    }
+
+    /// Checks if the alias is the canonical alias or one of the alternative aliases of the room.
+    pub fn is_canonical_alias(&self, room_id: &RoomId, alias: &RoomAliasId) -> Result<bool> {
+        let content = match self.room_state_get(room_id, &EventType::RoomCanonicalAlias, "")? {
+            Some(pdu) => pdu.content,
+            None => return Ok(false),
+        };
 
-        self.aliasid_alias
-            .scan_prefix(prefix)
-            .values()
-            .map(|bytes| {
-                Ok(serde_json::from_slice(&bytes?)
-                    .map_err(|_| Error::bad_database("Alias in aliasid_alias is invalid."))?)
+        let is_alias = |value: &serde_json::Value| value.as_str() == Some(alias.as_str());
+
+        Ok(content.get("alias").map_or(false, is_alias)
+            || content
+                .get("alt_aliases")
+                .and_then(|alt_aliases| alt_aliases.as_array())
+                .map_or(false, |alt_aliases| alt_aliases.iter().any(is_alias)))
+    }
+
+    /// Checks if the user may delete aliases of the room, which needs the power level of
+    /// `m.room.canonical_alias` events.
+    pub fn is_room_admin(&self, room_id: &RoomId, user_id: &UserId) -> Result<bool> {
+        self.can_send_state_event(room_id, user_id, &EventType::RoomCanonicalAlias)
+    }
+
+    /// Checks if the power level of the user is high enough to send state events of this type.
+    pub fn can_send_state_event(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        event_type: &EventType,
+    ) -> Result<bool> {
+        let (user_level, state_level) =
+            self.power_levels_for(room_id, user_id, "state_default", 50)?;
+
+        let event_level = self
+            .room_state_get(room_id, &EventType::RoomPowerLevels, "")?
+            .and_then(|pdu| {
+                pdu.content
+                    .get("events")?
+                    .get(event_type.to_string())?
+                    .as_i64()
             })
+            .unwrap_or(state_level);
+
+        Ok(user_level >= event_level)
     }
 
     pub fn set_public(&self, room_id: &RoomId, public: bool) -> Result<()> {
@@ -1018,43 +2389,272 @@ impl Rooms {
         })
     }
 
-    pub fn search_pdus<'a>(
-        &'a self,
+    /// Adds the searchable fields of the pdu to the search index.
+    fn index_pdu(&self, pdu_id: &[u8], pdu: &PduEvent) -> Result<()> {
+        for token in searchable_tokens(pdu) {
+            let mut key = token.as_bytes().to_vec();
+            key.push(0xff);
+            key.extend_from_slice(pdu_id);
+            self.search_index
+                .insert(key, pdu.event_id.to_string().as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the pdu from the search index.
+    fn unindex_pdu(&self, pdu_id: &[u8], pdu: &PduEvent) -> Result<()> {
+        for token in searchable_tokens(pdu) {
+            let mut key = token.as_bytes().to_vec();
+            key.push(0xff);
+            key.extend_from_slice(pdu_id);
+            self.search_index.remove(key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Remembers the event the pdu relates to with `m.relates_to`, if any.
+    fn index_relation(&self, pdu_id: &[u8], pdu: &PduEvent) -> Result<()> {
+        if let Some(key) = relation_key(pdu_id, pdu) {
+            self.parentid_relations.insert(key, pdu_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the pdu from the relations of its parent event.
+    fn unindex_relation(&self, pdu_id: &[u8], pdu: &PduEvent) -> Result<()> {
+        if let Some(key) = relation_key(pdu_id, pdu) {
+            self.parentid_relations.remove(key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns an iterator over all events that relate to the event, from old to new.
+    pub fn relations(
+        &self,
         room_id: &RoomId,
-        search_string: &str,
-    ) -> Result<(impl Iterator<Item = IVec> + 'a, Vec<String>)> {
+        event_id: &EventId,
+    ) -> impl DoubleEndedIterator<Item = Result<(u64, PduEvent)>> + '_ {
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(event_id.to_string().as_bytes());
+        prefix.push(0xff);
+
+        self.parentid_relations.scan_prefix(prefix).map(move |r| {
+            let (key, pdu_id) = r?;
+            let count = utils::u64_from_bytes(&key[key.len() - mem::size_of::<u64>()..])
+                .map_err(|_| Error::bad_database("Relation has invalid count bytes."))?;
+            let pdu = self
+                .get_pdu_from_id(&pdu_id)?
+                .ok_or_else(|| Error::bad_database("Relation points to invalid pdu."))?;
+
+            Ok((count, pdu))
+        })
+    }
+
+    /// Returns the thread roots of the room with the count of their newest reply, newest thread
+    /// first.
+    ///
+    /// This is derived from the relations index, so nothing has to be kept in memory.
+    pub fn threads(&self, room_id: &RoomId) -> Result<Vec<(u64, EventId)>> {
         let mut prefix = room_id.to_string().as_bytes().to_vec();
         prefix.push(0xff);
 
-        let words = search_string
-            .split_terminator(|c: char| !c.is_alphanumeric())
-            .map(str::to_lowercase)
+        let mut threads = HashMap::new();
+        for relation in self.parentid_relations.scan_prefix(&prefix) {
+            let (key, pdu_id) = relation?;
+            let count = utils::u64_from_bytes(&key[key.len() - mem::size_of::<u64>()..])
+                .map_err(|_| Error::bad_database("Relation has invalid count bytes."))?;
+
+            let is_thread_reply = self.get_pdu_from_id(&pdu_id)?.map_or(false, |pdu| {
+                pdu.content
+                    .get("m.relates_to")
+                    .and_then(|relates_to| relates_to.get("rel_type")?.as_str())
+                    == Some("m.thread")
+            });
+            if !is_thread_reply {
+                continue;
+            }
+
+            // The parent id is between the room id and the count
+            let root_id = EventId::try_from(
+                utils::string_from_bytes(&key[prefix.len()..key.len() - mem::size_of::<u64>() - 1])
+                    .map_err(|_| Error::bad_database("Relation has invalid parent id bytes."))?,
+            )
+            .map_err(|_| Error::bad_database("Relation has invalid parent id."))?;
+
+            let latest = threads.entry(root_id).or_insert(count);
+            *latest = count.max(*latest);
+        }
+
+        let mut threads = threads
+            .into_iter()
+            .map(|(root_id, count)| (count, root_id))
             .collect::<Vec<_>>();
+        threads.sort_by(|a, b| b.cmp(a));
+
+        Ok(threads)
+    }
 
+    /// Adds the aggregations of the related events to `unsigned.m.relations`:
+    ///
+    /// - `m.annotation`: How often each reaction was sent
+    /// - `m.replace`: The newest edit of the original sender
+    /// - `m.reference`: The events that reference this event
+    /// - `m.thread`: The newest reply, how many replies there are and if the user participated
+    pub fn add_bundled_relations(&self, pdu: &mut PduEvent, user_id: &UserId) -> Result<()> {
+        let mut annotations = BTreeMap::<(String, String), u64>::new();
+        let mut replace: Option<PduEvent> = None;
+        let mut references = Vec::new();
+        let mut thread_latest: Option<PduEvent> = None;
+        let mut thread_count = 0_u64;
+        let mut thread_participated = pdu.sender == *user_id;
+
+        for relation in self.relations(&pdu.room_id, &pdu.event_id) {
+            let (_, child) = relation?;
+            let relates_to = match child.content.get("m.relates_to") {
+                Some(relates_to) => relates_to,
+                None => continue,
+            };
+
+            match relates_to.get("rel_type").and_then(|r| r.as_str()) {
+                Some("m.annotation") => {
+                    if let Some(key) = relates_to.get("key").and_then(|k| k.as_str()) {
+                        *annotations
+                            .entry((child.kind.to_string(), key.to_owned()))
+                            .or_default() += 1;
+                    }
+                }
+                // Only the sender can edit their event
+                Some("m.replace") if child.sender == pdu.sender => {
+                    if replace.as_ref().map_or(true, |newest| {
+                        (child.origin_server_ts, &child.event_id)
+                            > (newest.origin_server_ts, &newest.event_id)
+                    }) {
+                        replace = Some(child);
+                    }
+                }
+                Some("m.reference") => references.push(json!({ "event_id": child.event_id })),
+                Some("m.thread") => {
+                    thread_count += 1;
+                    thread_participated |= child.sender == *user_id;
+                    // The relations are sorted from old to new
+                    thread_latest = Some(child);
+                }
+                _ => {}
+            }
+        }
+
+        let mut relations = serde_json::Map::new();
+        if !annotations.is_empty() {
+            let mut chunk = annotations.into_iter().collect::<Vec<_>>();
+            // Most used first
+            chunk.sort_by(|(_, a), (_, b)| b.cmp(a));
+            relations.insert(
+                "m.annotation".to_owned(),
+                json!({
+                    "chunk": chunk
+                        .into_iter()
+                        .map(|((kind, key), count)| json!({
+                            "type": kind,
+                            "key": key,
+                            "count": count,
+                        }))
+                        .collect::<Vec<_>>()
+                }),
+            );
+        }
+        if let Some(replace) = replace {
+            relations.insert(
+                "m.replace".to_owned(),
+                json!({
+                    "event_id": replace.event_id,
+                    "origin_server_ts": replace.origin_server_ts,
+                    "sender": replace.sender,
+                }),
+            );
+        }
+        if let Some(thread_latest) = thread_latest {
+            relations.insert(
+                "m.thread".to_owned(),
+                json!({
+                    "latest_event": thread_latest.to_room_event(),
+                    "count": thread_count,
+                    "current_user_participated": thread_participated,
+                }),
+            );
+        }
+        if !references.is_empty() {
+            relations.insert("m.reference".to_owned(), json!({ "chunk": references }));
+        }
+
+        if !relations.is_empty() {
+            pdu.unsigned
+                .insert("m.relations".to_owned(), relations.into());
+        }
+
+        Ok(())
+    }
+
+    /// Recreates the search index from all pdus in the database. Returns how many pdus were
+    /// indexed.
+    ///
+    /// This goes through every event on the server, so it should only be used when the index is
+    /// missing or broken.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn rebuild_search_index(&self) -> Result<u64> {
+        self.search_index.clear()?;
+
+        let mut indexed = 0;
+        for (pdu_id, pdu) in self.pduid_pdu.iter().filter_map(|r| r.ok()) {
+            let pdu = match serde_json::from_slice::<PduEvent>(&pdu) {
+                Ok(pdu) => pdu,
+                Err(_) => {
+                    error!("Skipped invalid pdu while rebuilding the search index.");
+                    continue;
+                }
+            };
+
+            if matches!(
+                pdu.kind,
+                EventType::RoomMessage | EventType::RoomName | EventType::RoomTopic
+            ) {
+                self.index_pdu(&pdu_id, &pdu)?;
+                indexed += 1;
+            }
+        }
+
+        Ok(indexed)
+    }
+
+    /// Returns the ids of all pdus in the room that contain every word of the search string,
+    /// newest first, and the words that were searched for.
+    pub fn search_pdus<'a>(
+        &'a self,
+        room_id: &RoomId,
+        search_string: &str,
+    ) -> Result<(impl Iterator<Item = IVec> + 'a, Vec<String>)> {
+        let mut words = tokenize(search_string).collect::<Vec<_>>();
+        words.sort();
+        words.dedup();
+
+        let room_id = room_id.clone();
         let iterators = words.clone().into_iter().map(move |word| {
-            let mut prefix2 = prefix.clone();
-            prefix2.extend_from_slice(word.as_bytes());
-            prefix2.push(0xff);
-            self.tokenids
-                .scan_prefix(&prefix2)
+            let mut prefix = word.as_bytes().to_vec();
+            prefix.push(0xff);
+            let token_len = prefix.len();
+            prefix.extend_from_slice(room_id.to_string().as_bytes());
+            prefix.push(0xff);
+
+            self.search_index
+                .scan_prefix(&prefix)
                 .keys()
                 .rev() // Newest pdus first
                 .filter_map(|r| r.ok())
-                .map(|key| {
-                    let pduid_index = key
-                        .iter()
-                        .enumerate()
-                        .filter(|(_, &b)| b == 0xff)
-                        .nth(1)
-                        .ok_or_else(|| Error::bad_database("Invalid tokenid in db."))?
-                        .0
-                        + 1; // +1 because the pdu id starts AFTER the separator
-
-                    let pdu_id = key.subslice(pduid_index, key.len() - pduid_index);
-
-                    Ok::<_, Error>(pdu_id)
-                })
-                .filter_map(|r| r.ok())
+                .map(move |key| key.subslice(token_len, key.len() - token_len))
         });
 
         Ok((
@@ -1062,7 +2662,8 @@ impl Rooms {
                 // We compare b with a because we reversed the iterator earlier
                 b.cmp(a)
             })
-            .unwrap(),
+            .into_iter()
+            .flatten(),
             words,
         ))
     }
@@ -1264,4 +2865,445 @@ impl Rooms {
 
         Ok(self.userroomid_left.get(userroom_id)?.is_some())
     }
+
+    /// Saves an invite of another server for a room this server doesn't participate in. The
+    /// stripped state is shown to the user instead of the room state.
+    pub fn add_remote_invite(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        invite_state: &[serde_json::Value],
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<()> {
+        let mut userroom_id = user_id.to_string().as_bytes().to_vec();
+        userroom_id.push(0xff);
+        userroom_id.extend_from_slice(room_id.to_string().as_bytes());
+
+        let mut roomuser_id = room_id.to_string().as_bytes().to_vec();
+        roomuser_id.push(0xff);
+        roomuser_id.extend_from_slice(user_id.to_string().as_bytes());
+
+        let mut value = globals.next_count()?.to_be_bytes().to_vec();
+        value.extend_from_slice(
+            serde_json::to_string(invite_state)
+                .expect("Value::to_string always works")
+                .as_bytes(),
+        );
+
+        self.userroomid_invitestate.insert(&userroom_id, value)?;
+        self.userroomid_invited.insert(&userroom_id, &[])?;
+        self.roomuserid_invited.insert(&roomuser_id, &[])?;
+        self.userroomid_left.remove(&userroom_id)?;
+
+        Ok(())
+    }
+
+    /// Returns the count and the stripped state of an invite of another server.
+    pub fn remote_invite(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<Option<(u64, Vec<serde_json::Value>)>> {
+        let mut userroom_id = user_id.to_string().as_bytes().to_vec();
+        userroom_id.push(0xff);
+        userroom_id.extend_from_slice(room_id.to_string().as_bytes());
+
+        self.userroomid_invitestate
+            .get(userroom_id)?
+            .map(|value| {
+                if value.len() < mem::size_of::<u64>() {
+                    return Err(Error::bad_database("Invalid invite state in db."));
+                }
+                let (count, invite_state) = value.split_at(mem::size_of::<u64>());
+
+                Ok((
+                    utils::u64_from_bytes(count)
+                        .map_err(|_| Error::bad_database("Invalid invite count in db."))?,
+                    serde_json::from_slice(invite_state)
+                        .map_err(|_| Error::bad_database("Invalid invite state in db."))?,
+                ))
+            })
+            .transpose()
+    }
+
+    /// Removes an invite of another server, for example after the user rejected it.
+    pub fn remove_remote_invite(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+        let mut userroom_id = user_id.to_string().as_bytes().to_vec();
+        userroom_id.push(0xff);
+        userroom_id.extend_from_slice(room_id.to_string().as_bytes());
+
+        let mut roomuser_id = room_id.to_string().as_bytes().to_vec();
+        roomuser_id.push(0xff);
+        roomuser_id.extend_from_slice(user_id.to_string().as_bytes());
+
+        self.userroomid_invitestate.remove(&userroom_id)?;
+        self.userroomid_invited.remove(&userroom_id)?;
+        self.roomuserid_invited.remove(&roomuser_id)?;
+
+        Ok(())
+    }
+
+    /// Checks if the member event was already sent to the device because of lazy loading. Newer
+    /// member events of the same user count as not sent.
+    pub fn lazy_load_was_sent(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        member_event: &PduEvent,
+    ) -> Result<bool> {
+        let key = lazy_load_id(user_id, device_id, member_event)?;
+
+        Ok(self.lazy_load_sent.get(key)?.as_deref() == Some(member_event.event_id.as_bytes()))
+    }
+
+    pub fn lazy_load_mark_sent(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        member_event: &PduEvent,
+    ) -> Result<()> {
+        let key = lazy_load_id(user_id, device_id, member_event)?;
+        self.lazy_load_sent
+            .insert(key, member_event.event_id.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Forgets which member events were sent to the device in the room or, without a room, in
+    /// all rooms. For example because the device does an initial sync.
+    pub fn lazy_load_reset(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        room_id: Option<&RoomId>,
+    ) -> Result<()> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(device_id.as_bytes());
+        prefix.push(0xff);
+        if let Some(room_id) = room_id {
+            prefix.extend_from_slice(room_id.to_string().as_bytes());
+            prefix.push(0xff);
+        }
+
+        for key in self.lazy_load_sent.scan_prefix(prefix).keys() {
+            self.lazy_load_sent.remove(key?)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The key of the current state of the room for the event type and state key. Newer state
+/// events overwrite the entry of older ones.
+fn room_state_id(room_id: &RoomId, event_type: &EventType, state_key: &str) -> Vec<u8> {
+    let mut key = room_id.to_string().as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(event_type.to_string().as_bytes());
+    key.push(0xff);
+    key.extend_from_slice(state_key.as_bytes());
+    key
+}
+
+fn lazy_load_id(
+    user_id: &UserId,
+    device_id: &DeviceId,
+    member_event: &PduEvent,
+) -> Result<Vec<u8>> {
+    let member = member_event
+        .state_key
+        .as_ref()
+        .ok_or_else(|| Error::bad_database("Member event has no state key."))?;
+
+    let mut key = user_id.to_string().as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(device_id.as_bytes());
+    key.push(0xff);
+    key.extend_from_slice(member_event.room_id.to_string().as_bytes());
+    key.push(0xff);
+    key.extend_from_slice(member.as_bytes());
+
+    Ok(key)
+}
+
+/// Splits the text into lowercase words.
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Returns the words of the body, name and topic of an event.
+fn searchable_tokens(pdu: &PduEvent) -> Vec<String> {
+    let mut tokens = ["body", "name", "topic"]
+        .iter()
+        .filter_map(|key| pdu.content.get(key)?.as_str())
+        .flat_map(tokenize)
+        .collect::<Vec<_>>();
+    tokens.sort();
+    tokens.dedup();
+    tokens
+}
+
+/// Returns the key of the pdu in the relations index if it relates to another event.
+fn relation_key(pdu_id: &[u8], pdu: &PduEvent) -> Option<Vec<u8>> {
+    let parent_id = pdu.content.get("m.relates_to")?.get("event_id")?.as_str()?;
+
+    let mut key = pdu.room_id.to_string().as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(parent_id.as_bytes());
+    key.push(0xff);
+    // The pdu id ends with the count
+    key.extend_from_slice(&pdu_id[pdu_id.len() - mem::size_of::<u64>()..]);
+
+    Some(key)
+}
+
+/// Checks if the content of an `m.room.server_acl` event bans the server.
+fn acl_denies(acl: &serde_json::Value, server_name: &ServerName) -> bool {
+    // The port is not part of the server name for ACLs
+    let host = match server_name.as_str().rfind(':') {
+        Some(colon) if !server_name.as_str().ends_with(']') => &server_name.as_str()[..colon],
+        _ => server_name.as_str(),
+    };
+
+    let is_ip_literal = host.starts_with('[') || host.parse::<std::net::Ipv4Addr>().is_ok();
+    if is_ip_literal && acl.get("allow_ip_literals").and_then(|a| a.as_bool()) == Some(false) {
+        return true;
+    }
+
+    let matches_any = |field| {
+        acl.get(field)
+            .and_then(|globs| globs.as_array())
+            .map_or(false, |globs| {
+                globs
+                    .iter()
+                    .filter_map(|glob| glob.as_str())
+                    .any(|glob| utils::glob_matches(glob, host))
+            })
+    };
+
+    matches_any("deny") || !matches_any("allow")
+}
+
+/// The rooms of the allow conditions, if the join rules are `restricted` or `knock_restricted`.
+fn restricted_join_rooms(join_rules: &serde_json::Value) -> Option<Vec<RoomId>> {
+    if !matches!(
+        join_rules.get("join_rule").and_then(|j| j.as_str()),
+        Some("restricted") | Some("knock_restricted")
+    ) {
+        return None;
+    }
+
+    Some(
+        join_rules
+            .get("allow")
+            .and_then(|allow| allow.as_array())
+            .map_or_else(Vec::new, |allow| {
+                allow
+                    .iter()
+                    .filter(|condition| {
+                        condition.get("type").and_then(|t| t.as_str()) == Some("m.room_membership")
+                    })
+                    .filter_map(|condition| {
+                        RoomId::try_from(condition.get("room_id")?.as_str()?).ok()
+                    })
+                    .collect()
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{acl_denies, restricted_join_rooms, room_state_id, VisibilityHistory};
+    use ruma::{events::EventType, RoomId, ServerName};
+    use serde_json::json;
+    use std::convert::TryFrom;
+
+    fn denies(acl: &serde_json::Value, server_name: &str) -> bool {
+        acl_denies(acl, &Box::<ServerName>::try_from(server_name).unwrap())
+    }
+
+    #[test]
+    fn deny_globs_win_over_allow_globs() {
+        let acl = json!({ "allow": ["*"], "deny": ["*.evil.com", "evil.com"] });
+
+        assert!(!denies(&acl, "good.org"));
+        assert!(denies(&acl, "evil.com"));
+        assert!(denies(&acl, "matrix.evil.com"));
+        assert!(denies(&acl, "matrix.EVIL.com"));
+    }
+
+    #[test]
+    fn servers_must_match_an_allow_glob() {
+        assert!(denies(&json!({}), "good.org"));
+        assert!(denies(&json!({ "allow": ["good.?rg"] }), "good.com"));
+        assert!(!denies(&json!({ "allow": ["good.?rg"] }), "good.org"));
+    }
+
+    #[test]
+    fn ports_are_ignored() {
+        let acl = json!({ "allow": ["good.org"] });
+
+        assert!(!denies(&acl, "good.org:8448"));
+
+        let acl = json!({ "allow": ["*"], "deny": ["evil.com"] });
+        assert!(denies(&acl, "evil.com:443"));
+    }
+
+    #[test]
+    fn ip_literals_are_allowed_unless_disabled() {
+        let allowed = json!({ "allow": ["*"] });
+        let denied = json!({ "allow": ["*"], "allow_ip_literals": false });
+
+        assert!(!denies(&allowed, "1.2.3.4"));
+        assert!(!denies(&allowed, "[::1]:8448"));
+        assert!(denies(&denied, "1.2.3.4"));
+        assert!(denies(&denied, "1.2.3.4:8448"));
+        assert!(denies(&denied, "[::1]"));
+        assert!(denies(&denied, "[::1]:8448"));
+        assert!(!denies(&denied, "good.org"));
+    }
+
+    /// The history of a viewer who is invited at 5 and joins at 10, followed by `memberships`.
+    fn viewer_history(visibility: Option<&str>, memberships: &[(u64, &str)]) -> VisibilityHistory {
+        let mut history = VisibilityHistory {
+            visibilities: visibility
+                .map(|visibility| (1, visibility.to_owned()))
+                .into_iter()
+                .collect(),
+            memberships: Vec::new(),
+        };
+        for (count, membership) in [(5, "invite"), (10, "join")].iter().chain(memberships) {
+            history.memberships.push((
+                *count,
+                "@alice:example.com".to_owned(),
+                (*membership).to_owned(),
+            ));
+        }
+        history
+    }
+
+    /// Checks the events before the invite, between invite and join and after the join.
+    fn visible(history: &VisibilityHistory, currently_joined: bool) -> [bool; 3] {
+        [
+            history.allows(3, currently_joined),
+            history.allows(7, currently_joined),
+            history.allows(12, currently_joined),
+        ]
+    }
+
+    #[test]
+    fn world_readable_events_are_visible_to_everyone() {
+        let history = viewer_history(Some("world_readable"), &[(15, "leave")]);
+        assert_eq!(visible(&history, false), [true, true, true]);
+
+        let mut history = history;
+        history.memberships.clear();
+        assert_eq!(visible(&history, false), [true, true, true]);
+    }
+
+    #[test]
+    fn shared_events_are_visible_to_current_members() {
+        let history = viewer_history(Some("shared"), &[]);
+        assert_eq!(visible(&history, true), [true, true, true]);
+
+        // After leaving only the events while joined stay visible
+        let history = viewer_history(Some("shared"), &[(15, "leave")]);
+        assert_eq!(visible(&history, false), [false, false, true]);
+        assert!(!history.allows(17, false));
+
+        // Rooms without history visibility are shared
+        let history = viewer_history(None, &[]);
+        assert_eq!(visible(&history, true), [true, true, true]);
+    }
+
+    #[test]
+    fn invited_events_are_visible_since_the_invite() {
+        let history = viewer_history(Some("invited"), &[]);
+        assert_eq!(visible(&history, true), [false, true, true]);
+    }
+
+    #[test]
+    fn joined_events_are_visible_since_the_join() {
+        let history = viewer_history(Some("joined"), &[]);
+        assert_eq!(visible(&history, true), [false, false, true]);
+
+        let history = viewer_history(Some("joined"), &[(15, "leave")]);
+        assert!(history.allows(14, false));
+        assert!(!history.allows(15, false));
+    }
+
+    #[test]
+    fn visibility_changes_apply_to_later_events() {
+        let mut history = viewer_history(Some("joined"), &[]);
+        history.visibilities.push((6, "world_readable".to_owned()));
+        assert_eq!(visible(&history, false), [false, true, true]);
+    }
+
+    #[test]
+    fn knock_restricted_rooms_can_be_joined_from_the_allowed_rooms() {
+        let allowed = RoomId::try_from("!allowed:example.com").unwrap();
+        for join_rule in &["restricted", "knock_restricted"] {
+            let join_rules = json!({
+                "join_rule": join_rule,
+                "allow": [
+                    { "type": "m.room_membership", "room_id": "!allowed:example.com" },
+                    { "type": "m.unknown", "room_id": "!other:example.com" },
+                    { "type": "m.room_membership", "room_id": "invalid" },
+                ],
+            });
+            assert_eq!(
+                restricted_join_rooms(&join_rules),
+                Some(vec![allowed.clone()])
+            );
+        }
+
+        let join_rules = json!({ "join_rule": "knock_restricted" });
+        assert_eq!(restricted_join_rooms(&join_rules), Some(Vec::new()));
+
+        for join_rule in &["public", "invite", "knock"] {
+            let join_rules = json!({ "join_rule": join_rule, "allow": [] });
+            assert_eq!(restricted_join_rooms(&join_rules), None);
+        }
+    }
+
+    #[test]
+    fn room_state_ids_keep_the_newest_state_of_each_key() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let roomstateid_pdu = db.open_tree("roomstateid_pdu").unwrap();
+        let room_id = RoomId::try_from("!room:example.com").unwrap();
+        // A room of another server whose id starts with the same characters
+        let other_room_id = RoomId::try_from("!room:example.com.au").unwrap();
+
+        let member = EventType::RoomMember;
+        for (room_id, event_type, state_key, event) in &[
+            (&room_id, &member, "@alice:example.com", "join"),
+            (&room_id, &EventType::RoomName, "", "first name"),
+            (&other_room_id, &EventType::RoomName, "", "other room"),
+            (&room_id, &member, "@alice:example.com", "leave"),
+            (&room_id, &member, "@bob:example.com", "join"),
+            (&room_id, &EventType::RoomName, "", "second name"),
+        ] {
+            roomstateid_pdu
+                .insert(room_state_id(room_id, event_type, state_key), *event)
+                .unwrap();
+        }
+
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+        let mut state = roomstateid_pdu
+            .scan_prefix(prefix)
+            .values()
+            .map(|event| String::from_utf8(event.unwrap().to_vec()).unwrap())
+            .collect::<Vec<_>>();
+        state.sort();
+        assert_eq!(state, ["join", "leave", "second name"]);
+
+        let key = room_state_id(&room_id, &member, "@alice:example.com");
+        assert_eq!(
+            roomstateid_pdu.get(key).unwrap().unwrap(),
+            b"leave".as_ref()
+        );
+    }
 }
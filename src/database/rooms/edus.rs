@@ -11,16 +11,19 @@ use ruma::{
 use std::{
     collections::HashMap,
     convert::{TryFrom, TryInto},
+    sync::RwLock,
 };
 
 pub struct RoomEdus {
     pub(in super::super) readreceiptid_readreceipt: sled::Tree, // ReadReceiptId = RoomId + Count + UserId
     pub(in super::super) roomuserid_privateread: sled::Tree, // RoomUserId = Room + User, PrivateRead = Count
     pub(in super::super) roomuserid_lastprivatereadupdate: sled::Tree, // LastPrivateReadUpdate = Count
-    pub(in super::super) typingid_userid: sled::Tree, // TypingId = RoomId + TimeoutTime + Count
+    pub(in super::super) roomusercount_notification: sled::Tree, // RoomUserCount = RoomUserId + Count of an unread event that notifies the user, Notification = Highlight
+    pub(in super::super) roomid_typing: RwLock<HashMap<RoomId, HashMap<UserId, u64>>>, // Typing users with their timeout (millis since unix epoch), not persisted
     pub(in super::super) roomid_lasttypingupdate: sled::Tree, // LastRoomTypingUpdate = Count
     pub(in super::super) presenceid_presence: sled::Tree, // PresenceId = RoomId + Count + UserId
     pub(in super::super) userid_lastpresenceupdate: sled::Tree, // LastPresenceUpdate = Count
+    pub(in super::super) userid_presence: sled::Tree, // Presence = Latest PresenceEvent of the user
 }
 
 impl RoomEdus {
@@ -91,7 +94,8 @@ impl RoomEdus {
             }))
     }
 
-    /// Sets a private read marker at `count`.
+    /// Sets a private read marker at `count`. The notifications of the events up to `count` are
+    /// read now.
     pub fn private_read_set(
         &self,
         room_id: &RoomId,
@@ -106,6 +110,18 @@ impl RoomEdus {
         self.roomuserid_privateread
             .insert(&key, &count.to_be_bytes())?;
 
+        let mut notification_prefix = key.clone();
+        notification_prefix.push(0xff);
+        let mut last_read = notification_prefix.clone();
+        last_read.extend_from_slice(&count.to_be_bytes());
+        for notification in self
+            .roomusercount_notification
+            .range(notification_prefix..=last_read)
+            .keys()
+        {
+            self.roomusercount_notification.remove(notification?)?;
+        }
+
         self.roomuserid_lastprivatereadupdate
             .insert(&key, &globals.next_count()?.to_be_bytes())?;
 
@@ -125,6 +141,47 @@ impl RoomEdus {
         })
     }
 
+    /// Remembers that the event with the `count` notifies the user, until the read marker of the
+    /// user passes it.
+    pub fn notification_add(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        count: u64,
+        highlight: bool,
+    ) -> Result<()> {
+        let mut key = room_id.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&user_id.to_string().as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(&count.to_be_bytes());
+
+        self.roomusercount_notification
+            .insert(key, &[highlight as u8])?;
+
+        Ok(())
+    }
+
+    /// Returns how many events after the read marker of the user notify them and how many of
+    /// them are highlights.
+    pub fn notification_counts(&self, room_id: &RoomId, user_id: &UserId) -> Result<(u32, u32)> {
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(&user_id.to_string().as_bytes());
+        prefix.push(0xff);
+
+        let mut notification_count = 0;
+        let mut highlight_count = 0;
+        for highlight in self.roomusercount_notification.scan_prefix(prefix).values() {
+            notification_count += 1;
+            if highlight?.first() == Some(&1) {
+                highlight_count += 1;
+            }
+        }
+
+        Ok((notification_count, highlight_count))
+    }
+
     /// Returns the count of the last typing update in this room.
     pub fn last_privateread_update(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
         let mut key = room_id.to_string().as_bytes().to_vec();
@@ -142,7 +199,7 @@ impl RoomEdus {
             .unwrap_or(0))
     }
 
-    /// Sets a user as typing until the timeout timestamp is reached or roomtyping_remove is
+    /// Sets a user as typing until the timeout timestamp is reached or typing_remove is
     /// called.
     pub fn typing_add(
         &self,
@@ -151,21 +208,17 @@ impl RoomEdus {
         timeout: u64,
         globals: &super::super::globals::Globals<'_>,
     ) -> Result<()> {
-        let mut prefix = room_id.to_string().as_bytes().to_vec();
-        prefix.push(0xff);
-
-        let count = globals.next_count()?.to_be_bytes();
-
-        let mut room_typing_id = prefix;
-        room_typing_id.extend_from_slice(&timeout.to_be_bytes());
-        room_typing_id.push(0xff);
-        room_typing_id.extend_from_slice(&count);
-
-        self.typingid_userid
-            .insert(&room_typing_id, &*user_id.to_string().as_bytes())?;
-
-        self.roomid_lasttypingupdate
-            .insert(&room_id.to_string().as_bytes(), &count)?;
+        self.roomid_typing
+            .write()
+            .unwrap()
+            .entry(room_id.clone())
+            .or_default()
+            .insert(user_id.clone(), timeout);
+
+        self.roomid_lasttypingupdate.insert(
+            &room_id.to_string().as_bytes(),
+            &globals.next_count()?.to_be_bytes(),
+        )?;
 
         Ok(())
     }
@@ -177,25 +230,14 @@ impl RoomEdus {
         room_id: &RoomId,
         globals: &super::super::globals::Globals<'_>,
     ) -> Result<()> {
-        let mut prefix = room_id.to_string().as_bytes().to_vec();
-        prefix.push(0xff);
-
-        let user_id = user_id.to_string();
-
-        let mut found_outdated = false;
-
-        // Maybe there are multiple ones from calling roomtyping_add multiple times
-        for outdated_edu in self
-            .typingid_userid
-            .scan_prefix(&prefix)
-            .filter_map(|r| r.ok())
-            .filter(|(_, v)| v == user_id.as_bytes())
-        {
-            self.typingid_userid.remove(outdated_edu.0)?;
-            found_outdated = true;
-        }
-
-        if found_outdated {
+        let removed = self
+            .roomid_typing
+            .write()
+            .unwrap()
+            .get_mut(room_id)
+            .map_or(false, |typing| typing.remove(user_id).is_some());
+
+        if removed {
             self.roomid_lasttypingupdate.insert(
                 &room_id.to_string().as_bytes(),
                 &globals.next_count()?.to_be_bytes(),
@@ -211,34 +253,14 @@ impl RoomEdus {
         room_id: &RoomId,
         globals: &super::super::globals::Globals<'_>,
     ) -> Result<()> {
-        let mut prefix = room_id.to_string().as_bytes().to_vec();
-        prefix.push(0xff);
-
         let current_timestamp = utils::millis_since_unix_epoch();
 
         let mut found_outdated = false;
 
-        // Find all outdated edus before inserting a new one
-        for outdated_edu in self
-            .typingid_userid
-            .scan_prefix(&prefix)
-            .keys()
-            .map(|key| {
-                let key = key?;
-                Ok::<_, Error>((
-                    key.clone(),
-                    utils::u64_from_bytes(key.split(|&b| b == 0xff).nth(1).ok_or_else(|| {
-                        Error::bad_database("RoomTyping has invalid timestamp or delimiters.")
-                    })?)
-                    .map_err(|_| Error::bad_database("RoomTyping has invalid timestamp bytes."))?,
-                ))
-            })
-            .filter_map(|r| r.ok())
-            .take_while(|&(_, timestamp)| timestamp < current_timestamp)
-        {
-            // This is an outdated edu (time > timestamp)
-            self.typingid_userid.remove(outdated_edu.0)?;
-            found_outdated = true;
+        if let Some(typing) = self.roomid_typing.write().unwrap().get_mut(room_id) {
+            let typing_count = typing.len();
+            typing.retain(|_, &mut timeout| timeout >= current_timestamp);
+            found_outdated = typing.len() != typing_count;
         }
 
         if found_outdated {
@@ -270,30 +292,18 @@ impl RoomEdus {
             .unwrap_or(0))
     }
 
+    /// Returns all users that are typing in this room.
     pub fn typings_all(
         &self,
         room_id: &RoomId,
     ) -> Result<SyncEphemeralRoomEvent<ruma::events::typing::TypingEventContent>> {
-        let mut prefix = room_id.to_string().as_bytes().to_vec();
-        prefix.push(0xff);
-
-        let mut user_ids = Vec::new();
-
-        for user_id in self
-            .typingid_userid
-            .scan_prefix(prefix)
-            .values()
-            .map(|user_id| {
-                Ok::<_, Error>(
-                    UserId::try_from(utils::string_from_bytes(&user_id?).map_err(|_| {
-                        Error::bad_database("User ID in typingid_userid is invalid unicode.")
-                    })?)
-                    .map_err(|_| Error::bad_database("User ID in typingid_userid is invalid."))?,
-                )
-            })
-        {
-            user_ids.push(user_id?);
-        }
+        let user_ids = self
+            .roomid_typing
+            .read()
+            .unwrap()
+            .get(room_id)
+            .map(|typing| typing.keys().cloned().collect())
+            .unwrap_or_default();
 
         Ok(SyncEphemeralRoomEvent {
             content: ruma::events::typing::TypingEventContent { user_ids },
@@ -321,10 +331,13 @@ impl RoomEdus {
         presence_id.push(0xff);
         presence_id.extend_from_slice(&presence.sender.to_string().as_bytes());
 
-        self.presenceid_presence.insert(
-            presence_id,
-            &*serde_json::to_string(&presence).expect("PresenceEvent can be serialized"),
-        )?;
+        let presence_json =
+            serde_json::to_string(&presence).expect("PresenceEvent can be serialized");
+
+        self.presenceid_presence
+            .insert(presence_id, presence_json.as_bytes())?;
+        self.userid_presence
+            .insert(&user_id.to_string().as_bytes(), presence_json.as_bytes())?;
 
         self.userid_lastpresenceupdate.insert(
             &user_id.to_string().as_bytes(),
@@ -356,13 +369,36 @@ impl RoomEdus {
             .transpose()
     }
 
-    /// Sets all users to offline who have been quiet for too long.
+    /// Returns the latest presence event of this user. `last_active_ago` is the timestamp of the
+    /// update in millis since the unix epoch.
+    pub fn current_presence(&self, user_id: &UserId) -> Result<Option<PresenceEvent>> {
+        self.userid_presence
+            .get(&user_id.to_string().as_bytes())?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid presence event in userid_presence."))
+            })
+            .transpose()
+    }
+
+    /// Returns true if the user was set to unavailable or offline because they were quiet for too
+    /// long. The user should be set online again when they come back.
+    pub fn is_idle(&self, user_id: &UserId) -> Result<bool> {
+        Ok(self
+            .current_presence(user_id)?
+            .map_or(false, |p| p.content.currently_active == Some(false)))
+    }
+
+    /// Sets all users to unavailable who have been quiet for longer than the idle timeout and to
+    /// offline who have been quiet for longer than the offline timeout.
     pub fn presence_maintain(
         &self,
         rooms: &super::Rooms,
         globals: &super::super::globals::Globals<'_>,
     ) -> Result<()> {
         let current_timestamp = utils::millis_since_unix_epoch();
+        let idle_timeout = globals.presence_idle_timeout().as_millis() as u64;
+        let offline_timeout = globals.presence_offline_timeout().as_millis() as u64;
 
         for (user_id_bytes, last_timestamp) in self
             .userid_lastpresenceupdate
@@ -378,19 +414,47 @@ impl RoomEdus {
                         .ok()?,
                 ))
             })
-            .take_while(|(_, timestamp)| current_timestamp - timestamp > 5 * 60_000)
-        // 5 Minutes
+            .filter(|(_, timestamp)| current_timestamp.saturating_sub(*timestamp) > idle_timeout)
         {
-            self.userid_lastpresenceupdate.remove(&user_id_bytes)?;
-
-            // Send new presence events to set the user offline
-            let count = globals.next_count()?.to_be_bytes();
-            let user_id = utils::string_from_bytes(&user_id_bytes)
+            let user_id: UserId = utils::string_from_bytes(&user_id_bytes)
                 .map_err(|_| {
                     Error::bad_database("Invalid UserId bytes in userid_lastpresenceupdate.")
                 })?
                 .try_into()
                 .map_err(|_| Error::bad_database("Invalid UserId in userid_lastpresenceupdate."))?;
+
+            let current_presence = self.current_presence(&user_id)?;
+
+            let new_state = if current_timestamp - last_timestamp > offline_timeout {
+                // Offline users don't need to be checked again until they are active again
+                self.userid_lastpresenceupdate.remove(&user_id_bytes)?;
+                PresenceState::Offline
+            } else if current_presence
+                .as_ref()
+                .map_or(true, |p| p.content.presence == PresenceState::Online)
+            {
+                PresenceState::Unavailable
+            } else {
+                continue;
+            };
+
+            // Send new presence events to set the user unavailable or offline
+            let presence = PresenceEvent {
+                content: PresenceEventContent {
+                    avatar_url: None,
+                    // This marks that the user did not choose this state, see `is_idle`
+                    currently_active: Some(false),
+                    displayname: None,
+                    last_active_ago: Some(last_timestamp.try_into().expect("time is valid")),
+                    presence: new_state,
+                    status_msg: current_presence.and_then(|p| p.content.status_msg),
+                },
+                sender: user_id.clone(),
+            };
+            let presence_json =
+                serde_json::to_string(&presence).expect("PresenceEvent can be serialized");
+
+            let count = globals.next_count()?.to_be_bytes();
             for room_id in rooms.rooms_joined(&user_id).filter_map(|r| r.ok()) {
                 let mut presence_id = room_id.to_string().as_bytes().to_vec();
                 presence_id.push(0xff);
@@ -398,24 +462,11 @@ impl RoomEdus {
                 presence_id.push(0xff);
                 presence_id.extend_from_slice(&user_id_bytes);
 
-                self.presenceid_presence.insert(
-                    presence_id,
-                    &*serde_json::to_string(&PresenceEvent {
-                        content: PresenceEventContent {
-                            avatar_url: None,
-                            currently_active: None,
-                            displayname: None,
-                            last_active_ago: Some(
-                                last_timestamp.try_into().expect("time is valid"),
-                            ),
-                            presence: PresenceState::Offline,
-                            status_msg: None,
-                        },
-                        sender: user_id.clone(),
-                    })
-                    .expect("PresenceEvent can be serialized"),
-                )?;
+                self.presenceid_presence
+                    .insert(presence_id, presence_json.as_bytes())?;
             }
+            self.userid_presence
+                .insert(&user_id_bytes, presence_json.as_bytes())?;
         }
 
         Ok(())
@@ -0,0 +1,274 @@
+//! Sends the queued events of the `servicepdus` tree to the appservices. Every appservice has
+//! its own task, so a slow appservice doesn't delay the others.
+
+use super::appservice::Registration;
+use crate::{utils, Error, PduEvent, Result};
+use serde_json::json;
+use std::{cmp, time::Duration};
+use tracing::warn;
+
+/// How many events are sent in one transaction at most
+const MAX_TRANSACTION_EVENTS: usize = 50;
+
+/// How long the first retry waits after a failed transaction. Every further failure doubles it.
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Starts the sending task of every appservice with a url.
+pub fn start_appservice_senders(
+    appservices: &[Registration],
+    servicepdus: sled::Tree,
+    pduid_pdu: sled::Tree,
+    client: reqwest::Client,
+) {
+    for appservice in appservices
+        .iter()
+        .filter(|appservice| appservice.url.is_some())
+    {
+        let appservice = appservice.clone();
+        let servicepdus = servicepdus.clone();
+        let pduid_pdu = pduid_pdu.clone();
+        let client = client.clone();
+
+        tokio::spawn(async move {
+            send_appservice_queue(&appservice, &servicepdus, &pduid_pdu, &client).await
+        });
+    }
+}
+
+/// Sends the queue of the appservice forever. A transaction is retried with the same id and the
+/// same events until the appservice accepts it, so events are sent in order and appservices can
+/// recognize repeated transactions.
+async fn send_appservice_queue(
+    appservice: &Registration,
+    servicepdus: &sled::Tree,
+    pduid_pdu: &sled::Tree,
+    client: &reqwest::Client,
+) {
+    let mut prefix = appservice.id.as_bytes().to_vec();
+    prefix.push(0xff);
+
+    loop {
+        // Subscribe before looking at the queue, so no new event is missed
+        let subscriber = servicepdus.watch_prefix(&prefix);
+
+        let batch = match queued_batch(servicepdus, &prefix) {
+            Ok(batch) => batch,
+            Err(e) => {
+                warn!(
+                    "Failed to read the queue of appservice {}: {}",
+                    appservice.id, e
+                );
+                tokio::time::delay_for(MAX_RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        let (last_key, _) = match batch.last() {
+            Some(last) => last,
+            None => {
+                subscriber.await;
+                continue;
+            }
+        };
+        let transaction_id = utils::u64_from_bytes(&last_key[prefix.len()..])
+            .expect("keys of servicepdus end with a count")
+            .to_string();
+
+        let events = batch
+            .iter()
+            .filter_map(|(_, pdu_id)| pduid_pdu.get(pdu_id).ok().flatten())
+            .filter_map(|pdu| serde_json::from_slice::<PduEvent>(&pdu).ok())
+            .map(|pdu| pdu.to_room_event())
+            .collect::<Vec<_>>();
+        let body = json!({ "events": events });
+
+        let mut delay = MIN_RETRY_DELAY;
+        while let Err(e) = send_transaction(appservice, &transaction_id, &body, client).await {
+            warn!(
+                "Failed to send transaction {} to appservice {}, retrying in {:?}: {}",
+                transaction_id, appservice.id, delay, e
+            );
+            tokio::time::delay_for(delay).await;
+            delay = cmp::min(delay * 2, MAX_RETRY_DELAY);
+        }
+
+        for (key, _) in batch {
+            if let Err(e) = servicepdus.remove(key) {
+                warn!("Failed to remove sent event from the queue: {}", e);
+            }
+        }
+    }
+}
+
+/// Returns the oldest queued events of the appservice, by their keys in `servicepdus`.
+fn queued_batch(servicepdus: &sled::Tree, prefix: &[u8]) -> Result<Vec<(sled::IVec, sled::IVec)>> {
+    servicepdus
+        .scan_prefix(prefix)
+        .take(MAX_TRANSACTION_EVENTS)
+        .map(|r| r.map_err(Error::from))
+        .collect()
+}
+
+async fn send_transaction(
+    appservice: &Registration,
+    transaction_id: &str,
+    body: &serde_json::Value,
+    client: &reqwest::Client,
+) -> Result<()> {
+    let url = appservice
+        .url
+        .as_ref()
+        .expect("only appservices with url are started");
+
+    let response = client
+        .put(&format!(
+            "{}/_matrix/app/v1/transactions/{}",
+            url,
+            utils::percent_encode(transaction_id)
+        ))
+        .query(&[("access_token", &appservice.hs_token)])
+        .json(body)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(Error::BadServerResponse(
+            "Appservice did not accept the transaction.",
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::send_appservice_queue;
+    use crate::database::appservice::Registration;
+    use ruma::UserId;
+    use serde_json::json;
+    use std::{convert::TryFrom, net::SocketAddr, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    /// Reads one http request and returns its request line and body.
+    async fn read_request(stream: &mut TcpStream) -> (String, serde_json::Value) {
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+        let header_end = loop {
+            let read = stream.read(&mut buffer).await.unwrap();
+            assert!(
+                read > 0,
+                "connection closed before the request was complete"
+            );
+            request.extend_from_slice(&buffer[..read]);
+            if let Some(index) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                break index + 4;
+            }
+        };
+
+        let head = String::from_utf8(request[..header_end].to_vec()).unwrap();
+        let content_length = head
+            .lines()
+            .filter_map(|line| {
+                let colon = line.find(':')?;
+                Some((&line[..colon], &line[colon + 1..]))
+            })
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .map_or(0, |(_, length)| length.trim().parse().unwrap());
+        while request.len() < header_end + content_length {
+            let read = stream.read(&mut buffer).await.unwrap();
+            assert!(read > 0, "connection closed before the body was complete");
+            request.extend_from_slice(&buffer[..read]);
+        }
+
+        (
+            head.lines().next().unwrap().to_owned(),
+            serde_json::from_slice(&request[header_end..]).unwrap(),
+        )
+    }
+
+    #[test]
+    fn queued_events_are_sent_exactly_once() {
+        let mut runtime = tokio::runtime::Builder::new()
+            .threaded_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let mut listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+                .await
+                .unwrap();
+            let appservice = Registration {
+                id: "bridge".to_owned(),
+                url: Some(format!("http://{}", listener.local_addr().unwrap())),
+                as_token: "as_token".to_owned(),
+                hs_token: "hs_token".to_owned(),
+                sender: UserId::try_from("@bridge:example.com").unwrap(),
+                namespaces: serde_json::from_value(json!({
+                    "rooms": [{ "regex": "!bridged_.*:example.com" }],
+                }))
+                .unwrap(),
+                rate_limited: false,
+                protocols: Vec::new(),
+            };
+
+            let db = sled::Config::new().temporary(true).open().unwrap();
+            let servicepdus = db.open_tree("servicepdus").unwrap();
+            let pduid_pdu = db.open_tree("pduid_pdu").unwrap();
+            let pdu = json!({
+                "event_id": "$event:example.com",
+                "room_id": "!bridged_room:example.com",
+                "sender": "@alice:example.com",
+                "origin": "example.com",
+                "origin_server_ts": 0,
+                "type": "m.room.message",
+                "content": { "msgtype": "m.text", "body": "Hello" },
+                "prev_events": [],
+                "depth": 1,
+                "auth_events": [],
+                "hashes": { "sha256": "" },
+                "signatures": {},
+            });
+            pduid_pdu
+                .insert(b"pduid", serde_json::to_vec(&pdu).unwrap())
+                .unwrap();
+            // The key `Rooms::queue_for_appservices` uses
+            let mut key = b"bridge\xff".to_vec();
+            key.extend_from_slice(&7_u64.to_be_bytes());
+            servicepdus.insert(key, &b"pduid"[..]).unwrap();
+
+            let queue = servicepdus.clone();
+            tokio::spawn(async move {
+                send_appservice_queue(&appservice, &queue, &pduid_pdu, &reqwest::Client::new())
+                    .await
+            });
+
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (request_line, body) = read_request(&mut stream).await;
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}")
+                .await
+                .unwrap();
+
+            assert_eq!(
+                request_line,
+                "PUT /_matrix/app/v1/transactions/7?access_token=hs_token HTTP/1.1"
+            );
+            let events = body["events"].as_array().unwrap();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0]["event_id"], "$event:example.com");
+
+            // The sent event is removed from the queue and not sent again
+            assert!(
+                tokio::time::timeout(Duration::from_secs(1), listener.accept())
+                    .await
+                    .is_err()
+            );
+            assert!(servicepdus.is_empty());
+        });
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
+
+/// Lets requests know that the server is shutting down and counts the requests that still have
+/// to be answered.
+pub struct Shutdown {
+    started: AtomicBool,
+    requests_in_flight: AtomicUsize,
+    sender: broadcast::Sender<()>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            started: AtomicBool::new(false),
+            requests_in_flight: AtomicUsize::new(0),
+            sender: broadcast::channel(1).0,
+        }
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.started.load(Ordering::SeqCst)
+    }
+
+    /// New requests are rejected from now on and long-polling requests return.
+    pub fn start(&self) {
+        self.started.store(true, Ordering::SeqCst);
+        // This only fails if nobody is waiting
+        let _ = self.sender.send(());
+    }
+
+    /// Returns as soon as the shutdown started.
+    pub async fn wait(&self) {
+        // Subscribe before checking, so we can't miss the start
+        let mut receiver = self.sender.subscribe();
+        if self.is_started() {
+            return;
+        }
+
+        let _ = receiver.recv().await;
+    }
+
+    pub fn request_started(&self) {
+        self.requests_in_flight.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn request_finished(&self) {
+        self.requests_in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn requests_in_flight(&self) -> usize {
+        self.requests_in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Waits until all running requests are answered, but at most `timeout`. Returns false if
+    /// requests are still running.
+    pub async fn wait_for_requests(&self, timeout: Duration) -> bool {
+        let started = Instant::now();
+        while self.requests_in_flight() > 0 {
+            if started.elapsed() >= timeout {
+                return false;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Shutdown;
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn waiting_returns_when_the_shutdown_starts() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let shutdown = Arc::new(Shutdown::new());
+            let waiting = tokio::spawn({
+                let shutdown = Arc::clone(&shutdown);
+                async move { shutdown.wait().await }
+            });
+
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+            assert!(!shutdown.is_started());
+            shutdown.start();
+            tokio::time::timeout(Duration::from_secs(5), waiting)
+                .await
+                .unwrap()
+                .unwrap();
+
+            // Requests that only start waiting now return immediately
+            tokio::time::timeout(Duration::from_secs(5), shutdown.wait())
+                .await
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn running_requests_are_waited_for_until_the_timeout() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let shutdown = Arc::new(Shutdown::new());
+            assert!(shutdown.wait_for_requests(Duration::from_secs(0)).await);
+
+            shutdown.request_started();
+            shutdown.request_started();
+            shutdown.request_finished();
+            assert_eq!(shutdown.requests_in_flight(), 1);
+            assert!(!shutdown.wait_for_requests(Duration::from_millis(200)).await);
+
+            tokio::spawn({
+                let shutdown = Arc::clone(&shutdown);
+                async move {
+                    tokio::time::delay_for(Duration::from_millis(200)).await;
+                    shutdown.request_finished();
+                }
+            });
+            assert!(shutdown.wait_for_requests(Duration::from_secs(5)).await);
+        });
+    }
+}
@@ -0,0 +1,130 @@
+use crate::{utils, Error, Result};
+use ruma::api::client::error::ErrorKind;
+use serde::{Deserialize, Serialize};
+
+/// Sessions are only valid for one hour
+const SESSION_LIFETIME_MS: u64 = 60 * 60 * 1000;
+/// Every email address can only request one token per minute
+const REQUEST_TOKEN_INTERVAL_MS: u64 = 60 * 1000;
+
+#[derive(Serialize, Deserialize)]
+pub struct ThreepidSession {
+    pub email: String,
+    pub client_secret: String,
+    pub token: String,
+    pub validated: bool,
+    pub created_at: u64,
+}
+
+pub struct ThreepidSessions {
+    pub(super) sid_session: sled::Tree,
+    pub(super) email_lasttokenrequest: sled::Tree,
+}
+
+impl ThreepidSessions {
+    /// Creates a new unvalidated session for the email address and returns the new sid and the
+    /// token that has to be sent to the user.
+    pub fn create(
+        &self,
+        email: &str,
+        client_secret: &str,
+        sid_length: usize,
+        token_length: usize,
+    ) -> Result<(String, String)> {
+        let now = utils::millis_since_unix_epoch();
+
+        // Make sure we can't be used to spam someone's inbox. The domain and usually the local
+        // part of addresses are case-insensitive, so the case doesn't bypass the limit.
+        let email_key = email.to_lowercase();
+        if let Some(last_request) = self.email_lasttokenrequest.get(&email_key)? {
+            let last_request = utils::u64_from_bytes(&last_request)
+                .map_err(|_| Error::bad_database("Invalid last token request in db."))?;
+            if now < last_request + REQUEST_TOKEN_INTERVAL_MS {
+                return Err(Error::BadRequest(
+                    ErrorKind::LimitExceeded,
+                    "Too many token requests for this email address.",
+                ));
+            }
+        }
+        self.email_lasttokenrequest
+            .insert(&email_key, &now.to_be_bytes())?;
+
+        let sid = utils::random_string(sid_length);
+        let token = utils::random_string(token_length);
+
+        self.sid_session.insert(
+            &sid,
+            &*serde_json::to_string(&ThreepidSession {
+                email: email.to_owned(),
+                client_secret: client_secret.to_owned(),
+                token: token.clone(),
+                validated: false,
+                created_at: now,
+            })
+            .expect("ThreepidSession::to_string always works"),
+        )?;
+
+        Ok((sid, token))
+    }
+
+    /// Marks the session as validated if the token is correct. Returns false otherwise.
+    pub fn validate(&self, sid: &str, client_secret: &str, token: &str) -> Result<bool> {
+        let mut session = match self.get(sid, client_secret)? {
+            Some(session) => session,
+            None => return Ok(false),
+        };
+
+        if !secrets_equal(&session.token, token) {
+            return Ok(false);
+        }
+
+        session.validated = true;
+        self.sid_session.insert(
+            sid,
+            &*serde_json::to_string(&session).expect("ThreepidSession::to_string always works"),
+        )?;
+
+        Ok(true)
+    }
+
+    /// Returns the email address of the session if the user clicked on the link already.
+    pub fn validated_email(&self, sid: &str, client_secret: &str) -> Result<Option<String>> {
+        Ok(self
+            .get(sid, client_secret)?
+            .filter(|session| session.validated)
+            .map(|session| session.email))
+    }
+
+    /// Removes the session, so it can't be used again.
+    pub fn remove(&self, sid: &str) -> Result<()> {
+        self.sid_session.remove(sid)?;
+        Ok(())
+    }
+
+    /// Returns the session if it exists, the client secret matches and it did not expire yet.
+    fn get(&self, sid: &str, client_secret: &str) -> Result<Option<ThreepidSession>> {
+        let session = match self.sid_session.get(sid)? {
+            Some(bytes) => serde_json::from_slice::<ThreepidSession>(&bytes)
+                .map_err(|_| Error::bad_database("Invalid threepid session in db."))?,
+            None => return Ok(None),
+        };
+
+        if session.created_at + SESSION_LIFETIME_MS < utils::millis_since_unix_epoch() {
+            // Expired sessions are cleaned up lazily
+            self.sid_session.remove(sid)?;
+            return Ok(None);
+        }
+
+        if !secrets_equal(&session.client_secret, client_secret) {
+            return Ok(None);
+        }
+
+        Ok(Some(session))
+    }
+}
+
+/// Compares in constant time, so the time of the comparison doesn't tell how much of a guess was
+/// correct.
+fn secrets_equal(expected: &str, actual: &str) -> bool {
+    ring::constant_time::verify_slices_are_equal(expected.as_bytes(), actual.as_bytes()).is_ok()
+}
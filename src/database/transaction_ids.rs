@@ -1,9 +1,24 @@
-use crate::Result;
-use ruma::{DeviceId, UserId};
+use crate::{utils, Error, Result};
+use ruma::{api::client::error::ErrorKind, DeviceId, EventId, UserId};
 use sled::IVec;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::warn;
+
+/// How long transaction ids are remembered. Clients retry requests much sooner.
+const TXNID_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often the retention task looks for old transaction ids
+const RETENTION_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 pub struct TransactionIds {
     pub(super) userdevicetxnid_response: sled::Tree, // Response can be empty (/sendToDevice) or the event id (/send)
+    pub(super) addedat_userdevicetxnid: sled::Tree, // AddedAt = Millis since unix epoch (u64) + UserDeviceTxnId
+    pub(super) txnid_locks: Mutex<HashMap<Vec<u8>, Arc<Mutex<()>>>>, // Transactions that are running
 }
 
 impl TransactionIds {
@@ -14,13 +29,13 @@ impl TransactionIds {
         txn_id: &str,
         data: &[u8],
     ) -> Result<()> {
-        let mut key = user_id.as_bytes().to_vec();
-        key.push(0xff);
-        key.extend_from_slice(device_id.as_bytes());
-        key.push(0xff);
-        key.extend_from_slice(txn_id.as_bytes());
+        let key = Self::key(user_id, device_id, txn_id);
+
+        let mut added_at = utils::millis_since_unix_epoch().to_be_bytes().to_vec();
+        added_at.extend_from_slice(&key);
 
         self.userdevicetxnid_response.insert(key, data)?;
+        self.addedat_userdevicetxnid.insert(added_at, &[])?;
 
         Ok(())
     }
@@ -31,13 +46,229 @@ impl TransactionIds {
         device_id: &DeviceId,
         txn_id: &str,
     ) -> Result<Option<IVec>> {
+        // If there's no entry, this is a new transaction
+        Ok(self
+            .userdevicetxnid_response
+            .get(Self::key(user_id, device_id, txn_id))?)
+    }
+
+    /// Returns the id of the event that was sent with this transaction id before.
+    pub fn existing_event_id(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        txn_id: &str,
+    ) -> Result<Option<EventId>> {
+        let response = match self.existing_txnid(user_id, device_id, txn_id)? {
+            Some(response) => response,
+            None => return Ok(None),
+        };
+
+        // The client might have sent a txnid of the /sendToDevice endpoint
+        // This txnid has no response associated with it
+        if response.is_empty() {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Tried to use txn id already used for an incompatible endpoint.",
+            ));
+        }
+
+        EventId::try_from(
+            utils::string_from_bytes(&response)
+                .map_err(|_| Error::bad_database("Invalid txnid bytes in database."))?,
+        )
+        .map(Some)
+        .map_err(|_| Error::bad_database("Invalid event id in txnid data."))
+    }
+
+    /// Runs `f` while no other request with the same transaction id runs. A retry that arrives
+    /// before the first request is finished waits for it and then finds its response.
+    pub fn exclusive<T>(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        txn_id: &str,
+        f: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let key = Self::key(user_id, device_id, txn_id);
+        let lock = Arc::clone(
+            self.txnid_locks
+                .lock()
+                .unwrap()
+                .entry(key.clone())
+                .or_default(),
+        );
+
+        let result = {
+            let _guard = lock.lock().unwrap();
+            f()
+        };
+
+        // The last request of this transaction id removes the lock
+        let mut txnid_locks = self.txnid_locks.lock().unwrap();
+        if txnid_locks.get(&key).map_or(false, |l| {
+            Arc::ptr_eq(l, &lock) && Arc::strong_count(&lock) == 2
+        }) {
+            txnid_locks.remove(&key);
+        }
+
+        result
+    }
+
+    /// Removes all transaction ids that were added before `older_than` (millis since unix
+    /// epoch). Returns how many were removed.
+    pub fn purge_txnids(&self, older_than: u64) -> Result<usize> {
+        let mut purged = 0;
+
+        for r in self
+            .addedat_userdevicetxnid
+            .range(..older_than.to_be_bytes())
+            .keys()
+        {
+            let added_at = r?;
+            self.userdevicetxnid_response
+                .remove(&added_at[std::mem::size_of::<u64>()..])?;
+            self.addedat_userdevicetxnid.remove(added_at)?;
+
+            purged += 1;
+        }
+
+        Ok(purged)
+    }
+
+    /// Periodically removes transaction ids that are older than `TXNID_RETENTION`.
+    pub fn start_txnid_retention(&self) {
+        let transaction_ids = Self {
+            userdevicetxnid_response: self.userdevicetxnid_response.clone(),
+            addedat_userdevicetxnid: self.addedat_userdevicetxnid.clone(),
+            txnid_locks: Mutex::new(HashMap::new()),
+        };
+
+        tokio::spawn(async move {
+            loop {
+                let older_than = utils::millis_since_unix_epoch()
+                    .saturating_sub(TXNID_RETENTION.as_millis() as u64);
+
+                if let Err(e) = transaction_ids.purge_txnids(older_than) {
+                    warn!("Failed to remove old transaction ids: {}", e);
+                }
+
+                tokio::time::delay_for(RETENTION_SWEEP_INTERVAL).await;
+            }
+        });
+    }
+
+    fn key(user_id: &UserId, device_id: &DeviceId, txn_id: &str) -> Vec<u8> {
         let mut key = user_id.as_bytes().to_vec();
         key.push(0xff);
         key.extend_from_slice(device_id.as_bytes());
         key.push(0xff);
         key.extend_from_slice(txn_id.as_bytes());
+        key
+    }
+}
 
-        // If there's no entry, this is a new transaction
-        Ok(self.userdevicetxnid_response.get(key)?)
+#[cfg(test)]
+mod tests {
+    use super::TransactionIds;
+    use crate::utils;
+    use ruma::{DeviceId, EventId, UserId};
+    use std::{
+        collections::HashMap,
+        convert::TryFrom,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Barrier, Mutex,
+        },
+        thread,
+        time::Duration,
+    };
+
+    fn transaction_ids() -> TransactionIds {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        TransactionIds {
+            userdevicetxnid_response: db.open_tree("userdevicetxnid_response").unwrap(),
+            addedat_userdevicetxnid: db.open_tree("addedat_userdevicetxnid").unwrap(),
+            txnid_locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn concurrent_retries_send_one_event() {
+        let transaction_ids = Arc::new(transaction_ids());
+        let sent = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+
+        let retries = (0..8)
+            .map(|_| {
+                let transaction_ids = Arc::clone(&transaction_ids);
+                let sent = Arc::clone(&sent);
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    let user_id = UserId::try_from("@alice:example.com").unwrap();
+                    let device_id = Box::<DeviceId>::from("DEVICE");
+                    barrier.wait();
+
+                    // Like send_message_event: check the transaction id, send and remember it
+                    transaction_ids.exclusive(&user_id, &device_id, "txn1", || {
+                        if let Some(event_id) =
+                            transaction_ids.existing_event_id(&user_id, &device_id, "txn1")?
+                        {
+                            return Ok(event_id);
+                        }
+
+                        let count = sent.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(50));
+                        let event_id =
+                            EventId::try_from(format!("$event{}:example.com", count).as_str())
+                                .unwrap();
+                        transaction_ids.add_txnid(
+                            &user_id,
+                            &device_id,
+                            "txn1",
+                            event_id.as_bytes(),
+                        )?;
+                        Ok(event_id)
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for retry in retries {
+            assert_eq!(
+                retry.join().unwrap().unwrap().as_str(),
+                "$event0:example.com"
+            );
+        }
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+        assert!(transaction_ids.txnid_locks.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn old_transaction_ids_are_purged() {
+        let transaction_ids = transaction_ids();
+        let user_id = UserId::try_from("@alice:example.com").unwrap();
+        let device_id = Box::<DeviceId>::from("DEVICE");
+
+        transaction_ids
+            .add_txnid(&user_id, &device_id, "old", b"$old:example.com")
+            .unwrap();
+        thread::sleep(Duration::from_millis(5));
+        let older_than = utils::millis_since_unix_epoch();
+        thread::sleep(Duration::from_millis(5));
+        transaction_ids
+            .add_txnid(&user_id, &device_id, "new", b"$new:example.com")
+            .unwrap();
+
+        assert_eq!(transaction_ids.purge_txnids(older_than).unwrap(), 1);
+        assert!(transaction_ids
+            .existing_txnid(&user_id, &device_id, "old")
+            .unwrap()
+            .is_none());
+        assert!(transaction_ids
+            .existing_txnid(&user_id, &device_id, "new")
+            .unwrap()
+            .is_some());
+        assert_eq!(transaction_ids.addedat_userdevicetxnid.len(), 1);
     }
 }
@@ -95,10 +95,7 @@ impl Uiaa {
 
                     // Check if password is correct
                     if let Some(hash) = users.password_hash(&user_id)? {
-                        let hash_matches =
-                            argon2::verify_encoded(&hash, password.as_bytes()).unwrap_or(false);
-
-                        if !hash_matches {
+                        if !users.verify_password(&hash, password) {
                             uiaainfo.auth_error = Some(ruma::api::client::error::ErrorBody {
                                 kind: ErrorKind::Forbidden,
                                 message: "Invalid username or password.".to_owned(),
@@ -113,34 +110,87 @@ impl Uiaa {
                 "m.login.dummy" => {
                     uiaainfo.completed.push("m.login.dummy".to_owned());
                 }
-                k => panic!("type not supported: {}", k),
-            }
+                "m.login.registration_token" => {
+                    let token = auth_parameters
+                        .get("token")
+                        .ok_or(Error::BadRequest(
+                            ErrorKind::MissingParam,
+                            "Registration token is missing.",
+                        ))?
+                        .as_str()
+                        .ok_or(Error::BadRequest(
+                            ErrorKind::BadJson,
+                            "Registration token is not a string.",
+                        ))?;
 
-            // Check if a flow now succeeds
-            let mut completed = false;
-            'flows: for flow in &mut uiaainfo.flows {
-                for stage in &flow.stages {
-                    if !uiaainfo.completed.contains(stage) {
-                        continue 'flows;
+                    if !users.use_registration_token(token)? {
+                        uiaainfo.auth_error = Some(ruma::api::client::error::ErrorBody {
+                            kind: ErrorKind::Forbidden,
+                            message: "Invalid registration token.".to_owned(),
+                        });
+                        return Ok((false, uiaainfo));
                     }
-                }
-                // We didn't break, so this flow succeeded!
-                completed = true;
-            }
 
-            if !completed {
-                self.update_uiaa_session(user_id, device_id, Some(&uiaainfo))?;
-                return Ok((false, uiaainfo));
+                    uiaainfo
+                        .completed
+                        .push("m.login.registration_token".to_owned());
+                }
+                k => panic!("type not supported: {}", k),
             }
 
-            // UIAA was successful! Remove this session and return true
-            self.update_uiaa_session(user_id, device_id, None)?;
-            Ok((true, uiaainfo))
+            self.check_flows(user_id, device_id, uiaainfo)
         } else {
             panic!("FallbackAcknowledgement is not supported yet");
         }
     }
 
+    /// Marks a stage as completed that was already verified by the caller. This is used for
+    /// stages that require requests to other servers, like `m.login.recaptcha`.
+    pub fn complete_stage(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        session: Option<&str>,
+        stage: &str,
+        uiaainfo: &UiaaInfo,
+    ) -> Result<(bool, UiaaInfo)> {
+        let mut uiaainfo = session
+            .map(|session| self.get_uiaa_session(&user_id, &device_id, session))
+            .unwrap_or_else(|| Ok(uiaainfo.clone()))?;
+
+        uiaainfo.completed.push(stage.to_owned());
+
+        self.check_flows(user_id, device_id, uiaainfo)
+    }
+
+    fn check_flows(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        mut uiaainfo: UiaaInfo,
+    ) -> Result<(bool, UiaaInfo)> {
+        // Check if a flow now succeeds
+        let mut completed = false;
+        'flows: for flow in &mut uiaainfo.flows {
+            for stage in &flow.stages {
+                if !uiaainfo.completed.contains(stage) {
+                    continue 'flows;
+                }
+            }
+            // We didn't break, so this flow succeeded!
+            completed = true;
+        }
+
+        if !completed {
+            self.update_uiaa_session(user_id, device_id, Some(&uiaainfo))?;
+            return Ok((false, uiaainfo));
+        }
+
+        // UIAA was successful! Remove this session and return true
+        self.update_uiaa_session(user_id, device_id, None)?;
+        Ok((true, uiaainfo))
+    }
+
     fn update_uiaa_session(
         &self,
         user_id: &UserId,
@@ -12,7 +12,21 @@ use ruma::{
     events::{AnyToDeviceEvent, EventType},
     DeviceId, DeviceKeyAlgorithm, DeviceKeyId, Raw, UserId,
 };
-use std::{collections::BTreeMap, convert::TryFrom, mem, time::SystemTime};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    mem,
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
+
+/// How precise the `last_seen_ts` of devices is
+const LAST_SEEN_RESOLUTION: Duration = Duration::from_secs(60);
+
+const FILTER_ID_LENGTH: usize = 10;
+
+/// How long used refresh tokens are remembered, so a second use can be detected
+const REFRESH_TOKEN_REUSE_DETECTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
 
 pub struct Users {
     pub(super) userid_password: sled::Tree,
@@ -24,13 +38,28 @@ pub struct Users {
 
     pub(super) onetimekeyid_onetimekeys: sled::Tree, // OneTimeKeyId = UserId + DeviceKeyId
     pub(super) userid_lastonetimekeyupdate: sled::Tree, // LastOneTimeKeyUpdate = Count
-    pub(super) keychangeid_userid: sled::Tree,       // KeyChangeId = UserId/RoomId + Count
-    pub(super) keyid_key: sled::Tree,                // KeyId = UserId + KeyId (depends on key type)
+    pub(super) fallbackkeyid_fallbackkey: sled::Tree, // FallbackKeyId = UserId + DeviceId + Algorithm
+    pub(super) keychangeid_userid: sled::Tree,        // KeyChangeId = UserId/RoomId + Count
+    pub(super) keyid_key: sled::Tree, // KeyId = UserId + KeyId (depends on key type)
     pub(super) userid_masterkeyid: sled::Tree,
     pub(super) userid_selfsigningkeyid: sled::Tree,
     pub(super) userid_usersigningkeyid: sled::Tree,
 
     pub(super) todeviceid_events: sled::Tree, // ToDeviceId = UserId + DeviceId + Count
+
+    pub(super) registrationnonce_expiresat: sled::Tree, // For shared-secret registration
+    pub(super) registration_tokens: sled::Tree, // Value = UsesRemaining (u64) + ExpiresAt (u64)
+    pub(super) email_userid: sled::Tree,
+    pub(super) user_threepids: sled::Tree, // ThreepidId = UserId + Medium + Address, value is json
+    pub(super) userid_guest: sled::Tree,   // Contains all guest accounts
+    pub(super) userid_admin: sled::Tree,   // Contains all server admins
+    pub(super) filters: sled::Tree,        // FilterId = UserId + FilterId, value is the filter json
+    pub(super) openid_tokens: sled::Tree,  // Value = ExpiresAt (u64) + UserId
+    pub(super) login_tokens: sled::Tree,   // Value = ExpiresAt (u64) + UserId
+    pub(super) token_expiresat: sled::Tree, // Only access tokens that can be refreshed expire
+    pub(super) refreshtoken_userdeviceid: sled::Tree, // Value = UsedAt (u64, 0 if unused) + UserDeviceId
+
+    pub(super) password_hashing: argon2::Config<'static>, // Parameters of new password hashes
 }
 
 impl Users {
@@ -57,6 +86,456 @@ impl Users {
         Ok(())
     }
 
+    /// Marks the account as a guest account. Guests can only use some endpoints.
+    pub fn set_guest(&self, user_id: &UserId) -> Result<()> {
+        self.userid_guest.insert(user_id.to_string(), &[])?;
+        Ok(())
+    }
+
+    /// Check if the account is a guest account.
+    pub fn is_guest(&self, user_id: &UserId) -> Result<bool> {
+        Ok(self.userid_guest.contains_key(user_id.to_string())?)
+    }
+
+    /// Marks the account as a server admin. Admins can use the `/_conduit/admin` endpoints.
+    pub fn make_admin(&self, user_id: &UserId) -> Result<()> {
+        self.userid_admin.insert(user_id.to_string(), &[])?;
+        Ok(())
+    }
+
+    /// Takes the server admin flag away from the account.
+    pub fn remove_admin(&self, user_id: &UserId) -> Result<()> {
+        self.userid_admin.remove(user_id.to_string())?;
+        Ok(())
+    }
+
+    /// Check if the account is a server admin.
+    pub fn is_admin(&self, user_id: &UserId) -> Result<bool> {
+        Ok(self.userid_admin.contains_key(user_id.to_string())?)
+    }
+
+    /// Stores a filter of the user and returns its id.
+    pub fn create_filter(&self, user_id: &UserId, filter: &serde_json::Value) -> Result<String> {
+        let filter_id = utils::random_string(FILTER_ID_LENGTH);
+
+        let mut key = user_id.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(filter_id.as_bytes());
+
+        self.filters.insert(
+            key,
+            &*serde_json::to_string(filter).expect("Value::to_string always works"),
+        )?;
+
+        Ok(filter_id)
+    }
+
+    pub fn get_filter(
+        &self,
+        user_id: &UserId,
+        filter_id: &str,
+    ) -> Result<Option<serde_json::Value>> {
+        let mut key = user_id.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(filter_id.as_bytes());
+
+        self.filters.get(key)?.map_or(Ok(None), |bytes| {
+            Ok(Some(serde_json::from_slice(&bytes).map_err(|_| {
+                Error::bad_database("Filter in db is invalid.")
+            })?))
+        })
+    }
+
+    /// Creates a nonce that can be used once for shared-secret registration. Expired nonces that
+    /// were never used are removed.
+    pub fn create_registration_nonce(&self, nonce_length: usize) -> Result<String> {
+        let now = utils::millis_since_unix_epoch();
+        for r in self.registrationnonce_expiresat.iter() {
+            let (nonce, expires_at) = r?;
+            if utils::u64_from_bytes(&expires_at).map_or(true, |expires_at| expires_at <= now) {
+                self.registrationnonce_expiresat.remove(nonce)?;
+            }
+        }
+
+        let nonce = utils::random_string(nonce_length);
+
+        // Nonces are only valid for one minute
+        let expires_at = now + 60 * 1000;
+        self.registrationnonce_expiresat
+            .insert(&nonce, &expires_at.to_be_bytes())?;
+
+        Ok(nonce)
+    }
+
+    /// Removes the nonce and returns true if it existed and did not expire yet.
+    pub fn take_registration_nonce(&self, nonce: &str) -> Result<bool> {
+        Ok(match self.registrationnonce_expiresat.remove(nonce)? {
+            Some(expires_at) => {
+                utils::u64_from_bytes(&expires_at)
+                    .map_err(|_| Error::bad_database("Invalid nonce expiry in db."))?
+                    > utils::millis_since_unix_epoch()
+            }
+            None => false,
+        })
+    }
+
+    /// Creates a token that proves to other servers that the user belongs to this server.
+    ///
+    /// `expires_at` is in millis since the unix epoch.
+    pub fn create_openid_token(
+        &self,
+        user_id: &UserId,
+        token_length: usize,
+        expires_at: u64,
+    ) -> Result<String> {
+        // Expired tokens are never used again
+        let now = utils::millis_since_unix_epoch();
+        for (token, value) in self.openid_tokens.iter().filter_map(|r| r.ok()) {
+            if value.len() < 8 || utils::u64_from_bytes(&value[..8]).map_or(true, |e| e <= now) {
+                self.openid_tokens.remove(token)?;
+            }
+        }
+
+        let token = utils::random_string(token_length);
+
+        let mut value = expires_at.to_be_bytes().to_vec();
+        value.extend_from_slice(user_id.as_str().as_bytes());
+        self.openid_tokens.insert(&token, value)?;
+
+        Ok(token)
+    }
+
+    /// Returns the user the OpenID token belongs to, if it did not expire yet.
+    pub fn find_from_openid_token(&self, token: &str) -> Result<Option<UserId>> {
+        let value = match self.openid_tokens.get(token)? {
+            Some(value) if value.len() > 8 => value,
+            Some(_) => return Err(Error::bad_database("Invalid OpenID token in db.")),
+            None => return Ok(None),
+        };
+
+        let expires_at = utils::u64_from_bytes(&value[..8])
+            .map_err(|_| Error::bad_database("Invalid OpenID token expiry in db."))?;
+        if expires_at <= utils::millis_since_unix_epoch() {
+            self.openid_tokens.remove(token)?;
+            return Ok(None);
+        }
+
+        Ok(Some(
+            UserId::try_from(utils::string_from_bytes(&value[8..]).map_err(|_| {
+                Error::bad_database("User ID in openid_tokens is invalid unicode.")
+            })?)
+            .map_err(|_| Error::bad_database("User ID in openid_tokens is invalid."))?,
+        ))
+    }
+
+    /// Creates a token the user can log in with once, e.g. after single sign-on.
+    ///
+    /// `expires_at` is in millis since the unix epoch.
+    pub fn create_login_token(
+        &self,
+        user_id: &UserId,
+        token_length: usize,
+        expires_at: u64,
+    ) -> Result<String> {
+        let token = utils::random_string(token_length);
+
+        let mut value = expires_at.to_be_bytes().to_vec();
+        value.extend_from_slice(user_id.as_str().as_bytes());
+        self.login_tokens.insert(&token, value)?;
+
+        Ok(token)
+    }
+
+    /// Removes the login token and returns its user if it did not expire yet.
+    pub fn take_login_token(&self, token: &str) -> Result<Option<UserId>> {
+        let value = match self.login_tokens.remove(token)? {
+            Some(value) if value.len() > 8 => value,
+            Some(_) => return Err(Error::bad_database("Invalid login token in db.")),
+            None => return Ok(None),
+        };
+
+        let expires_at = utils::u64_from_bytes(&value[..8])
+            .map_err(|_| Error::bad_database("Invalid login token expiry in db."))?;
+        if expires_at <= utils::millis_since_unix_epoch() {
+            return Ok(None);
+        }
+
+        Ok(Some(
+            UserId::try_from(
+                utils::string_from_bytes(&value[8..]).map_err(|_| {
+                    Error::bad_database("User ID in login_tokens is invalid unicode.")
+                })?,
+            )
+            .map_err(|_| Error::bad_database("User ID in login_tokens is invalid."))?,
+        ))
+    }
+
+    /// Lets the access token expire, the client has to use its refresh token to get a new one.
+    ///
+    /// `expires_at` is in millis since the unix epoch.
+    pub fn set_token_expiry(&self, token: &str, expires_at: u64) -> Result<()> {
+        self.token_expiresat
+            .insert(token, &expires_at.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Checks if the access token expired. Clients can still refresh expired access tokens.
+    pub fn token_expired(&self, token: &str) -> Result<bool> {
+        self.token_expiresat.get(token)?.map_or(Ok(false), |bytes| {
+            let expires_at = utils::u64_from_bytes(&bytes)
+                .map_err(|_| Error::bad_database("Invalid access token expiry in db."))?;
+            Ok(expires_at <= utils::millis_since_unix_epoch())
+        })
+    }
+
+    /// Creates a refresh token for the device. Each refresh token can only be used once.
+    pub fn create_refresh_token(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        token_length: usize,
+    ) -> Result<String> {
+        // Used tokens are only remembered for a while
+        let forget_before = utils::millis_since_unix_epoch()
+            .saturating_sub(REFRESH_TOKEN_REUSE_DETECTION.as_millis() as u64);
+        for (token, value) in self.refreshtoken_userdeviceid.iter().filter_map(|r| r.ok()) {
+            match value.get(..8).map(utils::u64_from_bytes) {
+                Some(Ok(used_at)) if used_at == 0 || used_at > forget_before => {}
+                _ => {
+                    self.refreshtoken_userdeviceid.remove(token)?;
+                }
+            }
+        }
+
+        let mut userdeviceid = user_id.to_string().as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        let token = utils::random_string(token_length);
+
+        let mut value = 0_u64.to_be_bytes().to_vec();
+        value.extend_from_slice(&userdeviceid);
+        self.refreshtoken_userdeviceid.insert(&token, value)?;
+
+        Ok(token)
+    }
+
+    /// Marks the refresh token as used and returns the device it belongs to. The bool is true if
+    /// the token was used before, which means that someone else probably knows it.
+    pub fn use_refresh_token(&self, token: &str) -> Result<Option<(UserId, Box<DeviceId>, bool)>> {
+        let now = utils::millis_since_unix_epoch();
+
+        // The update is atomic, so two uses of the same token can't both succeed
+        let old = match self
+            .refreshtoken_userdeviceid
+            .fetch_and_update(token, |old| {
+                let mut new = old?.to_vec();
+                if new.len() > 8 && new[..8] == [0; 8] {
+                    new[..8].copy_from_slice(&now.to_be_bytes());
+                }
+                Some(new)
+            })? {
+            Some(old) if old.len() > 8 => old,
+            Some(_) => return Err(Error::bad_database("Invalid refresh token in db.")),
+            None => return Ok(None),
+        };
+
+        let used = old[..8] != [0; 8];
+
+        let mut parts = old[8..].split(|&b| b == 0xff);
+        let user_bytes = parts.next().ok_or_else(|| {
+            Error::bad_database("User ID in refreshtoken_userdeviceid is invalid.")
+        })?;
+        let device_bytes = parts.next().ok_or_else(|| {
+            Error::bad_database("Device ID in refreshtoken_userdeviceid is invalid.")
+        })?;
+
+        Ok(Some((
+            UserId::try_from(utils::string_from_bytes(&user_bytes).map_err(|_| {
+                Error::bad_database("User ID in refreshtoken_userdeviceid is invalid unicode.")
+            })?)
+            .map_err(|_| Error::bad_database("User ID in refreshtoken_userdeviceid is invalid."))?,
+            utils::string_from_bytes(&device_bytes)
+                .map_err(|_| {
+                    Error::bad_database("Device ID in refreshtoken_userdeviceid is invalid.")
+                })?
+                .into(),
+            used,
+        )))
+    }
+
+    /// Creates a token that allows registration even if registration is disabled. Returns false
+    /// if the token already exists.
+    ///
+    /// `uses_allowed` and `expires_at` (millis since the unix epoch) are unlimited if None.
+    pub fn create_registration_token(
+        &self,
+        token: &str,
+        uses_allowed: Option<u64>,
+        expires_at: Option<u64>,
+    ) -> Result<bool> {
+        let mut value = uses_allowed.unwrap_or(u64::MAX).to_be_bytes().to_vec();
+        value.extend_from_slice(&expires_at.unwrap_or(0).to_be_bytes());
+
+        Ok(self
+            .registration_tokens
+            .compare_and_swap(token, None::<&[u8]>, Some(value))?
+            .is_ok())
+    }
+
+    /// Checks if the registration token can still be used without using it up.
+    pub fn registration_token_valid(&self, token: &str) -> Result<bool> {
+        Ok(self
+            .registration_tokens
+            .get(token)?
+            .map_or(Ok::<_, Error>(false), |value| {
+                registration_token_usable(&value)
+            })?)
+    }
+
+    /// Uses the registration token once. Returns false if the token is invalid, expired or used
+    /// up.
+    pub fn use_registration_token(&self, token: &str) -> Result<bool> {
+        // The update is atomic, so a token can never be used more often than allowed
+        let old = self.registration_tokens.fetch_and_update(token, |old| {
+            let old = old?;
+            match registration_token_usable(old) {
+                Ok(true) => {
+                    let uses_remaining = utils::u64_from_bytes(&old[..8])
+                        .expect("checked by registration_token_usable");
+                    let mut new = if uses_remaining == u64::MAX {
+                        uses_remaining
+                    } else {
+                        uses_remaining - 1
+                    }
+                    .to_be_bytes()
+                    .to_vec();
+                    new.extend_from_slice(&old[8..]);
+                    Some(new)
+                }
+                _ => Some(old.to_vec()),
+            }
+        })?;
+
+        old.map_or(Ok(false), |old| registration_token_usable(&old))
+    }
+
+    /// Associates a validated email address with the user.
+    pub fn add_email(&self, user_id: &UserId, email: &str) -> Result<()> {
+        self.add_threepid(user_id, "email", email, None)
+    }
+
+    /// Associates a validated third party id with the user. `bound_to` is the identity server
+    /// that publishes the id, if any.
+    pub fn add_threepid(
+        &self,
+        user_id: &UserId,
+        medium: &str,
+        address: &str,
+        bound_to: Option<&str>,
+    ) -> Result<()> {
+        // Email addresses are case insensitive
+        let address = if medium == "email" {
+            address.to_lowercase()
+        } else {
+            address.to_owned()
+        };
+
+        let now = utils::millis_since_unix_epoch();
+        let added_at = self
+            .threepid(user_id, medium, &address)?
+            .and_then(|threepid| threepid.get("added_at").and_then(|a| a.as_u64()))
+            .unwrap_or(now);
+
+        self.user_threepids.insert(
+            threepid_key(user_id, medium, &address),
+            &*serde_json::json!({
+                "medium": medium,
+                "address": address,
+                "validated_at": now,
+                "added_at": added_at,
+                "bound_to": bound_to,
+            })
+            .to_string(),
+        )?;
+
+        if medium == "email" {
+            self.email_userid.insert(&address, &*user_id.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the json of a third party id of the user with `medium`, `address`, `validated_at`,
+    /// `added_at` and `bound_to`.
+    pub fn threepid(
+        &self,
+        user_id: &UserId,
+        medium: &str,
+        address: &str,
+    ) -> Result<Option<serde_json::Value>> {
+        let address = if medium == "email" {
+            address.to_lowercase()
+        } else {
+            address.to_owned()
+        };
+
+        self.user_threepids
+            .get(threepid_key(user_id, medium, &address))?
+            .map_or(Ok(None), |bytes| {
+                serde_json::from_slice(&bytes)
+                    .map(Some)
+                    .map_err(|_| Error::bad_database("Invalid threepid in db."))
+            })
+    }
+
+    /// Returns all third party ids of the user like `threepid`.
+    pub fn threepids(&self, user_id: &UserId) -> impl Iterator<Item = Result<serde_json::Value>> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.user_threepids
+            .scan_prefix(prefix)
+            .values()
+            .map(|bytes| {
+                serde_json::from_slice(&bytes?)
+                    .map_err(|_| Error::bad_database("Invalid threepid in db."))
+            })
+    }
+
+    /// Removes the third party id from the account. Email addresses can be used by other accounts
+    /// afterwards.
+    pub fn remove_threepid(&self, user_id: &UserId, medium: &str, address: &str) -> Result<()> {
+        let address = if medium == "email" {
+            address.to_lowercase()
+        } else {
+            address.to_owned()
+        };
+
+        self.user_threepids
+            .remove(threepid_key(user_id, medium, &address))?;
+
+        if medium == "email" && self.find_from_email(&address)?.as_ref() == Some(user_id) {
+            self.email_userid.remove(&address)?;
+        }
+
+        Ok(())
+    }
+
+    /// Finds the user that owns the email address.
+    pub fn find_from_email(&self, email: &str) -> Result<Option<UserId>> {
+        self.email_userid
+            .get(email.to_lowercase())?
+            .map_or(Ok(None), |bytes| {
+                Ok(Some(
+                    UserId::try_from(utils::string_from_bytes(&bytes).map_err(|_| {
+                        Error::bad_database("User ID in email_userid is invalid unicode.")
+                    })?)
+                    .map_err(|_| Error::bad_database("User ID in email_userid is invalid."))?,
+                ))
+            })
+    }
+
     /// Find out which user an access token belongs to.
     pub fn find_from_token(&self, token: &str) -> Result<Option<(UserId, String)>> {
         self.token_userdeviceid
@@ -107,9 +586,31 @@ impl Users {
             })
     }
 
+    /// Hashes the password with argon2id and the configured cost.
+    pub fn hash_password(&self, password: &str) -> Result<String> {
+        utils::calculate_hash(password, &self.password_hashing).map_err(|_| {
+            Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Password does not meet the requirements.",
+            )
+        })
+    }
+
+    /// Checks the password against a stored hash. All argon2 variants and costs are recognized by
+    /// the prefix of the hash, other hashes never match. The comparison takes constant time.
+    pub fn verify_password(&self, hash: &str, password: &str) -> bool {
+        utils::verify_hash(hash, password)
+    }
+
+    /// Checks if the hash should be replaced by a new hash of the password, because it uses
+    /// another variant or cost than new hashes.
+    pub fn password_hash_is_outdated(&self, hash: &str) -> bool {
+        utils::hash_is_outdated(hash, &self.password_hashing)
+    }
+
     /// Hash and set the user's password to the Argon2 hash
     pub fn set_password(&self, user_id: &UserId, password: &str) -> Result<()> {
-        if let Ok(hash) = utils::calculate_hash(&password) {
+        if let Ok(hash) = self.hash_password(&password) {
             self.userid_password.insert(user_id.to_string(), &*hash)?;
             Ok(())
         } else {
@@ -186,7 +687,7 @@ impl Users {
             serde_json::to_string(&Device {
                 device_id: device_id.into(),
                 display_name: initial_device_display_name,
-                last_seen_ip: None, // TODO
+                last_seen_ip: None, // Set by the first request of the device
                 last_seen_ts: Some(SystemTime::now()),
             })
             .expect("Device::to_string never fails.")
@@ -207,6 +708,14 @@ impl Users {
         // Remove tokens
         if let Some(old_token) = self.userdeviceid_token.remove(&userdeviceid)? {
             self.token_userdeviceid.remove(&old_token)?;
+            self.token_expiresat.remove(&old_token)?;
+        }
+
+        // Remove refresh tokens
+        for (token, value) in self.refreshtoken_userdeviceid.iter().filter_map(|r| r.ok()) {
+            if value.get(8..) == Some(&userdeviceid[..]) {
+                self.refreshtoken_userdeviceid.remove(token)?;
+            }
         }
 
         // Remove todevice events
@@ -217,7 +726,18 @@ impl Users {
             self.todeviceid_events.remove(key?)?;
         }
 
-        // TODO: Remove onetimekeys
+        // Remove one-time keys
+        for key in self.onetimekeyid_onetimekeys.scan_prefix(&prefix).keys() {
+            self.onetimekeyid_onetimekeys.remove(key?)?;
+        }
+
+        // Remove fallback keys
+        for key in self.fallbackkeyid_fallbackkey.scan_prefix(&prefix).keys() {
+            self.fallbackkeyid_fallbackkey.remove(key?)?;
+        }
+
+        // Remove device keys
+        self.keyid_key.remove(&userdeviceid)?;
 
         self.userdeviceid_metadata.remove(&userdeviceid)?;
 
@@ -245,7 +765,7 @@ impl Users {
     }
 
     /// Replaces the access token of one device.
-    fn set_token(&self, user_id: &UserId, device_id: &DeviceId, token: &str) -> Result<()> {
+    pub fn set_token(&self, user_id: &UserId, device_id: &DeviceId, token: &str) -> Result<()> {
         let mut userdeviceid = user_id.to_string().as_bytes().to_vec();
         userdeviceid.push(0xff);
         userdeviceid.extend_from_slice(device_id.as_bytes());
@@ -255,7 +775,8 @@ impl Users {
 
         // Remove old token
         if let Some(old_token) = self.userdeviceid_token.get(&userdeviceid)? {
-            self.token_userdeviceid.remove(old_token)?;
+            self.token_userdeviceid.remove(&old_token)?;
+            self.token_expiresat.remove(old_token)?;
             // It will be removed from userdeviceid_token by the insert later
         }
 
@@ -336,26 +857,87 @@ impl Users {
             &globals.next_count()?.to_be_bytes(),
         )?;
 
-        self.onetimekeyid_onetimekeys
-            .scan_prefix(&prefix)
+        for key in self.onetimekeyid_onetimekeys.scan_prefix(&prefix).keys() {
+            let key = key?;
+
+            // Another request might have claimed the key since we found it. Only the request
+            // whose remove actually returned the key gets it, the others try the next key.
+            let value = match self.onetimekeyid_onetimekeys.remove(&key)? {
+                Some(value) => value,
+                None => continue,
+            };
+
+            return Ok(Some((
+                serde_json::from_slice(
+                    &*key
+                        .rsplit(|&b| b == 0xff)
+                        .next()
+                        .ok_or_else(|| Error::bad_database("OneTimeKeyId in db is invalid."))?,
+                )
+                .map_err(|_| Error::bad_database("OneTimeKeyId in db is invalid."))?,
+                serde_json::from_slice(&*value)
+                    .map_err(|_| Error::bad_database("OneTimeKeys in db are invalid."))?,
+            )));
+        }
+
+        Ok(None)
+    }
+
+    /// Replaces the fallback key of the device for the algorithm of the key id.
+    pub fn add_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        key_id: &str,
+        key: &serde_json::Value,
+    ) -> Result<()> {
+        let algorithm = key_id
+            .split(':')
             .next()
-            .map(|r| {
-                let (key, value) = r?;
-                self.onetimekeyid_onetimekeys.remove(&key)?;
-
-                Ok((
-                    serde_json::from_slice(
-                        &*key
-                            .rsplit(|&b| b == 0xff)
-                            .next()
-                            .ok_or_else(|| Error::bad_database("OneTimeKeyId in db is invalid."))?,
-                    )
-                    .map_err(|_| Error::bad_database("OneTimeKeyId in db is invalid."))?,
-                    serde_json::from_slice(&*value)
-                        .map_err(|_| Error::bad_database("OneTimeKeys in db are invalid."))?,
-                ))
+            .expect("split always returns an element");
+
+        let mut fallbackkeyid = user_id.to_string().as_bytes().to_vec();
+        fallbackkeyid.push(0xff);
+        fallbackkeyid.extend_from_slice(device_id.as_bytes());
+        fallbackkeyid.push(0xff);
+        fallbackkeyid.extend_from_slice(algorithm.as_bytes());
+
+        self.fallbackkeyid_fallbackkey.insert(
+            fallbackkeyid,
+            &*serde_json::json!({ "key_id": key_id, "key": key }).to_string(),
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the key id and key of the fallback key. Unlike one-time keys, fallback keys are
+    /// not removed when they are claimed.
+    pub fn get_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        key_algorithm: &DeviceKeyAlgorithm,
+    ) -> Result<Option<(String, serde_json::Value)>> {
+        let mut fallbackkeyid = user_id.to_string().as_bytes().to_vec();
+        fallbackkeyid.push(0xff);
+        fallbackkeyid.extend_from_slice(device_id.as_bytes());
+        fallbackkeyid.push(0xff);
+        fallbackkeyid.extend_from_slice(key_algorithm.to_string().as_bytes());
+
+        self.fallbackkeyid_fallbackkey
+            .get(fallbackkeyid)?
+            .map_or(Ok(None), |bytes| {
+                let mut fallback_key = serde_json::from_slice::<serde_json::Value>(&bytes)
+                    .map_err(|_| Error::bad_database("Fallback key in db is invalid."))?;
+
+                let key_id = fallback_key
+                    .get("key_id")
+                    .and_then(|key_id| key_id.as_str())
+                    .ok_or_else(|| Error::bad_database("Fallback key in db has no key id."))?
+                    .to_owned();
+
+                Ok(Some((key_id, fallback_key["key"].take())))
             })
-            .transpose()
     }
 
     pub fn count_one_time_keys(
@@ -366,6 +948,7 @@ impl Users {
         let mut userdeviceid = user_id.to_string().as_bytes().to_vec();
         userdeviceid.push(0xff);
         userdeviceid.extend_from_slice(device_id.as_bytes());
+        userdeviceid.push(0xff);
 
         let mut counts = BTreeMap::new();
 
@@ -422,7 +1005,30 @@ impl Users {
         rooms: &super::rooms::Rooms,
         globals: &super::globals::Globals<'_>,
     ) -> Result<()> {
-        // TODO: Check signatures
+        if master_key.user_id != *user_id {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Master key belongs to another user.",
+            ));
+        }
+
+        // The other keys are only trusted because the master key signed them
+        if let Some(self_signing_key) = self_signing_key {
+            verify_signed_by_master(
+                user_id,
+                self_signing_key,
+                master_key,
+                "Self signing key is not signed by the master key.",
+            )?;
+        }
+        if let Some(user_signing_key) = user_signing_key {
+            verify_signed_by_master(
+                user_id,
+                user_signing_key,
+                master_key,
+                "User signing key is not signed by the master key.",
+            )?;
+        }
 
         let mut prefix = user_id.to_string().as_bytes().to_vec();
         prefix.push(0xff);
@@ -590,7 +1196,7 @@ impl Users {
             })
     }
 
-    fn mark_device_key_update(
+    pub fn mark_device_key_update(
         &self,
         user_id: &UserId,
         rooms: &super::rooms::Rooms,
@@ -603,7 +1209,7 @@ impl Users {
                 .room_state_get(&room_id, &EventType::RoomEncryption, "")?
                 .is_none()
             {
-                return Ok(());
+                continue;
             }
 
             let mut key = room_id.to_string().as_bytes().to_vec();
@@ -621,6 +1227,23 @@ impl Users {
         Ok(())
     }
 
+    /// Returns the count of the last change of the device keys of the user, or 0 if they never
+    /// changed.
+    pub fn last_device_key_update(&self, user_id: &UserId) -> Result<u64> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.keychangeid_userid
+            .scan_prefix(&prefix)
+            .keys()
+            .next_back()
+            .map(|key| {
+                utils::u64_from_bytes(&key?[prefix.len()..])
+                    .map_err(|_| Error::bad_database("Count in keychangeid_userid is invalid."))
+            })
+            .unwrap_or(Ok(0))
+    }
+
     pub fn get_device_keys(
         &self,
         user_id: &UserId,
@@ -812,6 +1435,39 @@ impl Users {
         Ok(())
     }
 
+    /// Remembers when and from where the device made its last request.
+    ///
+    /// The timestamp is only written once per `LAST_SEEN_RESOLUTION`, so not every request
+    /// results in a database write.
+    pub fn update_device_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        ip: Option<IpAddr>,
+    ) -> Result<()> {
+        let mut device = match self.get_device_metadata(user_id, device_id)? {
+            Some(device) => device,
+            None => return Ok(()),
+        };
+
+        let now = SystemTime::now();
+        let last_seen_ip = ip.map(|ip| ip.to_string());
+        let outdated = device.last_seen_ts.map_or(true, |ts| {
+            now.duration_since(ts)
+                .map_or(false, |elapsed| elapsed >= LAST_SEEN_RESOLUTION)
+        });
+
+        if outdated || (last_seen_ip.is_some() && device.last_seen_ip != last_seen_ip) {
+            device.last_seen_ts = Some(now);
+            if last_seen_ip.is_some() {
+                device.last_seen_ip = last_seen_ip;
+            }
+            self.update_device_metadata(user_id, device_id, &device)?;
+        }
+
+        Ok(())
+    }
+
     /// Get device metadata.
     pub fn get_device_metadata(
         &self,
@@ -857,7 +1513,93 @@ impl Users {
         // password without logging in should check if the account is deactivated.
         self.userid_password.insert(user_id.to_string(), "")?;
 
-        // TODO: Unhook 3PID
+        // Remove cross-signing keys
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        for key in self.keyid_key.scan_prefix(&prefix).keys() {
+            self.keyid_key.remove(key?)?;
+        }
+        self.userid_masterkeyid.remove(user_id.to_string())?;
+        self.userid_selfsigningkeyid.remove(user_id.to_string())?;
+        self.userid_usersigningkeyid.remove(user_id.to_string())?;
+
+        // Integrations should not accept the user anymore
+        for (token, value) in self.openid_tokens.iter().filter_map(|r| r.ok()) {
+            if value.get(8..) == Some(user_id.as_str().as_bytes()) {
+                self.openid_tokens.remove(token)?;
+            }
+        }
+
+        // Unhook third party ids and email addresses, they can be used by other accounts now
+        for key in self.user_threepids.scan_prefix(&prefix).keys() {
+            self.user_threepids.remove(key?)?;
+        }
+        for (email, owner) in self.email_userid.iter().filter_map(|r| r.ok()) {
+            if owner == user_id.to_string().as_bytes() {
+                self.email_userid.remove(email)?;
+            }
+        }
+
         Ok(())
     }
 }
+
+/// Checks that the cross-signing key belongs to the user and has a valid signature of the master
+/// key.
+fn verify_signed_by_master(
+    user_id: &UserId,
+    key: &CrossSigningKey,
+    master_key: &CrossSigningKey,
+    error: &'static str,
+) -> Result<()> {
+    if key.user_id != *user_id {
+        return Err(Error::BadRequest(ErrorKind::InvalidParam, error));
+    }
+
+    let mut public_key_map = ruma::signatures::PublicKeyMap::new();
+    public_key_map.insert(
+        user_id.to_string(),
+        master_key
+            .keys
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect(),
+    );
+
+    // Only the signatures of the user itself are checked, other users may have signed it too
+    let mut key_json = serde_json::to_value(key).expect("CrossSigningKey::to_value always works");
+    let own_signatures = key_json
+        .get("signatures")
+        .and_then(|signatures| signatures.get(user_id.as_str()))
+        .cloned()
+        .ok_or(Error::BadRequest(ErrorKind::InvalidParam, error))?;
+    let mut signatures = serde_json::Map::new();
+    signatures.insert(user_id.to_string(), own_signatures);
+    key_json["signatures"] = signatures.into();
+
+    ruma::signatures::verify_json(&public_key_map, &key_json)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, error))
+}
+
+fn registration_token_usable(value: &[u8]) -> Result<bool> {
+    if value.len() != 16 {
+        return Err(Error::bad_database("Invalid registration token in db."));
+    }
+
+    let uses_remaining = utils::u64_from_bytes(&value[..8])
+        .map_err(|_| Error::bad_database("Invalid registration token uses in db."))?;
+    let expires_at = utils::u64_from_bytes(&value[8..])
+        .map_err(|_| Error::bad_database("Invalid registration token expiry in db."))?;
+
+    Ok(uses_remaining > 0 && (expires_at == 0 || expires_at > utils::millis_since_unix_epoch()))
+}
+
+fn threepid_key(user_id: &UserId, medium: &str, address: &str) -> Vec<u8> {
+    let mut key = user_id.to_string().as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(medium.as_bytes());
+    key.push(0xff);
+    key.extend_from_slice(address.as_bytes());
+    key
+}
@@ -1,6 +1,6 @@
-use log::error;
 use ruma::api::client::{error::ErrorKind, r0::uiaa::UiaaInfo};
 use thiserror::Error;
+use tracing::error;
 
 #[cfg(feature = "conduit_bin")]
 use {
@@ -32,6 +32,16 @@ pub enum Error {
         #[from]
         source: reqwest::Error,
     },
+    #[error("Could not connect to server.")]
+    HyperError {
+        #[from]
+        source: hyper::Error,
+    },
+    #[error("Could not read or write a file: {source}")]
+    IoError {
+        #[from]
+        source: std::io::Error,
+    },
     #[error("{0}")]
     BadServerResponse(&'static str),
     #[error("{0}")]
@@ -39,13 +49,23 @@ pub enum Error {
     #[error("{0}")]
     /// Don't create this directly. Use Error::bad_database instead.
     BadDatabase(&'static str),
+    #[error("{0}")]
+    BadArchive(&'static str), // Database exports that can't be imported
     #[error("uiaa")]
     Uiaa(UiaaInfo),
 
     #[error("{0}: {1}")]
     BadRequest(ErrorKind, &'static str),
+    #[error("Too many requests.")]
+    RateLimited(u64), // Milliseconds until the client can try again
     #[error("{0}")]
     Conflict(&'static str), // This is only needed for when a room alias already exists
+    #[error("{0}")]
+    UnableToAuthoriseJoin(&'static str), // The ruma error kind does not exist yet
+    #[error("{0}")]
+    BadAlias(&'static str), // The ruma error kind does not exist yet
+    #[error("{0}")]
+    UnknownToken(&'static str, bool), // The bool is soft_logout, which ruma doesn't know yet
 }
 
 impl Error {
@@ -55,6 +75,46 @@ impl Error {
     }
 }
 
+#[cfg(feature = "conduit_bin")]
+impl Error {
+    /// The status and body of errors that can't be expressed with the ruma error type.
+    fn custom_response(&self) -> Option<(rocket::http::Status, serde_json::Value)> {
+        match self {
+            Self::RateLimited(retry_after_ms) => Some((
+                rocket::http::Status::TooManyRequests,
+                serde_json::json!({
+                    "errcode": "M_LIMIT_EXCEEDED",
+                    "error": format!("{}", self),
+                    "retry_after_ms": retry_after_ms,
+                }),
+            )),
+            Self::UnableToAuthoriseJoin(_) => Some((
+                rocket::http::Status::BadRequest,
+                serde_json::json!({
+                    "errcode": "M_UNABLE_TO_AUTHORISE_JOIN",
+                    "error": format!("{}", self),
+                }),
+            )),
+            Self::BadAlias(_) => Some((
+                rocket::http::Status::BadRequest,
+                serde_json::json!({
+                    "errcode": "M_BAD_ALIAS",
+                    "error": format!("{}", self),
+                }),
+            )),
+            Self::UnknownToken(_, soft_logout) => Some((
+                rocket::http::Status::Unauthorized,
+                serde_json::json!({
+                    "errcode": "M_UNKNOWN_TOKEN",
+                    "error": format!("{}", self),
+                    "soft_logout": soft_logout,
+                }),
+            )),
+            _ => None,
+        }
+    }
+}
+
 #[cfg(feature = "conduit_bin")]
 impl<'r, 'o> Responder<'r, 'o> for Error
 where
@@ -65,6 +125,16 @@ where
             return RumaResponse::from(UiaaResponse::AuthResponse(uiaainfo.clone())).respond_to(r);
         }
 
+        if let Some((status, body)) = self.custom_response() {
+            let body = body.to_string();
+
+            return response::Response::build()
+                .status(status)
+                .header(rocket::http::ContentType::JSON)
+                .sized_body(body.len(), std::io::Cursor::new(body))
+                .ok();
+        }
+
         let message = format!("{}", self);
 
         use ErrorKind::*;
@@ -84,6 +154,10 @@ where
                 },
             ),
             Self::Conflict(_) => (Unknown, StatusCode::CONFLICT),
+            // Another server did not answer or sent something we can't use
+            Self::BadServerResponse(_) | Self::ReqwestError { .. } | Self::HyperError { .. } => {
+                (Unknown, StatusCode::BAD_GATEWAY)
+            }
             _ => (Unknown, StatusCode::INTERNAL_SERVER_ERROR),
         };
 
@@ -95,3 +169,36 @@ where
         .respond_to(r)
     }
 }
+
+#[cfg(all(test, feature = "conduit_bin"))]
+mod tests {
+    use super::Error;
+    use rocket::http::Status;
+    use serde_json::json;
+
+    #[test]
+    fn expired_tokens_are_soft_logouts() {
+        assert_eq!(
+            Error::UnknownToken("Access token expired.", true).custom_response(),
+            Some((
+                Status::Unauthorized,
+                json!({
+                    "errcode": "M_UNKNOWN_TOKEN",
+                    "error": "Access token expired.",
+                    "soft_logout": true,
+                })
+            ))
+        );
+        assert_eq!(
+            Error::UnknownToken("Unknown access token.", false).custom_response(),
+            Some((
+                Status::Unauthorized,
+                json!({
+                    "errcode": "M_UNKNOWN_TOKEN",
+                    "error": "Unknown access token.",
+                    "soft_logout": false,
+                })
+            ))
+        );
+    }
+}
@@ -1,3 +1,4 @@
+pub mod admin;
 pub mod client_server;
 mod database;
 mod error;
@@ -5,6 +6,7 @@ mod pdu;
 mod push_rules;
 mod ruma_wrapper;
 pub mod server_server;
+mod state_res;
 mod utils;
 
 pub use database::Database;
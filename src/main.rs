@@ -1,5 +1,6 @@
 #![warn(rust_2018_idioms)]
 
+pub mod admin;
 pub mod client_server;
 pub mod server_server;
 
@@ -8,6 +9,7 @@ mod error;
 mod pdu;
 mod push_rules;
 mod ruma_wrapper;
+mod state_res;
 mod utils;
 
 pub use database::Database;
@@ -16,28 +18,66 @@ pub use pdu::PduEvent;
 pub use rocket::State;
 pub use ruma_wrapper::{ConduitResult, Ruma, RumaResponse};
 
-use rocket::{fairing::AdHoc, routes};
+use database::{counter::Counter, shutdown::Shutdown};
+use rocket::{
+    catchers,
+    fairing::AdHoc,
+    http::{uri::Origin, Method},
+    routes,
+};
+use ruma_wrapper::RequestSpan;
+use std::{
+    convert::TryFrom,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{info, info_span};
 
-fn setup_rocket() -> rocket::Rocket {
-    rocket::ignite()
+/// Length of the ids that are generated for every request
+const REQUEST_ID_LENGTH: usize = 16;
+
+fn setup_rocket(rocket: rocket::Rocket) -> rocket::Rocket {
+    rocket
         .mount(
             "/",
             routes![
                 client_server::get_supported_versions_route,
+                client_server::get_metrics_route,
                 client_server::get_register_available_route,
                 client_server::register_route,
+                client_server::registration_token_validity_route,
+                client_server::get_shared_secret_register_nonce_route,
+                client_server::shared_secret_register_route,
                 client_server::get_login_types_route,
                 client_server::login_route,
+                client_server::get_login_token_route,
+                client_server::refresh_route,
+                client_server::sso_redirect_route,
+                client_server::sso_redirect_idp_route,
+                client_server::cas_ticket_route,
+                client_server::oidc_callback_route,
                 client_server::whoami_route,
                 client_server::logout_route,
                 client_server::logout_all_route,
                 client_server::change_password_route,
+                client_server::request_registration_token_via_email_route,
+                client_server::request_password_change_token_via_email_route,
+                client_server::submit_email_token_route,
+                client_server::get_threepids_route,
+                client_server::add_threepid_route,
+                client_server::bind_threepid_route,
+                client_server::unbind_threepid_route,
+                client_server::delete_threepid_route,
                 client_server::deactivate_route,
                 client_server::get_capabilities_route,
                 client_server::get_pushrules_all_route,
+                client_server::get_pushrule_route,
                 client_server::set_pushrule_route,
+                client_server::delete_pushrule_route,
                 client_server::set_pushrule_enabled_route,
+                client_server::set_pushrule_actions_route,
                 client_server::get_room_event_route,
+                client_server::report_event_route,
                 client_server::get_filter_route,
                 client_server::create_filter_route,
                 client_server::set_global_account_data_route,
@@ -56,6 +96,7 @@ fn setup_rocket() -> rocket::Rocket {
                 client_server::delete_backup_route,
                 client_server::get_latest_backup_route,
                 client_server::get_backup_route,
+                client_server::add_backup_key_session_route,
                 client_server::add_backup_key_sessions_route,
                 client_server::add_backup_keys_route,
                 client_server::delete_backup_key_session_route,
@@ -65,14 +106,20 @@ fn setup_rocket() -> rocket::Rocket {
                 client_server::get_backup_key_sessions_route,
                 client_server::get_backup_keys_route,
                 client_server::set_read_marker_route,
+                client_server::create_receipt_route,
                 client_server::create_typing_event_route,
                 client_server::create_room_route,
                 client_server::redact_event_route,
+                client_server::get_relations_route,
+                client_server::get_relations_with_rel_type_route,
+                client_server::get_relations_with_rel_type_and_event_type_route,
+                client_server::get_threads_route,
                 client_server::create_alias_route,
                 client_server::delete_alias_route,
                 client_server::get_alias_route,
                 client_server::join_room_by_id_route,
                 client_server::join_room_by_id_or_alias_route,
+                client_server::knock_room_route,
                 client_server::joined_members_route,
                 client_server::leave_room_route,
                 client_server::forget_room_route,
@@ -95,12 +142,17 @@ fn setup_rocket() -> rocket::Rocket {
                 client_server::get_state_events_for_key_route,
                 client_server::get_state_events_for_empty_key_route,
                 client_server::sync_events_route,
+                client_server::sliding_sync_route,
+                client_server::room_initial_sync_route,
                 client_server::get_context_route,
                 client_server::get_message_events_route,
                 client_server::search_events_route,
+                client_server::get_hierarchy_route,
+                client_server::get_room_summary_route,
                 client_server::turn_server_route,
                 client_server::send_event_to_device_route,
                 client_server::get_media_config_route,
+                client_server::get_media_preview_route,
                 client_server::create_content_route,
                 client_server::get_content_route,
                 client_server::get_content_thumbnail_route,
@@ -119,19 +171,260 @@ fn setup_rocket() -> rocket::Rocket {
                 client_server::get_pushers_route,
                 client_server::set_pushers_route,
                 client_server::upgrade_room_route,
+                client_server::request_openid_token_route,
+                client_server::well_known_client_route,
+                server_server::federation_disabled_not_found_route,
+                server_server::federation_disabled_forbidden_route,
                 server_server::well_known_server,
                 server_server::get_server_version,
                 server_server::get_server_keys,
                 server_server::get_server_keys_deprecated,
                 server_server::get_public_rooms_route,
                 server_server::send_transaction_message_route,
+                server_server::get_event_route,
+                server_server::get_backfill_route,
+                server_server::claim_keys_route,
+                server_server::get_hierarchy_route,
+                server_server::get_profile_information_route,
+                server_server::get_devices_route,
+                server_server::get_room_information_route,
+                server_server::get_openid_userinfo_route,
+                server_server::create_invite_route,
+                server_server::make_knock_route,
+                server_server::send_knock_route,
+                server_server::third_party_invite_onbind_route,
+                server_server::exchange_third_party_invite_route,
             ],
         )
+        .register(catchers![
+            client_server::unauthorized_catcher,
+            client_server::guest_access_forbidden_catcher,
+            client_server::payload_too_large_catcher
+        ])
+        .mount("/_conduit/admin", admin::routes())
         .attach(AdHoc::on_attach("Config", |mut rocket| async {
             let data = Database::load_or_create(rocket.config().await).expect("valid config");
 
+            // Requests are only counted if metrics are enabled
+            if data.globals.metrics().is_some() {
+                rocket = rocket.attach(AdHoc::on_response("Metrics", |request, response| {
+                    Box::pin(async move {
+                        let db = request.guard::<State<'_, Database<'_>>>().await.succeeded();
+                        if let (Some(route), Some(metrics)) = (
+                            request.route(),
+                            db.as_ref().and_then(|db| db.globals.metrics()),
+                        ) {
+                            metrics.count_request(
+                                request.method().as_str(),
+                                route.uri.path(),
+                                response.status().code,
+                            );
+                        }
+                    })
+                }));
+            }
+
+            tokio::spawn(shutdown_on_signal(
+                Arc::clone(data.globals.shutdown()),
+                Arc::clone(data.globals.counter()),
+                data._db.clone(),
+                data.globals.shutdown_timeout(),
+            ));
+
             Ok(rocket.manage(data))
         }))
+        .attach(AdHoc::on_request("Tracing", |request, _| {
+            Box::pin(async move {
+                let id = utils::random_string(REQUEST_ID_LENGTH);
+                // The query is not logged, it can contain the access token
+                let span = info_span!(
+                    "request",
+                    request_id = %id,
+                    method = %request.method(),
+                    path = %request.uri().path(),
+                    endpoint = tracing::field::Empty,
+                    user_id = tracing::field::Empty,
+                );
+                request.local_cache(|| RequestSpan {
+                    id,
+                    span,
+                    started: Instant::now(),
+                });
+            })
+        }))
+        .attach(AdHoc::on_request("Federation", |request, _| {
+            Box::pin(async move {
+                // Requests to federation routes go to a route that answers with the error
+                let federation_disabled = request
+                    .guard::<State<'_, Database<'_>>>()
+                    .await
+                    .succeeded()
+                    .map_or(false, |db| db.globals.federation_disabled());
+                if !federation_disabled {
+                    return;
+                }
+
+                if let Some(route) = server_server::federation_disabled_route(request.uri().path())
+                {
+                    request.set_method(Method::Get);
+                    request.set_uri(Origin::parse(route).expect("route is a valid uri"));
+                }
+            })
+        }))
+        .attach(AdHoc::on_response("Tracing", |request, response| {
+            Box::pin(async move {
+                let request_span = request.local_cache(RequestSpan::none);
+                request_span.span.in_scope(|| {
+                    info!(
+                        status = response.status().code,
+                        elapsed_ms = request_span.started.elapsed().as_millis() as u64,
+                        "Finished request"
+                    )
+                });
+                if !request_span.id.is_empty() {
+                    response.set_raw_header("X-Request-Id", request_span.id.clone());
+                }
+            })
+        }))
+        .attach(AdHoc::on_response("CORS", |request, response| {
+            Box::pin(async move {
+                // Browser clients need the headers on all responses, also on errors, to read them
+                if !is_browser_route(request.uri().path()) {
+                    return;
+                }
+
+                let allow_origin = request
+                    .guard::<State<'_, Database<'_>>>()
+                    .await
+                    .succeeded()
+                    .map_or_else(
+                        || "*".to_owned(),
+                        |db| db.globals.cors_allow_origin().to_owned(),
+                    );
+                for (name, value) in cors_headers(allow_origin) {
+                    response.set_raw_header(name, value);
+                }
+            })
+        }))
+        .attach(AdHoc::on_request("Shutdown", |request, _| {
+            Box::pin(async move {
+                if let Some(db) = request.guard::<State<'_, Database<'_>>>().await.succeeded() {
+                    db.globals.shutdown().request_started();
+                }
+            })
+        }))
+        .attach(AdHoc::on_response("Shutdown", |request, _| {
+            Box::pin(async move {
+                if let Some(db) = request.guard::<State<'_, Database<'_>>>().await.succeeded() {
+                    db.globals.shutdown().request_finished();
+                }
+            })
+        }))
+}
+
+/// Client, media and well-known routes are used by browser clients.
+fn is_browser_route(path: &str) -> bool {
+    path.starts_with("/_matrix/client/")
+        || path.starts_with("/_matrix/media/")
+        || path.starts_with("/.well-known/")
+}
+
+/// The CORS headers of responses to browser clients, whatever the status of the response is.
+fn cors_headers(allow_origin: String) -> Vec<(&'static str, String)> {
+    vec![
+        ("Access-Control-Allow-Origin", allow_origin),
+        (
+            "Access-Control-Allow-Methods",
+            "GET, POST, PUT, DELETE, OPTIONS".to_owned(),
+        ),
+        (
+            "Access-Control-Allow-Headers",
+            "Origin, X-Requested-With, Content-Type, Accept, Authorization".to_owned(),
+        ),
+    ]
+}
+
+/// Waits for SIGINT or SIGTERM, gives running requests `shutdown_timeout` to finish and flushes
+/// the database before exiting. A second signal exits immediately.
+async fn shutdown_on_signal(
+    shutdown: Arc<Shutdown>,
+    counter: Arc<Counter>,
+    db: sled::Db,
+    timeout: Duration,
+) {
+    wait_for_signal().await;
+    println!(
+        "Shutting down, waiting up to {:?} for running requests.",
+        timeout
+    );
+    shutdown.start();
+
+    tokio::spawn(async {
+        wait_for_signal().await;
+        println!("Received a second signal, exiting immediately.");
+        std::process::exit(1);
+    });
+
+    if !shutdown.wait_for_requests(timeout).await {
+        println!(
+            "{} requests didn't finish in time.",
+            shutdown.requests_in_flight()
+        );
+    }
+
+    if let Err(e) = counter.store() {
+        eprintln!("Failed to store the counter: {}", e);
+    }
+
+    // The counter and the keypair are stored in the database, so this persists them too
+    if let Err(e) = db.flush_async().await {
+        eprintln!("Failed to flush the database: {}", e);
+        std::process::exit(1);
+    }
+
+    std::process::exit(0);
+}
+
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("we can listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Prints the log entries of Conduit as text or json, filtered by `log_level`. Rocket has its own
+/// log, which is configured with `ROCKET_LOG`.
+fn init_tracing(config: &rocket::Config) {
+    let filter =
+        tracing_subscriber::EnvFilter::try_new(config.get_str("log_level").unwrap_or("warn"))
+            .unwrap_or_else(|e| {
+                eprintln!("Invalid log_level, using warn: {}", e);
+                tracing_subscriber::EnvFilter::new("warn")
+            });
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+
+    let result = match config.get_str("log_format").unwrap_or("pretty") {
+        "json" => subscriber.json().try_init(),
+        "pretty" => subscriber.try_init(),
+        _ => {
+            eprintln!("Invalid log_format, using pretty.");
+            subscriber.try_init()
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to set up logging: {}", e);
+    }
 }
 
 #[rocket::main]
@@ -141,5 +434,281 @@ async fn main() {
         std::env::set_var("ROCKET_LOG", "critical");
     }
 
-    setup_rocket().launch().await.unwrap();
+    let mut rocket = rocket::ignite();
+    init_tracing(rocket.config().await);
+
+    // Admin command: conduit rebuild-search-index
+    if std::env::args().nth(1).as_deref() == Some("rebuild-search-index") {
+        let db = Database::load_or_create(rocket.config().await).expect("valid config");
+        let indexed = db
+            .rooms
+            .rebuild_search_index()
+            .expect("search index can be rebuilt");
+        println!("Rebuilt the search index from {} events.", indexed);
+        return;
+    }
+
+    // Admin commands: conduit export <path> and conduit import <path>
+    if let Some(command) = std::env::args()
+        .nth(1)
+        .filter(|command| command == "export" || command == "import")
+    {
+        let archive_path = match std::env::args().nth(2) {
+            Some(path) => std::path::PathBuf::from(path),
+            None => {
+                eprintln!("Usage: conduit {} <path>", command);
+                return;
+            }
+        };
+
+        let config = rocket.config().await;
+        let result = if command == "export" {
+            Database::export(config, &archive_path)
+        } else {
+            Database::import(config, &archive_path)
+        };
+        match result {
+            Ok(entries) if command == "export" => println!("Exported {} entries.", entries),
+            Ok(entries) => println!("Imported {} entries.", entries),
+            Err(e) => eprintln!("Failed to {}: {}", command, e),
+        }
+        return;
+    }
+
+    // Admin command: conduit make-admin <user id>
+    if std::env::args().nth(1).as_deref() == Some("make-admin") {
+        let user_id = match std::env::args().nth(2).map(ruma::UserId::try_from) {
+            Some(Ok(user_id)) => user_id,
+            Some(Err(_)) => {
+                eprintln!("Invalid user id.");
+                return;
+            }
+            None => {
+                eprintln!("Usage: conduit make-admin <user id>");
+                return;
+            }
+        };
+
+        let db = Database::load_or_create(rocket.config().await).expect("valid config");
+        match db.users.exists(&user_id) {
+            Ok(true) => match db.users.make_admin(&user_id) {
+                Ok(()) => println!("{} is a server admin now.", user_id),
+                Err(e) => eprintln!("Failed to make {} an admin: {}", user_id, e),
+            },
+            Ok(false) => eprintln!("{} does not exist.", user_id),
+            Err(e) => eprintln!("Failed to look up {}: {}", user_id, e),
+        }
+        return;
+    }
+
+    // Admin command: conduit send-server-notice <user id> <message>
+    if std::env::args().nth(1).as_deref() == Some("send-server-notice") {
+        let (user_id, message) = match (std::env::args().nth(2), std::env::args().nth(3)) {
+            (Some(user_id), Some(message)) => (user_id, message),
+            _ => {
+                eprintln!("Usage: conduit send-server-notice <user id> <message>");
+                return;
+            }
+        };
+        let user_id = match ruma::UserId::try_from(user_id) {
+            Ok(user_id) => user_id,
+            Err(_) => {
+                eprintln!("Invalid user id.");
+                return;
+            }
+        };
+
+        let db = Database::load_or_create(rocket.config().await).expect("valid config");
+        match db.send_server_notice(
+            &user_id,
+            serde_json::json!({ "msgtype": "m.text", "body": message }),
+        ) {
+            Ok(event_id) => println!("Sent server notice {}.", event_id),
+            Err(e) => eprintln!("Failed to send server notice: {}", e),
+        }
+        return;
+    }
+
+    setup_rocket(rocket).launch().await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cors_headers, is_browser_route, setup_rocket};
+    use crate::utils;
+    use rocket::{
+        config::{Config, Environment, Value},
+        http::Status,
+        local::asynchronous::{Client, LocalResponse},
+    };
+    use std::path::PathBuf;
+
+    /// A server with the routes and fairings of conduit and a new database, which is removed
+    /// when the server is dropped.
+    struct TestServer {
+        client: Client,
+        database_path: PathBuf,
+    }
+
+    impl TestServer {
+        async fn new(extras: Vec<(&str, Value)>) -> Self {
+            let database_path =
+                std::env::temp_dir().join(format!("conduit-test-{}", utils::random_string(16)));
+
+            let mut config = Config::build(Environment::Development)
+                .extra("server_name", "example.com")
+                .extra(
+                    "database_path",
+                    database_path.to_str().expect("temp dir is valid unicode"),
+                );
+            for (name, value) in extras {
+                config = config.extra(name, value);
+            }
+
+            let rocket = setup_rocket(rocket::custom(config.finalize().unwrap()));
+            TestServer {
+                client: Client::new(rocket).await.unwrap(),
+                database_path,
+            }
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.database_path);
+        }
+    }
+
+    async fn errcode(response: LocalResponse<'_>) -> String {
+        let body = response.into_string().await.unwrap();
+        serde_json::from_str::<serde_json::Value>(&body).unwrap()["errcode"]
+            .as_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn browser_routes_get_cors_headers() {
+        for path in &[
+            "/_matrix/client/r0/login",
+            "/_matrix/client/r0/rooms/!room:example.com/send/m.room.message/1",
+            "/_matrix/media/r0/download/example.com/abc",
+            "/.well-known/matrix/client",
+        ] {
+            assert!(is_browser_route(path), "{}", path);
+        }
+        for path in &[
+            "/_matrix/federation/v1/send/1",
+            "/_matrix/key/v2/server",
+            "/_matrix/app/v1/transactions/1",
+            "/_matrix/clientx",
+        ] {
+            assert!(!is_browser_route(path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn cors_headers_allow_authorized_requests_from_the_origin() {
+        let headers = cors_headers("https://app.example.com".to_owned());
+        let header = |name| {
+            headers
+                .iter()
+                .find(|(header, _)| *header == name)
+                .map(|(_, value)| value.as_str())
+        };
+
+        assert_eq!(
+            header("Access-Control-Allow-Origin"),
+            Some("https://app.example.com")
+        );
+        assert!(header("Access-Control-Allow-Methods")
+            .unwrap()
+            .contains("OPTIONS"));
+        let allowed_headers = header("Access-Control-Allow-Headers").unwrap();
+        assert!(allowed_headers.contains("Authorization"));
+        assert!(allowed_headers.contains("Content-Type"));
+    }
+
+    #[rocket::async_test]
+    async fn cors_headers_are_on_successful_and_failed_responses() {
+        let server = TestServer::new(vec![(
+            "cors_allow_origin",
+            "https://app.example.com".into(),
+        )])
+        .await;
+
+        let success = server
+            .client
+            .get("/_matrix/client/versions")
+            .dispatch()
+            .await;
+        assert_eq!(success.status(), Status::Ok);
+
+        // No access token
+        let failure = server
+            .client
+            .get("/_matrix/client/r0/account/whoami")
+            .dispatch()
+            .await;
+        assert!(failure.status().class().is_client_error());
+
+        for response in &[success, failure] {
+            let headers = response.headers();
+            assert_eq!(
+                headers.get_one("Access-Control-Allow-Origin"),
+                Some("https://app.example.com")
+            );
+            assert!(headers
+                .get_one("Access-Control-Allow-Methods")
+                .unwrap()
+                .contains("OPTIONS"));
+            assert!(headers
+                .get_one("Access-Control-Allow-Headers")
+                .unwrap()
+                .contains("Authorization"));
+        }
+    }
+
+    #[rocket::async_test]
+    async fn federation_routes_are_refused_while_federation_is_disabled() {
+        let server = TestServer::new(vec![("federation_disabled", true.into())]).await;
+        let client = &server.client;
+
+        let response = client
+            .get("/_matrix/federation/v1/version")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(errcode(response).await, "M_FORBIDDEN");
+
+        let response = client
+            .put("/_matrix/federation/v1/send/1")
+            .body(r#"{"origin":"remote.example","pdus":[]}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(errcode(response).await, "M_FORBIDDEN");
+
+        let response = client.get("/_matrix/key/v2/server").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(errcode(response).await, "M_NOT_FOUND");
+
+        // Local clients can still use the server
+        let response = client.get("/_matrix/client/versions").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn federation_routes_answer_while_federation_is_enabled() {
+        let server = TestServer::new(Vec::new()).await;
+
+        let response = server
+            .client
+            .get("/_matrix/federation/v1/version")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = server.client.get("/_matrix/key/v2/server").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+    }
 }
@@ -1,17 +1,17 @@
-use crate::{Error, Result};
+use crate::{database::globals, Error, Result};
 use js_int::UInt;
 use ruma::{
     events::{
         pdu::EventHash, room::member::MemberEventContent, AnyRoomEvent, AnyStateEvent,
         AnyStrippedStateEvent, AnySyncRoomEvent, AnySyncStateEvent, EventType, StateEvent,
     },
-    EventId, Raw, RoomId, ServerName, UserId,
+    EventId, Raw, RoomId, RoomVersionId, ServerName, UserId,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct PduEvent {
     pub event_id: EventId,
     pub room_id: RoomId,
@@ -35,12 +35,34 @@ pub struct PduEvent {
 }
 
 impl PduEvent {
-    pub fn redact(&mut self, reason: &PduEvent) -> Result<()> {
+    /// Removes all content keys that are not protected by the redaction algorithm of the room
+    /// version.
+    pub fn redact(&mut self, reason: &PduEvent, room_version: &RoomVersionId) -> Result<()> {
         self.unsigned.clear();
 
         let allowed: &[&str] = match self.kind {
+            // Room version 6 stopped protecting aliases, they are set in m.room.canonical_alias
+            EventType::RoomAliases
+                if matches!(
+                    room_version,
+                    RoomVersionId::Version1
+                        | RoomVersionId::Version2
+                        | RoomVersionId::Version3
+                        | RoomVersionId::Version4
+                        | RoomVersionId::Version5
+                ) =>
+            {
+                &["aliases"]
+            }
+            // Room versions 8 and 9 started protecting the fields of restricted joins
+            EventType::RoomMember if globals::redaction_keeps_join_authoriser(room_version) => {
+                &["membership", "join_authorised_via_users_server"]
+            }
             EventType::RoomMember => &["membership"],
             EventType::RoomCreate => &["creator"],
+            EventType::RoomJoinRules if globals::redaction_keeps_join_rule_allow(room_version) => {
+                &["join_rule", "allow"]
+            }
             EventType::RoomJoinRules => &["join_rule"],
             EventType::RoomPowerLevels => &[
                 "ban",
@@ -71,9 +93,7 @@ impl PduEvent {
 
         self.unsigned.insert(
             "redacted_because".to_owned(),
-            serde_json::to_string(reason)
-                .expect("PduEvent::to_string always works")
-                .into(),
+            serde_json::to_value(reason.to_room_event()).expect("Raw can always be serialized"),
         );
 
         self.content = new_content.into();
@@ -101,6 +121,13 @@ impl PduEvent {
         serde_json::from_value(json).expect("Raw::from_value always works")
     }
 
+    /// The event as it is sent over federation. Clients can request this format with the
+    /// `event_format` of a filter.
+    pub fn to_federation_event<T>(&self) -> Raw<T> {
+        serde_json::from_value(serde_json::to_value(self).expect("PduEvent can be serialized"))
+            .expect("Raw::from_value always works")
+    }
+
     pub fn to_room_event(&self) -> Raw<AnyRoomEvent> {
         let mut json = json!({
             "content": self.content,
@@ -3,8 +3,9 @@ use ruma::{
         Action, ConditionalPushRule, ConditionalPushRuleInit, PatternedPushRule,
         PatternedPushRuleInit, PushCondition, RoomMemberCountIs, Ruleset, Tweak,
     },
-    UserId,
+    RoomId, UserId,
 };
+use serde_json::Value;
 
 pub fn default_pushrules(user_id: &UserId) -> Ruleset {
     let mut rules = Ruleset::default();
@@ -245,3 +246,449 @@ pub fn encrypted_rule() -> ConditionalPushRule {
     }
     .into()
 }
+
+/// The rule kinds in the order they are evaluated.
+pub const RULE_KINDS: [&str; 5] = ["override", "content", "room", "sender", "underride"];
+
+/// What should happen to an event according to the push rules of a user.
+#[derive(Debug, Default, PartialEq)]
+pub struct PushActions {
+    pub notify: bool,
+    pub highlight: bool,
+    pub sound: Option<String>,
+}
+
+/// Everything about the receiving user and the room that push conditions can depend on.
+pub struct PushContext<'a> {
+    pub user_id: &'a UserId,
+    pub user_display_name: Option<&'a str>,
+    pub room_id: &'a RoomId,
+    pub member_count: u64,
+    /// Content of the m.room.power_levels event of the room
+    pub power_levels: Option<&'a Value>,
+}
+
+/// Returns the actions of the first enabled rule that matches the event.
+///
+/// The rules are evaluated in their JSON form, so it works the same for rules we created and for
+/// rules that were set by clients.
+pub fn evaluate(ruleset: &Ruleset, event: &Value, context: &PushContext<'_>) -> PushActions {
+    // The user's own events never notify them
+    if event.get("sender").and_then(|s| s.as_str()) == Some(context.user_id.as_str()) {
+        return PushActions::default();
+    }
+
+    let rules = match serde_json::to_value(ruleset) {
+        Ok(rules) => rules,
+        Err(_) => return PushActions::default(),
+    };
+
+    for kind in RULE_KINDS.iter() {
+        for rule in rules
+            .get(kind)
+            .and_then(|rules| rules.as_array())
+            .into_iter()
+            .flatten()
+        {
+            if rule.get("enabled").and_then(|e| e.as_bool()) != Some(true) {
+                continue;
+            }
+
+            let rule_id = rule.get("rule_id").and_then(|id| id.as_str());
+
+            let matches = match *kind {
+                "content" => rule
+                    .get("pattern")
+                    .and_then(|pattern| pattern.as_str())
+                    .map_or(false, |pattern| {
+                        event_body(event).map_or(false, |body| matches_words(body, pattern, false))
+                    }),
+                "room" => rule_id == Some(context.room_id.as_str()),
+                "sender" => rule_id == event.get("sender").and_then(|s| s.as_str()),
+                _ => rule
+                    .get("conditions")
+                    .and_then(|conditions| conditions.as_array())
+                    .map_or(true, |conditions| {
+                        conditions
+                            .iter()
+                            .all(|condition| condition_matches(condition, event, context))
+                    }),
+            };
+
+            if matches {
+                return parse_actions(rule.get("actions"));
+            }
+        }
+    }
+
+    PushActions::default()
+}
+
+fn parse_actions(actions: Option<&Value>) -> PushActions {
+    let mut result = PushActions::default();
+
+    for action in actions.and_then(|a| a.as_array()).into_iter().flatten() {
+        match action {
+            Value::String(action) if action == "notify" || action == "coalesce" => {
+                result.notify = true
+            }
+            Value::Object(tweak) => match tweak.get("set_tweak").and_then(|t| t.as_str()) {
+                Some("highlight") => {
+                    result.highlight = tweak.get("value").and_then(|v| v.as_bool()).unwrap_or(true)
+                }
+                Some("sound") => {
+                    result.sound = tweak
+                        .get("value")
+                        .and_then(|v| v.as_str())
+                        .map(|sound| sound.to_owned())
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    // Tweaks don't mean anything for events that don't notify
+    if !result.notify {
+        result = PushActions::default();
+    }
+
+    result
+}
+
+fn condition_matches(condition: &Value, event: &Value, context: &PushContext<'_>) -> bool {
+    match condition.get("kind").and_then(|k| k.as_str()) {
+        Some("event_match") => {
+            let key = condition.get("key").and_then(|k| k.as_str());
+            let pattern = condition.get("pattern").and_then(|p| p.as_str());
+            let (key, pattern) = match (key, pattern) {
+                (Some(key), Some(pattern)) => (key, pattern),
+                _ => return false,
+            };
+
+            let value = match key
+                .split('.')
+                .try_fold(event, |value, part| value.get(part))
+                .and_then(|value| value.as_str())
+            {
+                Some(value) => value,
+                None => return false,
+            };
+
+            if key == "content.body" {
+                matches_words(value, pattern, false)
+            } else {
+                glob_matches(pattern, value)
+            }
+        }
+        Some("contains_display_name") => match (event_body(event), context.user_display_name) {
+            (Some(body), Some(display_name)) if !display_name.is_empty() => {
+                matches_words(body, display_name, true)
+            }
+            _ => false,
+        },
+        Some("room_member_count") => condition
+            .get("is")
+            .and_then(|is| is.as_str())
+            .map_or(false, |is| member_count_matches(is, context.member_count)),
+        Some("sender_notification_permission") => {
+            let (key, power_levels) = match (
+                condition.get("key").and_then(|k| k.as_str()),
+                context.power_levels,
+            ) {
+                (Some(key), Some(power_levels)) => (key, power_levels),
+                _ => return false,
+            };
+
+            let required = power_levels
+                .get("notifications")
+                .and_then(|n| n.get(key))
+                .and_then(|level| level.as_i64())
+                .unwrap_or(50);
+
+            let sender_level = event
+                .get("sender")
+                .and_then(|sender| sender.as_str())
+                .and_then(|sender| power_levels.get("users")?.get(sender)?.as_i64())
+                .or_else(|| power_levels.get("users_default")?.as_i64())
+                .unwrap_or(0);
+
+            sender_level >= required
+        }
+        // Conditions we don't understand never match
+        _ => false,
+    }
+}
+
+fn event_body(event: &Value) -> Option<&str> {
+    event.get("content")?.get("body")?.as_str()
+}
+
+/// Parses conditions like `2`, `==2`, `<10` or `>=3`.
+fn member_count_matches(is: &str, member_count: u64) -> bool {
+    let operator_len = is
+        .find(|c: char| c.is_ascii_digit())
+        .unwrap_or_else(|| is.len());
+    let (operator, count) = is.split_at(operator_len);
+
+    let count = match count.parse::<u64>() {
+        Ok(count) => count,
+        Err(_) => return false,
+    };
+
+    match operator {
+        "" | "==" => member_count == count,
+        "<" => member_count < count,
+        ">" => member_count > count,
+        "<=" => member_count <= count,
+        ">=" => member_count >= count,
+        _ => false,
+    }
+}
+
+/// Returns true if the pattern matches a sequence of whole words in the text. Case insensitive,
+/// `*` and `?` are wildcards unless `literal` is set.
+fn matches_words(text: &str, pattern: &str, literal: bool) -> bool {
+    let text = text.to_lowercase().chars().collect::<Vec<_>>();
+    let pattern = pattern.to_lowercase().chars().collect::<Vec<_>>();
+
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+    let is_boundary = |j: usize| {
+        j == 0 || j == text.len() || !is_word_char(text[j - 1]) || !is_word_char(text[j])
+    };
+
+    // matches[j] is true if the pattern so far matches the text from any word start up to j
+    let mut matches = (0..=text.len()).map(is_boundary).collect::<Vec<_>>();
+    advance(&mut matches, &pattern, &text, literal);
+
+    (0..=text.len()).any(|j| matches[j] && is_boundary(j))
+}
+
+/// Case insensitive glob matching where `*` matches any sequence and `?` any single character.
+fn glob_matches(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.to_lowercase().chars().collect::<Vec<_>>();
+    let value = value.to_lowercase().chars().collect::<Vec<_>>();
+
+    let mut matches = vec![false; value.len() + 1];
+    matches[0] = true;
+    advance(&mut matches, &pattern, &value, false);
+
+    matches[value.len()]
+}
+
+/// Moves the match positions forward over every character of the pattern.
+fn advance(matches: &mut Vec<bool>, pattern: &[char], value: &[char], literal: bool) {
+    for &p in pattern {
+        let mut next = vec![false; value.len() + 1];
+        for j in 0..=value.len() {
+            next[j] = match p {
+                '*' if !literal => matches[j] || (j > 0 && next[j - 1]),
+                '?' if !literal => j > 0 && matches[j - 1],
+                c => j > 0 && matches[j - 1] && value[j - 1] == c,
+            };
+        }
+        *matches = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{default_pushrules, evaluate, PushActions, PushContext};
+    use ruma::{push::Ruleset, RoomId, UserId};
+    use serde_json::{json, Value};
+    use std::convert::TryFrom;
+
+    fn ruleset(rules: Value) -> Ruleset {
+        let mut ruleset = json!({
+            "override": [],
+            "content": [],
+            "room": [],
+            "sender": [],
+            "underride": [],
+        });
+        for (kind, rules) in rules.as_object().unwrap() {
+            ruleset[kind] = rules.clone();
+        }
+        serde_json::from_value(ruleset).unwrap()
+    }
+
+    fn rule(rule_id: &str, extra: Value, actions: Value) -> Value {
+        let mut rule = json!({
+            "rule_id": rule_id,
+            "default": false,
+            "enabled": true,
+            "actions": actions,
+        });
+        for (key, value) in extra.as_object().unwrap() {
+            rule[key] = value.clone();
+        }
+        rule
+    }
+
+    fn message(sender: &str, body: &str) -> Value {
+        json!({
+            "type": "m.room.message",
+            "sender": sender,
+            "room_id": "!room:example.com",
+            "content": { "msgtype": "m.text", "body": body },
+        })
+    }
+
+    fn actions(ruleset: &Ruleset, event: &Value) -> PushActions {
+        let user_id = UserId::try_from("@alice:example.com").unwrap();
+        let room_id = RoomId::try_from("!room:example.com").unwrap();
+        let power_levels = json!({ "users": { "@admin:example.com": 100 } });
+
+        evaluate(
+            ruleset,
+            event,
+            &PushContext {
+                user_id: &user_id,
+                user_display_name: Some("Alice Liddell"),
+                room_id: &room_id,
+                member_count: 5,
+                power_levels: Some(&power_levels),
+            },
+        )
+    }
+
+    fn notify(highlight: bool) -> PushActions {
+        PushActions {
+            notify: true,
+            highlight,
+            sound: None,
+        }
+    }
+
+    #[test]
+    fn override_rules_match_conditions_and_win_over_other_kinds() {
+        let rules = ruleset(json!({
+            "override": [rule(
+                "silence_bots",
+                json!({ "conditions": [
+                    { "kind": "event_match", "key": "sender", "pattern": "@bot*:example.com" },
+                ] }),
+                json!(["dont_notify"]),
+            )],
+            "underride": [rule("all", json!({ "conditions": [] }), json!(["notify"]))],
+        }));
+
+        assert_eq!(
+            actions(&rules, &message("@bot1:example.com", "hi")),
+            PushActions::default()
+        );
+        assert_eq!(
+            actions(&rules, &message("@bob:example.com", "hi")),
+            notify(false)
+        );
+    }
+
+    #[test]
+    fn content_rules_match_whole_words_of_the_body() {
+        let rules = ruleset(json!({
+            "content": [rule(
+                "cake",
+                json!({ "pattern": "cake*" }),
+                json!(["notify", { "set_tweak": "highlight" }, { "set_tweak": "sound", "value": "default" }]),
+            )],
+        }));
+
+        assert_eq!(
+            actions(&rules, &message("@bob:example.com", "Who wants CAKES?")),
+            PushActions {
+                notify: true,
+                highlight: true,
+                sound: Some("default".to_owned()),
+            }
+        );
+        assert_eq!(
+            actions(&rules, &message("@bob:example.com", "pancakes")),
+            PushActions::default()
+        );
+    }
+
+    #[test]
+    fn room_and_sender_rules_match_by_rule_id() {
+        let rules = ruleset(json!({
+            "room": [rule("!room:example.com", json!({}), json!(["notify"]))],
+            "sender": [rule("@bob:example.com", json!({}), json!(["notify", { "set_tweak": "highlight" }]))],
+        }));
+        assert_eq!(
+            actions(&rules, &message("@bob:example.com", "hi")),
+            notify(false)
+        );
+
+        let rules = ruleset(json!({
+            "sender": [rule("@bob:example.com", json!({}), json!(["notify", { "set_tweak": "highlight" }]))],
+        }));
+        assert_eq!(
+            actions(&rules, &message("@bob:example.com", "hi")),
+            notify(true)
+        );
+        assert_eq!(
+            actions(&rules, &message("@carol:example.com", "hi")),
+            PushActions::default()
+        );
+    }
+
+    #[test]
+    fn underride_rules_check_member_count_and_notification_permission() {
+        let rules = ruleset(json!({
+            "underride": [
+                rule(
+                    "room_notif",
+                    json!({ "conditions": [
+                        { "kind": "event_match", "key": "content.body", "pattern": "@room" },
+                        { "kind": "sender_notification_permission", "key": "room" },
+                    ] }),
+                    json!(["notify", { "set_tweak": "highlight" }]),
+                ),
+                rule(
+                    "small_room",
+                    json!({ "conditions": [{ "kind": "room_member_count", "is": "<10" }] }),
+                    json!(["notify"]),
+                ),
+            ],
+        }));
+
+        assert_eq!(
+            actions(&rules, &message("@admin:example.com", "@room hi")),
+            notify(true)
+        );
+        // Bob doesn't have the power level to notify the room, but the room is small
+        assert_eq!(
+            actions(&rules, &message("@bob:example.com", "@room hi")),
+            notify(false)
+        );
+    }
+
+    #[test]
+    fn disabled_rules_and_own_events_never_notify() {
+        let mut all = rule("all", json!({ "conditions": [] }), json!(["notify"]));
+        let rules = ruleset(json!({ "underride": [all.clone()] }));
+        assert_eq!(
+            actions(&rules, &message("@alice:example.com", "hi")),
+            PushActions::default()
+        );
+
+        all["enabled"] = json!(false);
+        let rules = ruleset(json!({ "underride": [all] }));
+        assert_eq!(
+            actions(&rules, &message("@bob:example.com", "hi")),
+            PushActions::default()
+        );
+    }
+
+    #[test]
+    fn default_rules_highlight_mentions_and_notify_for_messages() {
+        let user_id = UserId::try_from("@alice:example.com").unwrap();
+        let rules = default_pushrules(&user_id);
+
+        assert!(actions(&rules, &message("@bob:example.com", "hey alice!")).highlight);
+        assert!(actions(&rules, &message("@bob:example.com", "Alice Liddell, hi")).highlight);
+        assert_eq!(
+            actions(&rules, &message("@bob:example.com", "hi")),
+            notify(false)
+        );
+    }
+}
@@ -1,25 +1,53 @@
 use crate::Error;
 use ruma::identifiers::{DeviceId, UserId};
-use std::{convert::TryInto, ops::Deref};
+use std::{
+    convert::{TryFrom, TryInto},
+    net::IpAddr,
+    ops::Deref,
+};
 
 #[cfg(feature = "conduit_bin")]
 use {
     crate::utils,
-    log::warn,
     rocket::{
         data::{
             Data, FromDataFuture, FromTransformedData, Transform, TransformFuture, Transformed,
         },
         http::Status,
         outcome::Outcome::*,
+        request::{self, FromRequest},
         response::{self, Responder},
         tokio::io::AsyncReadExt,
         Request, State,
     },
     ruma::api::IncomingRequest,
     std::io::Cursor,
+    tracing::warn,
 };
 
+/// Tells the unauthorized catcher that the access token of the request expired, so the client
+/// can refresh it instead of logging in again.
+pub struct TokenExpired(pub bool);
+
+/// The span every request runs in. It is created before the request is routed and closed when
+/// the request is dropped. The id is also sent back in the `X-Request-Id` header.
+pub struct RequestSpan {
+    pub id: String,
+    pub span: tracing::Span,
+    pub started: std::time::Instant,
+}
+
+impl RequestSpan {
+    /// Used for requests that didn't go through the tracing fairing.
+    pub fn none() -> Self {
+        Self {
+            id: String::new(),
+            span: tracing::Span::none(),
+            started: std::time::Instant::now(),
+        }
+    }
+}
+
 /// This struct converts rocket requests into ruma structs by converting them into http requests
 /// first.
 pub struct Ruma<T> {
@@ -27,6 +55,26 @@ pub struct Ruma<T> {
     pub sender_id: Option<UserId>,
     pub device_id: Option<Box<DeviceId>>,
     pub json_body: Option<Box<serde_json::value::RawValue>>, // This is None when body is not a valid string
+    pub client_ip: Option<IpAddr>, // X-Real-IP is only used from trusted proxies, see client_ip
+    pub appservice_id: Option<String>, // Id of the registration if an appservice sent the request
+}
+
+/// The address of the client for routes that don't use `Ruma`, see `client_ip`.
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[cfg(feature = "conduit_bin")]
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for ClientIp {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let db = request
+            .guard::<State<'_, crate::Database<'_>>>()
+            .await
+            .expect("database was loaded");
+
+        Success(ClientIp(client_ip(request, &db)))
+    }
 }
 
 #[cfg(feature = "conduit_bin")]
@@ -53,29 +101,108 @@ impl<'a, T: IncomingRequest> FromTransformedData<'a> for Ruma<T> {
                 .await
                 .expect("database was loaded");
 
-            let (user_id, device_id) = if T::METADATA.requires_authentication {
-                // Get token from header or query value
-                let token = match request
-                    .headers()
-                    .get_one("Authorization")
-                    .map(|s| s[7..].to_owned()) // Split off "Bearer "
-                    .or_else(|| request.get_query_value("access_token").and_then(|r| r.ok()))
+            // Requests that are already running can still finish
+            if db.globals.shutdown().is_started() {
+                return Failure((Status::ServiceUnavailable, ()));
+            }
+
+            // Get token from header or query value
+            let token = request
+                .headers()
+                .get_one("Authorization")
+                .and_then(|header| header.strip_prefix("Bearer "))
+                .map(|token| token.to_owned())
+                .or_else(|| request.get_query_value("access_token").and_then(|r| r.ok()));
+
+            // Appservices are recognized even on endpoints without authentication, so they can
+            // register the users of their namespaces
+            let appservice = token
+                .as_deref()
+                .and_then(|token| db.globals.appservice_from_token(token));
+
+            let (user_id, device_id) = if let Some(appservice) =
+                appservice.filter(|_| T::METADATA.requires_authentication)
+            {
+                // Appservices act as their sender or masquerade as a user of their namespaces
+                let user_id = match request
+                    .get_query_value::<String>("user_id")
+                    .and_then(|r| r.ok())
                 {
-                    // TODO: M_MISSING_TOKEN
-                    None => return Failure((Status::Unauthorized, ())),
-                    Some(token) => token,
+                    Some(user_id) => match UserId::try_from(user_id) {
+                        Ok(user_id)
+                            if user_id.server_name() == db.globals.server_name()
+                                && appservice.is_user_match(&user_id) =>
+                        {
+                            user_id
+                        }
+                        // TODO: M_FORBIDDEN
+                        _ => return Failure((Status::Unauthorized, ())),
+                    },
+                    None => appservice.sender.clone(),
                 };
 
-                // Check if token is valid
-                match db.users.find_from_token(&token).unwrap() {
-                    // TODO: M_UNKNOWN_TOKEN
+                if !db.users.exists(&user_id).unwrap() {
+                    if user_id != appservice.sender {
+                        // The appservice has to register its users first
+                        return Failure((Status::Unauthorized, ()));
+                    }
+                    crate::client_server::create_external_user(&db, &user_id).unwrap();
+                }
+
+                // Appservice requests have no device, the id of the registration is used instead
+                // so transaction ids still work
+                (Some(user_id), Some(appservice.id.clone().into()))
+            } else if T::METADATA.requires_authentication {
+                match token {
+                    // Passwords can be reset with an email address without logging in
+                    None if T::METADATA.path == "/_matrix/client/r0/account/password" => {
+                        (None, None)
+                    }
+                    // The unauthorized catcher responds with M_MISSING_TOKEN
                     None => return Failure((Status::Unauthorized, ())),
-                    Some((user_id, device_id)) => (Some(user_id), Some(device_id.into())),
+                    // Check if token is valid
+                    Some(token) => match db.users.find_from_token(&token).unwrap() {
+                        // The unauthorized catcher responds with M_UNKNOWN_TOKEN
+                        None => return Failure((Status::Unauthorized, ())),
+                        Some(_) if db.users.token_expired(&token).unwrap() => {
+                            request.local_cache(|| TokenExpired(true));
+                            return Failure((Status::Unauthorized, ()));
+                        }
+                        Some((user_id, device_id)) => {
+                            // The forbidden catcher responds with M_GUEST_ACCESS_FORBIDDEN
+                            if db.users.is_guest(&user_id).unwrap()
+                                && !guest_allowed(
+                                    &T::METADATA.method,
+                                    T::METADATA.path,
+                                    request.uri().path(),
+                                )
+                            {
+                                return Failure((Status::Forbidden, ()));
+                            }
+
+                            let device_id: Box<DeviceId> = device_id.into();
+                            db.users
+                                .update_device_last_seen(
+                                    &user_id,
+                                    &device_id,
+                                    client_ip(request, &db),
+                                )
+                                .unwrap();
+
+                            (Some(user_id), Some(device_id))
+                        }
+                    },
                 }
             } else {
                 (None, None)
             };
 
+            let span = &request.local_cache(RequestSpan::none).span;
+            span.record("endpoint", &T::METADATA.name);
+            if let Some(user_id) = &user_id {
+                span.record("user_id", &tracing::field::display(user_id));
+            }
+
             let mut http_request = http::Request::builder()
                 .uri(request.uri().to_string())
                 .method(&*request.method().to_string());
@@ -83,13 +210,29 @@ impl<'a, T: IncomingRequest> FromTransformedData<'a> for Ruma<T> {
                 http_request = http_request.header(header.name.as_str(), &*header.value);
             }
 
-            let limit = db.globals.max_request_size();
-            let mut handle = data.open().take(limit.into());
+            // Bigger bodies are rejected instead of cut off. The payload too large catcher responds
+            // with M_TOO_LARGE
+            let limit = if T::METADATA.path == "/_matrix/media/r0/upload" {
+                db.globals.media_max_upload_size()
+            } else {
+                db.globals.max_request_size()
+            };
+            let content_length = request
+                .headers()
+                .get_one("Content-Length")
+                .and_then(|length| length.parse::<u64>().ok());
+            if content_length.map_or(false, |length| length > limit.into()) {
+                return Failure((Status::PayloadTooLarge, ()));
+            }
+
+            let mut handle = data.open().take(u64::from(limit) + 1);
             let mut body = Vec::new();
             handle.read_to_end(&mut body).await.unwrap();
+            if body.len() as u64 > limit.into() {
+                return Failure((Status::PayloadTooLarge, ()));
+            }
 
             let http_request = http_request.body(body.clone()).unwrap();
-            log::info!("{:?}", http_request);
 
             match T::try_from(http_request) {
                 Ok(t) => Success(Ruma {
@@ -100,9 +243,11 @@ impl<'a, T: IncomingRequest> FromTransformedData<'a> for Ruma<T> {
                     json_body: utils::string_from_bytes(&body)
                         .ok()
                         .and_then(|s| serde_json::value::RawValue::from_string(s).ok()),
+                    client_ip: client_ip(request, &db),
+                    appservice_id: appservice.map(|appservice| appservice.id.clone()),
                 }),
                 Err(e) => {
-                    warn!("{:?}", e);
+                    span.in_scope(|| warn!("Invalid request: {:?}", e));
                     Failure((Status::BadRequest, ()))
                 }
             }
@@ -110,6 +255,95 @@ impl<'a, T: IncomingRequest> FromTransformedData<'a> for Ruma<T> {
     }
 }
 
+/// The endpoints guests can use, as ruma paths without the `/_matrix/client/r0` prefix.
+#[cfg(feature = "conduit_bin")]
+const GUEST_ENDPOINTS: &[(&str, &str)] = &[
+    ("GET", "/sync"),
+    ("GET", "/events"),
+    ("GET", "/rooms/:/state"),
+    ("GET", "/rooms/:/state/:"),
+    ("GET", "/rooms/:/state/:/:"),
+    ("GET", "/rooms/:/context/:"),
+    ("GET", "/rooms/:/event/:"),
+    ("GET", "/rooms/:/messages"),
+    ("GET", "/rooms/:/members"),
+    ("GET", "/rooms/:/joined_members"),
+    ("GET", "/rooms/:/initialSync"),
+    ("PUT", "/rooms/:/send/:/:"),
+    ("POST", "/rooms/:/join"),
+    ("POST", "/join/:"),
+    ("POST", "/rooms/:/leave"),
+    ("PUT", "/rooms/:/typing/:"),
+    ("POST", "/rooms/:/receipt/:/:"),
+    ("POST", "/rooms/:/read_markers"),
+    ("GET", "/presence/:/status"),
+    ("PUT", "/presence/:/status"),
+    ("GET", "/profile/:"),
+    ("GET", "/profile/:/displayname"),
+    ("PUT", "/profile/:/displayname"),
+    ("GET", "/profile/:/avatar_url"),
+    ("PUT", "/sendToDevice/:/:"),
+    ("GET", "/devices"),
+    ("GET", "/devices/:"),
+    ("PUT", "/devices/:"),
+    ("DELETE", "/devices/:"),
+    ("POST", "/keys/upload"),
+    ("POST", "/keys/query"),
+    ("POST", "/keys/claim"),
+    ("GET", "/keys/changes"),
+    ("GET", "/account/whoami"),
+    ("POST", "/logout"),
+    ("GET", "/capabilities"),
+    ("GET", "/pushrules"),
+    ("GET", "/user/:/filter/:"),
+    ("POST", "/user/:/filter"),
+    ("GET", "/voip/turnServer"),
+];
+
+/// Returns the address of the client. Anyone can set X-Real-IP, so it is only used if the
+/// connection comes from a trusted proxy.
+#[cfg(feature = "conduit_bin")]
+fn client_ip(request: &Request<'_>, db: &crate::Database<'_>) -> Option<IpAddr> {
+    let remote = request.remote()?.ip();
+    if db.globals.is_trusted_proxy(remote) {
+        request.real_ip().or(Some(remote))
+    } else {
+        Some(remote)
+    }
+}
+
+/// Checks if guests can use the endpoint. Guests can only send messages, no other events.
+#[cfg(feature = "conduit_bin")]
+fn guest_allowed(method: &http::Method, ruma_path: &str, request_path: &str) -> bool {
+    // Path parameters are named differently in ruma, so we only compare the other segments
+    let ruma_path = ruma_path
+        .trim_start_matches("/_matrix/client/r0")
+        .split('/')
+        .map(|segment| {
+            if segment.starts_with(':') {
+                ":"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+
+    if !GUEST_ENDPOINTS
+        .iter()
+        .any(|(m, path)| *m == method.as_str() && *path == ruma_path)
+    {
+        return false;
+    }
+
+    if ruma_path == "/rooms/:/send/:/:" {
+        // /_matrix/client/r0/rooms/{roomId}/send/{eventType}/{txnId}
+        return request_path.split('/').nth(7) == Some("m.room.message");
+    }
+
+    true
+}
+
 impl<T> Deref for Ruma<T> {
     type Target = T;
 
@@ -154,15 +388,7 @@ where
 
                 response.sized_body(http_body.len(), Cursor::new(http_body));
 
-                response.raw_header("Access-Control-Allow-Origin", "*");
-                response.raw_header(
-                    "Access-Control-Allow-Methods",
-                    "GET, POST, PUT, DELETE, OPTIONS",
-                );
-                response.raw_header(
-                    "Access-Control-Allow-Headers",
-                    "Origin, X-Requested-With, Content-Type, Accept, Authorization",
-                );
+                // The CORS headers are added by the CORS fairing
                 response.ok()
             }
             Err(_) => Err(Status::InternalServerError),
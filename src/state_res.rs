@@ -0,0 +1,1312 @@
+//! State resolution v2, which decides the state of a room when forks of the room have
+//! conflicting state. See https://matrix.org/docs/spec/rooms/v2#state-resolution
+
+use crate::{database::globals, Error, PduEvent, Result};
+use ruma::{api::client::error::ErrorKind, events::EventType, EventId, RoomVersionId, UserId};
+use serde_json::Value;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap, HashSet},
+    convert::TryFrom,
+};
+
+/// State type + state key -> value
+pub type StateMap<T> = HashMap<(EventType, String), T>;
+
+/// Resolves the state sets of the forks of a room into one state.
+///
+/// - `auth_chains` contains the full auth chain of every state set
+/// - `events` has to contain all events of the state sets and the auth chains, unknown events are
+/// ignored
+/// - Room version 1 uses state resolution v1, which is not supported
+/// - Soft-failed events are part of the room graph and resolved like all other events
+pub fn resolve(
+    room_version: &RoomVersionId,
+    state_sets: &[StateMap<EventId>],
+    auth_chains: &[HashSet<EventId>],
+    events: &HashMap<EventId, PduEvent>,
+) -> Result<StateMap<EventId>> {
+    if let RoomVersionId::Version1 = room_version {
+        return Err(Error::BadRequest(
+            ErrorKind::UnsupportedRoomVersion,
+            "State resolution v1 is not supported.",
+        ));
+    }
+
+    let (unconflicted, conflicted) = separate(state_sets);
+    if conflicted.is_empty() {
+        return Ok(unconflicted);
+    }
+
+    let full_conflicted = conflicted
+        .iter()
+        .chain(auth_difference(auth_chains).iter())
+        .filter(|event_id| events.contains_key(*event_id))
+        .cloned()
+        .collect::<HashSet<_>>();
+
+    // Power events and the events of their auth chains that are in the full conflicted set
+    let mut power_events = HashSet::new();
+    let mut visited = HashSet::new();
+    let mut stack = full_conflicted
+        .iter()
+        .filter(|event_id| is_power_event(&events[*event_id]))
+        .cloned()
+        .collect::<Vec<_>>();
+    while let Some(event_id) = stack.pop() {
+        if !visited.insert(event_id.clone()) {
+            continue;
+        }
+        if full_conflicted.contains(&event_id) {
+            power_events.insert(event_id.clone());
+        }
+        if let Some(pdu) = events.get(&event_id) {
+            stack.extend(pdu.auth_events.iter().cloned());
+        }
+    }
+
+    let sorted_power_events = reverse_topological_power_sort(&power_events, events);
+    let resolved = iterative_auth_check(&sorted_power_events, unconflicted.clone(), events);
+
+    // The other events are ordered by the power levels the power events resolved to
+    let mut other_events = full_conflicted
+        .iter()
+        .filter(|event_id| !power_events.contains(*event_id))
+        .cloned()
+        .collect::<Vec<_>>();
+    mainline_sort(
+        &mut other_events,
+        resolved.get(&(EventType::RoomPowerLevels, String::new())),
+        events,
+    );
+    let mut resolved = iterative_auth_check(&other_events, resolved, events);
+
+    // The unconflicted state is never changed by the resolution
+    resolved.extend(unconflicted);
+
+    Ok(resolved)
+}
+
+/// Splits the state sets into the state all of them agree on and the conflicting events.
+fn separate(state_sets: &[StateMap<EventId>]) -> (StateMap<EventId>, HashSet<EventId>) {
+    let keys = state_sets
+        .iter()
+        .flat_map(|state_set| state_set.keys())
+        .collect::<HashSet<_>>();
+
+    let mut unconflicted = HashMap::new();
+    let mut conflicted = HashSet::new();
+    for key in keys {
+        let values = state_sets
+            .iter()
+            .map(|state_set| state_set.get(key))
+            .collect::<Vec<_>>();
+
+        match values[0] {
+            Some(first) if values.iter().all(|value| *value == Some(first)) => {
+                unconflicted.insert(key.clone(), first.clone());
+            }
+            _ => conflicted.extend(values.into_iter().flatten().cloned()),
+        }
+    }
+
+    (unconflicted, conflicted)
+}
+
+/// Returns the events that are in some, but not all auth chains.
+fn auth_difference(auth_chains: &[HashSet<EventId>]) -> HashSet<EventId> {
+    let union = auth_chains.iter().flatten().collect::<HashSet<_>>();
+
+    union
+        .into_iter()
+        .filter(|event_id| !auth_chains.iter().all(|chain| chain.contains(*event_id)))
+        .cloned()
+        .collect()
+}
+
+/// Power events can take away permissions of other users: power levels, join rules, the create
+/// event and kicks and bans.
+fn is_power_event(pdu: &PduEvent) -> bool {
+    match pdu.kind {
+        EventType::RoomPowerLevels | EventType::RoomJoinRules | EventType::RoomCreate => {
+            pdu.state_key.as_deref() == Some("")
+        }
+        EventType::RoomMember => {
+            let membership = pdu.content.get("membership").and_then(|m| m.as_str());
+            (membership == Some("leave") || membership == Some("ban"))
+                && pdu.state_key.as_deref() != Some(pdu.sender.as_str())
+        }
+        _ => false,
+    }
+}
+
+/// Orders the events so that auth events come before the events they authorise (Kahn's
+/// algorithm). Events that could come next are ordered by the power level of the sender
+/// (highest first), then by origin_server_ts and then by event id.
+fn reverse_topological_power_sort(
+    event_ids: &HashSet<EventId>,
+    events: &HashMap<EventId, PduEvent>,
+) -> Vec<EventId> {
+    let mut missing_auth_events = HashMap::new();
+    let mut dependents = HashMap::<_, Vec<_>>::new();
+    for event_id in event_ids {
+        let auth_events = events[event_id]
+            .auth_events
+            .iter()
+            .filter(|auth_event| event_ids.contains(*auth_event))
+            .collect::<Vec<_>>();
+
+        missing_auth_events.insert(event_id, auth_events.len());
+        for auth_event in auth_events {
+            dependents.entry(auth_event).or_default().push(event_id);
+        }
+    }
+
+    let sort_key = |event_id: &EventId| {
+        let pdu = &events[event_id];
+        (
+            Reverse(sender_power_level(pdu, events)),
+            pdu.origin_server_ts,
+            event_id.clone(),
+        )
+    };
+
+    let mut ready = missing_auth_events
+        .iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(event_id, _)| sort_key(*event_id))
+        .collect::<BTreeSet<_>>();
+
+    let mut sorted = Vec::new();
+    while let Some(next) = ready.iter().next().cloned() {
+        ready.remove(&next);
+        let (_, _, event_id) = next;
+
+        for dependent in dependents.get(&event_id).into_iter().flatten() {
+            let count = missing_auth_events
+                .get_mut(dependent)
+                .expect("every dependent has a count");
+            *count -= 1;
+            if *count == 0 {
+                ready.insert(sort_key(*dependent));
+            }
+        }
+
+        sorted.push(event_id);
+    }
+
+    sorted
+}
+
+/// Returns the power level of the sender in the power levels of the auth events of the event.
+fn sender_power_level(pdu: &PduEvent, events: &HashMap<EventId, PduEvent>) -> i64 {
+    let auth_events = pdu
+        .auth_events
+        .iter()
+        .filter_map(|event_id| events.get(event_id))
+        .collect::<Vec<_>>();
+
+    let power_levels = auth_events
+        .iter()
+        .find(|auth_event| auth_event.kind == EventType::RoomPowerLevels);
+    let create = auth_events
+        .iter()
+        .find(|auth_event| auth_event.kind == EventType::RoomCreate);
+
+    user_level(power_levels.copied(), create.copied(), &pdu.sender)
+}
+
+/// Orders the events by the position of their closest power levels event in the mainline of
+/// `power_levels`, which are the power levels events it was authorised by. Events of older power
+/// levels come first, then the events are ordered by origin_server_ts and event id.
+fn mainline_sort(
+    event_ids: &mut Vec<EventId>,
+    power_levels: Option<&EventId>,
+    events: &HashMap<EventId, PduEvent>,
+) {
+    let power_levels_auth_event = |pdu: &PduEvent| {
+        pdu.auth_events
+            .iter()
+            .find(|event_id| {
+                events.get(event_id).map_or(false, |auth_event| {
+                    auth_event.kind == EventType::RoomPowerLevels
+                })
+            })
+            .cloned()
+    };
+
+    let mut mainline = Vec::new();
+    let mut current = power_levels.cloned();
+    while let Some(event_id) = current {
+        current = events.get(&event_id).and_then(power_levels_auth_event);
+        mainline.push(event_id);
+    }
+
+    // The oldest power levels event gets the smallest position
+    let positions = mainline
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(position, event_id)| (event_id.clone(), position + 1))
+        .collect::<HashMap<_, _>>();
+
+    let mainline_position = |event_id: &EventId| {
+        let mut current = Some(event_id.clone());
+        let mut visited = HashSet::new();
+        while let Some(event_id) = current {
+            if let Some(position) = positions.get(&event_id) {
+                return *position;
+            }
+            if !visited.insert(event_id.clone()) {
+                break;
+            }
+            current = events.get(&event_id).and_then(power_levels_auth_event);
+        }
+        0
+    };
+
+    event_ids.sort_by_cached_key(|event_id| {
+        (
+            mainline_position(event_id),
+            events[event_id].origin_server_ts,
+            event_id.clone(),
+        )
+    });
+}
+
+/// Adds the events one after another to the state if they are allowed by their auth events,
+/// where the state replaces the auth events of the same type.
+fn iterative_auth_check(
+    event_ids: &[EventId],
+    mut state: StateMap<EventId>,
+    events: &HashMap<EventId, PduEvent>,
+) -> StateMap<EventId> {
+    for event_id in event_ids {
+        let pdu = &events[event_id];
+        let state_key = match &pdu.state_key {
+            Some(state_key) => state_key.clone(),
+            None => continue,
+        };
+
+        let mut auth_state = HashMap::new();
+        for auth_event in pdu
+            .auth_events
+            .iter()
+            .filter_map(|event_id| events.get(event_id))
+        {
+            if let Some(auth_state_key) = &auth_event.state_key {
+                auth_state.insert(
+                    (auth_event.kind.clone(), auth_state_key.clone()),
+                    auth_event,
+                );
+            }
+        }
+        for key in auth_types(pdu) {
+            if let Some(current) = state.get(&key).and_then(|event_id| events.get(event_id)) {
+                auth_state.insert(key, current);
+            }
+        }
+
+        if auth_check(pdu, &auth_state) {
+            state.insert((pdu.kind.clone(), state_key), event_id.clone());
+        }
+    }
+
+    state
+}
+
+/// Checks the event against the auth rules with its own auth events. Auth events that are not in
+/// `events` are missing, then the event is rejected.
+pub fn allowed_by_auth_events(pdu: &PduEvent, events: &HashMap<EventId, PduEvent>) -> bool {
+    let mut auth_state = HashMap::new();
+    for auth_event_id in &pdu.auth_events {
+        let auth_event = match events.get(auth_event_id) {
+            Some(auth_event) => auth_event,
+            None => return false,
+        };
+        if let Some(state_key) = &auth_event.state_key {
+            auth_state.insert((auth_event.kind.clone(), state_key.clone()), auth_event);
+        }
+    }
+
+    auth_check(pdu, &auth_state)
+}
+
+/// Checks the event against the auth rules with the state of the room instead of its auth
+/// events. Events that are allowed by their auth events but not by the current state have to be
+/// soft-failed.
+pub fn allowed_by_state(pdu: &PduEvent, state: &StateMap<PduEvent>) -> bool {
+    let auth_state: HashMap<_, _> = auth_types(pdu)
+        .into_iter()
+        .filter_map(|key| {
+            let event = state.get(&key)?;
+            Some((key, event))
+        })
+        .collect();
+
+    auth_check(pdu, &auth_state)
+}
+
+/// The state the auth rules look at for this event.
+fn auth_types(pdu: &PduEvent) -> Vec<(EventType, String)> {
+    let mut auth_types = vec![
+        (EventType::RoomCreate, String::new()),
+        (EventType::RoomPowerLevels, String::new()),
+        (EventType::RoomMember, pdu.sender.to_string()),
+    ];
+
+    if pdu.kind == EventType::RoomMember {
+        if let Some(state_key) = &pdu.state_key {
+            auth_types.push((EventType::RoomMember, state_key.clone()));
+        }
+        auth_types.push((EventType::RoomJoinRules, String::new()));
+
+        if let Some(authoriser) = pdu
+            .content
+            .get("join_authorised_via_users_server")
+            .and_then(|authoriser| authoriser.as_str())
+        {
+            auth_types.push((EventType::RoomMember, authoriser.to_owned()));
+        }
+
+        if let Some(token) = pdu
+            .content
+            .get("third_party_invite")
+            .and_then(|invite| invite.get("signed"))
+            .and_then(|signed| signed.get("token"))
+            .and_then(|token| token.as_str())
+        {
+            auth_types.push((EventType::RoomThirdPartyInvite, token.to_owned()));
+        }
+    }
+
+    auth_types
+}
+
+/// Checks the event against the auth rules of room versions 2 to 10. Join rules are only allowed
+/// in the room versions that added them.
+fn auth_check(pdu: &PduEvent, auth_state: &HashMap<(EventType, String), &PduEvent>) -> bool {
+    if pdu.kind == EventType::RoomCreate {
+        return pdu.state_key.as_deref() == Some("") && pdu.auth_events.is_empty();
+    }
+
+    let create = match auth_state.get(&(EventType::RoomCreate, String::new())) {
+        Some(create) => *create,
+        None => return false,
+    };
+    if create.content.get("m.federate") == Some(&Value::Bool(false))
+        && pdu.sender.server_name() != create.sender.server_name()
+    {
+        return false;
+    }
+
+    let power_levels = auth_state
+        .get(&(EventType::RoomPowerLevels, String::new()))
+        .copied();
+    let sender_level = user_level(power_levels, Some(create), &pdu.sender);
+    let sender_membership = membership(auth_state, pdu.sender.as_str());
+
+    if pdu.kind == EventType::RoomMember {
+        return member_auth_check(pdu, auth_state, create, power_levels, sender_level);
+    }
+
+    if sender_membership != "join" {
+        return false;
+    }
+
+    if sender_level < event_level(power_levels, &pdu.kind, pdu.state_key.is_some()) {
+        return false;
+    }
+
+    // State keys that are user ids can only be set by that user
+    if let Some(state_key) = &pdu.state_key {
+        if state_key.starts_with('@') && state_key != pdu.sender.as_str() {
+            return false;
+        }
+    }
+
+    if pdu.kind == EventType::RoomPowerLevels {
+        let integer_levels = globals::integer_power_levels(&room_version(create));
+        if integer_levels && !only_integer_levels(&pdu.content) {
+            return false;
+        }
+
+        return power_levels_change_allowed(power_levels, pdu, sender_level, integer_levels);
+    }
+
+    // Redactions are checked when they are applied, event ids of these room versions don't
+    // contain a server name
+    true
+}
+
+fn member_auth_check(
+    pdu: &PduEvent,
+    auth_state: &HashMap<(EventType, String), &PduEvent>,
+    create: &PduEvent,
+    power_levels: Option<&PduEvent>,
+    sender_level: i64,
+) -> bool {
+    let target = match pdu
+        .state_key
+        .as_ref()
+        .and_then(|state_key| UserId::try_from(state_key.as_str()).ok())
+    {
+        Some(target) => target,
+        None => return false,
+    };
+
+    let target_membership = membership(auth_state, target.as_str());
+    let sender_membership = membership(auth_state, pdu.sender.as_str());
+    let target_level = user_level(power_levels, Some(create), &target);
+    let join_rule = auth_state
+        .get(&(EventType::RoomJoinRules, String::new()))
+        .and_then(|join_rules| join_rules.content.get("join_rule"))
+        .and_then(|join_rule| join_rule.as_str())
+        .filter(|join_rule| globals::join_rule_supported(&room_version(create), join_rule))
+        .unwrap_or("invite");
+
+    match pdu.content.get("membership").and_then(|m| m.as_str()) {
+        Some("join") => {
+            // The creator joins right after the create event
+            if pdu.prev_events.len() == 1
+                && pdu.prev_events[0] == create.event_id
+                && target == creator(create)
+            {
+                return true;
+            }
+
+            if pdu.sender != target || target_membership == "ban" {
+                return false;
+            }
+
+            match join_rule {
+                "public" => true,
+                "invite" | "knock" => target_membership == "join" || target_membership == "invite",
+                "restricted" | "knock_restricted" => {
+                    if target_membership == "join" || target_membership == "invite" {
+                        return true;
+                    }
+
+                    // The authorising user has to be in the room and allowed to invite
+                    pdu.content
+                        .get("join_authorised_via_users_server")
+                        .and_then(|authoriser| authoriser.as_str())
+                        .and_then(|authoriser| UserId::try_from(authoriser).ok())
+                        .map_or(false, |authoriser| {
+                            membership(auth_state, authoriser.as_str()) == "join"
+                                && user_level(power_levels, Some(create), &authoriser)
+                                    >= named_level(power_levels, "invite", 0)
+                        })
+                }
+                _ => false,
+            }
+        }
+        Some("invite") => {
+            if let Some(third_party_invite) = pdu.content.get("third_party_invite") {
+                let token = third_party_invite
+                    .get("signed")
+                    .and_then(|signed| signed.get("token"))
+                    .and_then(|token| token.as_str())
+                    .unwrap_or_default();
+
+                return target_membership != "ban"
+                    && third_party_invite_allowed(
+                        &target,
+                        &pdu.sender,
+                        third_party_invite,
+                        auth_state
+                            .get(&(EventType::RoomThirdPartyInvite, token.to_owned()))
+                            .map(|invite| (&invite.sender, &invite.content)),
+                    );
+            }
+
+            sender_membership == "join"
+                && target_membership != "join"
+                && target_membership != "ban"
+                && sender_level >= named_level(power_levels, "invite", 0)
+        }
+        Some("leave") => {
+            if pdu.sender == target {
+                return target_membership == "join"
+                    || target_membership == "invite"
+                    || target_membership == "knock";
+            }
+
+            sender_membership == "join"
+                && (target_membership != "ban"
+                    || sender_level >= named_level(power_levels, "ban", 50))
+                && sender_level >= named_level(power_levels, "kick", 50)
+                && target_level < sender_level
+        }
+        Some("ban") => {
+            sender_membership == "join"
+                && sender_level >= named_level(power_levels, "ban", 50)
+                && target_level < sender_level
+        }
+        Some("knock") => {
+            (join_rule == "knock" || join_rule == "knock_restricted")
+                && pdu.sender == target
+                && target_membership != "ban"
+                && target_membership != "invite"
+                && target_membership != "join"
+        }
+        _ => false,
+    }
+}
+
+/// The sender can only change levels that are not higher than their own, before and after the
+/// change, and can't change the levels of other users with the same level.
+fn power_levels_change_allowed(
+    current: Option<&PduEvent>,
+    new_pdu: &PduEvent,
+    sender_level: i64,
+    check_notifications: bool,
+) -> bool {
+    let current = match current {
+        Some(current) => &current.content,
+        // The first power levels event can set any levels
+        None => return true,
+    };
+    let new = &new_pdu.content;
+
+    let too_high = |old: Option<i64>, new: Option<i64>| {
+        old != new
+            && (old.map_or(false, |level| level > sender_level)
+                || new.map_or(false, |level| level > sender_level))
+    };
+
+    for key in &[
+        "users_default",
+        "events_default",
+        "state_default",
+        "ban",
+        "redact",
+        "kick",
+        "invite",
+    ] {
+        if too_high(
+            current.get(*key).and_then(level),
+            new.get(*key).and_then(level),
+        ) {
+            return false;
+        }
+    }
+
+    let map_keys = |content: &Value, key: &str| {
+        content
+            .get(key)
+            .and_then(|map| map.as_object())
+            .map(|map| map.keys().cloned().collect::<Vec<_>>())
+            .unwrap_or_default()
+    };
+    let map_level = |content: &Value, key: &str, entry: &str| {
+        content
+            .get(key)
+            .and_then(|map| map.get(entry))
+            .and_then(level)
+    };
+
+    for event_type in map_keys(current, "events")
+        .into_iter()
+        .chain(map_keys(new, "events"))
+    {
+        if too_high(
+            map_level(current, "events", &event_type),
+            map_level(new, "events", &event_type),
+        ) {
+            return false;
+        }
+    }
+
+    if check_notifications {
+        for key in map_keys(current, "notifications")
+            .into_iter()
+            .chain(map_keys(new, "notifications"))
+        {
+            if too_high(
+                map_level(current, "notifications", &key),
+                map_level(new, "notifications", &key),
+            ) {
+                return false;
+            }
+        }
+    }
+
+    for user_id in map_keys(current, "users")
+        .into_iter()
+        .chain(map_keys(new, "users"))
+    {
+        let old_level = map_level(current, "users", &user_id);
+        let new_level = map_level(new, "users", &user_id);
+        if too_high(old_level, new_level) {
+            return false;
+        }
+
+        // Users with the same level can't demote each other
+        if user_id != new_pdu.sender.as_str()
+            && old_level != new_level
+            && old_level.map_or(false, |level| level >= sender_level)
+        {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Checks the `third_party_invite` of an invite against the `m.room.third_party_invite` event
+/// with the token of the invite: The identity server must have signed the user id and the token
+/// with one of the public keys of the event and the sender must be the same.
+pub fn third_party_invite_allowed(
+    target: &UserId,
+    sender: &UserId,
+    third_party_invite: &Value,
+    invite_event: Option<(&UserId, &Value)>,
+) -> bool {
+    let (invite_sender, invite_content) = match invite_event {
+        Some(invite_event) => invite_event,
+        None => return false,
+    };
+
+    let signed = match third_party_invite.get("signed") {
+        Some(signed) => signed,
+        None => return false,
+    };
+    if signed.get("mxid").and_then(|mxid| mxid.as_str()) != Some(target.as_str())
+        || signed
+            .get("token")
+            .and_then(|token| token.as_str())
+            .is_none()
+        || sender != invite_sender
+    {
+        return false;
+    }
+
+    let public_keys = invite_content
+        .get("public_key")
+        .into_iter()
+        .chain(
+            invite_content
+                .get("public_keys")
+                .and_then(|keys| keys.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|key| key.get("public_key")),
+        )
+        .filter_map(|key| key.as_str())
+        .collect::<Vec<_>>();
+
+    // Any signature of the identity server with any of the keys is enough
+    signed
+        .get("signatures")
+        .and_then(|signatures| signatures.as_object())
+        .into_iter()
+        .flatten()
+        .flat_map(|(server, signatures)| {
+            signatures
+                .as_object()
+                .into_iter()
+                .flatten()
+                .map(move |(key_id, _)| (server, key_id))
+        })
+        .any(|(server, key_id)| {
+            public_keys.iter().any(|public_key| {
+                let mut public_key_map = ruma::signatures::PublicKeyMap::new();
+                public_key_map.insert(
+                    server.clone(),
+                    vec![(key_id.clone(), (*public_key).to_owned())]
+                        .into_iter()
+                        .collect(),
+                );
+                ruma::signatures::verify_json(&public_key_map, signed).is_ok()
+            })
+        })
+}
+
+fn membership<'a>(
+    auth_state: &HashMap<(EventType, String), &'a PduEvent>,
+    user_id: &str,
+) -> &'a str {
+    auth_state
+        .get(&(EventType::RoomMember, user_id.to_owned()))
+        .copied()
+        .and_then(|member| member.content.get("membership"))
+        .and_then(|membership| membership.as_str())
+        .unwrap_or("leave")
+}
+
+/// Version 1 rooms may not have a version in their create event.
+fn room_version(create: &PduEvent) -> RoomVersionId {
+    create
+        .content
+        .get("room_version")
+        .and_then(|version| version.as_str())
+        .and_then(|version| RoomVersionId::try_from(version).ok())
+        .unwrap_or(RoomVersionId::Version1)
+}
+
+fn creator(create: &PduEvent) -> UserId {
+    create
+        .content
+        .get("creator")
+        .and_then(|creator| creator.as_str())
+        .and_then(|creator| UserId::try_from(creator).ok())
+        .unwrap_or_else(|| create.sender.clone())
+}
+
+fn user_level(power_levels: Option<&PduEvent>, create: Option<&PduEvent>, user_id: &UserId) -> i64 {
+    match power_levels {
+        Some(power_levels) => power_levels
+            .content
+            .get("users")
+            .and_then(|users| users.get(user_id.as_str()))
+            .and_then(level)
+            .unwrap_or_else(|| named_level(Some(power_levels), "users_default", 0)),
+        // Without power levels, the creator has all permissions
+        None => {
+            if create.map_or(false, |create| &creator(create) == user_id) {
+                100
+            } else {
+                0
+            }
+        }
+    }
+}
+
+fn event_level(power_levels: Option<&PduEvent>, event_type: &EventType, is_state: bool) -> i64 {
+    let power_levels = match power_levels {
+        Some(power_levels) => power_levels,
+        None => return 0,
+    };
+
+    power_levels
+        .content
+        .get("events")
+        .and_then(|events| events.get(event_type.to_string()))
+        .and_then(level)
+        .unwrap_or_else(|| {
+            if is_state {
+                named_level(Some(power_levels), "state_default", 50)
+            } else {
+                named_level(Some(power_levels), "events_default", 0)
+            }
+        })
+}
+
+fn named_level(power_levels: Option<&PduEvent>, key: &str, default: i64) -> i64 {
+    power_levels
+        .and_then(|power_levels| power_levels.content.get(key))
+        .and_then(level)
+        .unwrap_or(default)
+}
+
+/// Checks that all levels of the power levels content are integers and not strings.
+fn only_integer_levels(content: &Value) -> bool {
+    let is_integer = |value: &Value| value.is_i64();
+    let map_is_integer = |key: &str| {
+        content
+            .get(key)
+            .and_then(|map| map.as_object())
+            .map_or(true, |map| map.values().all(is_integer))
+    };
+
+    [
+        "users_default",
+        "events_default",
+        "state_default",
+        "ban",
+        "redact",
+        "kick",
+        "invite",
+    ]
+    .iter()
+    .all(|key| content.get(*key).map_or(true, is_integer))
+        && map_is_integer("events")
+        && map_is_integer("users")
+        && map_is_integer("notifications")
+}
+
+/// Levels are integers, but older rooms can contain them as strings.
+fn level(value: &Value) -> Option<i64> {
+    value
+        .as_i64()
+        .or_else(|| value.as_str().and_then(|level| level.parse().ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{allowed_by_auth_events, allowed_by_state, resolve, StateMap};
+    use crate::PduEvent;
+    use ruma::{events::EventType, EventId, RoomVersionId};
+    use serde_json::{json, Value};
+    use std::{
+        collections::{HashMap, HashSet},
+        convert::TryFrom,
+    };
+
+    fn event_id(name: &str) -> EventId {
+        EventId::try_from(format!("${}:foo", name).as_str()).unwrap()
+    }
+
+    fn user_id(name: &str) -> String {
+        format!("@{}:foo", name)
+    }
+
+    /// A room graph like in the examples of the spec. Events are named, auth and prev events are
+    /// separated by spaces. The state after every event is resolved from the states after its
+    /// prev events.
+    #[derive(Default)]
+    struct Room {
+        events: HashMap<EventId, PduEvent>,
+        states: HashMap<EventId, StateMap<EventId>>,
+    }
+
+    impl Room {
+        /// A public room of alice that bob, charlie and zara joined. The last event is `IMZ`.
+        fn new() -> Self {
+            let mut room = Room::default();
+            let creator = json!({ "creator": user_id("alice") });
+            room.state("CREATE", "alice", "m.room.create", creator, "", "");
+            room.join("IMA", "alice", "CREATE", "CREATE");
+            room.power_levels("IPOWER", "alice", &[("alice", 100)], "CREATE IMA", "IMA");
+            let public = json!({ "join_rule": "public" });
+            room.state(
+                "IJR",
+                "alice",
+                "m.room.join_rules",
+                public,
+                "CREATE IMA IPOWER",
+                "IPOWER",
+            );
+            room.join("IMB", "bob", "CREATE IJR IPOWER", "IJR");
+            room.join("IMC", "charlie", "CREATE IJR IPOWER", "IMB");
+            room.join("IMZ", "zara", "CREATE IJR IPOWER", "IMC");
+            room
+        }
+
+        fn join(&mut self, name: &str, user: &str, auth_events: &str, prev_events: &str) {
+            self.member(name, user, user, "join", auth_events, prev_events);
+        }
+
+        fn member(
+            &mut self,
+            name: &str,
+            sender: &str,
+            target: &str,
+            membership: &str,
+            auth_events: &str,
+            prev_events: &str,
+        ) {
+            let content = json!({ "membership": membership });
+            let kind = "m.room.member";
+            self.add(
+                name,
+                sender,
+                kind,
+                &user_id(target),
+                content,
+                auth_events,
+                prev_events,
+            );
+        }
+
+        fn power_levels(
+            &mut self,
+            name: &str,
+            sender: &str,
+            users: &[(&str, i64)],
+            auth_events: &str,
+            prev_events: &str,
+        ) {
+            let users = users
+                .iter()
+                .map(|(user, level)| (user_id(user), Value::from(*level)))
+                .collect::<serde_json::Map<_, _>>();
+            let content = json!({ "users": users });
+            let kind = "m.room.power_levels";
+            self.add(name, sender, kind, "", content, auth_events, prev_events);
+        }
+
+        fn state(
+            &mut self,
+            name: &str,
+            sender: &str,
+            kind: &str,
+            content: Value,
+            auth_events: &str,
+            prev_events: &str,
+        ) {
+            self.add(name, sender, kind, "", content, auth_events, prev_events);
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn add(
+            &mut self,
+            name: &str,
+            sender: &str,
+            kind: &str,
+            state_key: &str,
+            content: Value,
+            auth_events: &str,
+            prev_events: &str,
+        ) {
+            let auth_events = auth_events
+                .split_whitespace()
+                .map(event_id)
+                .collect::<Vec<_>>();
+            let prev_events = prev_events
+                .split_whitespace()
+                .map(event_id)
+                .collect::<Vec<_>>();
+            let depth = prev_events
+                .iter()
+                .map(|prev| u64::from(self.events[prev].depth) + 1)
+                .max()
+                .unwrap_or(0);
+
+            let pdu = serde_json::from_value::<PduEvent>(json!({
+                "event_id": event_id(name),
+                "room_id": "!room:foo",
+                "sender": user_id(sender),
+                "origin": "foo",
+                "origin_server_ts": self.events.len(),
+                "type": kind,
+                "content": content,
+                "state_key": state_key,
+                "prev_events": prev_events,
+                "depth": depth,
+                "auth_events": auth_events,
+                "hashes": { "sha256": "" },
+                "signatures": {},
+            }))
+            .unwrap();
+
+            let mut state = match prev_events.len() {
+                0 => StateMap::new(),
+                1 => self.states[&prev_events[0]].clone(),
+                _ => self.state_after(&prev_events),
+            };
+            state.insert(
+                (pdu.kind.clone(), state_key.to_owned()),
+                pdu.event_id.clone(),
+            );
+
+            self.states.insert(pdu.event_id.clone(), state);
+            self.events.insert(pdu.event_id.clone(), pdu);
+        }
+
+        /// Resolves the states after the events, like for an event with them as prev events.
+        fn state_after(&self, prev_events: &[EventId]) -> StateMap<EventId> {
+            let state_sets = prev_events
+                .iter()
+                .map(|prev| self.states[prev].clone())
+                .collect::<Vec<_>>();
+            let auth_chains = state_sets
+                .iter()
+                .map(|state_set| {
+                    let mut auth_chain = HashSet::new();
+                    let mut stack = state_set
+                        .values()
+                        .flat_map(|event_id| self.events[event_id].auth_events.clone())
+                        .collect::<Vec<_>>();
+                    while let Some(event_id) = stack.pop() {
+                        if auth_chain.insert(event_id.clone()) {
+                            stack.extend(self.events[&event_id].auth_events.clone());
+                        }
+                    }
+                    auth_chain
+                })
+                .collect::<Vec<_>>();
+
+            resolve(
+                &RoomVersionId::Version6,
+                &state_sets,
+                &auth_chains,
+                &self.events,
+            )
+            .unwrap()
+        }
+
+        fn resolved(&self, prev_events: &str) -> StateMap<EventId> {
+            let prev_events = prev_events
+                .split_whitespace()
+                .map(event_id)
+                .collect::<Vec<_>>();
+            self.state_after(&prev_events)
+        }
+    }
+
+    /// A room of alice with the join rule and a knock of bob, `KB`.
+    fn knocked_room(room_version: &str, join_rule: &str) -> Room {
+        let mut room = Room::default();
+        let create = json!({ "creator": user_id("alice"), "room_version": room_version });
+        room.state("CREATE", "alice", "m.room.create", create, "", "");
+        room.join("IMA", "alice", "CREATE", "CREATE");
+        let join_rule = json!({ "join_rule": join_rule });
+        let kind = "m.room.join_rules";
+        room.state("IJR", "alice", kind, join_rule, "CREATE IMA", "IMA");
+        room.member("KB", "bob", "bob", "knock", "CREATE IJR", "IJR");
+        room
+    }
+
+    /// A public room of alice where bob has level 50 and notifications.room needs level 75. The
+    /// last event is `IMB`.
+    fn room_with_levels(room_version: &str) -> Room {
+        let mut room = Room::default();
+        let create = json!({ "creator": user_id("alice"), "room_version": room_version });
+        room.state("CREATE", "alice", "m.room.create", create, "", "");
+        room.join("IMA", "alice", "CREATE", "CREATE");
+        let levels = json!({
+            "users": { user_id("alice"): 100, user_id("bob"): 50 },
+            "notifications": { "room": 75 },
+        });
+        let kind = "m.room.power_levels";
+        room.state("IPOWER", "alice", kind, levels, "CREATE IMA", "IMA");
+        let public = json!({ "join_rule": "public" });
+        let kind = "m.room.join_rules";
+        room.state("IJR", "alice", kind, public, "CREATE IMA IPOWER", "IPOWER");
+        room.join("IMB", "bob", "CREATE IJR IPOWER", "IJR");
+        room
+    }
+
+    fn get<'a>(state: &'a StateMap<EventId>, kind: EventType, state_key: &str) -> Option<&'a str> {
+        state
+            .get(&(kind, state_key.to_owned()))
+            .map(|event_id| event_id.as_str())
+    }
+
+    #[test]
+    fn ban_wins_against_power_levels_of_the_banned_user() {
+        let mut room = Room::new();
+        let levels = &[("alice", 100), ("bob", 50)];
+        room.power_levels("PA", "alice", levels, "CREATE IMA IPOWER", "IMZ");
+        room.join("MA", "alice", "CREATE IJR IMA PA", "PA");
+        room.member("MB", "alice", "bob", "ban", "CREATE MA IMB PA", "MA");
+        room.power_levels("PB", "bob", levels, "CREATE IMB PA", "PA");
+
+        let state = room.resolved("MB PB");
+
+        assert_eq!(get(&state, EventType::RoomPowerLevels, ""), Some("$PA:foo"));
+        assert_eq!(
+            get(&state, EventType::RoomMember, "@alice:foo"),
+            Some("$MA:foo")
+        );
+        assert_eq!(
+            get(&state, EventType::RoomMember, "@bob:foo"),
+            Some("$MB:foo")
+        );
+    }
+
+    #[test]
+    fn topic_of_a_demoted_user_loses() {
+        let mut room = Room::new();
+        let topic = "m.room.topic";
+        room.state("T1", "alice", topic, json!({}), "CREATE IMA IPOWER", "IMZ");
+        let levels = &[("alice", 100), ("bob", 50)];
+        room.power_levels("PA1", "alice", levels, "CREATE IMA IPOWER", "T1");
+        room.state("T2", "alice", topic, json!({}), "CREATE IMA PA1", "PA1");
+        let demoted = &[("alice", 100), ("bob", 0)];
+        room.power_levels("PA2", "alice", demoted, "CREATE IMA PA1", "T2");
+        room.power_levels("PB", "bob", levels, "CREATE IMB PA1", "PA1");
+        room.state("T3", "bob", topic, json!({}), "CREATE IMB PB", "PB");
+
+        let state = room.resolved("PA2 T3");
+
+        assert_eq!(
+            get(&state, EventType::RoomPowerLevels, ""),
+            Some("$PA2:foo")
+        );
+        assert_eq!(get(&state, EventType::RoomTopic, ""), Some("$T2:foo"));
+    }
+
+    #[test]
+    fn join_after_the_room_became_private_is_rejected() {
+        let mut room = Room::new();
+        let invite = json!({ "join_rule": "invite" });
+        room.state(
+            "JR",
+            "alice",
+            "m.room.join_rules",
+            invite,
+            "CREATE IMA IPOWER",
+            "IMZ",
+        );
+        room.join("ME", "ella", "CREATE IJR IPOWER", "IMZ");
+
+        let state = room.resolved("JR ME");
+
+        assert_eq!(get(&state, EventType::RoomJoinRules, ""), Some("$JR:foo"));
+        assert_eq!(get(&state, EventType::RoomMember, "@ella:foo"), None);
+    }
+
+    #[test]
+    fn power_levels_of_a_linear_history_are_applied_in_order() {
+        let mut room = Room::new();
+        let levels = &[("alice", 100), ("bob", 50)];
+        room.power_levels("PA", "alice", levels, "CREATE IMA IPOWER", "IMZ");
+        let levels = &[("alice", 100), ("bob", 50), ("charlie", 50)];
+        room.power_levels("PB", "bob", levels, "CREATE IMB PA", "PA");
+        let levels = &[("alice", 100), ("bob", 50), ("charlie", 0)];
+        room.power_levels("PC", "charlie", levels, "CREATE IMC PB", "PB");
+
+        let state = room.resolved("PC PA");
+
+        assert_eq!(get(&state, EventType::RoomPowerLevels, ""), Some("$PC:foo"));
+    }
+
+    #[test]
+    fn state_without_conflicts_is_kept() {
+        let room = Room::new();
+
+        assert_eq!(room.resolved("IMZ IMZ"), room.states[&event_id("IMZ")]);
+    }
+
+    #[test]
+    fn join_rules_need_the_room_version_that_added_them() {
+        let cases = &[
+            ("6", "knock", false),
+            ("7", "knock", true),
+            ("10", "knock", true),
+            ("9", "knock_restricted", false),
+            ("10", "knock_restricted", true),
+        ];
+        for (room_version, join_rule, allowed) in cases {
+            let room = knocked_room(room_version, join_rule);
+            let knock = &room.events[&event_id("KB")];
+
+            assert_eq!(
+                allowed_by_auth_events(knock, &room.events),
+                *allowed,
+                "{} in room version {}",
+                join_rule,
+                room_version
+            );
+        }
+    }
+
+    #[test]
+    fn messages_of_banned_users_are_soft_failed() {
+        let mut room = Room::new();
+        room.member("BAN", "alice", "bob", "ban", "CREATE IMA IPOWER IMB", "IMZ");
+        // Bob's server hasn't seen the ban yet
+        for (name, sender, auth_events) in &[
+            ("MB", "bob", "CREATE IPOWER IMB"),
+            ("MC", "charlie", "CREATE IPOWER IMC"),
+        ] {
+            let content = json!({ "msgtype": "m.text", "body": "Hello" });
+            room.state(name, sender, "m.room.message", content, auth_events, "IMZ");
+            room.events.get_mut(&event_id(name)).unwrap().state_key = None;
+        }
+
+        let current_state = room.states[&event_id("BAN")]
+            .iter()
+            .map(|(key, event_id)| (key.clone(), room.events[event_id].clone()))
+            .collect::<StateMap<_>>();
+        let message = &room.events[&event_id("MB")];
+        assert!(allowed_by_auth_events(message, &room.events));
+        assert!(!allowed_by_state(message, &current_state));
+
+        let message = &room.events[&event_id("MC")];
+        assert!(allowed_by_auth_events(message, &room.events));
+        assert!(allowed_by_state(message, &current_state));
+    }
+
+    #[test]
+    fn room_version_1_is_not_supported() {
+        let room = Room::new();
+        let state_set = room.states[&event_id("IMZ")].clone();
+
+        assert!(resolve(&RoomVersionId::Version1, &[state_set], &[], &room.events).is_err());
+    }
+
+    #[test]
+    fn members_of_allowed_rooms_join_knock_restricted_rooms_directly() {
+        let mut room = knocked_room("10", "knock_restricted");
+        let authorised_by = |authoriser: &str| {
+            json!({
+                "membership": "join",
+                "join_authorised_via_users_server": user_id(authoriser),
+            })
+        };
+        let kind = "m.room.member";
+        let bob = &user_id("bob");
+        let auth_events = "CREATE IMA IJR KB";
+        room.add(
+            "JA",
+            "bob",
+            kind,
+            bob,
+            authorised_by("alice"),
+            auth_events,
+            "KB",
+        );
+        room.add(
+            "JC",
+            "bob",
+            kind,
+            bob,
+            authorised_by("charlie"),
+            auth_events,
+            "KB",
+        );
+        let content = json!({ "membership": "join" });
+        room.add("J", "bob", kind, bob, content, auth_events, "KB");
+
+        // The server of alice checked that bob is in an allowed room
+        assert!(allowed_by_auth_events(
+            &room.events[&event_id("JA")],
+            &room.events
+        ));
+        // Charlie isn't in the room and can't authorise joins
+        assert!(!allowed_by_auth_events(
+            &room.events[&event_id("JC")],
+            &room.events
+        ));
+        // Without an authorising user bob can only knock
+        assert!(!allowed_by_auth_events(
+            &room.events[&event_id("J")],
+            &room.events
+        ));
+        assert!(allowed_by_auth_events(
+            &room.events[&event_id("KB")],
+            &room.events
+        ));
+    }
+
+    #[test]
+    fn power_levels_of_room_version_10_are_integers() {
+        for (room_version, allowed) in &[("6", true), ("10", false)] {
+            let mut room = room_with_levels(room_version);
+            let levels = json!({ "users": { user_id("alice"): "100", user_id("bob"): 50 } });
+            let kind = "m.room.power_levels";
+            room.state("PA", "alice", kind, levels, "CREATE IMA IPOWER", "IMB");
+
+            assert_eq!(
+                allowed_by_auth_events(&room.events[&event_id("PA")], &room.events),
+                *allowed,
+                "room version {}",
+                room_version
+            );
+        }
+    }
+
+    #[test]
+    fn notification_levels_of_room_version_10_are_protected() {
+        for (room_version, allowed) in &[("6", true), ("10", false)] {
+            let mut room = room_with_levels(room_version);
+            let levels = json!({
+                "users": { user_id("alice"): 100, user_id("bob"): 50 },
+                "notifications": { "room": 50 },
+            });
+            let kind = "m.room.power_levels";
+            room.state("PB", "bob", kind, levels, "CREATE IMB IPOWER", "IMB");
+
+            assert_eq!(
+                allowed_by_auth_events(&room.events[&event_id("PB")], &room.events),
+                *allowed,
+                "room version {}",
+                room_version
+            );
+        }
+    }
+}
@@ -1,10 +1,15 @@
-use argon2::{Config, Variant};
+use argon2::Config;
 use cmp::Ordering;
 use rand::prelude::*;
 use sled::IVec;
 use std::{
     cmp,
     convert::TryInto,
+    future::Future,
+    io,
+    net::{IpAddr, ToSocketAddrs},
+    pin::Pin,
+    task::{Context, Poll},
     time::{SystemTime, UNIX_EPOCH},
 };
 
@@ -15,18 +20,6 @@ pub fn millis_since_unix_epoch() -> u64 {
         .as_millis() as u64
 }
 
-pub fn increment(old: Option<&[u8]>) -> Option<Vec<u8>> {
-    let number = match old.map(|bytes| bytes.try_into()) {
-        Some(Ok(bytes)) => {
-            let number = u64::from_be_bytes(bytes);
-            number + 1
-        }
-        _ => 1, // Start at one. since 0 should return the first event in the db
-    };
-
-    Some(number.to_be_bytes().to_vec())
-}
-
 pub fn generate_keypair(old: Option<&[u8]>) -> Option<Vec<u8>> {
     Some(old.map(|s| s.to_vec()).unwrap_or_else(|| {
         ruma::signatures::Ed25519KeyPair::generate()
@@ -53,14 +46,29 @@ pub fn random_string(length: usize) -> String {
 }
 
 /// Calculate a new hash for the given password
-pub fn calculate_hash(password: &str) -> Result<String, argon2::Error> {
-    let hashing_config = Config {
-        variant: Variant::Argon2id,
-        ..Default::default()
-    };
-
+pub fn calculate_hash(password: &str, config: &Config<'_>) -> Result<String, argon2::Error> {
     let salt = random_string(32);
-    argon2::hash_encoded(password.as_bytes(), salt.as_bytes(), &hashing_config)
+    argon2::hash_encoded(password.as_bytes(), salt.as_bytes(), config)
+}
+
+/// Checks the password against an argon2 hash, other hashes never match.
+pub fn verify_hash(hash: &str, password: &str) -> bool {
+    hash.starts_with("$argon2")
+        && argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false)
+}
+
+/// Checks if the hash was calculated with other parameters than new hashes, for example by an
+/// older version or with another cost. Such hashes should be replaced after the next successful
+/// login.
+pub fn hash_is_outdated(hash: &str, config: &Config<'_>) -> bool {
+    !hash.starts_with(&format!(
+        "${}$v={}$m={},t={},p={}$",
+        config.variant.as_lowercase_str(),
+        config.version.as_u32(),
+        config.mem_cost,
+        config.time_cost,
+        config.lanes
+    ))
 }
 
 pub fn common_elements(
@@ -90,3 +98,198 @@ pub fn common_elements(
             .all(|b| b)
     }))
 }
+
+/// Matches the text against a glob where `*` matches any number of characters and `?` matches a
+/// single character. Case is ignored.
+pub fn glob_matches(glob: &str, text: &str) -> bool {
+    let glob = glob.to_lowercase().chars().collect::<Vec<_>>();
+    let text = text.to_lowercase().chars().collect::<Vec<_>>();
+
+    // Position after the last `*` in the glob and the text position it was tried at, so we can
+    // backtrack and let the star match one more character
+    let (mut g, mut t) = (0, 0);
+    let mut star = None;
+
+    while t < text.len() {
+        if g < glob.len() && (glob[g] == '?' || glob[g] == text[t]) {
+            g += 1;
+            t += 1;
+        } else if g < glob.len() && glob[g] == '*' {
+            star = Some((g + 1, t));
+            g += 1;
+        } else if let Some((star_g, star_t)) = star {
+            g = star_g;
+            t = star_t + 1;
+            star = Some((star_g, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+/// Encodes all characters that are not allowed in a query parameter of an url.
+pub fn percent_encode(input: &str) -> String {
+    input
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Checks if the address is reachable from the internet, all private, loopback, link-local and
+/// reserved ranges are rejected.
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || octets[0] == 0
+                || (octets[0] == 100 && octets[1] & 0xc0 == 64) // Shared address space
+                || (octets[0] == 198 && octets[1] & 0xfe == 18) // Benchmarking
+                || octets[0] >= 240)
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || segments[0] & 0xfe00 == 0xfc00 // Unique local
+                || segments[0] & 0xffc0 == 0xfe80 // Link local
+                || (segments[0] == 0x2001 && segments[1] == 0x0db8)) // Documentation
+                // IPv4 addresses in IPv6 have to be public IPv4 addresses
+                && ip.to_ipv4().map_or(true, |ip| is_public_ip(IpAddr::V4(ip)))
+        }
+    }
+}
+
+/// The client for url previews. It doesn't follow redirects.
+pub type UrlPreviewClient = hyper::Client<
+    hyper_tls::HttpsConnector<hyper::client::HttpConnector<PublicResolver>>,
+    hyper::Body,
+>;
+
+/// Resolves host names with the system resolver, but fails with `PermissionDenied` if any address
+/// is not public. The client connects to exactly the addresses that were checked, so a host can't
+/// resolve to a private address after the check.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PublicResolver;
+
+impl tower_service::Service<hyper::client::connect::dns::Name> for PublicResolver {
+    type Response = std::vec::IntoIter<IpAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: hyper::client::connect::dns::Name) -> Self::Future {
+        let host = name.as_str().to_owned();
+        Box::pin(async move {
+            let addresses =
+                tokio::task::spawn_blocking(move || (host.as_str(), 0).to_socket_addrs())
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))??
+                    .map(|address| address.ip())
+                    .collect::<Vec<_>>();
+
+            if addresses.is_empty() || !addresses.iter().all(|ip| is_public_ip(*ip)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "The host resolves to a private address.",
+                ));
+            }
+
+            Ok(addresses.into_iter())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{calculate_hash, glob_matches, hash_is_outdated, verify_hash};
+    use argon2::{Config, Variant};
+
+    /// Cheap parameters, the tests don't need secure hashes
+    fn new_hashing() -> Config<'static> {
+        Config {
+            variant: Variant::Argon2id,
+            mem_cost: 64,
+            time_cost: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn glob_without_wildcards_matches_exactly() {
+        assert!(glob_matches("example.org", "example.org"));
+        assert!(glob_matches("Example.ORG", "example.org"));
+        assert!(!glob_matches("example.org", "example.org.evil.com"));
+        assert!(!glob_matches("example.org", "example.or"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character() {
+        assert!(glob_matches("ex?mple.org", "example.org"));
+        assert!(!glob_matches("ex?mple.org", "exmple.org"));
+        assert!(!glob_matches("ex?mple.org", "exaample.org"));
+    }
+
+    #[test]
+    fn star_matches_any_number_of_characters() {
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("*", "example.org"));
+        assert!(glob_matches("*.org", "example.org"));
+        assert!(glob_matches("*.org", ".org"));
+        assert!(glob_matches("a*b*c", "aXbYbZc"));
+        assert!(glob_matches("*ab", "aab"));
+        assert!(!glob_matches("*.org", "example.com"));
+        assert!(!glob_matches("a*b*c", "aXbYbZ"));
+    }
+
+    #[test]
+    fn outdated_password_hashes_still_verify_and_are_replaced() {
+        // Older versions hashed with argon2i and the default cost
+        let old_hash = calculate_hash("hunter2", &Config::default()).unwrap();
+        assert!(old_hash.starts_with("$argon2i$"));
+        assert!(verify_hash(&old_hash, "hunter2"));
+        assert!(!verify_hash(&old_hash, "hunter3"));
+        assert!(hash_is_outdated(&old_hash, &new_hashing()));
+
+        // Another cost is outdated too
+        let cheaper = Config {
+            mem_cost: 32,
+            ..new_hashing()
+        };
+        let cheaper_hash = calculate_hash("hunter2", &cheaper).unwrap();
+        assert!(hash_is_outdated(&cheaper_hash, &new_hashing()));
+
+        let new_hash = calculate_hash("hunter2", &new_hashing()).unwrap();
+        assert!(new_hash.starts_with("$argon2id$"));
+        assert!(verify_hash(&new_hash, "hunter2"));
+        assert!(!hash_is_outdated(&new_hash, &new_hashing()));
+    }
+
+    #[test]
+    fn other_password_hash_formats_never_match() {
+        assert!(!verify_hash("", ""));
+        assert!(!verify_hash("hunter2", "hunter2"));
+        assert!(!verify_hash(
+            "$2b$12$GhvMmNVjRW29ulnudl.LbuAnUtN/LRfe1JsBm1Xu6LE3059z5Tr8m",
+            "hunter2"
+        ));
+        assert!(!verify_hash("$argon2id$broken", "hunter2"));
+    }
+}